@@ -0,0 +1,55 @@
+use std::mem::size_of;
+
+use bytemuck::{bytes_of_mut, Zeroable};
+use solana_program::pubkey::Pubkey;
+
+use crate::{
+    error::{check_assert, QuasarErrorCode, QuasarResult, SourceFileId},
+    state::{BaseToken, LeverageToken, QuasarGroup, RedeemRequest, MAX_REDEEM_REQUESTS},
+};
+
+declare_check_assert_macros!(SourceFileId::Client);
+
+impl QuasarGroup {
+    /// Decode a group from the data of its account, with the checks of `load_checked` but the
+    /// owner's, which the client checks on the account it fetched. The data is copied out, RPC
+    /// buffers being no more aligned than the runtime's
+    pub fn unpack_from_slice(data: &[u8]) -> QuasarResult<Self> {
+        check_eq!(
+            data.len(),
+            size_of::<Self>(),
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let mut quasar_group = Self::zeroed();
+        bytes_of_mut(&mut quasar_group).copy_from_slice(data);
+        quasar_group.check_header()?;
+        quasar_group.check_version()?;
+
+        Ok(quasar_group)
+    }
+
+    pub fn leverage_token_by_mint(&self, mint: &Pubkey) -> Option<&LeverageToken> {
+        self.find_leverage_token_index_by_mint(mint)
+            .map(|i| &self.leverage_tokens[i])
+    }
+
+    pub fn base_token_by_mint(&self, mint: &Pubkey) -> Option<&BaseToken> {
+        self.find_base_token_index(mint)
+            .map(|i| &self.base_tokens[i])
+    }
+
+    /// Queued redeems of the leverage token at `token_index`, oldest first as
+    /// `ProcessRedeemQueue` settles them. Those a group queued before version 5 that didn't fit
+    /// the token's queue yet come last
+    pub fn redeem_requests(&self, token_index: usize) -> impl Iterator<Item = &RedeemRequest> {
+        let mint = self.leverage_tokens[token_index].mint;
+        let legacy = (0..self.legacy_redeem_queue_len as usize)
+            .map(move |i| {
+                &self.legacy_redeem_queue
+                    [(self.legacy_redeem_queue_head as usize + i) % MAX_REDEEM_REQUESTS]
+            })
+            .filter(move |request| request.mint == mint);
+        self.redeem_queues[token_index].iter().chain(legacy)
+    }
+}
@@ -10,8 +10,10 @@ fn process_instruction(
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
+    // Panics abort the program on chain and can't be caught here, every handled failure comes
+    // back as a QuasarError
     Processor::process(program_id, accounts, instruction_data).map_err(|e| {
-        msg!("{}", e); // log the error
-        e.into() // convert MangoError to generic ProgramError
+        msg!("{}", e); // log the error code name and where it was raised
+        e.into() // QuasarErrorCode becomes ProgramError::Custom(code), the rest pass through
     })
 }
@@ -1,7 +1,7 @@
 use thiserror::Error;
 
 use bytemuck::Contiguous;
-use solana_program::program_error::ProgramError;
+use solana_program::{msg, program_error::ProgramError};
 
 use mango;
 use num_enum::IntoPrimitive;
@@ -14,6 +14,10 @@ pub enum SourceFileId {
     Processor = 0,
     State = 1,
     Oracle = 2,
+    Math = 3,
+    Utils = 4,
+    Events = 5,
+    Client = 6,
 }
 
 impl std::fmt::Display for SourceFileId {
@@ -22,6 +26,10 @@ impl std::fmt::Display for SourceFileId {
             SourceFileId::Processor => write!(f, "src/processor.rs"),
             SourceFileId::State => write!(f, "src/state.rs"),
             SourceFileId::Oracle => write!(f, "src/oracle.rs"),
+            SourceFileId::Math => write!(f, "src/math.rs"),
+            SourceFileId::Utils => write!(f, "src/utils.rs"),
+            SourceFileId::Events => write!(f, "src/events.rs"),
+            SourceFileId::Client => write!(f, "src/client.rs"),
         }
     }
 }
@@ -71,6 +79,110 @@ pub enum QuasarErrorCode {
     InvalidAccount,
     #[error("QuasarErrorCode::SignerNecessary")]
     SignerNecessary,
+    #[error("QuasarErrorCode::MangoCacheStale")]
+    MangoCacheStale,
+    #[error("QuasarErrorCode::InitialMintTooSmall")]
+    InitialMintTooSmall,
+    #[error("QuasarErrorCode::TokenSupplyNonzero")]
+    TokenSupplyNonzero,
+    #[error("QuasarErrorCode::OpenPositionRemaining")]
+    OpenPositionRemaining,
+    #[error("QuasarErrorCode::MathOverflow")]
+    MathOverflow,
+    #[error("QuasarErrorCode::GroupAlreadyInitialized")]
+    GroupAlreadyInitialized,
+    #[error("QuasarErrorCode::DuplicateBaseToken")]
+    DuplicateBaseToken,
+    #[error("QuasarErrorCode::DuplicateLeverageToken")]
+    DuplicateLeverageToken,
+    #[error("QuasarErrorCode::SlotNotEmpty")]
+    SlotNotEmpty,
+    #[error("QuasarErrorCode::AdminSignatureMissing")]
+    AdminSignatureMissing,
+    #[error("QuasarErrorCode::InsufficientHealth")]
+    InsufficientHealth,
+    #[error("QuasarErrorCode::TokenHealthy")]
+    TokenHealthy,
+    #[error("QuasarErrorCode::InvalidQuoteMint")]
+    InvalidQuoteMint,
+    #[error("QuasarErrorCode::RebalanceTooSoon")]
+    RebalanceTooSoon,
+    #[error("QuasarErrorCode::InsufficientRent")]
+    InsufficientRent,
+    #[error("QuasarErrorCode::DepositCapExceeded")]
+    DepositCapExceeded,
+    #[error("QuasarErrorCode::UnsupportedOutputMint")]
+    UnsupportedOutputMint,
+    #[error("QuasarErrorCode::InvalidTokenAccount")]
+    InvalidTokenAccount,
+    #[error("QuasarErrorCode::GroupShutdown")]
+    GroupShutdown,
+    #[error("QuasarErrorCode::FeeVaultAlreadyInitialized")]
+    FeeVaultAlreadyInitialized,
+    #[error("QuasarErrorCode::MintTooSmall")]
+    MintTooSmall,
+    #[error("QuasarErrorCode::RedeemTooSmall")]
+    RedeemTooSmall,
+    #[error("QuasarErrorCode::InvalidMangoGroup")]
+    InvalidMangoGroup,
+    #[error("QuasarErrorCode::RedeemQueueFull")]
+    RedeemQueueFull,
+    #[error("QuasarErrorCode::RedeemQueueEmpty")]
+    RedeemQueueEmpty,
+    #[error("QuasarErrorCode::OracleQuorumNotMet")]
+    OracleQuorumNotMet,
+    #[error("QuasarErrorCode::MangoDepositFailed")]
+    MangoDepositFailed,
+    #[error("QuasarErrorCode::BaseTokenInUse")]
+    BaseTokenInUse,
+    #[error("QuasarErrorCode::InvalidLeverage")]
+    InvalidLeverage,
+    #[error("QuasarErrorCode::InvalidMangoProgram")]
+    InvalidMangoProgram,
+    #[error("QuasarErrorCode::PriceSampleTooSoon")]
+    PriceSampleTooSoon,
+    #[error("QuasarErrorCode::TooManyTokensForBase")]
+    TooManyTokensForBase,
+    #[error("QuasarErrorCode::InvalidMintAuthority")]
+    InvalidMintAuthority,
+    #[error("QuasarErrorCode::InvalidMint")]
+    InvalidMint,
+    #[error("QuasarErrorCode::SupplyDriftFlagged")]
+    SupplyDriftFlagged,
+    #[error("QuasarErrorCode::InvalidIndex")]
+    InvalidIndex,
+    #[error("QuasarErrorCode::InvalidQuantity")]
+    InvalidQuantity,
+    #[error("QuasarErrorCode::RebalanceBackoff")]
+    RebalanceBackoff,
+    #[error("QuasarErrorCode::MangoProgramInUse")]
+    MangoProgramInUse,
+    #[error("QuasarErrorCode::AccountNotWritable")]
+    AccountNotWritable,
+    #[error("QuasarErrorCode::AccountNotSigner")]
+    AccountNotSigner,
+    #[error("QuasarErrorCode::CollateralAboveMax")]
+    CollateralAboveMax,
+    #[error("QuasarErrorCode::InvalidMangoAccountOwner")]
+    InvalidMangoAccountOwner,
+    #[error("QuasarErrorCode::HoldPeriodNotElapsed")]
+    HoldPeriodNotElapsed,
+    #[error("QuasarErrorCode::GroupNeedsMigration")]
+    GroupNeedsMigration,
+    #[error("QuasarErrorCode::PerpMarketMismatch")]
+    PerpMarketMismatch,
+    #[error("QuasarErrorCode::InsufficientBookDepth")]
+    InsufficientBookDepth,
+    #[error("QuasarErrorCode::InvalidStubOracle")]
+    InvalidStubOracle,
+    #[error("QuasarErrorCode::TokenMintMismatch")]
+    TokenMintMismatch,
+    #[error("QuasarErrorCode::GroupPaused")]
+    GroupPaused,
+    #[error("QuasarErrorCode::PriceDeviatesFromTwap")]
+    PriceDeviatesFromTwap,
+    #[error("QuasarErrorCode::DeleverageTooSoon")]
+    DeleverageTooSoon,
 
     #[error("QuasarErrorCode::Default Check the source code for more info")]
     Default = u32::MAX_VALUE,
@@ -106,6 +218,13 @@ pub fn check_assert(
     if cond {
         Ok(())
     } else {
+        // Log where the check fired, the error alone doesn't tell what was being checked
+        msg!(
+            "check failed: {}; {}:{}",
+            quasar_error_code,
+            source_file_id,
+            line
+        );
         Err(QuasarError::QuasarErrorCode {
             quasar_error_code,
             line,
@@ -157,7 +276,7 @@ macro_rules! declare_check_assert_macros {
         macro_rules! math_err {
             () => {
                 QuasarError::QuasarErrorCode {
-                    quasar_error_code: QuasarErrorCode::MathError,
+                    quasar_error_code: QuasarErrorCode::MathOverflow,
                     line: line!(),
                     source_file_id: $source_file_id,
                 }
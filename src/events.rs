@@ -0,0 +1,284 @@
+use borsh::BorshSerialize;
+use fixed::types::I80F48;
+use mango::state::{MangoAccount, MangoCache, MangoGroup};
+use solana_program::{account_info::AccountInfo, msg, program_option::COption, pubkey::Pubkey};
+
+use crate::{
+    error::{QuasarError, QuasarErrorCode, QuasarResult, SourceFileId},
+    oracle::{determine_oracle_type, OracleType},
+    state::{load_mint, QuasarGroup},
+    utils::{get_mango_account_equity, ACCOUNT_DISCRIMINATOR_LEN},
+};
+
+declare_check_assert_macros!(SourceFileId::Events);
+
+/// Prefix of the log lines carrying an event, clients look for it in the transaction logs
+pub const EVENT_LOG_PREFIX: &str = "QUASAR_EVENT ";
+
+/// Borsh encode `event` and write it to the program logs as base64 behind `EVENT_LOG_PREFIX`.
+/// `sol_log_data` isn't available on this version of the runtime, so the encoding has to be
+/// done here. `I80F48` values are logged as their raw `i128` bits, same as in instructions
+pub fn emit<E: BorshSerialize>(event: &E) {
+    let data = event.try_to_vec().unwrap();
+    msg!("{}{}", EVENT_LOG_PREFIX, base64::encode(&data));
+}
+
+/// What a mint would cost, logged by `QuoteMint`
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct MintQuote {
+    pub mint: Pubkey,
+    /// Native leverage token units to mint
+    pub quantity: u64,
+    /// Native quote units the mint takes, `price_impact` included
+    pub collateral: u64,
+    /// Native quote units per whole leverage token the mint is priced at
+    pub native_price: i128,
+    /// Decimals of the quote mint, to turn the native quote units into whole ones
+    pub quote_decimals: u8,
+    /// Management fee accrued to the fee token account before the mint
+    pub management_fee_tokens: u64,
+    /// Native quote units of slippage opening the mint's exposure would take on the books
+    pub price_impact: u64,
+}
+
+/// What a redeem would pay out, logged by `QuoteRedeem`
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct RedeemQuote {
+    pub mint: Pubkey,
+    /// Native leverage token units to redeem
+    pub quantity: u64,
+    /// Native quote units the redeem pays out
+    pub collateral: u64,
+    /// Native quote units per whole leverage token the redeem is priced at
+    pub native_price: i128,
+    /// Decimals of the quote mint, to turn the native quote units into whole ones
+    pub quote_decimals: u8,
+    /// Management fee accrued to the fee token account before the redeem
+    pub management_fee_tokens: u64,
+}
+
+/// Target and actual leverage of a leverage token, logged by `LogTokenLeverage`
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct TokenLeverage {
+    pub mint: Pubkey,
+    pub target_leverage: i128,
+    /// Perp notional over net asset value, negative when the token is short
+    pub actual_leverage: i128,
+}
+
+/// What `ReconcileToken` measured and did about it
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct ReconcileEvent {
+    pub mint: Pubkey,
+    pub tracked_supply: u64,
+    /// Supply of the mint before any correction
+    pub supply: u64,
+    /// `supply` less `tracked_supply`, in native leverage token units
+    pub drift: i128,
+    /// Native quote units `tracked_supply` should be backed by at the current price
+    pub expected_backing: i128,
+    /// Equity of the Mango account, in native quote units
+    pub backing: i128,
+    /// Decimals of the quote mint, to turn the native quote units into whole ones
+    pub quote_decimals: u8,
+    /// Native units minted to the fee token account
+    pub minted: u64,
+    /// Native units burnt from the fee token account
+    pub burnt: u64,
+    pub flagged: bool,
+}
+
+/// Something `ValidateGroup` found not to match the group's configuration
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub enum Discrepancy {
+    /// The account isn't an initialized mint of the token program
+    InvalidMint { mint: Pubkey },
+    /// The leverage token's mint can be minted by someone other than the group signer
+    MintAuthority {
+        mint: Pubkey,
+        mint_authority: Option<Pubkey>,
+    },
+    /// The base token's mint has different decimals than the ones stored when it was added
+    Decimals {
+        mint: Pubkey,
+        stored: u8,
+        actual: u8,
+    },
+    /// The oracle isn't of a type the program reads, or not of the one it was added as
+    OracleType {
+        base_token_mint: Pubkey,
+        oracle: Pubkey,
+        stored: u8,
+        actual: u8,
+    },
+}
+
+/// Logged by `ValidateGroup`, nothing is wrong when `discrepancies` is empty
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct ValidateEvent {
+    pub num_leverage_tokens: u8,
+    pub num_base_tokens: u8,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl ValidateEvent {
+    /// Check the group against `validation_ais`, the accounts of
+    /// `QuasarGroup::validation_accounts` in the same order
+    pub fn new(quasar_group: &QuasarGroup, validation_ais: &[AccountInfo]) -> Self {
+        let mut discrepancies = Vec::new();
+        let mut ais = validation_ais.iter();
+
+        for (_, leverage_token) in quasar_group.active_leverage_tokens() {
+            let mint_ai = ais.next().unwrap();
+            match load_mint(mint_ai) {
+                Ok(mint) if mint.mint_authority == COption::Some(quasar_group.signer_key) => {}
+                Ok(mint) => discrepancies.push(Discrepancy::MintAuthority {
+                    mint: leverage_token.mint,
+                    mint_authority: mint.mint_authority.into(),
+                }),
+                Err(_) => discrepancies.push(Discrepancy::InvalidMint {
+                    mint: leverage_token.mint,
+                }),
+            }
+        }
+
+        for (_, base_token) in quasar_group.active_base_tokens() {
+            let mint_ai = ais.next().unwrap();
+            match load_mint(mint_ai) {
+                Ok(mint) if mint.decimals == base_token.decimals => {}
+                Ok(mint) => discrepancies.push(Discrepancy::Decimals {
+                    mint: base_token.mint,
+                    stored: base_token.decimals,
+                    actual: mint.decimals,
+                }),
+                Err(_) => discrepancies.push(Discrepancy::InvalidMint {
+                    mint: base_token.mint,
+                }),
+            }
+
+            for i in 0..base_token.num_oracles as usize {
+                let oracle_ai = ais.next().unwrap();
+                // Too short to carry a magic number is as unknown as a wrong one
+                let actual = if oracle_ai.data_len() < ACCOUNT_DISCRIMINATOR_LEN + 4 {
+                    OracleType::Unknown
+                } else {
+                    determine_oracle_type(oracle_ai)
+                };
+                // Base tokens added before types were stored have none to compare against
+                let stored = base_token.oracle_type(i);
+                if actual == OracleType::Unknown
+                    || (stored != OracleType::Unknown && stored != actual)
+                {
+                    discrepancies.push(Discrepancy::OracleType {
+                        base_token_mint: base_token.mint,
+                        oracle: base_token.oracles[i],
+                        stored: stored.into(),
+                        actual: actual.into(),
+                    });
+                }
+            }
+        }
+
+        Self {
+            num_leverage_tokens: quasar_group.num_leverage_tokens as u8,
+            num_base_tokens: quasar_group.num_base_tokens as u8,
+            discrepancies,
+        }
+    }
+}
+
+/// Value locked in the whole group, logged by `LogTotalTvl`
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct TotalTvl {
+    pub num_leverage_tokens: u8,
+    /// Equity of every active leverage token's Mango account summed up, in native quote units.
+    /// Valued at the Mango cache's oracle prices, unsettled perp pnl included since it backs the
+    /// tokens as much as settled quote does
+    pub tvl: i128,
+    /// Decimals of the quote mint, to turn the native quote units into whole ones
+    pub quote_decimals: u8,
+}
+
+impl TotalTvl {
+    /// Sum up the equity of `mango_accounts`, the ones of `QuasarGroup::tvl_accounts`
+    pub fn new<'a>(
+        quote_decimals: u8,
+        mango_group: &MangoGroup,
+        mango_cache: &MangoCache,
+        mango_accounts: impl IntoIterator<Item = &'a MangoAccount>,
+    ) -> QuasarResult<Self> {
+        let mut num_leverage_tokens = 0u8;
+        let mut tvl = I80F48::from_num(0);
+        for mango_account in mango_accounts {
+            let equity = get_mango_account_equity(mango_group, mango_account, mango_cache)?;
+            tvl = tvl.checked_add(equity).ok_or(math_err!())?;
+            num_leverage_tokens += 1;
+        }
+
+        Ok(Self {
+            num_leverage_tokens,
+            tvl: tvl.to_bits(),
+            quote_decimals,
+        })
+    }
+}
+
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct LeverageTokenSummary {
+    pub index: u8,
+    pub mint: Pubkey,
+    pub base_token_mint: Pubkey,
+    pub target_leverage: i128,
+    pub mango_account: Pubkey,
+    pub num_perp_markets: u8,
+    pub pending_rebalance: bool,
+}
+
+#[derive(BorshSerialize, Debug, PartialEq)]
+pub struct GroupSummary {
+    pub num_base_tokens: u8,
+    pub num_leverage_tokens: u8,
+    pub admin_key: Pubkey,
+    /// The default key while the group has no risk admin
+    pub risk_admin_key: Pubkey,
+    pub signer_key: Pubkey,
+    pub quote_mint: Pubkey,
+    pub fee_vault: Pubkey,
+    pub min_health_ratio: i128,
+    pub deleverage_health_ratio: i128,
+    pub min_rebalance_interval: u64,
+    pub shutdown: bool,
+    pub paused: bool,
+    pub leverage_tokens: Vec<LeverageTokenSummary>,
+}
+
+impl GroupSummary {
+    pub fn new(quasar_group: &QuasarGroup) -> Self {
+        Self {
+            num_base_tokens: quasar_group.num_base_tokens as u8,
+            num_leverage_tokens: quasar_group.num_leverage_tokens as u8,
+            admin_key: quasar_group.admin_key,
+            risk_admin_key: quasar_group.risk_admin_key,
+            signer_key: quasar_group.signer_key,
+            quote_mint: quasar_group.quote_mint,
+            fee_vault: quasar_group.fee_vault,
+            min_health_ratio: quasar_group.min_health_ratio.to_bits(),
+            deleverage_health_ratio: quasar_group.deleverage_health_ratio.to_bits(),
+            min_rebalance_interval: quasar_group.min_rebalance_interval,
+            shutdown: quasar_group.shutdown,
+            paused: quasar_group.paused,
+            leverage_tokens: quasar_group
+                .active_leverage_tokens()
+                .map(|(i, lt)| LeverageTokenSummary {
+                    index: i as u8,
+                    mint: lt.mint,
+                    base_token_mint: lt.base_token_mint,
+                    target_leverage: lt.target_leverage.to_bits(),
+                    mango_account: lt.mango_account,
+                    num_perp_markets: lt.num_perp_markets as u8,
+                    pending_rebalance: lt.pending_rebalance,
+                })
+                .collect(),
+        }
+    }
+}
@@ -1,134 +1,2196 @@
-use arrayref::{array_ref, array_refs};
+use borsh::{BorshDeserialize, BorshSerialize};
 use fixed::types::I80F48;
-use solana_program::program_error::ProgramError;
-use std::convert::TryInto;
+use mango::state::{MAX_PAIRS, ZERO_I80F48};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+use std::io::{self, Write};
 
+use crate::state::{QuasarGroup, RebalanceOrderType, MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN};
+
+/// Instructions are Borsh encoded: a little endian `u32` discriminant followed by the fields of
+/// the variant in declaration order, `Option`s being prefixed with a `0` (None) or `1` (Some)
+/// byte. `I80F48` has no Borsh representation of its own and is encoded as its raw `i128` bits,
+/// i.e. the value times 2^48 as 16 little endian bytes, which is what `I80F48::to_le_bytes`
+/// produces.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum QuasarInstruction {
     /// Initialize a quasar group account
     ///
-    /// Accounts expected by this instruction (12):
+    /// Accounts expected by this instruction (6):
     ///
     /// 0. `[writable]` quasar_group_ai
-    /// 1. `[signer]` signer_ai
-    /// 2. `[]` admin_ai
-    /// 3. `[]` mango_program_ai    
+    /// 1. `[]` signer_ai
+    /// 2. `[signer]` admin_ai
+    /// 3. `[]` mango_program_ai
+    /// 4. `[]` quote_mint_ai
+    /// 5. `[]` mango_group_ai - Mango group all leverage tokens trade on
     InitQuasarGroup { signer_nonce: u64 },
 
-    /// Add a base token which leveraged tokens are going to use as the underlying
+    /// Add a base token which leveraged tokens are going to use as the underlying. Its price is
+    /// the median of the first majority of fresh prices among up to
+    /// `MAX_ORACLES_PER_BASE_TOKEN` oracles, read in order
     ///
-    /// Accounts expected by this instruction (8):
+    /// Accounts expected by this instruction (4 + up to 2 extra oracles):
     ///
     /// 0. `[writable]` quasar_group_ai
     /// 1. `[]` mint_ai
     /// 2. `[]` oracle_ai
     /// 3. `[signer]` admin_ai
+    /// 4.. `[]` extra_oracle_ais - backup oracles, in the order they are read
     AddBaseToken,
 
-    /// Add a leveraged token
+    /// Add a leveraged token backed by up to `MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN` perp markets.
+    /// The target exposure is split between the markets by `perp_market_weights`, the first
+    /// `num_perp_markets` of which must sum up to one. Each market must trade the listed base
+    /// token at the same index of `perp_market_base_mints`, and at least one of them the base
+    /// token of the leverage token. `target_leverage` must be within
+    /// `[1.01, MAX_TARGET_LEVERAGE]`. The mint gets `decimals` decimals, at most
+    /// `MAX_LEVERAGE_TOKEN_DECIMALS` and `LEVERGAE_TOKEN_DECIMALS` if `None`. Rebalances leave
+    /// deviations within `rebalance_deadband_bps` of the target alone, zero meaning the group's
+    /// deadband, and at most `MAX_REBALANCE_DEADBAND_BPS`. The admin adds leverage tokens for
+    /// free, anyone else can once the group has a `creation_fee`, paid into its fee vault
     ///
-    /// Accounts expected by this instruction (8):
+    /// Accounts expected by this instruction (11 + num_perp_markets, + 2 when paying the fee):
     ///
     /// 0. `[writable]` quasar_group_ai
-    /// 1. `[]` mint_ai
+    /// 1. `[writable]` mint_ai - uninitialized, PDA of the group, the base token mint and the
+    ///    first free leverage token slot, see `gen_leverage_token_mint_key`
     /// 2. `[]` base_token_mint_ai
-    /// 3. `[]` mango_account_ai
-    /// 4. `[]` mango_perp_market_ai
-    /// 5. `[signer]` admin_ai
-    AddLeverageToken { target_leverage: I80F48 },
+    /// 3. `[]` mango_program_ai
+    /// 4. `[]` mango_group_ai
+    /// 5. `[writable]` mango_account_ai - uninitialized, PDA of the group and the token mint
+    /// 6. `[]` system_program_ai
+    /// 7. `[]` token_program_ai
+    /// 8. `[]` rent_program_ai
+    /// 9. `[writable, signer]` creator_ai - the admin, or anyone paying the creation fee
+    /// 10. `[]` pda_ai
+    /// 11+ `[]` mango_perp_market_ais - one for each of num_perp_markets
+    /// then only when the creator isn't the admin:
+    ///     `[writable]` creator_quote_token_account_ai
+    ///     `[writable]` fee_vault_ai
+    AddLeverageToken {
+        target_leverage: I80F48,
+        num_perp_markets: u8,
+        perp_market_weights: [I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN],
+        perp_market_base_mints: [Pubkey; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN],
+        decimals: Option<u8>,
+        rebalance_deadband_bps: u16,
+    },
 
-    /// mint a leveraged token
+    /// mint a leveraged token, creating the owner's associated token account if needed. While
+    /// the group has a `min_hold_secs` the mint is stamped on the owner's hold record, created
+    /// on their first mint. The mint costs the NAV of the tokens plus the slippage the perp
+    /// orders levering up its collateral would take on the current books, so a large mint
+    /// pays for moving the market instead of the existing holders. Refused while the Mango
+    /// cache prices a perp market further than `MAX_TWAP_DEVIATION_BPS` from the TWAP of the
+    /// base token
     ///
-    /// Accounts expected by this instruction (8):
+    /// Accounts expected by this instruction (19 + 3 * num_perp_markets):
     ///
     /// 0. `[writable]` quasar_group_ai
-    /// 2. `[]` leverage_token_ai
-    /// 3. `[]` mango_account_ai
-    /// 4. `[]` mint_ai
-    /// 4. `[]` base_token_mint_ai
-    /// 4. `[]` oracle_ai
-    /// 8. `[signer]` admin_ai
+    /// 1. `[writable]` token_mint_ai
+    /// 2. `[writable]` owner_leverage_token_account_ai
+    /// 3. `[]` mango_program_ai
+    /// 4. `[]` mango_group_ai
+    /// 5. `[writable]` mango_account_ai
+    /// 6. `[writable, signer]` owner_ai
+    /// 7. `[]` mango_cache_ai
+    /// 8. `[]` root_bank_ai
+    /// 9. `[writable]` node_bank_ai
+    /// 10. `[writable]` vault_ai
+    /// 11. `[]` token_program_ai
+    /// 12. `[writable]` owner_quote_token_account_ai
+    /// 13. `[]` pda_ai
+    /// 14. `[]` system_program_ai
+    /// 15. `[]` associated_token_program_ai
+    /// 16. `[]` rent_program_ai
+    /// 17. `[writable]` fee_token_account_ai - the group signer's account of the leverage token
+    /// 18. `[writable]` hold_record_ai - PDA of the group, `token_mint_ai`, `owner_ai` and
+    ///     "hold_record"
+    /// then for each perp market of the leverage token, in the order they were added:
+    ///     `[]` mango_perp_market_ai
+    ///     `[]` mango_bids_ai
+    ///     `[]` mango_asks_ai
     MintLeverageToken { quantity: u64 },
 
-    /// redeem a leveraged token
+    /// redeem some or all of a holding of a leveraged token, reducing every perp position by the
+    /// redeemed share of the supply. The collateral is paid out in `output_mint`, which defaults to
+    /// the quote mint. Leverage token collateral is only ever held in the quote token, so no
+    /// other mint can be paid out yet. Refused within the group's `min_hold_secs` of the owner's
+    /// last mint of the token, and to an owner who never minted it while there is a wait.
+    /// Unlike a mint it isn't refused off the TWAP, holders can always get out
     ///
-    /// Accounts expected by this instruction (8):
+    /// Accounts expected by this instruction (17 + MAX_PAIRS + 4 * num_perp_markets):
     ///
     /// 0. `[writable]` quasar_group_ai
-    /// 2. `[]` leverage_token_ai
+    /// 1. `[writable]` token_mint_ai
+    /// 2. `[writable]` owner_leverage_token_account_ai
     /// 3. `[]` mango_program_ai
-    /// 4. `[]` mint_ai
-    /// 4. `[]` base_token_mint_ai
-    /// 4. `[]` oracle_ai
-    /// 8. `[signer]` admin_ai
-    BurnLeverageToken { quantity: u64 },
+    /// 4. `[]` mango_group_ai
+    /// 5. `[writable]` mango_account_ai
+    /// 6. `[signer]` owner_ai
+    /// 7. `[]` mango_cache_ai
+    /// 8. `[]` root_bank_ai
+    /// 9. `[writable]` node_bank_ai
+    /// 10. `[writable]` vault_ai
+    /// 11. `[]` token_program_ai
+    /// 12. `[writable]` owner_quote_token_account_ai - receives the collateral
+    /// 13. `[]` pda_ai
+    /// 14. `[]` mango_signer_ai
+    /// 15. `[writable]` fee_token_account_ai - the group signer's account of the leverage token
+    /// 16. `[]` hold_record_ai - the owner's, as for `MintLeverageToken`
+    /// 17..17+MAX_PAIRS `[]` mango_open_orders_ais
+    /// then for each perp market of the leverage token, in the order they were added:
+    ///     `[writable]` mango_perp_market_ai
+    ///     `[writable]` mango_bids_ai
+    ///     `[writable]` mango_asks_ai
+    ///     `[writable]` mango_event_queue_ai
+    BurnLeverageToken {
+        quantity: u64,
+        output_mint: Option<Pubkey>,
+    },
 
-    /// rebalance a leveraged token
+    /// rebalance a leveraged token, placing a perp order for each of its perp markets. Only
+    /// `MAX_LEGS_PER_REBALANCE` legs are traded per instruction to stay within the compute
+    /// budget, the token stays pending and the next crank carries on with the remaining legs.
+    /// A crank adding the reward accounts is paid the group's `crank_reward_bps` of the notional
+    /// its orders filled, as long as the fee vault has it. A leg a taking order only partially
+    /// filled is ordered again for the rest, up to `MAX_REBALANCE_ORDER_ATTEMPTS` orders, and
+    /// left pending when it still falls short. Refused off the TWAP the same way as
+    /// `MintLeverageToken`
     ///
-    /// Accounts expected by this instruction (8):
+    /// Accounts expected by this instruction (10 + MAX_PAIRS + 4 * num_perp_markets [+ 2]):
     ///
     /// 0. `[writable]` quasar_group_ai
-    /// 2. `[]` leverage_token_ai
+    /// 1. `[writable]` token_mint_ai
+    /// 2. `[]` pda_ai
     /// 3. `[]` mango_program_ai
-    /// 4. `[]` mint_ai
-    /// 4. `[]` base_token_mint_ai
-    /// 4. `[]` oracle_ai
-    /// 8. `[signer]` admin_ai
+    /// 4. `[]` mango_group_ai
+    /// 5. `[writable]` mango_account_ai
+    /// 6. `[]` owner_ai
+    /// 7. `[]` mango_cache_ai
+    /// 8. `[]` token_program_ai
+    /// 9. `[writable]` fee_token_account_ai - the group signer's account of the leverage token
+    /// 10..10+MAX_PAIRS `[]` mango_open_orders_ais
+    /// then for each perp market of the leverage token, in the order they were added:
+    ///     `[writable]` mango_perp_market_ai
+    ///     `[writable]` mango_bids_ai
+    ///     `[writable]` mango_asks_ai
+    ///     `[writable]` mango_event_queue_ai
+    /// then optionally, for the crank reward:
+    ///     `[writable]` fee_vault_ai of the group
+    ///     `[writable]` crank_quote_token_account_ai
     Rebalance,
+
+    /// Close a leverage token whose supply has been fully redeemed and clear its slot. Its
+    /// mint and Mango account are left as they are, `AddLeverageToken` takes them over when a
+    /// token of the same base token is added in the slot
+    ///
+    /// Accounts expected by this instruction (6):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` mango_program_ai
+    /// 3. `[]` mango_group_ai
+    /// 4. `[]` mango_account_ai
+    /// 5. `[signer]` admin_ai
+    CloseLeverageToken,
+
+    /// Change the parameters of a quasar group, leaving the ones which are `None` untouched.
+    /// A `max_tokens_per_base` of zero lifts the cap on leverage tokens per base token.
+    /// `rebalance_deadband_bps` applies to leverage tokens without a deadband of their own.
+    /// A `creation_fee` of zero leaves adding leverage tokens to the admin, a `min_hold_secs`
+    /// of zero lets leverage tokens be redeemed right after they are minted. A `crank_reward_bps`
+    /// of zero stops paying rebalance cranks, `max_crank_reward` is in whole quote tokens.
+    /// The risk admin can sign in place of the admin when only `min_rebalance_interval` and
+    /// `rebalance_deadband_bps` are changed
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[signer]` admin_ai - the admin, or the risk admin for the rebalance parameters
+    ChangeGroupParams {
+        min_health_ratio: Option<I80F48>,
+        deleverage_health_ratio: Option<I80F48>,
+        min_rebalance_interval: Option<u64>,
+        max_tokens_per_base: Option<u64>,
+        rebalance_deadband_bps: Option<u16>,
+        creation_fee: Option<u64>,
+        min_hold_secs: Option<u64>,
+        crank_reward_bps: Option<u16>,
+        max_crank_reward: Option<u16>,
+    },
+
+    /// Cut the perp positions of a leverage token whose maint health ratio fell below the
+    /// group's deleverage health ratio. Anyone can call this, there is no bounty for it. Refused
+    /// on a stale Mango cache, off the TWAP the same way as `MintLeverageToken`, and within
+    /// `MIN_DELEVERAGE_INTERVAL_SECS` of the token's last rebalance or deleverage
+    ///
+    /// Accounts expected by this instruction (7 + MAX_PAIRS + 4 * num_perp_markets):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` pda_ai
+    /// 3. `[]` mango_program_ai
+    /// 4. `[]` mango_group_ai
+    /// 5. `[writable]` mango_account_ai
+    /// 6. `[]` mango_cache_ai
+    /// 7..7+MAX_PAIRS `[]` mango_open_orders_ais
+    /// then for each perp market of the leverage token, in the order they were added:
+    ///     `[writable]` mango_perp_market_ai
+    ///     `[writable]` mango_bids_ai
+    ///     `[writable]` mango_asks_ai
+    ///     `[writable]` mango_event_queue_ai
+    DeleverageToken,
+
+    /// Change the target leverage of a leverage token. The next rebalance moves the position
+    /// to the new target and may run before the rebalance interval has elapsed. `new_leverage`
+    /// must be within `[1.01, MAX_TARGET_LEVERAGE]`. The rebalance deadband of the token is left
+    /// untouched when `rebalance_deadband_bps` is `None`
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[signer]` admin_ai
+    SetTargetLeverage {
+        new_leverage: I80F48,
+        rebalance_deadband_bps: Option<u16>,
+    },
+
+    /// Log a Borsh encoded `GroupSummary` of the group and its active leverage tokens, see
+    /// `events::emit` for the log format
+    ///
+    /// Accounts expected by this instruction (1):
+    ///
+    /// 0. `[]` quasar_group_ai
+    LogGroupState,
+
+    /// Set the maximum equity in native quote units mints can take a leverage token's Mango
+    /// account to, zero meaning unlimited
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[signer]` admin_ai
+    SetDepositCap { deposit_cap: u64 },
+
+    /// Set the yearly management fee of a leverage token, after accruing the fee due at the
+    /// previous rate
+    ///
+    /// Accounts expected by this instruction (6):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[writable]` token_mint_ai
+    /// 2. `[writable]` fee_token_account_ai - the group signer's account of the leverage token
+    /// 3. `[]` token_program_ai
+    /// 4. `[]` pda_ai
+    /// 5. `[signer]` admin_ai
+    SetManagementFee { management_fee_bps_annual: u16 },
+
+    /// Shut the group down for good. Minting and adding tokens is disabled afterwards, only
+    /// redeeming keeps working
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[signer]` admin_ai
+    InitiateShutdown,
+
+    /// Create the group fee vault, the group signer's associated token account of `mint_ai`.
+    /// The vault can only be set once
+    ///
+    /// Accounts expected by this instruction (9):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[writable]` fee_vault_ai
+    /// 2. `[]` mint_ai
+    /// 3. `[writable, signer]` admin_ai - pays for the account
+    /// 4. `[]` pda_ai
+    /// 5. `[]` system_program_ai
+    /// 6. `[]` token_program_ai
+    /// 7. `[]` associated_token_program_ai
+    /// 8. `[]` rent_program_ai
+    InitFeeVault,
+
+    /// Set the smallest quantity, in native units, a single mint of a leverage token can create
+    /// and a single `RequestRedeem` can queue
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[signer]` admin_ai
+    SetMinMintQuantity { min_mint_quantity: u64 },
+
+    /// Escrow leverage tokens to be redeemed later by `ProcessRedeemQueue`, for when the perp
+    /// markets are too thin to unwind an immediate redeem. The request joins the back of the
+    /// token's redeem queue, which holds `MAX_REDEEM_REQUESTS_PER_TOKEN`. Refused below the
+    /// token's `min_mint_quantity`, within the group's `min_hold_secs` of the owner's last mint
+    /// of the token, and to an owner who never minted it while there is a wait
+    ///
+    /// Accounts expected by this instruction (10):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[writable]` owner_leverage_token_account_ai
+    /// 3. `[signer, writable]` owner_ai - also pays for the escrow on the first request
+    /// 4. `[writable]` redeem_escrow_ai - PDA of the group, `token_mint_ai` and "redeem_escrow"
+    /// 5. `[]` pda_ai
+    /// 6. `[]` token_program_ai
+    /// 7. `[]` system_program_ai
+    /// 8. `[]` rent_program_ai
+    /// 9. `[]` hold_record_ai - the owner's, as for `MintLeverageToken`
+    RequestRedeem { quantity: u64 },
+
+    /// Unwind the oldest queued redeem of the leverage token as far as liquidity allows, paying
+    /// its owner for the part that filled. What didn't fill of a partly settled request stays at
+    /// the front of the queue, a request nothing of which filled moves to the back so the
+    /// others get their turn. Not refused off the TWAP, same as `BurnLeverageToken`
+    ///
+    /// Accounts expected by this instruction (15 + MAX_PAIRS + 4 * num_perp_markets):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[writable]` token_mint_ai - mint of the leverage token whose queue to process
+    /// 2. `[writable]` redeem_escrow_ai
+    /// 3. `[]` mango_program_ai
+    /// 4. `[]` mango_group_ai
+    /// 5. `[writable]` mango_account_ai
+    /// 6. `[]` mango_cache_ai
+    /// 7. `[]` root_bank_ai
+    /// 8. `[writable]` node_bank_ai
+    /// 9. `[writable]` vault_ai
+    /// 10. `[]` token_program_ai
+    /// 11. `[writable]` owner_quote_token_account_ai - quote account of the request's owner
+    /// 12. `[]` pda_ai
+    /// 13. `[]` mango_signer_ai
+    /// 14. `[writable]` fee_token_account_ai - the group signer's account of the leverage token
+    /// 15..15+MAX_PAIRS `[]` mango_open_orders_ais
+    /// then for each perp market of the leverage token, in the order they were added:
+    ///     `[writable]` mango_perp_market_ai
+    ///     `[writable]` mango_bids_ai
+    ///     `[writable]` mango_asks_ai
+    ///     `[writable]` mango_event_queue_ai
+    ProcessRedeemQueue,
+
+    /// Rebalance a single leg of a leveraged token, for tokens with too many legs to crank
+    /// together. Leaves the progress of `Rebalance` untouched
+    ///
+    /// Accounts expected by this instruction (10 + MAX_PAIRS + 4 [+ 2]):
+    ///
+    /// 0..10+MAX_PAIRS same as `Rebalance`
+    /// then for the perp market at `leg_index`:
+    ///     `[writable]` mango_perp_market_ai
+    ///     `[writable]` mango_bids_ai
+    ///     `[writable]` mango_asks_ai
+    ///     `[writable]` mango_event_queue_ai
+    /// then optionally the crank reward accounts of `Rebalance`
+    RebalanceLeg { leg_index: u8 },
+
+    /// Remove a base token no leverage token is built on or trades a perp market of anymore
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` mint_ai - mint of the base token
+    /// 2. `[signer]` admin_ai
+    RemoveBaseToken,
+
+    /// Settle the unrealized pnl of one perp leg of a leveraged token against a Mango account
+    /// holding the opposite pnl, turning it into quote deposits.
+    ///
+    /// Settling is optional for pricing: the NAV used by mint and redeem already counts the
+    /// unsettled quote position of every perp account. It is mandatory before a redeem, or a
+    /// redeem queue crank, whose payout exceeds the quote the Mango account has on deposit,
+    /// since Mango only lets settled quote be withdrawn. What the quote deposits gain or lose
+    /// is counted into `QuasarGroup::settled_pnl` for compounding. Settlements run straight on
+    /// Mango with the token's account as the counterparty aren't
+    ///
+    /// Accounts expected by this instruction (9):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` mango_program_ai
+    /// 3. `[]` mango_group_ai
+    /// 4. `[writable]` mango_account_ai
+    /// 5. `[writable]` counterparty_mango_account_ai - with pnl of the opposite sign
+    /// 6. `[]` mango_cache_ai
+    /// 7. `[]` root_bank_ai - of the quote token
+    /// 8. `[writable]` node_bank_ai
+    SettleTokenPnl { leg_index: u8 },
+
+    /// Sample the oracle price of a base token into its TWAP buffer. Samples have to be at
+    /// least `MIN_PRICE_SAMPLE_INTERVAL_SECS` apart. Anyone can call this. The TWAP over the
+    /// last `TWAP_WINDOW_SECS` bounds the prices `MintLeverageToken` and `Rebalance` go
+    /// through at
+    ///
+    /// Accounts expected by this instruction (2 + num_oracles):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` mint_ai - mint of the base token
+    /// 2.. `[]` oracle_ais - every oracle of the base token, in the order they were added
+    UpdatePriceSample,
+
+    /// Log a Borsh encoded `MintQuote` of what minting `quantity` native units would cost right
+    /// now, without moving any funds. Anyone can call this
+    ///
+    /// Accounts expected by this instruction (6 + 3 * num_perp_markets):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` mango_program_ai
+    /// 3. `[]` mango_group_ai
+    /// 4. `[]` mango_account_ai
+    /// 5. `[]` mango_cache_ai
+    /// then for each perp market of the leverage token, in the order they were added:
+    ///     `[]` mango_perp_market_ai
+    ///     `[]` mango_bids_ai
+    ///     `[]` mango_asks_ai
+    QuoteMint { quantity: u64 },
+
+    /// Compare the supply of a leverage token with the supply this program minted and burnt,
+    /// logging a Borsh encoded `ReconcileEvent`. Drift past `RECONCILE_TOLERANCE_BPS` is minted
+    /// to, or burnt from, the fee token account when within `MAX_RECONCILE_CORRECTION_BPS`, and
+    /// flags the token against further mints when not. The backing it logs is read off a fresh
+    /// Mango cache. Anyone can call this
+    ///
+    /// Accounts expected by this instruction (9):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[writable]` token_mint_ai
+    /// 2. `[writable]` fee_token_account_ai - the group signer's account of the leverage token
+    /// 3. `[]` pda_ai
+    /// 4. `[]` token_program_ai
+    /// 5. `[]` mango_program_ai
+    /// 6. `[]` mango_group_ai
+    /// 7. `[]` mango_account_ai
+    /// 8. `[]` mango_cache_ai
+    ReconcileToken,
+
+    /// Bring the funding of one perp leg of a leveraged token up to date in the Mango cache. The
+    /// NAV counts the funding of every perp position against the cached funding index, so
+    /// funding accrued on the perp market only shows up in mint and redeem prices once this, or
+    /// any other `UpdateFunding` of the market, went through. Does nothing if the cache entry was
+    /// already updated at the current timestamp. Anyone can call this
+    ///
+    /// Accounts expected by this instruction (9):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` mango_program_ai
+    /// 3. `[]` mango_group_ai
+    /// 4. `[]` mango_account_ai
+    /// 5. `[writable]` mango_cache_ai
+    /// 6. `[writable]` mango_perp_market_ai - of the leg at `leg_index`
+    /// 7. `[]` mango_bids_ai
+    /// 8. `[]` mango_asks_ai
+    SettleFunding { leg_index: u8 },
+
+    /// Move the group to a new Mango program and a Mango group of it, for when Mango is
+    /// redeployed under another program id. Leverage tokens keep their Mango accounts in the old
+    /// group, so this is refused while there are any
+    ///
+    /// Accounts expected by this instruction (4):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` mango_program_ai - the new Mango program, executable
+    /// 2. `[]` mango_group_ai - owned by the new Mango program
+    /// 3. `[signer]` admin_ai
+    SetMangoProgram,
+
+    /// Mint a leveraged token with collateral the owner already has on deposit in a Mango
+    /// account of their own, rather than in a token account. The collateral is withdrawn from
+    /// `source_mango_account_ai` to the owner's quote token account and deposited from there in
+    /// the same instruction, so that account only has to exist
+    ///
+    /// Accounts expected by this instruction (21 + MAX_PAIRS + 3 * num_perp_markets):
+    ///
+    /// 0..19. the fixed accounts of `MintLeverageToken`
+    /// 19. `[writable]` source_mango_account_ai - owned by owner_ai, in the same Mango group
+    /// 20. `[]` mango_signer_ai
+    /// 21..21 + MAX_PAIRS. `[]` source_open_orders_ais - of source_mango_account_ai
+    /// then the perp market accounts of `MintLeverageToken`
+    MintFromMangoBalance { quantity: u64 },
+
+    /// Check the group's configuration against the accounts it points to, logging a Borsh
+    /// encoded `ValidateEvent` of whatever doesn't match. Anyone can call this
+    ///
+    /// Accounts expected by this instruction (1 + validation accounts):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1.. `[]` the accounts of `QuasarGroup::validation_accounts`, in that order
+    ValidateGroup,
+
+    /// Price a base token off the EMA of its Pyth oracles rather than their latest aggregate,
+    /// smoothing the NAV of the leverage tokens built on it. Stub oracles have no EMA and are
+    /// read as before
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` base_token_mint_ai
+    /// 2. `[signer]` admin_ai
+    SetUseEma { use_ema: bool },
+
+    /// Log a Borsh encoded `TokenLeverage` of the target and actual leverage of a leverage
+    /// token, the actual one being its perp notional over its net asset value. Anyone can
+    /// call this
+    ///
+    /// Accounts expected by this instruction (6):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` mango_program_ai
+    /// 3. `[]` mango_group_ai
+    /// 4. `[]` mango_account_ai
+    /// 5. `[]` mango_cache_ai
+    LogTokenLeverage,
+
+    /// Rotate the freeze authority of a leverage mint to `freeze_authority`, or revoke it for
+    /// good with `None`. Mints are created with the group signer as their freeze authority.
+    /// No instruction freezes token accounts, but as long as the signer holds the authority,
+    /// holders have to trust every upgrade of the program not to. Revoking it takes that trust
+    /// away, rotating it hands it to the new authority. Only the signer can change it, so this
+    /// fails once it has been rotated or revoked
+    ///
+    /// Accounts expected by this instruction (5):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1. `[writable]` token_mint_ai
+    /// 2. `[]` pda_ai
+    /// 3. `[]` token_program_ai
+    /// 4. `[signer]` admin_ai
+    SetMintFreezeAuthority { freeze_authority: Option<Pubkey> },
+
+    /// Mint several leverage tokens at once, each of the first `num_entries` entries the same
+    /// as a `MintLeverageToken` of `quantity` of the leverage token at `token_index` that takes
+    /// at most `max_collateral`. If any of them fails none of them are minted
+    ///
+    /// Accounts expected by this instruction (14 + (5 + 3 * num_perp_markets) * num_entries):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` mango_program_ai
+    /// 2. `[]` mango_group_ai
+    /// 3. `[writable, signer]` owner_ai
+    /// 4. `[]` mango_cache_ai
+    /// 5. `[]` root_bank_ai
+    /// 6. `[writable]` node_bank_ai
+    /// 7. `[writable]` vault_ai
+    /// 8. `[]` token_program_ai
+    /// 9. `[writable]` owner_quote_token_account_ai
+    /// 10. `[]` pda_ai
+    /// 11. `[]` system_program_ai
+    /// 12. `[]` associated_token_program_ai
+    /// 13. `[]` rent_program_ai
+    /// then for each entry, in order:
+    ///     `[writable]` token_mint_ai
+    ///     `[writable]` owner_leverage_token_account_ai
+    ///     `[writable]` mango_account_ai
+    ///     `[writable]` hold_record_ai
+    ///     `[writable]` fee_token_account_ai - the group signer's account of this entry's token
+    ///     then the perp market accounts of `MintLeverageToken` for its leverage token
+    BatchMint {
+        num_entries: u8,
+        entries: [BatchMintEntry; MAX_BATCH_MINTS],
+    },
+
+    /// Bring a group created by an older version of the program up to `QUASAR_GROUP_VERSION`,
+    /// giving the fields its layout didn't have their defaults. Every other instruction refuses
+    /// a group until it is migrated
+    ///
+    /// Groups of a smaller layout, all of them before version 5, are grown to the current one,
+    /// the admin paying for the rent. An instruction can only grow an account by
+    /// `MAX_PERMITTED_DATA_INCREASE`, so version 0 groups take three of these, the first two
+    /// only growing the group. They can all go in the same transaction
+    ///
+    /// Accounts expected by this instruction (5 + leverage token mints):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[writable, signer]` admin_ai
+    /// 2. `[]` mango_group_ai - the group's, version 0 groups take it from here
+    /// 3. `[]` quote_mint_ai - the quote token of the Mango group, groups before version 2
+    ///        store its decimals
+    /// 4. `[]` system_program_ai
+    /// then only for version 0 groups, `[]` the mint of every leverage token in slot order, see
+    /// `QuasarGroup::v0_leverage_token_mints`
+    MigrateGroup,
+
+    /// Log a Borsh encoded `RedeemQuote` of what redeeming `quantity` native units with
+    /// `BurnLeverageToken` would pay out right now, without moving any funds. Anyone can call
+    /// this
+    ///
+    /// Accounts expected by this instruction (6):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` mango_program_ai
+    /// 3. `[]` mango_group_ai
+    /// 4. `[]` mango_account_ai
+    /// 5. `[]` mango_cache_ai
+    QuoteRedeem { quantity: u64 },
+
+    /// Move the quote collateral left in the Mango account of a leverage token whose supply is
+    /// zero to the group fee vault. Rounding leaves dust behind after the last redeem that no
+    /// holder can claim. Refused while the token has a supply, or any perp position, order or
+    /// unsettled pnl. Anyone can call this
+    ///
+    /// Accounts expected by this instruction (13 + MAX_PAIRS):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[]` mango_program_ai
+    /// 3. `[]` mango_group_ai
+    /// 4. `[writable]` mango_account_ai
+    /// 5. `[]` mango_cache_ai
+    /// 6. `[]` root_bank_ai - of the quote token
+    /// 7. `[writable]` node_bank_ai
+    /// 8. `[writable]` vault_ai
+    /// 9. `[writable]` fee_vault_ai - the group's, set by `InitFeeVault`
+    /// 10. `[]` pda_ai
+    /// 11. `[]` mango_signer_ai
+    /// 12. `[]` token_program_ai
+    /// 13..13+MAX_PAIRS `[]` mango_open_orders_ais
+    SweepDust,
+
+    /// Set how `Rebalance` places the perp orders of a leverage token on Mango, a
+    /// `RebalanceOrderType`. `offset_bps` is the distance from the oracle price post only orders
+    /// rest at and has to be zero for the other types
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[signer]` admin_ai
+    SetRebalanceOrderType { order_type: u8, offset_bps: u16 },
+
+    /// Log a Borsh encoded `TotalTvl`, the equity of every active leverage token's Mango
+    /// account summed up in native quote units, unsettled perp pnl included. Anyone can call this
+    ///
+    /// Accounts expected by this instruction (4 + num_leverage_tokens):
+    ///
+    /// 0. `[]` quasar_group_ai
+    /// 1. `[]` mango_program_ai
+    /// 2. `[]` mango_group_ai
+    /// 3. `[]` mango_cache_ai
+    /// 4.. `[]` the Mango accounts of `QuasarGroup::tvl_accounts`, in that order
+    LogTotalTvl,
+
+    /// Set whether `Rebalance` reinvests the perp pnl and funding `SettleTokenPnl` settled into
+    /// a leverage token's quote deposits, rather than leaving them unlevered. See
+    /// `get_rebalance_net_asset_value` for what it does to the NAV
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[]` token_mint_ai
+    /// 2. `[signer]` admin_ai
+    SetCompound { compound: bool },
+
+    /// Hand the risk admin role to `risk_admin`, or take it away with `None`. The risk admin can
+    /// pause minting with `SetPaused` and change the rebalance parameters of `ChangeGroupParams`,
+    /// everything moving funds stays with the admin
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[signer]` admin_ai
+    SetRiskAdmin { risk_admin: Option<Pubkey> },
+
+    /// Pause or resume minting leverage tokens. Redeems, rebalances and deleverages carry on
+    /// while paused, so holders can always get out
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` quasar_group_ai
+    /// 1. `[signer]` admin_ai - the admin or the risk admin
+    SetPaused { paused: bool },
 }
 
 impl QuasarInstruction {
     pub fn unpack(input: &[u8]) -> Option<Self> {
-        let (&discrim, data) = array_refs![input, 4; ..;];
-        let discrim = u32::from_le_bytes(discrim);
+        Self::try_from_slice(input).ok()
+    }
 
-        Some(match discrim {
-            0 => {
-                let signer_nonce = array_ref![data, 0, 8];
+    pub fn pack(&self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
+    }
+}
 
-                Self::InitQuasarGroup {
-                    signer_nonce: u64::from_le_bytes(*signer_nonce),
+impl BorshSerialize for QuasarInstruction {
+    fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::InitQuasarGroup { signer_nonce } => {
+                0u32.serialize(writer)?;
+                signer_nonce.serialize(writer)
+            }
+            Self::AddBaseToken => 1u32.serialize(writer),
+            Self::AddLeverageToken {
+                target_leverage,
+                num_perp_markets,
+                perp_market_weights,
+                perp_market_base_mints,
+                decimals,
+                rebalance_deadband_bps,
+            } => {
+                2u32.serialize(writer)?;
+                serialize_i80f48(target_leverage, writer)?;
+                num_perp_markets.serialize(writer)?;
+                for weight in perp_market_weights.iter() {
+                    serialize_i80f48(weight, writer)?;
                 }
+                perp_market_base_mints.serialize(writer)?;
+                decimals.serialize(writer)?;
+                rebalance_deadband_bps.serialize(writer)
             }
-            1 => Self::AddBaseToken,
-            2 => {
-                let target_leverage = array_ref![data, 0, 16];
-                QuasarInstruction::AddLeverageToken {
-                    target_leverage: I80F48::from_le_bytes(*target_leverage),
+            Self::MintLeverageToken { quantity } => {
+                3u32.serialize(writer)?;
+                quantity.serialize(writer)
+            }
+            Self::BurnLeverageToken {
+                quantity,
+                output_mint,
+            } => {
+                4u32.serialize(writer)?;
+                quantity.serialize(writer)?;
+                output_mint.serialize(writer)
+            }
+            Self::Rebalance => 5u32.serialize(writer),
+            Self::CloseLeverageToken => 6u32.serialize(writer),
+            Self::ChangeGroupParams {
+                min_health_ratio,
+                deleverage_health_ratio,
+                min_rebalance_interval,
+                max_tokens_per_base,
+                rebalance_deadband_bps,
+                creation_fee,
+                min_hold_secs,
+                crank_reward_bps,
+                max_crank_reward,
+            } => {
+                7u32.serialize(writer)?;
+                serialize_i80f48_opt(min_health_ratio, writer)?;
+                serialize_i80f48_opt(deleverage_health_ratio, writer)?;
+                min_rebalance_interval.serialize(writer)?;
+                max_tokens_per_base.serialize(writer)?;
+                rebalance_deadband_bps.serialize(writer)?;
+                creation_fee.serialize(writer)?;
+                min_hold_secs.serialize(writer)?;
+                Ok(())
+            }
+            Self::DeleverageToken => 8u32.serialize(writer),
+            Self::SetTargetLeverage {
+                new_leverage,
+                rebalance_deadband_bps,
+            } => {
+                9u32.serialize(writer)?;
+                serialize_i80f48(new_leverage, writer)?;
+                rebalance_deadband_bps.serialize(writer)
+            }
+            Self::LogGroupState => 10u32.serialize(writer),
+            Self::SetDepositCap { deposit_cap } => {
+                11u32.serialize(writer)?;
+                deposit_cap.serialize(writer)
+            }
+            Self::SetManagementFee {
+                management_fee_bps_annual,
+            } => {
+                12u32.serialize(writer)?;
+                management_fee_bps_annual.serialize(writer)
+            }
+            Self::InitiateShutdown => 13u32.serialize(writer),
+            Self::InitFeeVault => 14u32.serialize(writer),
+            Self::SetMinMintQuantity { min_mint_quantity } => {
+                15u32.serialize(writer)?;
+                min_mint_quantity.serialize(writer)
+            }
+            Self::RequestRedeem { quantity } => {
+                16u32.serialize(writer)?;
+                quantity.serialize(writer)
+            }
+            Self::ProcessRedeemQueue => 17u32.serialize(writer),
+            Self::RebalanceLeg { leg_index } => {
+                18u32.serialize(writer)?;
+                leg_index.serialize(writer)
+            }
+            Self::RemoveBaseToken => 19u32.serialize(writer),
+            Self::SettleTokenPnl { leg_index } => {
+                20u32.serialize(writer)?;
+                leg_index.serialize(writer)
+            }
+            Self::UpdatePriceSample => 21u32.serialize(writer),
+            Self::QuoteMint { quantity } => {
+                22u32.serialize(writer)?;
+                quantity.serialize(writer)
+            }
+            Self::ReconcileToken => 23u32.serialize(writer),
+            Self::SettleFunding { leg_index } => {
+                24u32.serialize(writer)?;
+                leg_index.serialize(writer)
+            }
+            Self::SetMangoProgram => 25u32.serialize(writer),
+            Self::MintFromMangoBalance { quantity } => {
+                26u32.serialize(writer)?;
+                quantity.serialize(writer)
+            }
+            Self::ValidateGroup => 27u32.serialize(writer),
+            Self::SetUseEma { use_ema } => {
+                28u32.serialize(writer)?;
+                use_ema.serialize(writer)
+            }
+            Self::LogTokenLeverage => 29u32.serialize(writer),
+            Self::SetMintFreezeAuthority { freeze_authority } => {
+                30u32.serialize(writer)?;
+                freeze_authority.serialize(writer)
+            }
+            Self::BatchMint {
+                num_entries,
+                entries,
+            } => {
+                31u32.serialize(writer)?;
+                num_entries.serialize(writer)?;
+                for entry in entries.iter() {
+                    entry.serialize(writer)?;
                 }
+                Ok(())
+            }
+            Self::MigrateGroup => 32u32.serialize(writer),
+            Self::QuoteRedeem { quantity } => {
+                33u32.serialize(writer)?;
+                quantity.serialize(writer)
+            }
+            Self::SweepDust => 34u32.serialize(writer),
+            Self::SetRebalanceOrderType {
+                order_type,
+                offset_bps,
+            } => {
+                35u32.serialize(writer)?;
+                order_type.serialize(writer)?;
+                offset_bps.serialize(writer)
+            }
+            Self::LogTotalTvl => 36u32.serialize(writer),
+            Self::SetCompound { compound } => {
+                37u32.serialize(writer)?;
+                compound.serialize(writer)
             }
-            3 => {
-                let quantity = array_ref![data, 0, 8];
+            Self::SetRiskAdmin { risk_admin } => {
+                38u32.serialize(writer)?;
+                risk_admin.serialize(writer)
+            }
+            Self::SetPaused { paused } => {
+                39u32.serialize(writer)?;
+                paused.serialize(writer)
+            }
+        }
+    }
+}
 
-                QuasarInstruction::MintLeverageToken {
-                    quantity: u64::from_le_bytes(*quantity),
+impl BorshDeserialize for QuasarInstruction {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        let discrim = u32::deserialize(buf)?;
+
+        Ok(match discrim {
+            0 => Self::InitQuasarGroup {
+                signer_nonce: u64::deserialize(buf)?,
+            },
+            1 => Self::AddBaseToken,
+            2 => {
+                let target_leverage = deserialize_i80f48(buf)?;
+                let num_perp_markets = u8::deserialize(buf)?;
+
+                let mut perp_market_weights = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+                for weight in perp_market_weights.iter_mut() {
+                    *weight = deserialize_i80f48(buf)?;
                 }
-            }
-            4 => {
-                let quantity = array_ref![data, 0, 8];
+                let perp_market_base_mints =
+                    <[Pubkey; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN]>::deserialize(buf)?;
 
-                QuasarInstruction::BurnLeverageToken {
-                    quantity: u64::from_le_bytes(*quantity),
+                Self::AddLeverageToken {
+                    target_leverage,
+                    num_perp_markets,
+                    perp_market_weights,
+                    perp_market_base_mints,
+                    decimals: Option::<u8>::deserialize(buf)?,
+                    rebalance_deadband_bps: u16::deserialize(buf)?,
                 }
             }
+            3 => Self::MintLeverageToken {
+                quantity: u64::deserialize(buf)?,
+            },
+            4 => Self::BurnLeverageToken {
+                quantity: u64::deserialize(buf)?,
+                output_mint: Option::<Pubkey>::deserialize(buf)?,
+            },
             5 => Self::Rebalance,
-            _ => return None,
+            6 => Self::CloseLeverageToken,
+            7 => Self::ChangeGroupParams {
+                min_health_ratio: deserialize_i80f48_opt(buf)?,
+                deleverage_health_ratio: deserialize_i80f48_opt(buf)?,
+                min_rebalance_interval: Option::<u64>::deserialize(buf)?,
+                max_tokens_per_base: Option::<u64>::deserialize(buf)?,
+                rebalance_deadband_bps: Option::<u16>::deserialize(buf)?,
+                creation_fee: Option::<u64>::deserialize(buf)?,
+                min_hold_secs: Option::<u64>::deserialize(buf)?,
+                crank_reward_bps: Option::<u16>::deserialize(buf)?,
+                max_crank_reward: Option::<u16>::deserialize(buf)?,
+            },
+            8 => Self::DeleverageToken,
+            9 => Self::SetTargetLeverage {
+                new_leverage: deserialize_i80f48(buf)?,
+                rebalance_deadband_bps: Option::<u16>::deserialize(buf)?,
+            },
+            10 => Self::LogGroupState,
+            11 => Self::SetDepositCap {
+                deposit_cap: u64::deserialize(buf)?,
+            },
+            12 => Self::SetManagementFee {
+                management_fee_bps_annual: u16::deserialize(buf)?,
+            },
+            13 => Self::InitiateShutdown,
+            14 => Self::InitFeeVault,
+            15 => Self::SetMinMintQuantity {
+                min_mint_quantity: u64::deserialize(buf)?,
+            },
+            16 => Self::RequestRedeem {
+                quantity: u64::deserialize(buf)?,
+            },
+            17 => Self::ProcessRedeemQueue,
+            18 => Self::RebalanceLeg {
+                leg_index: u8::deserialize(buf)?,
+            },
+            19 => Self::RemoveBaseToken,
+            20 => Self::SettleTokenPnl {
+                leg_index: u8::deserialize(buf)?,
+            },
+            21 => Self::UpdatePriceSample,
+            22 => Self::QuoteMint {
+                quantity: u64::deserialize(buf)?,
+            },
+            23 => Self::ReconcileToken,
+            24 => Self::SettleFunding {
+                leg_index: u8::deserialize(buf)?,
+            },
+            25 => Self::SetMangoProgram,
+            26 => Self::MintFromMangoBalance {
+                quantity: u64::deserialize(buf)?,
+            },
+            27 => Self::ValidateGroup,
+            28 => Self::SetUseEma {
+                use_ema: bool::deserialize(buf)?,
+            },
+            29 => Self::LogTokenLeverage,
+            30 => Self::SetMintFreezeAuthority {
+                freeze_authority: Option::<Pubkey>::deserialize(buf)?,
+            },
+            31 => {
+                let num_entries = u8::deserialize(buf)?;
+                let mut entries = [BatchMintEntry::default(); MAX_BATCH_MINTS];
+                for entry in entries.iter_mut() {
+                    *entry = BatchMintEntry::deserialize(buf)?;
+                }
+                Self::BatchMint {
+                    num_entries,
+                    entries,
+                }
+            }
+            32 => Self::MigrateGroup,
+            33 => Self::QuoteRedeem {
+                quantity: u64::deserialize(buf)?,
+            },
+            34 => Self::SweepDust,
+            35 => Self::SetRebalanceOrderType {
+                order_type: u8::deserialize(buf)?,
+                offset_bps: u16::deserialize(buf)?,
+            },
+            36 => Self::LogTotalTvl,
+            37 => Self::SetCompound {
+                compound: bool::deserialize(buf)?,
+            },
+            38 => Self::SetRiskAdmin {
+                risk_admin: Option::<Pubkey>::deserialize(buf)?,
+            },
+            39 => Self::SetPaused {
+                paused: bool::deserialize(buf)?,
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown instruction",
+                ))
+            }
         })
     }
+}
 
-    fn unpack_i80f48_opt(data: &[u8; 17]) -> Option<I80F48> {
-        let (opt, val) = array_refs![data, 1, 16];
-        if opt[0] == 0 {
-            None
-        } else {
-            Some(I80F48::from_le_bytes(*val))
+fn serialize_i80f48<W: Write>(value: &I80F48, writer: &mut W) -> io::Result<()> {
+    value.to_bits().serialize(writer)
+}
+
+fn serialize_i80f48_opt<W: Write>(value: &Option<I80F48>, writer: &mut W) -> io::Result<()> {
+    value.map(|v| v.to_bits()).serialize(writer)
+}
+
+fn deserialize_i80f48(buf: &mut &[u8]) -> io::Result<I80F48> {
+    Ok(I80F48::from_bits(i128::deserialize(buf)?))
+}
+
+fn deserialize_i80f48_opt(buf: &mut &[u8]) -> io::Result<Option<I80F48>> {
+    Ok(Option::<i128>::deserialize(buf)?.map(I80F48::from_bits))
+}
+
+/// An account an instruction expects, as listed in the docs of `QuasarInstruction`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccountDescriptor {
+    pub name: &'static str,
+    pub is_writable: bool,
+    pub is_signer: bool,
+}
+
+impl AccountDescriptor {
+    const fn new(name: &'static str, is_writable: bool, is_signer: bool) -> Self {
+        Self {
+            name,
+            is_writable,
+            is_signer,
         }
     }
-    fn unpack_u64_opt(data: &[u8; 9]) -> Option<u64> {
-        let (opt, val) = array_refs![data, 1, 8];
-        if opt[0] == 0 {
-            None
+
+    fn meta(&self, pubkey: Pubkey) -> AccountMeta {
+        if self.is_writable {
+            AccountMeta::new(pubkey, self.is_signer)
         } else {
-            Some(u64::from_le_bytes(*val))
+            AccountMeta::new_readonly(pubkey, self.is_signer)
         }
     }
 }
+
+const fn readonly(name: &'static str) -> AccountDescriptor {
+    AccountDescriptor::new(name, false, false)
+}
+
+const fn writable(name: &'static str) -> AccountDescriptor {
+    AccountDescriptor::new(name, true, false)
+}
+
+const fn signer(name: &'static str) -> AccountDescriptor {
+    AccountDescriptor::new(name, false, true)
+}
+
+const fn writable_signer(name: &'static str) -> AccountDescriptor {
+    AccountDescriptor::new(name, true, true)
+}
+
+// The fixed accounts of each instruction, in the order the handlers unpack them. Accounts
+// whose number varies, like oracles or perp markets, come after these and are described in the
+// docs of the instruction
+
+// Compute units an instruction is expected to stay under, for clients to size the compute budget
+// of their transactions. `tests/compute_budget.rs` runs `InitQuasarGroup`, `AddBaseToken` and,
+// on a stand-in Mango program doing nothing, `MintLeverageToken` under these limits. The others
+// are estimates waiting for a Mango test fixture
+pub const INIT_QUASAR_GROUP_COMPUTE_UNITS: u64 = 25_000;
+pub const ADD_BASE_TOKEN_COMPUTE_UNITS: u64 = 30_000;
+pub const ADD_LEVERAGE_TOKEN_COMPUTE_UNITS: u64 = 120_000;
+pub const MINT_LEVERAGE_TOKEN_COMPUTE_UNITS: u64 = 200_000;
+pub const BURN_LEVERAGE_TOKEN_COMPUTE_UNITS: u64 = 200_000;
+
+/// Most entries a `BatchMint` takes. Each costs about a `MintLeverageToken`, so a full batch
+/// needs a budget of `BATCH_MINT_COMPUTE_UNITS`, and its accounts have to fit in a transaction
+pub const MAX_BATCH_MINTS: usize = 3;
+pub const BATCH_MINT_COMPUTE_UNITS: u64 =
+    MINT_LEVERAGE_TOKEN_COMPUTE_UNITS * MAX_BATCH_MINTS as u64;
+
+pub const INIT_QUASAR_GROUP_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("signer"),
+    signer("admin"),
+    readonly("mango_program"),
+    readonly("quote_mint"),
+    readonly("mango_group"),
+];
+
+pub const ADD_BASE_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("mint"),
+    readonly("oracle"),
+    signer("admin"),
+];
+
+pub const ADD_LEVERAGE_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("mint"),
+    readonly("base_token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    readonly("system_program"),
+    readonly("token_program"),
+    readonly("rent_program"),
+    writable_signer("creator"),
+    readonly("pda"),
+];
+
+/// Trailing accounts of `AddLeverageToken` when the creator pays the creation fee
+pub const CREATION_FEE_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("creator_quote_token_account"),
+    writable("fee_vault"),
+];
+
+pub const MINT_LEVERAGE_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("token_mint"),
+    writable("owner_leverage_token_account"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    writable_signer("owner"),
+    readonly("mango_cache"),
+    readonly("root_bank"),
+    writable("node_bank"),
+    writable("vault"),
+    readonly("token_program"),
+    writable("owner_quote_token_account"),
+    readonly("pda"),
+    readonly("system_program"),
+    readonly("associated_token_program"),
+    readonly("rent_program"),
+    writable("fee_token_account"),
+    writable("hold_record"),
+];
+
+pub const BURN_LEVERAGE_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("token_mint"),
+    writable("owner_leverage_token_account"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    signer("owner"),
+    readonly("mango_cache"),
+    readonly("root_bank"),
+    writable("node_bank"),
+    writable("vault"),
+    readonly("token_program"),
+    writable("owner_quote_token_account"),
+    readonly("pda"),
+    readonly("mango_signer"),
+    writable("fee_token_account"),
+    readonly("hold_record"),
+];
+
+pub const REBALANCE_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("token_mint"),
+    readonly("pda"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    readonly("owner"),
+    readonly("mango_cache"),
+    readonly("token_program"),
+    writable("fee_token_account"),
+];
+
+pub const CLOSE_LEVERAGE_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    readonly("mango_account"),
+    signer("admin"),
+];
+
+pub const CHANGE_GROUP_PARAMS_ACCOUNTS: &[AccountDescriptor] =
+    &[writable("quasar_group"), signer("admin")];
+
+pub const DELEVERAGE_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    readonly("pda"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    readonly("mango_cache"),
+];
+
+pub const SET_TARGET_LEVERAGE_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    signer("admin"),
+];
+
+pub const LOG_GROUP_STATE_ACCOUNTS: &[AccountDescriptor] = &[readonly("quasar_group")];
+
+pub const SET_DEPOSIT_CAP_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    signer("admin"),
+];
+
+pub const SET_MANAGEMENT_FEE_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("token_mint"),
+    writable("fee_token_account"),
+    readonly("token_program"),
+    readonly("pda"),
+    signer("admin"),
+];
+
+pub const INITIATE_SHUTDOWN_ACCOUNTS: &[AccountDescriptor] =
+    &[writable("quasar_group"), signer("admin")];
+
+pub const INIT_FEE_VAULT_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("fee_vault"),
+    readonly("mint"),
+    writable_signer("admin"),
+    readonly("pda"),
+    readonly("system_program"),
+    readonly("token_program"),
+    readonly("associated_token_program"),
+    readonly("rent_program"),
+];
+
+pub const SET_MIN_MINT_QUANTITY_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    signer("admin"),
+];
+
+pub const REQUEST_REDEEM_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    writable("owner_leverage_token_account"),
+    writable_signer("owner"),
+    writable("redeem_escrow"),
+    readonly("pda"),
+    readonly("token_program"),
+    readonly("system_program"),
+    readonly("rent_program"),
+    readonly("hold_record"),
+];
+
+pub const PROCESS_REDEEM_QUEUE_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("token_mint"),
+    writable("redeem_escrow"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    readonly("mango_cache"),
+    readonly("root_bank"),
+    writable("node_bank"),
+    writable("vault"),
+    readonly("token_program"),
+    writable("owner_quote_token_account"),
+    readonly("pda"),
+    readonly("mango_signer"),
+    writable("fee_token_account"),
+];
+
+pub const REBALANCE_LEG_ACCOUNTS: &[AccountDescriptor] = REBALANCE_ACCOUNTS;
+
+pub const REMOVE_BASE_TOKEN_ACCOUNTS: &[AccountDescriptor] =
+    &[writable("quasar_group"), readonly("mint"), signer("admin")];
+
+pub const SETTLE_TOKEN_PNL_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    writable("counterparty_mango_account"),
+    readonly("mango_cache"),
+    readonly("root_bank"),
+    writable("node_bank"),
+];
+
+pub const UPDATE_PRICE_SAMPLE_ACCOUNTS: &[AccountDescriptor] =
+    &[writable("quasar_group"), readonly("mint")];
+
+pub const QUOTE_MINT_ACCOUNTS: &[AccountDescriptor] = &[
+    readonly("quasar_group"),
+    readonly("token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    readonly("mango_account"),
+    readonly("mango_cache"),
+];
+
+pub const RECONCILE_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("token_mint"),
+    writable("fee_token_account"),
+    readonly("pda"),
+    readonly("token_program"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    readonly("mango_account"),
+    readonly("mango_cache"),
+];
+
+pub const SETTLE_FUNDING_ACCOUNTS: &[AccountDescriptor] = &[
+    readonly("quasar_group"),
+    readonly("token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    readonly("mango_account"),
+    writable("mango_cache"),
+    writable("mango_perp_market"),
+    readonly("mango_bids"),
+    readonly("mango_asks"),
+];
+
+pub const SET_MANGO_PROGRAM_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    signer("admin"),
+];
+
+/// `MINT_LEVERAGE_TOKEN_ACCOUNTS`, then the Mango account the collateral comes from
+pub const MINT_FROM_MANGO_BALANCE_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable("token_mint"),
+    writable("owner_leverage_token_account"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    writable_signer("owner"),
+    readonly("mango_cache"),
+    readonly("root_bank"),
+    writable("node_bank"),
+    writable("vault"),
+    readonly("token_program"),
+    writable("owner_quote_token_account"),
+    readonly("pda"),
+    readonly("system_program"),
+    readonly("associated_token_program"),
+    readonly("rent_program"),
+    writable("fee_token_account"),
+    writable("hold_record"),
+    writable("source_mango_account"),
+    readonly("mango_signer"),
+];
+
+pub const VALIDATE_GROUP_ACCOUNTS: &[AccountDescriptor] = &[readonly("quasar_group")];
+
+pub const SET_USE_EMA_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("base_token_mint"),
+    signer("admin"),
+];
+
+pub const LOG_TOKEN_LEVERAGE_ACCOUNTS: &[AccountDescriptor] = &[
+    readonly("quasar_group"),
+    readonly("token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    readonly("mango_account"),
+    readonly("mango_cache"),
+];
+
+pub const SET_MINT_FREEZE_AUTHORITY_ACCOUNTS: &[AccountDescriptor] = &[
+    readonly("quasar_group"),
+    writable("token_mint"),
+    readonly("pda"),
+    readonly("token_program"),
+    signer("admin"),
+];
+
+pub const BATCH_MINT_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable_signer("owner"),
+    readonly("mango_cache"),
+    readonly("root_bank"),
+    writable("node_bank"),
+    writable("vault"),
+    readonly("token_program"),
+    writable("owner_quote_token_account"),
+    readonly("pda"),
+    readonly("system_program"),
+    readonly("associated_token_program"),
+    readonly("rent_program"),
+];
+
+/// Accounts following `BATCH_MINT_ACCOUNTS` for each entry of a `BatchMint`
+pub const BATCH_MINT_TOKEN_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("token_mint"),
+    writable("owner_leverage_token_account"),
+    writable("mango_account"),
+    writable("hold_record"),
+    writable("fee_token_account"),
+];
+
+pub const MIGRATE_GROUP_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    writable_signer("admin"),
+    readonly("mango_group"),
+    readonly("quote_mint"),
+    readonly("system_program"),
+];
+
+pub const QUOTE_REDEEM_ACCOUNTS: &[AccountDescriptor] = &[
+    readonly("quasar_group"),
+    readonly("token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    readonly("mango_account"),
+    readonly("mango_cache"),
+];
+
+pub const SWEEP_DUST_ACCOUNTS: &[AccountDescriptor] = &[
+    readonly("quasar_group"),
+    readonly("token_mint"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    writable("mango_account"),
+    readonly("mango_cache"),
+    readonly("root_bank"),
+    writable("node_bank"),
+    writable("vault"),
+    writable("fee_vault"),
+    readonly("pda"),
+    readonly("mango_signer"),
+    readonly("token_program"),
+];
+
+pub const SET_REBALANCE_ORDER_TYPE_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    signer("admin"),
+];
+
+pub const LOG_TOTAL_TVL_ACCOUNTS: &[AccountDescriptor] = &[
+    readonly("quasar_group"),
+    readonly("mango_program"),
+    readonly("mango_group"),
+    readonly("mango_cache"),
+];
+
+pub const SET_COMPOUND_ACCOUNTS: &[AccountDescriptor] = &[
+    writable("quasar_group"),
+    readonly("token_mint"),
+    signer("admin"),
+];
+
+pub const SET_RISK_ADMIN_ACCOUNTS: &[AccountDescriptor] =
+    &[writable("quasar_group"), signer("admin")];
+
+pub const SET_PAUSED_ACCOUNTS: &[AccountDescriptor] = &[writable("quasar_group"), signer("admin")];
+
+impl QuasarInstruction {
+    /// The fixed accounts this instruction expects, see the `*_ACCOUNTS` constants
+    pub fn fixed_accounts(&self) -> &'static [AccountDescriptor] {
+        match self {
+            Self::InitQuasarGroup { .. } => INIT_QUASAR_GROUP_ACCOUNTS,
+            Self::AddBaseToken => ADD_BASE_TOKEN_ACCOUNTS,
+            Self::AddLeverageToken { .. } => ADD_LEVERAGE_TOKEN_ACCOUNTS,
+            Self::MintLeverageToken { .. } => MINT_LEVERAGE_TOKEN_ACCOUNTS,
+            Self::BurnLeverageToken { .. } => BURN_LEVERAGE_TOKEN_ACCOUNTS,
+            Self::Rebalance => REBALANCE_ACCOUNTS,
+            Self::CloseLeverageToken => CLOSE_LEVERAGE_TOKEN_ACCOUNTS,
+            Self::ChangeGroupParams { .. } => CHANGE_GROUP_PARAMS_ACCOUNTS,
+            Self::DeleverageToken => DELEVERAGE_TOKEN_ACCOUNTS,
+            Self::SetTargetLeverage { .. } => SET_TARGET_LEVERAGE_ACCOUNTS,
+            Self::LogGroupState => LOG_GROUP_STATE_ACCOUNTS,
+            Self::SetDepositCap { .. } => SET_DEPOSIT_CAP_ACCOUNTS,
+            Self::SetManagementFee { .. } => SET_MANAGEMENT_FEE_ACCOUNTS,
+            Self::InitiateShutdown => INITIATE_SHUTDOWN_ACCOUNTS,
+            Self::InitFeeVault => INIT_FEE_VAULT_ACCOUNTS,
+            Self::SetMinMintQuantity { .. } => SET_MIN_MINT_QUANTITY_ACCOUNTS,
+            Self::RequestRedeem { .. } => REQUEST_REDEEM_ACCOUNTS,
+            Self::ProcessRedeemQueue => PROCESS_REDEEM_QUEUE_ACCOUNTS,
+            Self::RebalanceLeg { .. } => REBALANCE_LEG_ACCOUNTS,
+            Self::RemoveBaseToken => REMOVE_BASE_TOKEN_ACCOUNTS,
+            Self::SettleTokenPnl { .. } => SETTLE_TOKEN_PNL_ACCOUNTS,
+            Self::UpdatePriceSample => UPDATE_PRICE_SAMPLE_ACCOUNTS,
+            Self::QuoteMint { .. } => QUOTE_MINT_ACCOUNTS,
+            Self::ReconcileToken => RECONCILE_TOKEN_ACCOUNTS,
+            Self::SettleFunding { .. } => SETTLE_FUNDING_ACCOUNTS,
+            Self::SetMangoProgram { .. } => SET_MANGO_PROGRAM_ACCOUNTS,
+            Self::MintFromMangoBalance { .. } => MINT_FROM_MANGO_BALANCE_ACCOUNTS,
+            Self::ValidateGroup => VALIDATE_GROUP_ACCOUNTS,
+            Self::SetUseEma { .. } => SET_USE_EMA_ACCOUNTS,
+            Self::LogTokenLeverage => LOG_TOKEN_LEVERAGE_ACCOUNTS,
+            Self::SetMintFreezeAuthority { .. } => SET_MINT_FREEZE_AUTHORITY_ACCOUNTS,
+            Self::BatchMint { .. } => BATCH_MINT_ACCOUNTS,
+            Self::MigrateGroup => MIGRATE_GROUP_ACCOUNTS,
+            Self::QuoteRedeem { .. } => QUOTE_REDEEM_ACCOUNTS,
+            Self::SweepDust => SWEEP_DUST_ACCOUNTS,
+            Self::SetRebalanceOrderType { .. } => SET_REBALANCE_ORDER_TYPE_ACCOUNTS,
+            Self::LogTotalTvl => LOG_TOTAL_TVL_ACCOUNTS,
+            Self::SetCompound { .. } => SET_COMPOUND_ACCOUNTS,
+            Self::SetRiskAdmin { .. } => SET_RISK_ADMIN_ACCOUNTS,
+            Self::SetPaused { .. } => SET_PAUSED_ACCOUNTS,
+        }
+    }
+}
+
+/// One mint of a `BatchMint`
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct BatchMintEntry {
+    /// Slot of the leverage token in the group
+    pub token_index: u8,
+    /// Native units of the leverage token to mint
+    pub quantity: u64,
+    /// Most native quote units the mint may take, the mint fails if it's priced higher
+    pub max_collateral: u64,
+}
+
+/// The four accounts of a perp market a leg trades on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PerpMarketAccounts {
+    pub perp_market: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_queue: Pubkey,
+}
+
+impl PerpMarketAccounts {
+    fn metas(&self) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.perp_market, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.event_queue, false),
+        ]
+    }
+}
+
+/// The accounts of a perp market a mint reads the book of
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PerpBookAccounts {
+    pub perp_market: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+}
+
+impl PerpBookAccounts {
+    fn metas(&self) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.perp_market, false),
+            AccountMeta::new_readonly(self.bids, false),
+            AccountMeta::new_readonly(self.asks, false),
+        ]
+    }
+}
+
+/// The accounts a rebalance crank adds to be paid its reward
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrankRewardAccounts {
+    /// The group's fee vault
+    pub fee_vault: Pubkey,
+    /// Quote token account of the crank the reward is paid to
+    pub crank_quote_token_account: Pubkey,
+}
+
+impl CrankRewardAccounts {
+    fn metas(&self) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.fee_vault, false),
+            AccountMeta::new(self.crank_quote_token_account, false),
+        ]
+    }
+}
+
+fn book_accounts(books: &[PerpBookAccounts]) -> impl Iterator<Item = AccountMeta> + '_ {
+    books.iter().flat_map(|accounts| accounts.metas())
+}
+
+// Builders take the fixed accounts as an array in the order of the matching `*_ACCOUNTS`
+// constant, followed by the variable accounts of the instruction if it has any
+
+fn build(
+    program_id: &Pubkey,
+    instruction: QuasarInstruction,
+    fixed_accounts: &[Pubkey],
+    remaining_accounts: impl IntoIterator<Item = AccountMeta>,
+) -> Instruction {
+    let descriptors = instruction.fixed_accounts();
+    assert_eq!(descriptors.len(), fixed_accounts.len());
+    let accounts = descriptors
+        .iter()
+        .zip(fixed_accounts)
+        .map(|(descriptor, pubkey)| descriptor.meta(*pubkey))
+        .chain(remaining_accounts)
+        .collect();
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: instruction.pack(),
+    }
+}
+
+fn trading_accounts<'a>(
+    open_orders: &'a [Pubkey; MAX_PAIRS],
+    perp_markets: &'a [PerpMarketAccounts],
+) -> impl Iterator<Item = AccountMeta> + 'a {
+    open_orders
+        .iter()
+        .map(|pubkey| AccountMeta::new_readonly(*pubkey, false))
+        .chain(perp_markets.iter().flat_map(|accounts| accounts.metas()))
+}
+
+pub fn init_quasar_group(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; INIT_QUASAR_GROUP_ACCOUNTS.len()],
+    signer_nonce: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::InitQuasarGroup { signer_nonce };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn add_base_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; ADD_BASE_TOKEN_ACCOUNTS.len()],
+    extra_oracles: &[Pubkey],
+) -> Instruction {
+    let extra_oracles = extra_oracles
+        .iter()
+        .map(|pubkey| AccountMeta::new_readonly(*pubkey, false));
+    build(
+        program_id,
+        QuasarInstruction::AddBaseToken,
+        accounts,
+        extra_oracles,
+    )
+}
+
+/// `perp_market_weights` and `perp_market_base_mints` hold one weight and one base token mint
+/// for each of `perp_markets`
+#[allow(clippy::too_many_arguments)]
+pub fn add_leverage_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; ADD_LEVERAGE_TOKEN_ACCOUNTS.len()],
+    target_leverage: I80F48,
+    perp_market_weights: &[I80F48],
+    perp_market_base_mints: &[Pubkey],
+    perp_markets: &[Pubkey],
+    decimals: Option<u8>,
+    rebalance_deadband_bps: u16,
+    creation_fee_accounts: Option<&[Pubkey; CREATION_FEE_ACCOUNTS.len()]>,
+) -> Instruction {
+    assert_eq!(perp_market_weights.len(), perp_markets.len());
+    assert_eq!(perp_market_base_mints.len(), perp_markets.len());
+    let mut weights = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    weights[..perp_market_weights.len()].copy_from_slice(perp_market_weights);
+    let mut base_mints = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    base_mints[..perp_market_base_mints.len()].copy_from_slice(perp_market_base_mints);
+    let instruction = QuasarInstruction::AddLeverageToken {
+        target_leverage,
+        num_perp_markets: perp_markets.len() as u8,
+        perp_market_weights: weights,
+        perp_market_base_mints: base_mints,
+        decimals,
+        rebalance_deadband_bps,
+    };
+    let perp_markets = perp_markets
+        .iter()
+        .map(|pubkey| AccountMeta::new_readonly(*pubkey, false));
+    let creation_fee_accounts = creation_fee_accounts
+        .into_iter()
+        .flat_map(|accounts| CREATION_FEE_ACCOUNTS.iter().zip(accounts.iter()))
+        .map(|(descriptor, pubkey)| descriptor.meta(*pubkey));
+    build(
+        program_id,
+        instruction,
+        accounts,
+        perp_markets.chain(creation_fee_accounts),
+    )
+}
+
+pub fn mint_leverage_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; MINT_LEVERAGE_TOKEN_ACCOUNTS.len()],
+    books: &[PerpBookAccounts],
+    quantity: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::MintLeverageToken { quantity };
+    build(program_id, instruction, accounts, book_accounts(books))
+}
+
+pub fn burn_leverage_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; BURN_LEVERAGE_TOKEN_ACCOUNTS.len()],
+    open_orders: &[Pubkey; MAX_PAIRS],
+    perp_markets: &[PerpMarketAccounts],
+    quantity: u64,
+    output_mint: Option<Pubkey>,
+) -> Instruction {
+    let instruction = QuasarInstruction::BurnLeverageToken {
+        quantity,
+        output_mint,
+    };
+    let remaining_accounts = trading_accounts(open_orders, perp_markets);
+    build(program_id, instruction, accounts, remaining_accounts)
+}
+
+pub fn rebalance(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; REBALANCE_ACCOUNTS.len()],
+    open_orders: &[Pubkey; MAX_PAIRS],
+    perp_markets: &[PerpMarketAccounts],
+    crank_reward: Option<&CrankRewardAccounts>,
+) -> Instruction {
+    let remaining_accounts = trading_accounts(open_orders, perp_markets).chain(
+        crank_reward
+            .into_iter()
+            .flat_map(|accounts| accounts.metas()),
+    );
+    build(
+        program_id,
+        QuasarInstruction::Rebalance,
+        accounts,
+        remaining_accounts,
+    )
+}
+
+pub fn close_leverage_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; CLOSE_LEVERAGE_TOKEN_ACCOUNTS.len()],
+) -> Instruction {
+    build(
+        program_id,
+        QuasarInstruction::CloseLeverageToken,
+        accounts,
+        None,
+    )
+}
+
+pub fn change_group_params(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; CHANGE_GROUP_PARAMS_ACCOUNTS.len()],
+    min_health_ratio: Option<I80F48>,
+    deleverage_health_ratio: Option<I80F48>,
+    min_rebalance_interval: Option<u64>,
+    max_tokens_per_base: Option<u64>,
+    rebalance_deadband_bps: Option<u16>,
+    creation_fee: Option<u64>,
+    min_hold_secs: Option<u64>,
+    crank_reward_bps: Option<u16>,
+    max_crank_reward: Option<u16>,
+) -> Instruction {
+    let instruction = QuasarInstruction::ChangeGroupParams {
+        min_health_ratio,
+        deleverage_health_ratio,
+        min_rebalance_interval,
+        max_tokens_per_base,
+        rebalance_deadband_bps,
+        creation_fee,
+        min_hold_secs,
+        crank_reward_bps,
+        max_crank_reward,
+    };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn deleverage_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; DELEVERAGE_TOKEN_ACCOUNTS.len()],
+    open_orders: &[Pubkey; MAX_PAIRS],
+    perp_markets: &[PerpMarketAccounts],
+) -> Instruction {
+    let remaining_accounts = trading_accounts(open_orders, perp_markets);
+    build(
+        program_id,
+        QuasarInstruction::DeleverageToken,
+        accounts,
+        remaining_accounts,
+    )
+}
+
+pub fn set_target_leverage(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_TARGET_LEVERAGE_ACCOUNTS.len()],
+    new_leverage: I80F48,
+    rebalance_deadband_bps: Option<u16>,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetTargetLeverage {
+        new_leverage,
+        rebalance_deadband_bps,
+    };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn log_group_state(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; LOG_GROUP_STATE_ACCOUNTS.len()],
+) -> Instruction {
+    build(program_id, QuasarInstruction::LogGroupState, accounts, None)
+}
+
+pub fn set_deposit_cap(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_DEPOSIT_CAP_ACCOUNTS.len()],
+    deposit_cap: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetDepositCap { deposit_cap };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn set_management_fee(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_MANAGEMENT_FEE_ACCOUNTS.len()],
+    management_fee_bps_annual: u16,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetManagementFee {
+        management_fee_bps_annual,
+    };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn initiate_shutdown(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; INITIATE_SHUTDOWN_ACCOUNTS.len()],
+) -> Instruction {
+    build(
+        program_id,
+        QuasarInstruction::InitiateShutdown,
+        accounts,
+        None,
+    )
+}
+
+pub fn init_fee_vault(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; INIT_FEE_VAULT_ACCOUNTS.len()],
+) -> Instruction {
+    build(program_id, QuasarInstruction::InitFeeVault, accounts, None)
+}
+
+pub fn set_min_mint_quantity(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_MIN_MINT_QUANTITY_ACCOUNTS.len()],
+    min_mint_quantity: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetMinMintQuantity { min_mint_quantity };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn request_redeem(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; REQUEST_REDEEM_ACCOUNTS.len()],
+    quantity: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::RequestRedeem { quantity };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn process_redeem_queue(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; PROCESS_REDEEM_QUEUE_ACCOUNTS.len()],
+    open_orders: &[Pubkey; MAX_PAIRS],
+    perp_markets: &[PerpMarketAccounts],
+) -> Instruction {
+    let remaining_accounts = trading_accounts(open_orders, perp_markets);
+    let instruction = QuasarInstruction::ProcessRedeemQueue;
+    build(program_id, instruction, accounts, remaining_accounts)
+}
+
+pub fn rebalance_leg(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; REBALANCE_LEG_ACCOUNTS.len()],
+    open_orders: &[Pubkey; MAX_PAIRS],
+    perp_market: &PerpMarketAccounts,
+    leg_index: u8,
+    crank_reward: Option<&CrankRewardAccounts>,
+) -> Instruction {
+    let remaining_accounts = trading_accounts(open_orders, std::slice::from_ref(perp_market))
+        .chain(
+            crank_reward
+                .into_iter()
+                .flat_map(|accounts| accounts.metas()),
+        );
+    let instruction = QuasarInstruction::RebalanceLeg { leg_index };
+    build(program_id, instruction, accounts, remaining_accounts)
+}
+
+pub fn remove_base_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; REMOVE_BASE_TOKEN_ACCOUNTS.len()],
+) -> Instruction {
+    build(
+        program_id,
+        QuasarInstruction::RemoveBaseToken,
+        accounts,
+        None,
+    )
+}
+
+pub fn settle_token_pnl(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SETTLE_TOKEN_PNL_ACCOUNTS.len()],
+    leg_index: u8,
+) -> Instruction {
+    let instruction = QuasarInstruction::SettleTokenPnl { leg_index };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn update_price_sample(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; UPDATE_PRICE_SAMPLE_ACCOUNTS.len()],
+    oracles: &[Pubkey],
+) -> Instruction {
+    let oracles = oracles
+        .iter()
+        .map(|pubkey| AccountMeta::new_readonly(*pubkey, false));
+    build(
+        program_id,
+        QuasarInstruction::UpdatePriceSample,
+        accounts,
+        oracles,
+    )
+}
+
+pub fn quote_mint(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; QUOTE_MINT_ACCOUNTS.len()],
+    books: &[PerpBookAccounts],
+    quantity: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::QuoteMint { quantity };
+    build(program_id, instruction, accounts, book_accounts(books))
+}
+
+pub fn reconcile_token(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; RECONCILE_TOKEN_ACCOUNTS.len()],
+) -> Instruction {
+    build(
+        program_id,
+        QuasarInstruction::ReconcileToken,
+        accounts,
+        None,
+    )
+}
+
+pub fn settle_funding(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SETTLE_FUNDING_ACCOUNTS.len()],
+    leg_index: u8,
+) -> Instruction {
+    let instruction = QuasarInstruction::SettleFunding { leg_index };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn set_mango_program(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_MANGO_PROGRAM_ACCOUNTS.len()],
+) -> Instruction {
+    build(
+        program_id,
+        QuasarInstruction::SetMangoProgram,
+        accounts,
+        None,
+    )
+}
+
+pub fn mint_from_mango_balance(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; MINT_FROM_MANGO_BALANCE_ACCOUNTS.len()],
+    source_open_orders: &[Pubkey; MAX_PAIRS],
+    books: &[PerpBookAccounts],
+    quantity: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::MintFromMangoBalance { quantity };
+    let remaining_accounts = source_open_orders
+        .iter()
+        .map(|pubkey| AccountMeta::new_readonly(*pubkey, false))
+        .chain(book_accounts(books));
+    build(program_id, instruction, accounts, remaining_accounts)
+}
+
+pub fn validate_group(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; VALIDATE_GROUP_ACCOUNTS.len()],
+    quasar_group: &QuasarGroup,
+) -> Instruction {
+    let validation_accounts = quasar_group
+        .validation_accounts()
+        .into_iter()
+        .map(|pubkey| AccountMeta::new_readonly(pubkey, false));
+    build(
+        program_id,
+        QuasarInstruction::ValidateGroup,
+        accounts,
+        validation_accounts,
+    )
+}
+
+pub fn set_use_ema(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_USE_EMA_ACCOUNTS.len()],
+    use_ema: bool,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetUseEma { use_ema };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn log_token_leverage(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; LOG_TOKEN_LEVERAGE_ACCOUNTS.len()],
+) -> Instruction {
+    build(
+        program_id,
+        QuasarInstruction::LogTokenLeverage,
+        accounts,
+        None,
+    )
+}
+
+pub fn set_mint_freeze_authority(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_MINT_FREEZE_AUTHORITY_ACCOUNTS.len()],
+    freeze_authority: Option<Pubkey>,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetMintFreezeAuthority { freeze_authority };
+    build(program_id, instruction, accounts, None)
+}
+
+/// The accounts `BatchMint` needs for each leverage token it mints
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchMintAccounts {
+    pub token_mint: Pubkey,
+    pub owner_leverage_token_account: Pubkey,
+    pub mango_account: Pubkey,
+    pub hold_record: Pubkey,
+    /// The group signer's account of the leverage token the management fee is minted to
+    pub fee_token_account: Pubkey,
+    /// One for each perp market of the leverage token, in the order they were added
+    pub books: Vec<PerpBookAccounts>,
+}
+
+pub fn batch_mint(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; BATCH_MINT_ACCOUNTS.len()],
+    entries: &[(BatchMintEntry, BatchMintAccounts)],
+) -> Instruction {
+    assert!(entries.len() <= MAX_BATCH_MINTS);
+    let mut batch = [BatchMintEntry::default(); MAX_BATCH_MINTS];
+    for (slot, (entry, _)) in batch.iter_mut().zip(entries) {
+        *slot = *entry;
+    }
+    let instruction = QuasarInstruction::BatchMint {
+        num_entries: entries.len() as u8,
+        entries: batch,
+    };
+    let token_accounts = entries.iter().flat_map(|(_, token_accounts)| {
+        BATCH_MINT_TOKEN_ACCOUNTS
+            .iter()
+            .zip(vec![
+                token_accounts.token_mint,
+                token_accounts.owner_leverage_token_account,
+                token_accounts.mango_account,
+                token_accounts.hold_record,
+                token_accounts.fee_token_account,
+            ])
+            .map(|(descriptor, pubkey)| descriptor.meta(pubkey))
+            .chain(book_accounts(&token_accounts.books))
+            .collect::<Vec<_>>()
+    });
+    build(program_id, instruction, accounts, token_accounts)
+}
+
+/// `token_mints` only for version 0 groups, empty otherwise
+pub fn migrate_group(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; MIGRATE_GROUP_ACCOUNTS.len()],
+    token_mints: &[Pubkey],
+) -> Instruction {
+    let token_mints = token_mints
+        .iter()
+        .map(|pubkey| AccountMeta::new_readonly(*pubkey, false));
+    build(
+        program_id,
+        QuasarInstruction::MigrateGroup,
+        accounts,
+        token_mints,
+    )
+}
+
+pub fn quote_redeem(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; QUOTE_REDEEM_ACCOUNTS.len()],
+    quantity: u64,
+) -> Instruction {
+    let instruction = QuasarInstruction::QuoteRedeem { quantity };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn sweep_dust(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SWEEP_DUST_ACCOUNTS.len()],
+    open_orders: &[Pubkey; MAX_PAIRS],
+) -> Instruction {
+    let remaining_accounts = open_orders
+        .iter()
+        .map(|pubkey| AccountMeta::new_readonly(*pubkey, false));
+    build(
+        program_id,
+        QuasarInstruction::SweepDust,
+        accounts,
+        remaining_accounts,
+    )
+}
+
+pub fn set_rebalance_order_type(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_REBALANCE_ORDER_TYPE_ACCOUNTS.len()],
+    order_type: RebalanceOrderType,
+    offset_bps: u16,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetRebalanceOrderType {
+        order_type: order_type.into(),
+        offset_bps,
+    };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn log_total_tvl(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; LOG_TOTAL_TVL_ACCOUNTS.len()],
+    quasar_group: &QuasarGroup,
+) -> Instruction {
+    let mango_accounts = quasar_group
+        .tvl_accounts()
+        .into_iter()
+        .map(|pubkey| AccountMeta::new_readonly(pubkey, false));
+    build(
+        program_id,
+        QuasarInstruction::LogTotalTvl,
+        accounts,
+        mango_accounts,
+    )
+}
+
+pub fn set_compound(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_COMPOUND_ACCOUNTS.len()],
+    compound: bool,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetCompound { compound };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn set_risk_admin(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_RISK_ADMIN_ACCOUNTS.len()],
+    risk_admin: Option<Pubkey>,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetRiskAdmin { risk_admin };
+    build(program_id, instruction, accounts, None)
+}
+
+pub fn set_paused(
+    program_id: &Pubkey,
+    accounts: &[Pubkey; SET_PAUSED_ACCOUNTS.len()],
+    paused: bool,
+) -> Instruction {
+    let instruction = QuasarInstruction::SetPaused { paused };
+    build(program_id, instruction, accounts, None)
+}
@@ -3,8 +3,15 @@ pub mod entrypoint;
 #[macro_use]
 pub mod error;
 
+// Decoding accounts fetched over RPC, which on-chain code has no use for
+#[cfg(not(any(target_arch = "bpf", target_os = "solana")))]
+pub mod client;
+pub mod events;
 pub mod instruction;
+pub mod math;
 pub mod oracle;
 pub mod processor;
 pub mod state;
 pub mod utils;
+
+pub use utils::quasar_group_signer;
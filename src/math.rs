@@ -0,0 +1,257 @@
+use fixed::types::I80F48;
+use mango::state::{ONE_I80F48, ZERO_I80F48};
+
+use crate::error::{check_assert, QuasarError, QuasarErrorCode, QuasarResult, SourceFileId};
+
+declare_check_assert_macros!(SourceFileId::Math);
+
+pub const BPS_UNIT: u64 = 10_000;
+pub const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+/// Number of native units in one whole token with `decimals`, `None` if it doesn't fit
+pub(crate) fn decimals_unit(decimals: u8) -> Option<I80F48> {
+    (0..decimals).try_fold(ONE_I80F48, |unit, _| unit.checked_mul(I80F48::from_num(10)))
+}
+
+/// Native units of a token with `decimals` for an `amount` of whole tokens. Rounded down, so
+/// fractions of a native unit are never credited.
+pub fn ui_to_native(amount: I80F48, decimals: u8) -> QuasarResult<u64> {
+    check!(!amount.is_negative(), QuasarErrorCode::InvalidParam)?;
+
+    decimals_unit(decimals)
+        .and_then(|unit| amount.checked_mul(unit))
+        .map(|v| v.floor())
+        .and_then(|v| v.checked_to_num::<u64>())
+        .ok_or(math_err!())
+}
+
+/// Whole tokens of a token with `decimals` for an `amount` of native units. Rounded down to
+/// the precision of `I80F48` when the division isn't exact.
+pub fn native_to_ui(amount: u64, decimals: u8) -> I80F48 {
+    let amount = I80F48::from_num(amount);
+    match decimals_unit(decimals) {
+        Some(unit) => amount / unit,
+        // Too many decimals to build the unit, divide it out one digit at a time instead
+        None => (0..decimals).fold(amount, |v, _| v / I80F48::from_num(10)),
+    }
+}
+
+// Rounding between collateral and leverage tokens always goes against the user: mints pay
+// collateral rounded up (or equivalently get tokens rounded down) and redeems get collateral
+// rounded down. Minting and immediately redeeming therefore never returns more than was
+// deposited, so no amount of dust round trips can drain the Mango account.
+
+/// Native collateral needed to mint `tokens` native units of a leverage token with `decimals`
+/// at a `nav` of native quote units per whole token, with a fee of `fee_bps` charged on top.
+/// Rounded up.
+pub fn collateral_for_tokens(
+    nav: I80F48,
+    tokens: u64,
+    decimals: u8,
+    fee_bps: u16,
+) -> QuasarResult<u64> {
+    check!(nav.is_positive(), QuasarErrorCode::InvalidParam)?;
+    let unit = decimals_unit(decimals).ok_or(math_err!())?;
+
+    I80F48::from_num(tokens)
+        .checked_mul(nav)
+        .and_then(|v| v.checked_mul(I80F48::from_num(BPS_UNIT + fee_bps as u64)))
+        .and_then(|v| v.checked_div(I80F48::from_num(BPS_UNIT)))
+        .and_then(|v| v.checked_div(unit))
+        .and_then(|v| v.checked_ceil())
+        .and_then(|v| v.checked_to_num::<u64>())
+        .ok_or(math_err!())
+}
+
+/// Native units of a leverage token with `decimals` minted for `collateral` native quote units
+/// at a `nav` of native quote units per whole token, after taking a fee of `fee_bps`. Inverse
+/// of `collateral_for_tokens`, rounded down.
+pub fn tokens_for_collateral(
+    nav: I80F48,
+    collateral: u64,
+    decimals: u8,
+    fee_bps: u16,
+) -> QuasarResult<u64> {
+    check!(nav.is_positive(), QuasarErrorCode::InvalidParam)?;
+    let unit = decimals_unit(decimals).ok_or(math_err!())?;
+
+    I80F48::from_num(collateral)
+        .checked_mul(I80F48::from_num(BPS_UNIT))
+        .and_then(|v| v.checked_div(I80F48::from_num(BPS_UNIT + fee_bps as u64)))
+        .and_then(|v| v.checked_mul(unit))
+        .and_then(|v| v.checked_div(nav))
+        .map(|v| v.floor())
+        .and_then(|v| v.checked_to_num::<u64>())
+        .ok_or(math_err!())
+}
+
+/// Native collateral paid out for redeeming `tokens` native units of a leverage token with
+/// `decimals` at a `nav` of native quote units per whole token. Rounded down, see the rounding
+/// note above.
+pub fn collateral_for_redeem(nav: I80F48, tokens: u64, decimals: u8) -> QuasarResult<u64> {
+    check!(nav.is_positive(), QuasarErrorCode::InvalidParam)?;
+    let unit = decimals_unit(decimals).ok_or(math_err!())?;
+
+    I80F48::from_num(tokens)
+        .checked_mul(nav)
+        .and_then(|v| v.checked_div(unit))
+        .map(|v| v.floor())
+        .and_then(|v| v.checked_to_num::<u64>())
+        .ok_or(math_err!())
+}
+
+/// Size of the base position in native base units needed to reach `leverage` on `collateral`
+/// native quote units, at a `price` of native quote units per native base unit
+pub fn target_position_size(
+    collateral: I80F48,
+    leverage: I80F48,
+    price: I80F48,
+) -> QuasarResult<I80F48> {
+    collateral
+        .checked_mul(leverage)
+        .and_then(|v| v.checked_div(price))
+        .ok_or(math_err!())
+}
+
+/// Relative distance of the `current` position from the `target` one, as a fraction of the
+/// target. Saturates to `I80F48::MAX` when the target is zero but the position isn't.
+pub fn position_deviation(current: I80F48, target: I80F48) -> I80F48 {
+    if target == ZERO_I80F48 {
+        return if current == ZERO_I80F48 {
+            ZERO_I80F48
+        } else {
+            I80F48::MAX
+        };
+    }
+
+    current
+        .checked_sub(target)
+        .and_then(|delta| delta.checked_abs())
+        .and_then(|delta| delta.checked_div(target.checked_abs()?))
+        .unwrap_or(I80F48::MAX)
+}
+
+/// Part of `exposure` backing `quantity` out of `supply` leverage tokens, which has to go when
+/// they are redeemed
+pub fn redeemed_exposure(exposure: I80F48, quantity: u64, supply: u64) -> QuasarResult<I80F48> {
+    check!(quantity <= supply, QuasarErrorCode::InvalidParam)?;
+    if quantity == supply {
+        return Ok(exposure);
+    }
+
+    exposure
+        .checked_mul(I80F48::from_num(quantity))
+        .and_then(|v| v.checked_div(I80F48::from_num(supply)))
+        .ok_or(math_err!())
+}
+
+/// Part of `quantity` a leg settles when `achieved` of the `intended` exposure change was
+/// filled. Rounded down so a partly filled unwind never pays out more than it closed
+pub fn filled_quantity(quantity: u64, intended: I80F48, achieved: I80F48) -> QuasarResult<u64> {
+    if intended == ZERO_I80F48 {
+        return Ok(quantity);
+    }
+
+    let ratio = achieved.checked_div(intended).ok_or(math_err!())?;
+    if !ratio.is_positive() {
+        return Ok(0);
+    }
+    if ratio >= ONE_I80F48 {
+        return Ok(quantity);
+    }
+
+    let filled = I80F48::from_num(quantity)
+        .checked_mul(ratio)
+        .ok_or(math_err!())?;
+    ui_to_native(filled, 0)
+}
+
+/// Whether `current` is within `tolerance_bps` of `target`, see `position_deviation`
+pub fn within_tolerance(current: I80F48, target: I80F48, tolerance_bps: u64) -> bool {
+    let tolerance = I80F48::from_num(tolerance_bps) / I80F48::from_num(BPS_UNIT);
+    position_deviation(current, target) <= tolerance
+}
+
+/// Exposure change a rebalance trades to take `current` to `target`, or nothing when `current`
+/// is already within the `deadband_bps` of it
+pub fn rebalance_exposure_delta(
+    current: I80F48,
+    target: I80F48,
+    deadband_bps: u64,
+) -> QuasarResult<I80F48> {
+    if within_tolerance(current, target, deadband_bps) {
+        return Ok(ZERO_I80F48);
+    }
+    target.checked_sub(current).ok_or(math_err!())
+}
+
+/// Native leverage token units to mint as a management fee of `fee_bps_annual` over
+/// `elapsed_secs` seconds on a `supply` of native units. Holders are diluted by the pro rata
+/// fee rate: the fee is taken linearly on the current supply rather than compounded. Rounded
+/// down.
+pub fn management_fee_tokens(
+    supply: u64,
+    fee_bps_annual: u16,
+    elapsed_secs: u64,
+) -> QuasarResult<u64> {
+    let tokens = (supply as u128)
+        .checked_mul(fee_bps_annual as u128)
+        .and_then(|v| v.checked_mul(elapsed_secs as u128))
+        .ok_or(math_err!())?
+        / (BPS_UNIT as u128 * SECONDS_PER_YEAR as u128);
+
+    check!(tokens <= u64::MAX as u128, QuasarErrorCode::MathOverflow)?;
+    Ok(tokens as u64)
+}
+
+/// Average price, in quote lots per base lot, of taking `base_lots` off one side of a perp
+/// book given as its `levels` of price and quantity in lots, best first. `None` when the side
+/// doesn't hold that many base lots
+pub fn average_fill_price(
+    levels: impl IntoIterator<Item = (i64, i64)>,
+    base_lots: I80F48,
+) -> QuasarResult<Option<I80F48>> {
+    check!(base_lots.is_positive(), QuasarErrorCode::InvalidParam)?;
+
+    let mut remaining = base_lots;
+    let mut cost = ZERO_I80F48;
+    for (price, quantity) in levels {
+        let taken = remaining.min(I80F48::from_num(quantity));
+        cost = taken
+            .checked_mul(I80F48::from_num(price))
+            .and_then(|v| cost.checked_add(v))
+            .ok_or(math_err!())?;
+        remaining -= taken;
+        if remaining == ZERO_I80F48 {
+            return cost.checked_div(base_lots).map(Some).ok_or(math_err!());
+        }
+    }
+    Ok(None)
+}
+
+/// Native quote units lost to slippage opening `notional` native quote units of exposure at an
+/// average `fill_price` rather than at `price`, both in the same units. Only buying above or
+/// selling below the price costs, a better fill isn't credited
+pub fn price_impact_cost(
+    notional: I80F48,
+    price: I80F48,
+    fill_price: I80F48,
+    is_buy: bool,
+) -> QuasarResult<I80F48> {
+    check!(price.is_positive(), QuasarErrorCode::InvalidParam)?;
+    let slippage = if is_buy {
+        fill_price.checked_sub(price)
+    } else {
+        price.checked_sub(fill_price)
+    }
+    .ok_or(math_err!())?;
+    if !slippage.is_positive() {
+        return Ok(ZERO_I80F48);
+    }
+
+    notional
+        .abs()
+        .checked_mul(slippage)
+        .and_then(|v| v.checked_div(price))
+        .ok_or(math_err!())
+}
@@ -1,23 +1,226 @@
 use fixed::types::I80F48;
 use mango_common::Loadable;
 use mango_macro::{Loadable, Pod};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+#[cfg(feature = "debug-errors")]
+use solana_program::msg;
 use solana_program::{account_info::AccountInfo, pubkey::Pubkey, rent::Rent};
-use std::{cell::RefMut, mem::size_of};
+use std::{
+    cell::{Ref, RefMut},
+    mem::size_of,
+};
 
-use crate::error::{check_assert, QuasarErrorCode, QuasarResult, SourceFileId};
+#[cfg(feature = "anchor-discriminator")]
+use crate::utils::account_discriminator;
+use crate::{
+    error::{check_assert, QuasarError, QuasarErrorCode, QuasarResult, SourceFileId},
+    math::{decimals_unit, within_tolerance},
+    utils::ACCOUNT_DISCRIMINATOR_LEN,
+};
 
 declare_check_assert_macros!(SourceFileId::Oracle);
 
+/// Oldest a Pyth aggregate price may be, in slots, to count towards the quorum
+pub const MAX_PYTH_PRICE_AGE_SLOTS: u64 = 25;
+/// Oldest a stub oracle price may be, in seconds, to count towards the quorum
+pub const MAX_STUB_PRICE_AGE_SECS: u64 = 60;
+
+/// Magic number `AddBaseToken` stamps on the stub oracles it initializes
+pub const STUB_ORACLE_MAGIC: u32 = 0x6F676E4D;
+
+/// Most oracles one instruction reads for a base token. Every one read costs the load of a Pyth
+/// or stub account out of the compute budget, so a base token can't have more than this
+pub const MAX_ORACLES_PER_READ: usize = 3;
+
+/// Most a quorum of prices may spread, between the lowest and the highest, for the oracles
+/// after them to be left unread
+pub const ORACLE_AGREEMENT_BPS: u64 = 100;
+
+/// Number of valid prices needed out of `num_oracles` configured ones, a strict majority
+pub fn oracle_quorum(num_oracles: usize) -> usize {
+    num_oracles / 2 + 1
+}
+
+/// Median of the prices read from the oracles of a base token, `None` standing for an oracle
+/// that was stale or invalid. The two middle prices are averaged when an even number is left
+pub fn median_price(prices: &[Option<I80F48>]) -> QuasarResult<I80F48> {
+    let mut valid: Vec<I80F48> = prices.iter().filter_map(|price| *price).collect();
+    check!(
+        valid.len() >= oracle_quorum(prices.len()),
+        QuasarErrorCode::OracleQuorumNotMet
+    )?;
+    median(&mut valid)
+}
+
+/// Read the prices of `num_oracles` oracles with `read_price`, in order, until a quorum of
+/// them is valid and within `ORACLE_AGREEMENT_BPS` of each other, and take the median of those
+/// read. The oracles after such a quorum aren't read, so a base token with more oracles than it
+/// needs only pays for the ones that are down or off. Gives up as soon as the oracles left
+/// can't make a quorum anymore
+pub fn read_quorum_price<F>(num_oracles: usize, mut read_price: F) -> QuasarResult<I80F48>
+where
+    F: FnMut(usize) -> QuasarResult<Option<I80F48>>,
+{
+    check!(
+        num_oracles <= MAX_ORACLES_PER_READ,
+        QuasarErrorCode::InvalidParam
+    )?;
+    let quorum = oracle_quorum(num_oracles);
+
+    let mut valid = [I80F48::from_num(0); MAX_ORACLES_PER_READ];
+    let mut num_valid = 0;
+    for i in 0..num_oracles {
+        if num_valid + (num_oracles - i) < quorum {
+            break;
+        }
+        if let Some(price) = read_price(i)? {
+            valid[num_valid] = price;
+            num_valid += 1;
+            if num_valid == quorum && prices_agree(&valid[..num_valid]) {
+                break;
+            }
+        }
+    }
+    check!(num_valid >= quorum, QuasarErrorCode::OracleQuorumNotMet)?;
+    median(&mut valid[..num_valid])
+}
+
+/// Whether the lowest and the highest of `prices` are within `ORACLE_AGREEMENT_BPS`, so one
+/// outlier among them can't set the median on its own
+fn prices_agree(prices: &[I80F48]) -> bool {
+    let lowest = prices.iter().min();
+    let highest = prices.iter().max();
+    match (lowest, highest) {
+        (Some(&lowest), Some(&highest)) => within_tolerance(highest, lowest, ORACLE_AGREEMENT_BPS),
+        _ => true,
+    }
+}
+
+/// Median of `values`, which can't be empty. The two middle values are averaged when there is
+/// an even number of them
+fn median(values: &mut [I80F48]) -> QuasarResult<I80F48> {
+    values.sort();
+    let mid = values.len() / 2;
+    if values.len() % 2 == 1 {
+        return Ok(values[mid]);
+    }
+    values[mid - 1]
+        .checked_add(values[mid])
+        .map(|sum| sum / 2)
+        .ok_or(math_err!())
+}
+
+/// Price of a base token as read from its oracles, in whole quote tokens per whole base token,
+/// the way Pyth publishes it. It carries the decimals of the base token so it can't be applied
+/// to native amounts without going through the conversions below
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OraclePrice {
+    pub value: I80F48,
+    pub base_decimals: u8,
+}
+
+impl OraclePrice {
+    pub fn new(value: I80F48, base_decimals: u8) -> Self {
+        Self {
+            value,
+            base_decimals,
+        }
+    }
+
+    /// Price of a base token with `base_decimals` from `native_price`, in native quote units per
+    /// native base unit the way Mango caches prices
+    pub fn from_native(
+        native_price: I80F48,
+        base_decimals: u8,
+        quote_decimals: u8,
+    ) -> QuasarResult<Self> {
+        let value = if base_decimals >= quote_decimals {
+            decimals_unit(base_decimals - quote_decimals)
+                .and_then(|unit| native_price.checked_mul(unit))
+        } else {
+            decimals_unit(quote_decimals - base_decimals)
+                .and_then(|unit| native_price.checked_div(unit))
+        };
+        Ok(Self::new(value.ok_or(math_err!())?, base_decimals))
+    }
+
+    /// Native quote units per native base unit, the units of Mango cache prices. Only scaled by
+    /// the difference of the decimals, so it gives back the exact price `from_native` took
+    /// whenever the base token has at least as many decimals as the quote token
+    pub fn native_price(&self, quote_decimals: u8) -> QuasarResult<I80F48> {
+        if self.base_decimals >= quote_decimals {
+            decimals_unit(self.base_decimals - quote_decimals)
+                .and_then(|unit| self.value.checked_div(unit))
+        } else {
+            decimals_unit(quote_decimals - self.base_decimals)
+                .and_then(|unit| self.value.checked_mul(unit))
+        }
+        .ok_or(math_err!())
+    }
+
+    /// Native quote units `base_native` native base units are worth. Rounded down
+    pub fn native_value(&self, base_native: u64, quote_decimals: u8) -> QuasarResult<u64> {
+        // Scaled up before dividing by the base unit so small prices keep their precision
+        decimals_unit(quote_decimals)
+            .and_then(|quote_unit| self.value.checked_mul(quote_unit))
+            .and_then(|price| price.checked_mul(I80F48::from_num(base_native)))
+            .zip(decimals_unit(self.base_decimals))
+            .and_then(|(value, base_unit)| value.checked_div(base_unit))
+            .map(|value| value.floor())
+            .and_then(|value| value.checked_to_num::<u64>())
+            .ok_or(math_err!())
+    }
+
+    /// Native base units `collateral` native quote units buy at this price. Rounded down
+    pub fn base_for_collateral(&self, collateral: u64, quote_decimals: u8) -> QuasarResult<u64> {
+        check!(self.value.is_positive(), QuasarErrorCode::InvalidParam)?;
+        decimals_unit(self.base_decimals)
+            .and_then(|base_unit| I80F48::from_num(collateral).checked_mul(base_unit))
+            .zip(decimals_unit(quote_decimals))
+            .and_then(|(base, quote_unit)| {
+                self.value
+                    .checked_mul(quote_unit)
+                    .and_then(|price| base.checked_div(price))
+            })
+            .map(|base| base.floor())
+            .and_then(|base| base.checked_to_num::<u64>())
+            .ok_or(math_err!())
+    }
+}
+
 #[derive(Copy, Clone, Pod, Loadable)]
 #[repr(C)]
 pub struct StubOracle {
+    /// `account_discriminator("StubOracle")`, only with the `anchor-discriminator` feature
+    #[cfg(feature = "anchor-discriminator")]
+    pub discriminator: [u8; 8],
     pub magic: u32,    // Magic byte
-    pub price: I80F48, // unit is interpreted as how many quote native tokens for 1 base native token
+    pub price: I80F48, // unit is whole quote tokens for 1 whole base token, same as Pyth
     pub last_update: u64,
 }
 
 // TODO move to separate program
 impl StubOracle {
+    /// Load a stub oracle to read its price, refusing accounts `AddBaseToken` didn't initialize
+    /// as one. A zeroed or foreign account would otherwise read as a price of zero
+    pub fn load_checked<'a>(account: &'a AccountInfo) -> QuasarResult<Ref<'a, Self>> {
+        check_eq!(
+            account.data_len(),
+            size_of::<Self>(),
+            QuasarErrorCode::InvalidStubOracle
+        )?;
+
+        let oracle = Self::load(account)?;
+        oracle.check_discriminator()?;
+        check_eq!(
+            oracle.magic,
+            STUB_ORACLE_MAGIC,
+            QuasarErrorCode::InvalidStubOracle
+        )?;
+
+        Ok(oracle)
+    }
+
     pub fn load_mut_checked<'a>(
         account: &'a AccountInfo,
         program_id: &Pubkey,
@@ -30,6 +233,7 @@ impl StubOracle {
         check_eq!(account.owner, program_id, QuasarErrorCode::InvalidOwner)?;
 
         let oracle = Self::load_mut(account)?;
+        oracle.check_discriminator()?;
 
         Ok(oracle)
     }
@@ -45,10 +249,27 @@ impl StubOracle {
             QuasarErrorCode::AccountNotRentExempt
         )?;
 
-        let oracle = Self::load_mut(account)?;
+        #[allow(unused_mut)]
+        let mut oracle = Self::load_mut(account)?;
+        #[cfg(feature = "anchor-discriminator")]
+        {
+            oracle.discriminator = account_discriminator("StubOracle");
+        }
 
         Ok(oracle)
     }
+
+    /// Error out if the Anchor discriminator isn't the one of stub oracles, a no-op without the
+    /// `anchor-discriminator` feature
+    pub fn check_discriminator(&self) -> QuasarResult {
+        #[cfg(feature = "anchor-discriminator")]
+        check_eq!(
+            self.discriminator,
+            account_discriminator("StubOracle"),
+            QuasarErrorCode::InvalidAccount
+        )?;
+        Ok(())
+    }
 }
 
 // Start of pyth implementation
@@ -60,13 +281,14 @@ pub const PROD_ACCT_SIZE: usize = 512;
 pub const PROD_HDR_SIZE: usize = 48;
 pub const PROD_ATTR_SIZE: usize = PROD_ACCT_SIZE - PROD_HDR_SIZE;
 
-// oracle can be of different types
-#[derive(PartialEq)]
-#[repr(C)]
+// oracle can be of different types, stored as a byte on `BaseToken`. Unknown is zero so that
+// base tokens added before the type was stored fall back to inspecting the oracle account
+#[derive(Copy, Clone, Debug, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
 pub enum OracleType {
-    Stub,
-    Pyth,
-    Unknown,
+    Unknown = 0,
+    Stub = 1,
+    Pyth = 2,
 }
 
 // each account has its own type
@@ -206,19 +428,56 @@ pub struct Price {
 impl Price {
     pub fn get_price<'a>(account: &'a AccountInfo) -> QuasarResult<Price> {
         let borrowed = &account.data.borrow();
+        check!(
+            borrowed.len() >= size_of::<Price>(),
+            QuasarErrorCode::InvalidAccount
+        )?;
         let price = cast::<Price>(&borrowed);
-        assert_eq!(price.magic, MAGIC, "not a valid pyth account");
-        assert_eq!(
+        // Not a valid Pyth price account, or of an unexpected version
+        check_eq!(price.magic, MAGIC, QuasarErrorCode::InvalidAccount)?;
+        check_eq!(
             price.atype,
             AccountType::Price as u32,
-            "not a valid pyth price account"
-        );
-        assert_eq!(
-            price.ver, VERSION_2,
-            "unexpected pyth price account version"
-        );
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(price.ver, VERSION_2, QuasarErrorCode::InvalidAccount)?;
         Ok(*price)
     }
+
+    /// Aggregate price, or its EMA when `use_ema`, scaled by the exponent of the account. The
+    /// EMA lives in the v2 `twap` field and shares the aggregate's exponent, so both come out in
+    /// the same units
+    pub fn scaled_price(&self, use_ema: bool) -> QuasarResult<I80F48> {
+        let value = I80F48::from_num(if use_ema { self.twap } else { self.agg.price });
+
+        let decimal_adj = 10u64
+            .checked_pow(self.expo.unsigned_abs())
+            .ok_or(math_err!())?;
+        let decimal_adj = I80F48::from_num(decimal_adj);
+        if self.expo < 0 {
+            value.checked_div(decimal_adj)
+        } else {
+            value.checked_mul(decimal_adj)
+        }
+        .ok_or(math_err!())
+    }
+
+    /// `scaled_price` of a base token. With the `debug-errors` feature a failure first logs
+    /// what the price was computed from
+    pub fn base_token_price(&self, use_ema: bool) -> QuasarResult<I80F48> {
+        let result = self.scaled_price(use_ema);
+        #[cfg(feature = "debug-errors")]
+        if result.is_err() {
+            msg!(
+                "pyth price failed: price {} twap {} expo {} use_ema {}",
+                self.agg.price,
+                self.twap,
+                self.expo,
+                use_ema
+            );
+        }
+        result
+    }
 }
 
 struct AccKeyU64 {
@@ -239,9 +498,11 @@ impl AccKey {
 
 pub fn determine_oracle_type<'a>(account: &'a AccountInfo) -> OracleType {
     let borrowed = &account.data.borrow();
+    // Stub oracles keep their magic behind the Anchor discriminator, if any
+    let stub = &borrowed[ACCOUNT_DISCRIMINATOR_LEN..];
     if borrowed[0] == 212 && borrowed[1] == 195 && borrowed[2] == 178 && borrowed[3] == 161 {
         return OracleType::Pyth;
-    } else if borrowed[0] == 77 && borrowed[1] == 110 && borrowed[2] == 103 && borrowed[3] == 111 {
+    } else if stub[0] == 77 && stub[1] == 110 && stub[2] == 103 && stub[3] == 111 {
         return OracleType::Stub;
     } else {
         return OracleType::Unknown;
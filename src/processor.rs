@@ -1,23 +1,25 @@
 use std::{cell::Ref, mem::size_of};
 
 use mango::{
-    matching::{OrderType, Side},
+    matching::{BookSide, OrderType, Side},
     state::{
-        MangoAccount, MangoCache, MangoGroup, RootBankCache, MAX_PAIRS, QUOTE_INDEX, ZERO_I80F48,
+        HealthType, MangoAccount, MangoCache, MangoGroup, NodeBank, PerpMarket, RootBank,
+        RootBankCache, MAX_PAIRS, ONE_I80F48, QUOTE_INDEX, ZERO_I80F48,
     },
 };
 use solana_program::{
     account_info::{next_account_info, Account, AccountInfo},
-    entrypoint::ProgramResult,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
     instruction::{AccountMeta, Instruction},
     msg,
     native_token::LAMPORTS_PER_SOL,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
     system_instruction, system_program,
-    sysvar::{rent::Rent, Sysvar},
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
 };
 use spl_associated_token_account::{create_associated_token_account, get_associated_token_address};
 use spl_token::state::{Account as TokenAccount, Mint};
@@ -26,15 +28,42 @@ use mango_common::Loadable;
 use mango_macro::{Loadable, Pod};
 
 use arrayref::{array_ref, array_refs};
+use bytemuck::Zeroable;
 use fixed::types::I80F48;
 use std::cell::RefMut;
 
 use crate::{
     error::{check_assert, QuasarError, QuasarErrorCode, QuasarResult, SourceFileId},
-    instruction::QuasarInstruction,
-    oracle::{determine_oracle_type, OracleType, Price, StubOracle},
-    state::{BaseToken, DataType, LeverageToken, MetaData, QuasarGroup, LEVERGAE_TOKEN_DECIMALS},
-    utils::{gen_signer_key, gen_signer_seeds, get_mango_spot_value},
+    events::{
+        emit, GroupSummary, MintQuote, ReconcileEvent, TokenLeverage, TotalTvl, ValidateEvent,
+    },
+    instruction::{self, AccountDescriptor, BatchMintEntry, QuasarInstruction, MAX_BATCH_MINTS},
+    math::{
+        average_fill_price, collateral_for_redeem, filled_quantity, native_to_ui,
+        price_impact_cost, rebalance_exposure_delta, redeemed_exposure, within_tolerance, BPS_UNIT,
+    },
+    oracle::{
+        determine_oracle_type, read_quorum_price, OraclePrice, OracleType, Price, PriceStatus,
+        StubOracle, MAX_PYTH_PRICE_AGE_SLOTS, MAX_STUB_PRICE_AGE_SECS, STUB_ORACLE_MAGIC,
+    },
+    state::{
+        check_deposit_balance, check_quote_decimals, check_rebalance_deadband,
+        check_rebalance_order, check_target_leverage, load_mint, load_token_account, require_admin,
+        require_admin_or_risk_admin, BaseToken, DataType, HoldRecord, LeverageToken, MetaData,
+        PriceSample, QuasarGroup, RebalanceOrderType, RedeemRequest, SupplyCorrection,
+        LEVERGAE_TOKEN_DECIMALS, MAX_BASE_TOKENS, MAX_CRANK_REWARD_BPS,
+        MAX_LEVERAGE_TOKEN_DECIMALS, MAX_ORACLES_PER_BASE_TOKEN,
+        MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN, MAX_PRICE_SAMPLES, MAX_REBALANCE_ORDER_ATTEMPTS,
+        MIN_DELEVERAGE_INTERVAL_SECS, MIN_INITIAL_MINT_QUANTITY, QUASAR_GROUP_VERSION,
+        REBALANCE_TOLERANCE_BPS,
+    },
+    utils::{
+        check_mango_cache_fresh, gen_hold_record_key, gen_leverage_token_mint_key,
+        gen_mango_account_key, gen_redeem_escrow_key, gen_signer_key, get_mango_account_equity,
+        get_mango_account_health_ratio, get_mango_cache_price, get_mango_perp_value,
+        get_mango_quote_value, get_rebalance_net_asset_value, HOLD_RECORD_SEED,
+        LEVERAGE_TOKEN_MINT_SEED, MANGO_ACCOUNT_SEED, REDEEM_ESCROW_SEED,
+    },
 };
 
 declare_check_assert_macros!(SourceFileId::Processor);
@@ -48,6 +77,7 @@ impl Processor {
     ) -> QuasarResult<()> {
         let instruction = QuasarInstruction::unpack(instruction_data)
             .ok_or(ProgramError::InvalidInstructionData)?;
+        check_account_roles(accounts, instruction.fixed_accounts())?;
 
         match instruction {
             QuasarInstruction::InitQuasarGroup { signer_nonce } => {
@@ -58,21 +88,214 @@ impl Processor {
                 msg!("Instruction: AddBaseToken");
                 Self::add_base_token(program_id, accounts)
             }
-            QuasarInstruction::AddLeverageToken { target_leverage } => {
+            QuasarInstruction::AddLeverageToken {
+                target_leverage,
+                num_perp_markets,
+                perp_market_weights,
+                perp_market_base_mints,
+                decimals,
+                rebalance_deadband_bps,
+            } => {
                 msg!("Instruction: AddLeverageToken");
-                Self::add_leverage_token(program_id, accounts, target_leverage)
+                Self::add_leverage_token(
+                    program_id,
+                    accounts,
+                    target_leverage,
+                    num_perp_markets as usize,
+                    &perp_market_weights,
+                    &perp_market_base_mints,
+                    decimals.unwrap_or(LEVERGAE_TOKEN_DECIMALS),
+                    rebalance_deadband_bps,
+                )
             }
             QuasarInstruction::MintLeverageToken { quantity } => {
                 msg!("Instruction: MintLeverageToken");
-                Self::mint_leverage_token(program_id, accounts, quantity)
+                Self::mint_leverage_token(program_id, accounts, quantity, None, false)
             }
-            QuasarInstruction::BurnLeverageToken { quantity } => {
+            QuasarInstruction::BurnLeverageToken {
+                quantity,
+                output_mint,
+            } => {
                 msg!("Instruction: BurnLeverageToken");
-                Self::burn_leverage_token(program_id, accounts, quantity)
+                Self::burn_leverage_token(program_id, accounts, quantity, output_mint)
             }
             QuasarInstruction::Rebalance => {
                 msg!("Instruction: Rebalance");
-                Self::rebalance(program_id, accounts)
+                Self::rebalance(program_id, accounts, None)
+            }
+            QuasarInstruction::CloseLeverageToken => {
+                msg!("Instruction: CloseLeverageToken");
+                Self::close_leverage_token(program_id, accounts)
+            }
+            QuasarInstruction::ChangeGroupParams {
+                min_health_ratio,
+                deleverage_health_ratio,
+                min_rebalance_interval,
+                max_tokens_per_base,
+                rebalance_deadband_bps,
+                creation_fee,
+                min_hold_secs,
+                crank_reward_bps,
+                max_crank_reward,
+            } => {
+                msg!("Instruction: ChangeGroupParams");
+                Self::change_group_params(
+                    program_id,
+                    accounts,
+                    min_health_ratio,
+                    deleverage_health_ratio,
+                    min_rebalance_interval,
+                    max_tokens_per_base,
+                    rebalance_deadband_bps,
+                    creation_fee,
+                    min_hold_secs,
+                    crank_reward_bps,
+                    max_crank_reward,
+                )
+            }
+            QuasarInstruction::DeleverageToken => {
+                msg!("Instruction: DeleverageToken");
+                Self::deleverage_token(program_id, accounts)
+            }
+            QuasarInstruction::SetTargetLeverage {
+                new_leverage,
+                rebalance_deadband_bps,
+            } => {
+                msg!("Instruction: SetTargetLeverage");
+                Self::set_target_leverage(
+                    program_id,
+                    accounts,
+                    new_leverage,
+                    rebalance_deadband_bps,
+                )
+            }
+            QuasarInstruction::LogGroupState => {
+                msg!("Instruction: LogGroupState");
+                Self::log_group_state(program_id, accounts)
+            }
+            QuasarInstruction::SetDepositCap { deposit_cap } => {
+                msg!("Instruction: SetDepositCap");
+                Self::set_deposit_cap(program_id, accounts, deposit_cap)
+            }
+            QuasarInstruction::SetManagementFee {
+                management_fee_bps_annual,
+            } => {
+                msg!("Instruction: SetManagementFee");
+                Self::set_management_fee(program_id, accounts, management_fee_bps_annual)
+            }
+            QuasarInstruction::InitiateShutdown => {
+                msg!("Instruction: InitiateShutdown");
+                Self::initiate_shutdown(program_id, accounts)
+            }
+            QuasarInstruction::InitFeeVault => {
+                msg!("Instruction: InitFeeVault");
+                Self::init_fee_vault(program_id, accounts)
+            }
+            QuasarInstruction::SetMinMintQuantity { min_mint_quantity } => {
+                msg!("Instruction: SetMinMintQuantity");
+                Self::set_min_mint_quantity(program_id, accounts, min_mint_quantity)
+            }
+            QuasarInstruction::RequestRedeem { quantity } => {
+                msg!("Instruction: RequestRedeem");
+                Self::request_redeem(program_id, accounts, quantity)
+            }
+            QuasarInstruction::ProcessRedeemQueue => {
+                msg!("Instruction: ProcessRedeemQueue");
+                Self::process_redeem_queue(program_id, accounts)
+            }
+            QuasarInstruction::RebalanceLeg { leg_index } => {
+                msg!("Instruction: RebalanceLeg");
+                Self::rebalance(program_id, accounts, Some(leg_index as usize))
+            }
+            QuasarInstruction::RemoveBaseToken => {
+                msg!("Instruction: RemoveBaseToken");
+                Self::remove_base_token(program_id, accounts)
+            }
+            QuasarInstruction::SettleTokenPnl { leg_index } => {
+                msg!("Instruction: SettleTokenPnl");
+                Self::settle_token_pnl(program_id, accounts, leg_index as usize)
+            }
+            QuasarInstruction::UpdatePriceSample => {
+                msg!("Instruction: UpdatePriceSample");
+                Self::update_price_sample(program_id, accounts)
+            }
+            QuasarInstruction::QuoteMint { quantity } => {
+                msg!("Instruction: QuoteMint");
+                Self::quote_mint(program_id, accounts, quantity)
+            }
+            QuasarInstruction::ReconcileToken => {
+                msg!("Instruction: ReconcileToken");
+                Self::reconcile_token(program_id, accounts)
+            }
+            QuasarInstruction::SettleFunding { leg_index } => {
+                msg!("Instruction: SettleFunding");
+                Self::settle_funding(program_id, accounts, leg_index as usize)
+            }
+            QuasarInstruction::SetMangoProgram => {
+                msg!("Instruction: SetMangoProgram");
+                Self::set_mango_program(program_id, accounts)
+            }
+            QuasarInstruction::MintFromMangoBalance { quantity } => {
+                msg!("Instruction: MintFromMangoBalance");
+                Self::mint_leverage_token(program_id, accounts, quantity, None, true)
+            }
+            QuasarInstruction::ValidateGroup => {
+                msg!("Instruction: ValidateGroup");
+                Self::validate_group(program_id, accounts)
+            }
+            QuasarInstruction::SetUseEma { use_ema } => {
+                msg!("Instruction: SetUseEma");
+                Self::set_use_ema(program_id, accounts, use_ema)
+            }
+            QuasarInstruction::LogTokenLeverage => {
+                msg!("Instruction: LogTokenLeverage");
+                Self::log_token_leverage(program_id, accounts)
+            }
+            QuasarInstruction::SetMintFreezeAuthority { freeze_authority } => {
+                msg!("Instruction: SetMintFreezeAuthority");
+                Self::set_mint_freeze_authority(program_id, accounts, freeze_authority)
+            }
+            QuasarInstruction::BatchMint {
+                num_entries,
+                entries,
+            } => {
+                msg!("Instruction: BatchMint");
+                Self::batch_mint(program_id, accounts, num_entries as usize, &entries)
+            }
+            QuasarInstruction::MigrateGroup => {
+                msg!("Instruction: MigrateGroup");
+                Self::migrate_group(program_id, accounts)
+            }
+            QuasarInstruction::QuoteRedeem { quantity } => {
+                msg!("Instruction: QuoteRedeem");
+                Self::quote_redeem(program_id, accounts, quantity)
+            }
+            QuasarInstruction::SweepDust => {
+                msg!("Instruction: SweepDust");
+                Self::sweep_dust(program_id, accounts)
+            }
+            QuasarInstruction::SetRebalanceOrderType {
+                order_type,
+                offset_bps,
+            } => {
+                msg!("Instruction: SetRebalanceOrderType");
+                Self::set_rebalance_order_type(program_id, accounts, order_type, offset_bps)
+            }
+            QuasarInstruction::LogTotalTvl => {
+                msg!("Instruction: LogTotalTvl");
+                Self::log_total_tvl(program_id, accounts)
+            }
+            QuasarInstruction::SetCompound { compound } => {
+                msg!("Instruction: SetCompound");
+                Self::set_compound(program_id, accounts, compound)
+            }
+            QuasarInstruction::SetRiskAdmin { risk_admin } => {
+                msg!("Instruction: SetRiskAdmin");
+                Self::set_risk_admin(program_id, accounts, risk_admin)
+            }
+            QuasarInstruction::SetPaused { paused } => {
+                msg!("Instruction: SetPaused");
+                Self::set_paused(program_id, accounts, paused)
             }
         }
     }
@@ -83,15 +306,21 @@ impl Processor {
         accounts: &[AccountInfo],
         signer_nonce: u64,
     ) -> QuasarResult {
-        const NUM_FIXED: usize = 4;
+        const NUM_FIXED: usize = instruction::INIT_QUASAR_GROUP_ACCOUNTS.len();
         let accounts = array_ref![accounts, 0, NUM_FIXED];
 
-        let [quasar_group_ai, signer_ai, admin_ai, mango_program_ai] = accounts;
+        let [quasar_group_ai, signer_ai, admin_ai, mango_program_ai, quote_mint_ai, mango_group_ai] =
+            accounts;
         check_eq!(
             quasar_group_ai.owner,
             program_id,
             QuasarErrorCode::InvalidGroupOwner
         )?;
+        // Not some data account the admin mixed up with the program
+        check!(
+            mango_program_ai.executable,
+            QuasarErrorCode::InvalidMangoProgram
+        )?;
         let rent = Rent::get()?;
         check!(
             rent.is_exempt(quasar_group_ai.lamports(), size_of::<QuasarGroup>()),
@@ -100,8 +329,9 @@ impl Processor {
         let mut quasar_group: RefMut<QuasarGroup> = QuasarGroup::load_mut(quasar_group_ai)?;
         check!(
             !quasar_group.meta_data.is_initialized,
-            QuasarErrorCode::Default
+            QuasarErrorCode::GroupAlreadyInitialized
         )?;
+        quasar_group.init_discriminator();
 
         check!(
             gen_signer_key(signer_nonce, quasar_group_ai.key, program_id)? == *signer_ai.key,
@@ -111,87 +341,149 @@ impl Processor {
         quasar_group.signer_key = *signer_ai.key;
         quasar_group.mango_program_id = *mango_program_ai.key;
 
-        check!(admin_ai.is_signer, QuasarErrorCode::Default)?;
+        // Make sure the Mango group belongs to the Mango program
+        MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+        quasar_group.mango_group = *mango_group_ai.key;
+
+        // Make sure the quote mint is an actual mint
+        let quote_mint = Mint::unpack(&quote_mint_ai.try_borrow_data()?)?;
+        check_quote_decimals(quote_mint.decimals)?;
+        quasar_group.quote_mint = *quote_mint_ai.key;
+        quasar_group.quote_decimals = quote_mint.decimals;
+
+        check!(admin_ai.is_signer, QuasarErrorCode::AdminSignatureMissing)?;
         quasar_group.admin_key = *admin_ai.key;
 
-        quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, 0, true);
+        quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
 
         Ok(())
     }
 
     #[inline(never)]
     fn add_base_token<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        const NUM_FIXED: usize = instruction::ADD_BASE_TOKEN_ACCOUNTS.len();
+        let (fixed_ais, extra_oracle_ais) = array_refs![accounts, NUM_FIXED; ..;];
 
-        let [quasar_group_ai, mint_ai, oracle_ai, admin_ai] = accounts;
+        let [quasar_group_ai, mint_ai, oracle_ai, admin_ai] = fixed_ais;
 
         let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
-        check!(admin_ai.is_signer, QuasarErrorCode::InvalidSignerKey)?;
-        check_eq!(
-            admin_ai.key,
-            &quasar_group.admin_key,
-            QuasarErrorCode::InvalidSignerKey
+        require_admin(&quasar_group, admin_ai)?;
+        quasar_group.check_not_shutdown()?;
+        // Base tokens are kept packed at the front, so a full group has no slot left
+        check!(
+            quasar_group.num_base_tokens < MAX_BASE_TOKENS,
+            QuasarErrorCode::OutOfSpace
         )?;
 
         // Make sure there is no duplicated base token which has the same mint key
         check!(
             quasar_group.find_base_token_index(mint_ai.key).is_none(),
-            QuasarErrorCode::Default
+            QuasarErrorCode::DuplicateBaseToken
         )?;
+        let mint = load_mint(mint_ai)?;
 
-        let oracle_type = determine_oracle_type(oracle_ai);
-        match oracle_type {
-            OracleType::Pyth => {
-                msg!("OracleType:Pyth"); // Do nothing really cause all that's needed is storing the pkey
-            }
-            OracleType::Stub | OracleType::Unknown => {
-                msg!("OracleType: got unknown or stub");
-                let rent = Rent::get()?;
-                let mut oracle = StubOracle::load_and_init(oracle_ai, program_id, &rent)?;
-                oracle.magic = 0x6F676E4D;
-            }
+        // The first oracle is required, up to two more can back it up
+        let num_oracles = 1 + extra_oracle_ais.len();
+        check!(
+            num_oracles <= MAX_ORACLES_PER_BASE_TOKEN,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let mut oracles = [Pubkey::default(); MAX_ORACLES_PER_BASE_TOKEN];
+        let mut oracle_types = [0u8; MAX_ORACLES_PER_BASE_TOKEN];
+        for (i, oracle_ai) in std::iter::once(oracle_ai)
+            .chain(extra_oracle_ais.iter())
+            .enumerate()
+        {
+            check!(
+                !oracles[..i].contains(oracle_ai.key),
+                QuasarErrorCode::InvalidAccount
+            )?;
+
+            let oracle_type = match determine_oracle_type(oracle_ai) {
+                OracleType::Pyth => {
+                    msg!("OracleType:Pyth"); // Do nothing really cause all that's needed is storing the pkey
+                    OracleType::Pyth
+                }
+                OracleType::Stub | OracleType::Unknown => {
+                    msg!("OracleType: got unknown or stub");
+                    let rent = Rent::get()?;
+                    let mut oracle = StubOracle::load_and_init(oracle_ai, program_id, &rent)?;
+                    oracle.magic = STUB_ORACLE_MAGIC;
+                    OracleType::Stub
+                }
+            };
+            oracles[i] = *oracle_ai.key;
+            oracle_types[i] = oracle_type.into();
         }
 
         let base_token_index = quasar_group.num_base_tokens;
         // Make sure base token at this index is not already initialized
         check!(
             quasar_group.base_tokens[base_token_index].is_empty(),
-            QuasarErrorCode::Default
+            QuasarErrorCode::SlotNotEmpty
         )?;
 
-        let mint = Mint::unpack(&mint_ai.try_borrow_data()?)?;
         quasar_group.base_tokens[base_token_index] = BaseToken {
             mint: *mint_ai.key,
             decimals: mint.decimals,
-            oracle: *oracle_ai.key,
-            padding: [0u8; 7],
+            oracles,
+            oracle_types,
+            num_oracles: num_oracles as u8,
+            next_price_sample: 0,
+            num_price_samples: 0,
+            use_ema: false,
+            padding: [0u8; 8],
+            price_samples: [PriceSample::zeroed(); MAX_PRICE_SAMPLES],
         };
-        quasar_group.num_base_tokens += 1;
+        quasar_group.num_base_tokens = quasar_group
+            .num_base_tokens
+            .checked_add(1)
+            .ok_or(math_err!())?;
 
         Ok(())
     }
 
     #[inline(never)]
+    #[allow(clippy::too_many_arguments)]
     /// Add a leveraged token to quasar group
-    /// Only allow admin
+    /// Only allow admin, or anyone paying the creation fee once the group charges one
     fn add_leverage_token(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         target_leverage: I80F48,
+        num_perp_markets: usize,
+        perp_market_weights: &[I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN],
+        perp_market_base_mints: &[Pubkey; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN],
+        decimals: u8,
+        rebalance_deadband_bps: u16,
     ) -> QuasarResult {
-        const NUM_FIXED: usize = 12;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [quasar_group_ai, mint_ai, base_token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_perp_market_ai, system_program_ai, token_program_ai, rent_program_ai, admin_ai, pda_ai] =
-            accounts;
+        const NUM_FIXED: usize = instruction::ADD_LEVERAGE_TOKEN_ACCOUNTS.len();
+        const NUM_FEE: usize = instruction::CREATION_FEE_ACCOUNTS.len();
+        let (fixed_ais, remaining_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai, mint_ai, base_token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, system_program_ai, token_program_ai, rent_program_ai, creator_ai, pda_ai] =
+            fixed_ais;
 
         let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
-        check!(admin_ai.is_signer, QuasarErrorCode::SignerNecessary)?;
-        check_eq!(
-            admin_ai.key,
-            &quasar_group.admin_key,
-            QuasarErrorCode::InvalidAdminKey
+        check!(creator_ai.is_signer, QuasarErrorCode::SignerNecessary)?;
+        let creation_fee = quasar_group.creation_fee_for(creator_ai.key)?;
+        quasar_group.check_not_shutdown()?;
+
+        // The new mint is created below, so it has to be an account of the system program
+        // distinct from the base token it tracks. Anyone can send lamports to its address
+        // beforehand, so it may already hold some
+        check!(
+            mint_ai.key != base_token_mint_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+
+        check_target_leverage(target_leverage)?;
+        check!(
+            decimals <= MAX_LEVERAGE_TOKEN_DECIMALS,
+            QuasarErrorCode::InvalidParam
         )?;
+        check_rebalance_deadband(rebalance_deadband_bps)?;
 
         // Make sure leverage token is referencing a proper base token
         check!(
@@ -200,49 +492,205 @@ impl Processor {
                 .is_some(),
             QuasarErrorCode::InvalidAccount
         )?;
+        quasar_group.check_room_for_base(base_token_mint_ai.key)?;
 
         // Make sure there is no duplicated leverage token which has the same base token and the leverage target
         check!(
             quasar_group
                 .find_leverage_token_index(base_token_mint_ai.key, target_leverage)
                 .is_none(),
-            QuasarErrorCode::Default
+            QuasarErrorCode::DuplicateLeverageToken
+        )?;
+
+        check!(
+            num_perp_markets > 0 && num_perp_markets <= MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN,
+            QuasarErrorCode::InvalidParam
+        )?;
+        let num_fee_ais = if creation_fee > 0 { NUM_FEE } else { 0 };
+        check_eq!(
+            remaining_ais.len(),
+            num_perp_markets + num_fee_ais,
+            QuasarErrorCode::InvalidAccount
         )?;
+        let (mango_perp_market_ais, fee_ais) = remaining_ais.split_at(num_perp_markets);
+
+        let mut mango_perp_markets = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        {
+            let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+            let mut weight_sum = ZERO_I80F48;
+
+            for (i, mango_perp_market_ai) in mango_perp_market_ais.iter().enumerate() {
+                // Every perp market has to be listed on the Mango group, and only once
+                let market_index = mango_group
+                    .find_perp_market_index(mango_perp_market_ai.key)
+                    .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?;
+                // and trade the listed base token given for it, not some other asset
+                let leg_base_index = quasar_group
+                    .find_base_token_index(&perp_market_base_mints[i])
+                    .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?;
+                check!(
+                    quasar_group.base_tokens[leg_base_index]
+                        .matches_perp_market(&mango_group, market_index),
+                    QuasarErrorCode::PerpMarketMismatch
+                )?;
+                check!(
+                    !mango_perp_markets[..i].contains(mango_perp_market_ai.key),
+                    QuasarErrorCode::InvalidAccount
+                )?;
+                check!(
+                    perp_market_weights[i].is_positive(),
+                    QuasarErrorCode::InvalidParam
+                )?;
 
-        let token_index = quasar_group.num_leverage_tokens;
+                mango_perp_markets[i] = *mango_perp_market_ai.key;
+                weight_sum = weight_sum.checked_add(perp_market_weights[i]).unwrap();
+            }
 
-        // Make sure leverage token at this index is not already initialized
+            check_eq!(weight_sum, ONE_I80F48, QuasarErrorCode::InvalidParam)?;
+        }
+        // A basket may mix in other base tokens, but has to trade the one it's named after
         check!(
-            quasar_group.leverage_tokens[token_index].is_empty(),
-            QuasarErrorCode::Default
+            perp_market_base_mints[..num_perp_markets].contains(base_token_mint_ai.key),
+            QuasarErrorCode::PerpMarketMismatch
         )?;
 
+        let mut weights = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        weights[..num_perp_markets].copy_from_slice(&perp_market_weights[..num_perp_markets]);
+        let mut base_mints = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        base_mints[..num_perp_markets].copy_from_slice(&perp_market_base_mints[..num_perp_markets]);
+
+        // Closed leverage tokens leave empty slots behind, so take the first free one
+        let token_index = quasar_group
+            .leverage_tokens
+            .iter()
+            .position(|lt| lt.is_empty())
+            .ok_or(throw_err!(QuasarErrorCode::OutOfSpace))?;
+
         check_eq!(
             *pda_ai.key,
             quasar_group.signer_key,
             QuasarErrorCode::InvalidSignerKey
         )?;
-        let signer_seeds = gen_signer_seeds(&quasar_group.signer_nonce, quasar_group_ai.key);
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
 
-        init_mango_account(
-            mango_program_ai,
-            mango_group_ai,
-            mango_account_ai,
-            pda_ai,
-            &[&signer_seeds],
+        if creation_fee > 0 {
+            let [creator_quote_token_account_ai, fee_vault_ai] = array_ref![fee_ais, 0, NUM_FEE];
+            check!(
+                quasar_group.fee_vault != Pubkey::default(),
+                QuasarErrorCode::InvalidAccount
+            )?;
+            check_eq!(
+                fee_vault_ai.key,
+                &quasar_group.fee_vault,
+                QuasarErrorCode::InvalidAccount
+            )?;
+            let creator_quote_token_account =
+                load_token_account(creator_quote_token_account_ai, creator_ai.key)?;
+            check_eq!(
+                creator_quote_token_account.mint,
+                quasar_group.quote_mint,
+                QuasarErrorCode::InvalidQuoteMint
+            )?;
+
+            msg!("creation fee: {}", creation_fee);
+            invoke_transfer(
+                token_program_ai,
+                creator_quote_token_account_ai,
+                fee_vault_ai,
+                creator_ai,
+                &[],
+                creation_fee,
+            )?;
+        }
+
+        // The mint is a PDA of the base token and the slot the token takes, so the creator
+        // can't wire in a mint of their own, and a token whose leverage moved since it was
+        // added doesn't hold the address of a new one at its old leverage
+        let (mint_key, mint_bump) = gen_leverage_token_mint_key(
+            quasar_group_ai.key,
+            base_token_mint_ai.key,
+            token_index as u8,
+            program_id,
+        );
+        check_eq!(*mint_ai.key, mint_key, QuasarErrorCode::InvalidAccount)?;
+        // Never take over the mint of a token still listed
+        check!(
+            quasar_group
+                .find_leverage_token_index_by_mint(mint_ai.key)
+                .is_none(),
+            QuasarErrorCode::InvalidAccount
         )?;
-        msg!("Init Mango Account succeeded");
 
-        create_and_initialize_mint_account(
-            admin_ai,
-            mint_ai,
-            pda_ai,
-            token_program_ai,
-            system_program_ai,
-            rent_program_ai,
-            &[&signer_seeds],
-            LEVERGAE_TOKEN_DECIMALS,
+        // The Mango account is a PDA so each token's account can be found from its mint, and
+        // the creator can't wire in an account by mistake
+        let (mango_account_key, mango_account_bump) =
+            gen_mango_account_key(quasar_group_ai.key, mint_ai.key, program_id);
+        check_eq!(
+            *mango_account_ai.key,
+            mango_account_key,
+            QuasarErrorCode::InvalidAccount
         )?;
+
+        // Closing a token leaves its mint and Mango account behind. A token of the same base
+        // token added in its slot takes them over as they are, only this program can sign for
+        // their addresses
+        if mango_account_ai.owner == mango_program_ai.key {
+            check_reusable_mango_account(
+                mango_program_ai,
+                mango_group_ai,
+                mango_account_ai,
+                &quasar_group.signer_key,
+            )?;
+            msg!("Mango account {} reused", mango_account_ai.key);
+        } else {
+            create_account(
+                creator_ai,
+                mango_account_ai,
+                size_of::<MangoAccount>(),
+                mango_program_ai.key,
+                system_program_ai,
+                &[&[
+                    quasar_group_ai.key.as_ref(),
+                    mint_ai.key.as_ref(),
+                    MANGO_ACCOUNT_SEED,
+                    &[mango_account_bump],
+                ]],
+            )?;
+
+            init_mango_account(
+                &quasar_group.mango_program_id,
+                mango_program_ai,
+                mango_group_ai,
+                mango_account_ai,
+                pda_ai,
+                &[&signer_seeds],
+            )?;
+            msg!("Init Mango Account succeeded");
+        }
+
+        if *mint_ai.owner == spl_token::id() {
+            check_reusable_mint(mint_ai, &quasar_group.signer_key, decimals)?;
+            msg!("mint account {} reused", mint_ai.key);
+        } else {
+            create_and_initialize_mint_account(
+                creator_ai,
+                mint_ai,
+                pda_ai,
+                token_program_ai,
+                system_program_ai,
+                rent_program_ai,
+                &[&signer_seeds],
+                &[&[
+                    quasar_group_ai.key.as_ref(),
+                    base_token_mint_ai.key.as_ref(),
+                    &[token_index as u8],
+                    LEVERAGE_TOKEN_MINT_SEED,
+                    &[mint_bump],
+                ]],
+                decimals,
+            )?;
+        }
         msg!("target leverage: {}", target_leverage);
 
         quasar_group.leverage_tokens[token_index] = LeverageToken {
@@ -250,9 +698,34 @@ impl Processor {
             base_token_mint: *base_token_mint_ai.key,
             target_leverage: target_leverage,
             mango_account: *mango_account_ai.key,
-            mango_perp_market: *mango_perp_market_ai.key,
+            perp_market_weights: weights,
+            mango_perp_markets,
+            num_perp_markets,
+            last_rebalance_ts: 0,
+            last_fee_accrual_ts: Clock::get()?.unix_timestamp as u64,
+            deposit_cap: 0,
+            min_mint_quantity: 0,
+            management_fee_bps_annual: 0,
+            pending_rebalance: false,
+            mango_account_bump,
+            next_rebalance_leg: 0,
+            rebalance_legs_missed: false,
+            decimals,
+            supply_drift_flagged: false,
+            tracked_supply: 0,
+            rebalance_fail_count: 0,
+            next_rebalance_allowed_ts: 0,
+            rebalance_deadband_bps,
+            rebalance_order_offset_bps: 0,
+            rebalance_order_type: RebalanceOrderType::ImmediateOrCancel.into(),
+            compound: false,
+            padding: [0u8; 2],
         };
-        quasar_group.num_leverage_tokens += 1;
+        quasar_group.perp_market_base_mints[token_index] = base_mints;
+        quasar_group.num_leverage_tokens = quasar_group
+            .num_leverage_tokens
+            .checked_add(1)
+            .ok_or(math_err!())?;
 
         Ok(())
     }
@@ -262,15 +735,117 @@ impl Processor {
         program_id: &Pubkey,
         accounts: &[AccountInfo<'a>],
         quantity: u64,
+        max_collateral: Option<u64>,
+        from_mango_balance: bool,
     ) -> QuasarResult {
-        const NUM_FIXED: usize = 14;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [quasar_group_ai, token_mint_ai, owner_leverage_token_account_ai, mango_program_ai, mango_group_ai, mango_account_ai, owner_ai, mango_cache_ai, root_bank_ai, node_bank_ai, vault_ai, token_program_ai, owner_quote_token_account_ai, pda_ai] =
+        const NUM_FIXED: usize = instruction::MINT_LEVERAGE_TOKEN_ACCOUNTS.len();
+        const NUM_SOURCE: usize = instruction::MINT_FROM_MANGO_BALANCE_ACCOUNTS.len() - NUM_FIXED;
+        let (accounts, remaining_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai, token_mint_ai, owner_leverage_token_account_ai, mango_program_ai, mango_group_ai, mango_account_ai, owner_ai, mango_cache_ai, root_bank_ai, node_bank_ai, vault_ai, token_program_ai, owner_quote_token_account_ai, pda_ai, system_program_ai, associated_token_program_ai, rent_program_ai, fee_token_account_ai, hold_record_ai] =
             accounts;
+        check!(quantity > 0, QuasarErrorCode::InvalidQuantity)?;
 
-        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        quasar_group.check_not_shutdown()?;
+        quasar_group.check_not_paused()?;
 
-        let native_price;
+        // The group signer only needs to match the stored key, and its seeds are reused by
+        // every signed invoke below
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
+
+        let owner_quote_token_account =
+            load_token_account(owner_quote_token_account_ai, owner_ai.key)?;
+        check_eq!(
+            owner_quote_token_account.mint,
+            quasar_group.quote_mint,
+            QuasarErrorCode::InvalidQuoteMint
+        )?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        quasar_group.leverage_tokens[leverage_token_index]
+            .check_mint_authority(token_mint_ai, &quasar_group.signer_key)?;
+        check_mango_account_owner(
+            mango_account_ai,
+            mango_program_ai.key,
+            mango_group_ai.key,
+            &quasar_group.signer_key,
+        )?;
+        check_quote_bank(
+            mango_program_ai.key,
+            mango_group_ai,
+            &quasar_group.quote_mint,
+            root_bank_ai,
+            node_bank_ai,
+            vault_ai,
+        )?;
+
+        // The Mango account the collateral comes from, and what Mango needs to withdraw from it
+        // ahead of the books the mint is priced on
+        let (source, book_ais) = if from_mango_balance {
+            check!(
+                remaining_ais.len() >= NUM_SOURCE + MAX_PAIRS,
+                QuasarErrorCode::InvalidAccount
+            )?;
+            let (source_ais, book_ais) = remaining_ais.split_at(NUM_SOURCE + MAX_PAIRS);
+            let source_ais = array_ref![source_ais, 0, NUM_SOURCE + MAX_PAIRS];
+            let (source_fixed_ais, source_open_orders_ais) =
+                array_refs![source_ais, NUM_SOURCE, MAX_PAIRS];
+            let [source_mango_account_ai, mango_signer_ai] = source_fixed_ais;
+            check_mango_balance_source(
+                source_mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+                owner_ai.key,
+                &quasar_group.leverage_tokens[leverage_token_index].mango_account,
+            )?;
+            let source = (
+                source_mango_account_ai,
+                mango_signer_ai,
+                source_open_orders_ais,
+            );
+            (Some(source), book_ais)
+        } else {
+            (None, remaining_ais)
+        };
+        check!(
+            !quasar_group.leverage_tokens[leverage_token_index].supply_drift_flagged,
+            QuasarErrorCode::SupplyDriftFlagged
+        )?;
+
+        // Take the fee before pricing the mint so new holders aren't charged for the past
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        accrue_management_fee(
+            &mut quasar_group.leverage_tokens[leverage_token_index],
+            token_program_ai,
+            token_mint_ai,
+            fee_token_account_ai,
+            pda_ai,
+            &[&signer_seeds],
+            now_ts,
+        )?;
+        let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
+
+        // The first mint bootstraps the token at a fixed price and has to be large enough
+        // that donating collateral to the Mango account can't cheaply inflate the NAV
+        leverage_token.check_mint_quantity(quantity)?;
+        let supply = leverage_token.current_supply(token_mint_ai)?;
+        if supply == 0 {
+            check!(
+                quantity >= MIN_INITIAL_MINT_QUANTITY,
+                QuasarErrorCode::InitialMintTooSmall
+            )?;
+        }
+
+        let quote;
         {
             let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
             let mango_cache =
@@ -285,28 +860,78 @@ impl Processor {
                 *owner_leverage_token_account_ai.key,
                 get_associated_token_address(owner_ai.key, token_mint_ai.key),
                 QuasarErrorCode::InvalidAccount
-            );
-
-            let leverage_token_index = quasar_group
-                .find_leverage_token_index_by_mint(token_mint_ai.key)
-                .unwrap();
-            let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
+            )?;
 
             check_eq!(
                 leverage_token.mango_account,
                 *mango_account_ai.key,
                 QuasarErrorCode::InvalidAccount
-            );
+            )?;
 
-            native_price = leverage_token.get_native_price(
-                token_mint_ai,
+            // A stale cache misprices both the mint and its effect on health
+            check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts)?;
+            quasar_group.check_price_near_twap(
+                leverage_token_index,
+                &mango_group,
+                &mango_cache,
+                now_ts,
+            )?;
+
+            // Priced the same way as `QuoteMint`, with the fee already accrued
+            let mut mint_quote = leverage_token.quote_mint(
+                supply,
+                quantity,
+                now_ts,
+                quasar_group.quote_decimals,
                 &mango_group,
                 &mango_account,
                 &mango_cache,
             )?;
+            add_mint_price_impact(
+                &mut mint_quote,
+                &leverage_token,
+                mango_program_ai.key,
+                mango_group_ai.key,
+                &mango_group,
+                &mango_cache,
+                book_ais,
+            )?;
+            quote = mint_quote;
         }
 
+        let collateral = quote.collateral;
+        if let Some(max_collateral) = max_collateral {
+            check!(
+                collateral <= max_collateral,
+                QuasarErrorCode::CollateralAboveMax
+            )?;
+        }
+        match source {
+            Some((source_mango_account_ai, mango_signer_ai, source_open_orders_ais)) => {
+                // Into the owner's own token account, for the deposit below to take it from
+                withdraw_from_mango_account(
+                    &quasar_group.mango_program_id,
+                    mango_program_ai,
+                    mango_group_ai,
+                    source_mango_account_ai,
+                    owner_ai,
+                    mango_cache_ai,
+                    root_bank_ai,
+                    node_bank_ai,
+                    vault_ai,
+                    owner_quote_token_account_ai,
+                    mango_signer_ai,
+                    token_program_ai,
+                    source_open_orders_ais,
+                    &[],
+                    collateral,
+                    false,
+                )?;
+            }
+            None => check_deposit_balance(&owner_quote_token_account, collateral)?,
+        }
         deposit_to_mango_account(
+            &quasar_group.mango_program_id,
             mango_program_ai,
             mango_group_ai,
             mango_account_ai,
@@ -318,10 +943,55 @@ impl Processor {
             token_program_ai,
             owner_quote_token_account_ai,
             &[&[]],
-            quantity * native_price.to_num::<u64>(),
+            collateral,
+        )?;
+
+        // Don't let the mint leave the Mango account close to liquidation
+        {
+            let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(&mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                &mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+
+            let health_ratio = get_mango_account_health_ratio(
+                &mango_group,
+                &mango_account,
+                &mango_cache,
+                HealthType::Init,
+            )?;
+            msg!("init health ratio: {}", health_ratio);
+            check!(
+                health_ratio >= quasar_group.min_health_ratio,
+                QuasarErrorCode::InsufficientHealth
+            )?;
+        }
+        // Nor take its equity past the deposit cap
+        let collateral = mango_account_equity(
+            mango_program_ai.key,
+            mango_group_ai,
+            mango_account_ai,
+            mango_cache_ai,
         )?;
+        leverage_token.check_deposit_cap(collateral)?;
+
+        // First mint for this owner, create their leverage token account
+        if owner_leverage_token_account_ai.data_is_empty() {
+            invoke_create_associated_token_account(
+                associated_token_program_ai,
+                owner_ai,
+                owner_ai,
+                owner_leverage_token_account_ai,
+                token_mint_ai,
+                system_program_ai,
+                token_program_ai,
+                rent_program_ai,
+            )?;
+        }
 
-        let signer_seeds = gen_signer_seeds(&quasar_group.signer_nonce, quasar_group_ai.key);
         invoke_mint_to(
             token_program_ai,
             token_mint_ai,
@@ -330,6 +1000,17 @@ impl Processor {
             &[&signer_seeds],
             quantity,
         )?;
+        quasar_group.leverage_tokens[leverage_token_index].record_mint(quantity)?;
+        record_hold(
+            program_id,
+            &quasar_group,
+            quasar_group_ai,
+            token_mint_ai,
+            owner_ai,
+            hold_record_ai,
+            system_program_ai,
+            now_ts,
+        )?;
 
         Ok(())
     }
@@ -339,64 +1020,157 @@ impl Processor {
         program_id: &Pubkey,
         accounts: &[AccountInfo<'a>],
         quantity: u64,
+        output_mint: Option<Pubkey>,
     ) -> QuasarResult {
-        const NUM_FIXED: usize = 15;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, mango_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
-        let [quasar_group_ai, token_mint_ai, owner_leverage_token_account_ai, mango_program_ai, mango_group_ai, mango_account_ai, owner_ai, mango_cache_ai, root_bank_ai, node_bank_ai, vault_ai, token_program_ai, owner_quote_token_account_ai, pda_ai, mango_signer_ai] =
+        const NUM_FIXED: usize = instruction::BURN_LEVERAGE_TOKEN_ACCOUNTS.len();
+        const NUM_LEG: usize = 4;
+        let (fixed_ais, mango_open_orders_ais, leg_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+        let [quasar_group_ai, token_mint_ai, owner_leverage_token_account_ai, mango_program_ai, mango_group_ai, mango_account_ai, owner_ai, mango_cache_ai, root_bank_ai, node_bank_ai, vault_ai, token_program_ai, owner_quote_token_account_ai, pda_ai, mango_signer_ai, fee_token_account_ai, hold_record_ai] =
             fixed_ais;
+        check!(quantity > 0, QuasarErrorCode::InvalidQuantity)?;
 
-        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
 
+        // The group signer only needs to match the stored key, and its seeds are reused by
+        // every signed invoke below
         check_eq!(
-            *owner_leverage_token_account_ai.key,
-            get_associated_token_address(owner_ai.key, token_mint_ai.key),
+            pda_ai.key,
+            &quasar_group.signer_key,
             QuasarErrorCode::InvalidAccount
-        );
+        )?;
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
 
-        let leverage_token_index =
-            quasar_group.find_leverage_token_index_by_mint(token_mint_ai.key);
+        // The Mango account only holds quote collateral, paying out any other base token would
+        // need a swap on Mango which this program doesn't route yet
+        let output_mint = output_mint.unwrap_or(quasar_group.quote_mint);
         check!(
-            leverage_token_index.is_some(),
+            output_mint == quasar_group.quote_mint
+                || quasar_group.find_base_token_index(&output_mint).is_some(),
             QuasarErrorCode::InvalidToken
-        );
-
-        let native_price;
-        {
-            let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
-            let mango_cache =
-                MangoCache::load_checked(&mango_cache_ai, mango_program_ai.key, &mango_group)?;
-            let mango_account = MangoAccount::load_checked(
-                &mango_account_ai,
-                mango_program_ai.key,
-                mango_group_ai.key,
-            )?;
+        )?;
+        check_eq!(
+            output_mint,
+            quasar_group.quote_mint,
+            QuasarErrorCode::UnsupportedOutputMint
+        )?;
+        let owner_quote_token_account =
+            load_token_account(owner_quote_token_account_ai, owner_ai.key)?;
+        check_eq!(
+            owner_quote_token_account.mint,
+            output_mint,
+            QuasarErrorCode::InvalidQuoteMint
+        )?;
 
-            check_eq!(
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        check_mango_account_owner(
+            mango_account_ai,
+            mango_program_ai.key,
+            mango_group_ai.key,
+            &quasar_group.signer_key,
+        )?;
+        check_hold_record(
+            program_id,
+            &quasar_group,
+            quasar_group_ai,
+            token_mint_ai,
+            owner_ai,
+            hold_record_ai,
+        )?;
+
+        // Take the fee before pricing the burn so the leaving holder pays their share
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        accrue_management_fee(
+            &mut quasar_group.leverage_tokens[leverage_token_index],
+            token_program_ai,
+            token_mint_ai,
+            fee_token_account_ai,
+            pda_ai,
+            &[&signer_seeds],
+            now_ts,
+        )?;
+
+        // Price in quote lots and quantity in base lots of the perp order of each leg
+        let mut orders = [(ZERO_I80F48, ZERO_I80F48); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        let quote;
+        let supply;
+        {
+            let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(&mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                &mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+
+            check_eq!(
                 *owner_leverage_token_account_ai.key,
                 get_associated_token_address(owner_ai.key, token_mint_ai.key),
                 QuasarErrorCode::InvalidAccount
-            );
+            )?;
 
-            let leverage_token_index = quasar_group
-                .find_leverage_token_index_by_mint(token_mint_ai.key)
-                .unwrap();
             let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
 
             check_eq!(
                 leverage_token.mango_account,
                 *mango_account_ai.key,
                 QuasarErrorCode::InvalidAccount
-            );
+            )?;
+            check_eq!(
+                leg_ais.len(),
+                leverage_token.num_perp_markets * NUM_LEG,
+                QuasarErrorCode::InvalidAccount
+            )?;
 
-            native_price = leverage_token.get_native_price(
-                token_mint_ai,
+            // Priced the same way as `QuoteRedeem`, with the fee already accrued
+            supply = leverage_token.current_supply(token_mint_ai)?;
+            quote = leverage_token.quote_redeem(
+                supply,
+                quantity,
+                now_ts,
+                quasar_group.quote_decimals,
                 &mango_group,
                 &mango_account,
                 &mango_cache,
             )?;
+
+            // Take the redeemed share of every position off, so the tokens left keep both
+            // their backing and their leverage
+            for leg in 0..leverage_token.num_perp_markets {
+                let mango_perp_market = leverage_token.mango_perp_markets[leg];
+                check_eq!(
+                    *leg_ais[leg * NUM_LEG].key,
+                    mango_perp_market,
+                    QuasarErrorCode::InvalidAccount
+                )?;
+
+                let market_index = mango_group
+                    .find_perp_market_index(&mango_perp_market)
+                    .unwrap();
+                let price = get_mango_cache_price(&mango_group, &mango_cache, market_index)?;
+
+                let (current_exposure, _) = get_mango_perp_value(
+                    &mango_group,
+                    &mango_account,
+                    &mango_cache,
+                    price,
+                    market_index,
+                )?;
+
+                let exposure_delta = -redeemed_exposure(current_exposure, quantity, supply)?;
+                orders[leg] = perp_order_lots(&mango_group, market_index, price, exposure_delta)?;
+            }
         }
 
+        let collateral = quote.collateral;
+        // Don't burn tokens for nothing
+        check!(collateral > 0, QuasarErrorCode::RedeemTooSmall)?;
+
         invoke_burn(
             token_program_ai,
             owner_leverage_token_account_ai,
@@ -405,10 +1179,45 @@ impl Processor {
             &[],
             quantity,
         )?;
+        quasar_group.leverage_tokens[leverage_token_index].record_burn(quantity);
+        quasar_group.redeem_settled_pnl(leverage_token_index, quantity, supply)?;
+
+        for leg in 0..quasar_group.leverage_tokens[leverage_token_index].num_perp_markets {
+            let (price, quantity) = orders[leg];
+            let [mango_perp_market_ai, mango_bids_ai, mango_asks_ai, mango_event_queue_ai] =
+                array_ref![leg_ais, leg * NUM_LEG, NUM_LEG];
 
-        let signer_seeds = gen_signer_seeds(&quasar_group.signer_nonce, quasar_group_ai.key);
+            if quantity.abs().to_num::<i64>() == 0 {
+                continue;
+            }
+
+            place_mango_perp_order(
+                &quasar_group.mango_program_id,
+                mango_program_ai,
+                mango_group_ai,
+                mango_account_ai,
+                pda_ai,
+                mango_cache_ai,
+                mango_perp_market_ai,
+                mango_bids_ai,
+                mango_asks_ai,
+                mango_event_queue_ai,
+                mango_open_orders_ais,
+                &[&signer_seeds],
+                price.to_num::<i64>(),
+                quantity.abs().to_num::<i64>(),
+                0,
+                if quantity > ZERO_I80F48 {
+                    Side::Bid
+                } else {
+                    Side::Ask
+                },
+                OrderType::ImmediateOrCancel,
+            )?;
+        }
 
         withdraw_from_mango_account(
+            &quasar_group.mango_program_id,
             mango_program_ai,
             mango_group_ai,
             mango_account_ai,
@@ -422,7 +1231,7 @@ impl Processor {
             token_program_ai,
             mango_open_orders_ais,
             &[&signer_seeds],
-            quantity * native_price.to_num::<u64>(),
+            collateral,
             false,
         )?;
 
@@ -430,33 +1239,99 @@ impl Processor {
     }
 
     #[inline(never)]
-    fn rebalance<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
-        const NUM_FIXED: usize = 12;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, mango_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
-        let [quasar_group_ai, token_mint_ai, pda_ai, mango_program_ai, mango_group_ai, mango_account_ai, owner_ai, mango_cache_ai, mango_perp_market_ai, mango_bids_ai, mango_asks_ai, mango_event_queue_ai] =
+    fn rebalance<'a>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'a>],
+        single_leg: Option<usize>,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::REBALANCE_ACCOUNTS.len();
+        const NUM_LEG: usize = 4;
+        const NUM_REWARD: usize = 2;
+        let (fixed_ais, mango_open_orders_ais, leg_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+        let [quasar_group_ai, token_mint_ai, pda_ai, mango_program_ai, mango_group_ai, mango_account_ai, owner_ai, mango_cache_ai, token_program_ai, fee_token_account_ai] =
             fixed_ais;
 
-        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
 
         let leverage_token_index = quasar_group
             .find_leverage_token_index_by_mint(token_mint_ai.key)
             .unwrap();
+        check_mango_account_owner(
+            mango_account_ai,
+            mango_program_ai.key,
+            mango_group_ai.key,
+            &quasar_group.signer_key,
+        )?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        accrue_management_fee(
+            &mut quasar_group.leverage_tokens[leverage_token_index],
+            token_program_ai,
+            token_mint_ai,
+            fee_token_account_ai,
+            pda_ai,
+            &[&signer_seeds],
+            now_ts,
+        )?;
         let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
 
         check_eq!(
             leverage_token.mango_account,
             *mango_account_ai.key,
             QuasarErrorCode::InvalidAccount
-        );
-        check_eq!(
-            leverage_token.mango_perp_market,
-            *mango_perp_market_ai.key,
+        )?;
+
+        // Rate limit rebalances so they can't be spammed to churn trading fees, unless the
+        // target changed since the last one
+        check!(
+            leverage_token.pending_rebalance
+                || now_ts
+                    >= leverage_token
+                        .last_rebalance_ts
+                        .saturating_add(quasar_group.min_rebalance_interval),
+            QuasarErrorCode::RebalanceTooSoon
+        )?;
+        // Even with the target changed, give a market the last rebalances failed on some rest
+        leverage_token.check_rebalance_backoff(now_ts)?;
+        let deadband_bps =
+            leverage_token.effective_rebalance_deadband_bps(quasar_group.rebalance_deadband_bps);
+
+        // A crank gets the accounts of every leg and trades as many legs as fit in its compute
+        // budget, a single leg rebalance only gets the accounts of its leg
+        let (legs, first_leg_ai, num_leg_ais) = match single_leg {
+            None => (
+                leverage_token.next_rebalance_legs(),
+                0,
+                leverage_token.num_perp_markets * NUM_LEG,
+            ),
+            Some(leg) => {
+                check!(
+                    leg < leverage_token.num_perp_markets,
+                    QuasarErrorCode::InvalidParam
+                )?;
+                (leg..leg + 1, leg, NUM_LEG)
+            }
+        };
+        // Cranks wanting the reward follow the legs with the fee vault and a quote token account
+        check!(
+            leg_ais.len() == num_leg_ais || leg_ais.len() == num_leg_ais + NUM_REWARD,
             QuasarErrorCode::InvalidAccount
-        );
+        )?;
+        let (leg_ais, reward_ais) = leg_ais.split_at(num_leg_ais);
 
-        let mut price;
-        let mut quantity;
+        // Price in quote lots and quantity in base lots of the perp order of each leg
+        let mut orders = [(ZERO_I80F48, ZERO_I80F48); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        let mut leg_target_exposures = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        let mut leg_exposures = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
         {
             let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
             let mango_cache =
@@ -467,158 +1342,2691 @@ impl Processor {
                 mango_program_ai.key,
                 mango_group_ai.key,
             )?;
+            quasar_group.check_price_near_twap(
+                leverage_token_index,
+                &mango_group,
+                &mango_cache,
+                now_ts,
+            )?;
+
+            let compounded_pnl = if leverage_token.compound {
+                quasar_group.settled_pnl[leverage_token_index]
+            } else {
+                ZERO_I80F48
+            };
+            let (net_asset_value, perp_asset_value) = get_rebalance_net_asset_value(
+                &mango_group,
+                &mango_account,
+                &mango_cache,
+                compounded_pnl,
+            )?;
 
-            let mut net_asset_value = ZERO_I80F48;
-            let mut perp_asset_value = ZERO_I80F48;
+            msg!("net asset value: {}", net_asset_value);
+            msg!("perp asset value: {}", perp_asset_value);
+            msg!("effective leverage: {}", perp_asset_value / net_asset_value);
 
-            let market_index = mango_group
-                .find_perp_market_index(&leverage_token.mango_perp_market)
+            let target_exposure = net_asset_value
+                .checked_mul(leverage_token.target_leverage)
                 .unwrap();
+            msg!("target leverage: {}", leverage_token.target_leverage);
+            msg!("target exposure: {}", target_exposure);
 
-            for i in 0..mango_group.num_oracles {
-                let spot_value = get_mango_spot_value(
-                    &mango_account,
-                    &mango_cache.root_bank_cache[i],
-                    mango_cache.price_cache[i].price,
-                    i,
+            for leg in legs.clone() {
+                let mango_perp_market = leverage_token.mango_perp_markets[leg];
+                check_eq!(
+                    *leg_ais[(leg - first_leg_ai) * NUM_LEG].key,
+                    mango_perp_market,
+                    QuasarErrorCode::InvalidAccount
                 )?;
 
-                let (perp_base_value, perp_quote_value) = mango_account.perp_accounts[i].get_val(
-                    &mango_group.perp_markets[i],
-                    &mango_cache.perp_market_cache[i],
-                    mango_cache.price_cache[i].price,
+                let market_index = mango_group
+                    .find_perp_market_index(&mango_perp_market)
+                    .unwrap();
+                let price = get_mango_cache_price(&mango_group, &mango_cache, market_index)?;
+
+                let (current_exposure, _) = get_mango_perp_value(
+                    &mango_group,
+                    &mango_account,
+                    &mango_cache,
+                    price,
+                    market_index,
                 )?;
+                let leg_target_exposure = target_exposure
+                    .checked_mul(leverage_token.perp_market_weights[leg])
+                    .unwrap();
+                msg!(
+                    "leg {}: market {} / price {} / target exposure {} / current exposure {}",
+                    leg,
+                    market_index,
+                    price.value,
+                    leg_target_exposure,
+                    current_exposure
+                );
+
+                leg_target_exposures[leg] = leg_target_exposure;
+                leg_exposures[leg] = current_exposure;
+                let exposure_delta =
+                    rebalance_exposure_delta(current_exposure, leg_target_exposure, deadband_bps)?;
+                orders[leg] = perp_order_lots(&mango_group, market_index, price, exposure_delta)?;
+            }
+        }
+
+        // Mango stops matching an order after a fixed number of resting orders, so a leg a
+        // taking order left short of target is ordered again for the rest, as long as the last
+        // order filled something. Post only orders rest on the book instead, they go out once
+        let max_order_attempts = match leverage_token.rebalance_order_type() {
+            RebalanceOrderType::PostOnly => 1,
+            _ => MAX_REBALANCE_ORDER_ATTEMPTS,
+        };
+        // Orders may still only partially fill, in which case the token stays flagged so the
+        // next crank can finish the job without waiting for the cooldown
+        let mut incomplete = false;
+        // Notional the orders actually filled, which is what the crank gets rewarded for
+        let mut traded_notional = ZERO_I80F48;
+        let mut quote_decimals = 0;
+        for attempt in 0..max_order_attempts {
+            for leg in legs.clone() {
+                let (price, quantity) = orders[leg];
+                let [mango_perp_market_ai, mango_bids_ai, mango_asks_ai, mango_event_queue_ai] =
+                    array_ref![leg_ais, (leg - first_leg_ai) * NUM_LEG, NUM_LEG];
 
                 msg!(
-                    "market {}: spot {} / perp_base {} / perp_quote {}",
-                    i,
-                    spot_value,
-                    perp_base_value,
-                    perp_quote_value,
+                    "leg {}: price: {}, quantity: {}",
+                    leg,
+                    price.to_num::<i64>(),
+                    quantity.abs().to_num::<i64>()
                 );
 
-                net_asset_value = net_asset_value
-                    .checked_add(
-                        spot_value
-                            .checked_add(perp_base_value.checked_add(perp_quote_value).unwrap())
-                            .unwrap(),
-                    )
+                if quantity.abs().to_num::<i64>() == 0 {
+                    continue;
+                }
+
+                let side = if quantity > ZERO_I80F48 {
+                    Side::Bid
+                } else {
+                    Side::Ask
+                };
+                let (price, order_type) =
+                    leverage_token.rebalance_order(price.to_num::<i64>(), side);
+                place_mango_perp_order(
+                    &quasar_group.mango_program_id,
+                    mango_program_ai,
+                    mango_group_ai,
+                    mango_account_ai,
+                    pda_ai,
+                    mango_cache_ai,
+                    mango_perp_market_ai,
+                    mango_bids_ai,
+                    mango_asks_ai,
+                    mango_event_queue_ai,
+                    mango_open_orders_ais,
+                    &[&signer_seeds],
+                    price,
+                    quantity.abs().to_num::<i64>(),
+                    0,
+                    side,
+                    order_type,
+                )?;
+            }
+
+            incomplete = false;
+            let mut filled = false;
+            let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
+            quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
+            let mango_cache =
+                MangoCache::load_checked(&mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                &mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+
+            for leg in legs.clone() {
+                let market_index = mango_group
+                    .find_perp_market_index(&leverage_token.mango_perp_markets[leg])
                     .unwrap();
+                let price = get_mango_cache_price(&mango_group, &mango_cache, market_index)?;
+                let (exposure, _) = get_mango_perp_value(
+                    &mango_group,
+                    &mango_account,
+                    &mango_cache,
+                    price,
+                    market_index,
+                )?;
+                let fill = (exposure - leg_exposures[leg]).abs();
+                traded_notional = traded_notional.checked_add(fill).ok_or(math_err!())?;
+                filled |= fill > ZERO_I80F48;
+                leg_exposures[leg] = exposure;
 
-                perp_asset_value = perp_asset_value.checked_add(perp_base_value).unwrap();
+                // Legs left alone within the deadband are as complete as they are going to get
+                orders[leg] = if within_tolerance(
+                    exposure,
+                    leg_target_exposures[leg],
+                    REBALANCE_TOLERANCE_BPS.max(deadband_bps),
+                ) {
+                    (ZERO_I80F48, ZERO_I80F48)
+                } else {
+                    msg!("leg {}: exposure {} short of target", leg, exposure);
+                    incomplete = true;
+                    perp_order_lots(
+                        &mango_group,
+                        market_index,
+                        price,
+                        leg_target_exposures[leg] - exposure,
+                    )?
+                };
             }
 
-            msg!("net asset value: {}", net_asset_value);
-            msg!("perp asset value: {}", perp_asset_value);
-            msg!("effective leverage: {}", perp_asset_value / net_asset_value);
+            if !incomplete || !filled {
+                break;
+            }
+            msg!("order attempt {} filled partially", attempt + 1);
+        }
+
+        let leverage_token = &mut quasar_group.leverage_tokens[leverage_token_index];
+        match single_leg {
+            None => {
+                leverage_token.record_rebalance_legs(legs, incomplete, now_ts);
+                leverage_token.record_rebalance_outcome(incomplete, now_ts);
+            }
+            // One leg can't tell the whole token is balanced, only that it isn't
+            Some(_) => {
+                leverage_token.pending_rebalance |= incomplete;
+                if incomplete {
+                    leverage_token.record_rebalance_outcome(true, now_ts);
+                }
+            }
+        }
+
+        if !reward_ais.is_empty() {
+            let [fee_vault_ai, crank_quote_token_account_ai] =
+                array_ref![reward_ais, 0, NUM_REWARD];
+            pay_crank_reward(
+                &quasar_group,
+                token_program_ai,
+                fee_vault_ai,
+                crank_quote_token_account_ai,
+                pda_ai,
+                &[&signer_seeds],
+                traded_notional,
+                quote_decimals,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Retire a leverage token once all of it has been redeemed
+    /// Only allow admin
+    fn close_leverage_token(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::CLOSE_LEVERAGE_TOKEN_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, admin_ai] =
+            accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        // The Mango account stays open with its rent, for `AddLeverageToken` to reuse
+        check_wound_down(
+            &leverage_token,
+            token_mint_ai,
+            mango_program_ai,
+            mango_group_ai,
+            mango_account_ai,
+        )?;
+
+        quasar_group.leverage_tokens[leverage_token_index] = LeverageToken::zeroed();
+        quasar_group.settled_pnl[leverage_token_index] = ZERO_I80F48;
+        quasar_group.perp_market_base_mints[leverage_token_index] =
+            [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        quasar_group.num_leverage_tokens -= 1;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Change the parameters of a quasar group
+    /// Only allow admin, or risk admin for the rebalance parameters
+    fn change_group_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_health_ratio: Option<I80F48>,
+        deleverage_health_ratio: Option<I80F48>,
+        min_rebalance_interval: Option<u64>,
+        max_tokens_per_base: Option<u64>,
+        rebalance_deadband_bps: Option<u16>,
+        creation_fee: Option<u64>,
+        min_hold_secs: Option<u64>,
+        crank_reward_bps: Option<u16>,
+        max_crank_reward: Option<u16>,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::CHANGE_GROUP_PARAMS_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        // The risk admin may only tune how often and how eagerly tokens rebalance
+        let rebalance_params_only = min_health_ratio.is_none()
+            && deleverage_health_ratio.is_none()
+            && max_tokens_per_base.is_none()
+            && creation_fee.is_none()
+            && min_hold_secs.is_none()
+            && crank_reward_bps.is_none()
+            && max_crank_reward.is_none();
+        if rebalance_params_only {
+            require_admin_or_risk_admin(&quasar_group, admin_ai)?;
+        } else {
+            require_admin(&quasar_group, admin_ai)?;
+        }
+
+        if let Some(min_health_ratio) = min_health_ratio {
+            msg!("min health ratio: {}", min_health_ratio);
+            quasar_group.min_health_ratio = min_health_ratio;
+        }
+        if let Some(deleverage_health_ratio) = deleverage_health_ratio {
+            msg!("deleverage health ratio: {}", deleverage_health_ratio);
+            quasar_group.deleverage_health_ratio = deleverage_health_ratio;
+        }
+        if let Some(min_rebalance_interval) = min_rebalance_interval {
+            msg!("min rebalance interval: {}", min_rebalance_interval);
+            quasar_group.min_rebalance_interval = min_rebalance_interval;
+        }
+        if let Some(max_tokens_per_base) = max_tokens_per_base {
+            msg!("max tokens per base: {}", max_tokens_per_base);
+            quasar_group.max_tokens_per_base = max_tokens_per_base;
+        }
+        if let Some(rebalance_deadband_bps) = rebalance_deadband_bps {
+            check_rebalance_deadband(rebalance_deadband_bps)?;
+            msg!("rebalance deadband bps: {}", rebalance_deadband_bps);
+            quasar_group.rebalance_deadband_bps = rebalance_deadband_bps;
+        }
+        if let Some(creation_fee) = creation_fee {
+            msg!("creation fee: {}", creation_fee);
+            quasar_group.creation_fee = creation_fee;
+        }
+        if let Some(min_hold_secs) = min_hold_secs {
+            msg!("min hold secs: {}", min_hold_secs);
+            quasar_group.min_hold_secs = min_hold_secs;
+        }
+        if let Some(crank_reward_bps) = crank_reward_bps {
+            check!(
+                crank_reward_bps <= MAX_CRANK_REWARD_BPS,
+                QuasarErrorCode::InvalidParam
+            )?;
+            msg!("crank reward bps: {}", crank_reward_bps);
+            quasar_group.crank_reward_bps = crank_reward_bps;
+        }
+        if let Some(max_crank_reward) = max_crank_reward {
+            msg!("max crank reward: {}", max_crank_reward);
+            quasar_group.max_crank_reward = max_crank_reward;
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Halve every perp position of a leverage token whose Mango account is close to being
+    /// liquidated, so it gets out of the danger zone on its own terms
+    /// Permissionless, without a bounty
+    fn deleverage_token<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::DELEVERAGE_TOKEN_ACCOUNTS.len();
+        const NUM_LEG: usize = 4;
+        let (fixed_ais, mango_open_orders_ais, leg_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+        let [quasar_group_ai, token_mint_ai, pda_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai] =
+            fixed_ais;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
+
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            *pda_ai.key,
+            quasar_group.signer_key,
+            QuasarErrorCode::InvalidSignerKey
+        )?;
+        check_eq!(
+            leg_ais.len(),
+            leverage_token.num_perp_markets * NUM_LEG,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        // Until the cache has caught up with the last fills the position still looks as
+        // unhealthy, and calling again would halve it again
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        check!(
+            now_ts
+                >= leverage_token
+                    .last_rebalance_ts
+                    .saturating_add(MIN_DELEVERAGE_INTERVAL_SECS),
+            QuasarErrorCode::DeleverageTooSoon
+        )?;
+
+        let mut orders = [(ZERO_I80F48, ZERO_I80F48); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        {
+            let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(&mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                &mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+
+            // A stale or pushed price could make a healthy token look like it needs cutting
+            check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts)?;
+            quasar_group.check_price_near_twap(
+                leverage_token_index,
+                &mango_group,
+                &mango_cache,
+                now_ts,
+            )?;
+
+            let health_ratio = get_mango_account_health_ratio(
+                &mango_group,
+                &mango_account,
+                &mango_cache,
+                HealthType::Maint,
+            )?;
+            msg!("maint health ratio: {}", health_ratio);
+            check!(
+                health_ratio < quasar_group.deleverage_health_ratio,
+                QuasarErrorCode::TokenHealthy
+            )?;
+
+            for leg in 0..leverage_token.num_perp_markets {
+                let mango_perp_market = leverage_token.mango_perp_markets[leg];
+                check_eq!(
+                    *leg_ais[leg * NUM_LEG].key,
+                    mango_perp_market,
+                    QuasarErrorCode::InvalidAccount
+                )?;
+
+                let market_index = mango_group
+                    .find_perp_market_index(&mango_perp_market)
+                    .unwrap();
+                let price = get_mango_cache_price(&mango_group, &mango_cache, market_index)?;
+
+                let (current_exposure, _) = get_mango_perp_value(
+                    &mango_group,
+                    &mango_account,
+                    &mango_cache,
+                    price,
+                    market_index,
+                )?;
+
+                let exposure_delta = -current_exposure.checked_div(I80F48::from_num(2)).unwrap();
+                orders[leg] = perp_order_lots(&mango_group, market_index, price, exposure_delta)?;
+            }
+        }
+
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
+
+        for leg in 0..leverage_token.num_perp_markets {
+            let (price, quantity) = orders[leg];
+            let [mango_perp_market_ai, mango_bids_ai, mango_asks_ai, mango_event_queue_ai] =
+                array_ref![leg_ais, leg * NUM_LEG, NUM_LEG];
+
+            if quantity.abs().to_num::<i64>() == 0 {
+                continue;
+            }
+
+            place_mango_perp_order(
+                &quasar_group.mango_program_id,
+                mango_program_ai,
+                mango_group_ai,
+                mango_account_ai,
+                pda_ai,
+                mango_cache_ai,
+                mango_perp_market_ai,
+                mango_bids_ai,
+                mango_asks_ai,
+                mango_event_queue_ai,
+                mango_open_orders_ais,
+                &[&signer_seeds],
+                price.to_num::<i64>(),
+                quantity.abs().to_num::<i64>(),
+                0,
+                if quantity > ZERO_I80F48 {
+                    Side::Bid
+                } else {
+                    Side::Ask
+                },
+                OrderType::ImmediateOrCancel,
+            )?;
+        }
+
+        // Also holds off the next rebalance, which would lever the position right back up
+        quasar_group.leverage_tokens[leverage_token_index].last_rebalance_ts = now_ts;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Change the target leverage of a leverage token
+    /// Only allow admin
+    fn set_target_leverage(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_leverage: I80F48,
+        rebalance_deadband_bps: Option<u16>,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_TARGET_LEVERAGE_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        check_target_leverage(new_leverage)?;
+        if let Some(rebalance_deadband_bps) = rebalance_deadband_bps {
+            check_rebalance_deadband(rebalance_deadband_bps)?;
+        }
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let base_token_mint = quasar_group.leverage_tokens[leverage_token_index].base_token_mint;
+
+        // Keep a single leverage token per base token and target leverage
+        check!(
+            quasar_group
+                .find_leverage_token_index(&base_token_mint, new_leverage)
+                .is_none(),
+            QuasarErrorCode::DuplicateLeverageToken
+        )?;
+
+        msg!("target leverage: {}", new_leverage);
+        let leverage_token = &mut quasar_group.leverage_tokens[leverage_token_index];
+        leverage_token.target_leverage = new_leverage;
+        leverage_token.pending_rebalance = true;
+        // Legs already traded towards the old target have to be redone
+        leverage_token.next_rebalance_leg = 0;
+        leverage_token.rebalance_legs_missed = false;
+        if let Some(rebalance_deadband_bps) = rebalance_deadband_bps {
+            msg!("rebalance deadband bps: {}", rebalance_deadband_bps);
+            leverage_token.rebalance_deadband_bps = rebalance_deadband_bps;
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Emit a summary of the group for clients that don't want to decode the account.
+    /// Permissionless
+    fn log_group_state(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::LOG_GROUP_STATE_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai] = accounts;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        emit(&GroupSummary::new(&quasar_group));
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Cap the collateral a leverage token can take
+    /// Only allow admin
+    fn set_deposit_cap(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        deposit_cap: u64,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_DEPOSIT_CAP_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+
+        msg!("deposit cap: {}", deposit_cap);
+        quasar_group.leverage_tokens[leverage_token_index].deposit_cap = deposit_cap;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Change the management fee of a leverage token
+    /// Only allow admin
+    fn set_management_fee<'a>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'a>],
+        management_fee_bps_annual: u16,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_MANAGEMENT_FEE_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, fee_token_account_ai, token_program_ai, pda_ai, admin_ai] =
+            accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check!(
+            management_fee_bps_annual as u64 <= BPS_UNIT,
+            QuasarErrorCode::InvalidParam
+        )?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
+        let leverage_token = &mut quasar_group.leverage_tokens[leverage_token_index];
+        accrue_management_fee(
+            leverage_token,
+            token_program_ai,
+            token_mint_ai,
+            fee_token_account_ai,
+            pda_ai,
+            &[&signer_seeds],
+            now_ts,
+        )?;
+
+        msg!("management fee: {} bps", management_fee_bps_annual);
+        leverage_token.management_fee_bps_annual = management_fee_bps_annual;
+        // Don't charge the new rate for the time the fee rounded down to nothing
+        leverage_token.last_fee_accrual_ts = now_ts;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Put the group in redeem only mode, this can't be undone
+    /// Only allow admin
+    fn initiate_shutdown(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::INITIATE_SHUTDOWN_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+        quasar_group.check_not_shutdown()?;
+
+        quasar_group.shutdown = true;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Create the token account group level fees are paid into
+    /// Only allow admin
+    fn init_fee_vault<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::INIT_FEE_VAULT_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, fee_vault_ai, mint_ai, admin_ai, pda_ai, system_program_ai, token_program_ai, associated_token_program_ai, rent_program_ai] =
+            accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check!(
+            quasar_group.fee_vault == Pubkey::default(),
+            QuasarErrorCode::FeeVaultAlreadyInitialized
+        )?;
+        // Fees and crank rewards are paid in the quote token, a vault of any other mint would
+        // take none of them
+        check_eq!(
+            *mint_ai.key,
+            quasar_group.quote_mint,
+            QuasarErrorCode::InvalidQuoteMint
+        )?;
+        check_eq!(
+            *fee_vault_ai.key,
+            get_associated_token_address(pda_ai.key, mint_ai.key),
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        // Anyone can create the signer's associated token account, reuse it if it exists
+        if fee_vault_ai.data_is_empty() {
+            invoke_create_associated_token_account(
+                associated_token_program_ai,
+                admin_ai,
+                pda_ai,
+                fee_vault_ai,
+                mint_ai,
+                system_program_ai,
+                token_program_ai,
+                rent_program_ai,
+            )?;
+        }
+        load_token_account(fee_vault_ai, pda_ai.key)?;
+
+        msg!("fee vault: {}", fee_vault_ai.key);
+        quasar_group.fee_vault = *fee_vault_ai.key;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the dust floor of mints of a leverage token
+    /// Only allow admin
+    fn set_min_mint_quantity(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_mint_quantity: u64,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_MIN_MINT_QUANTITY_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+
+        msg!("min mint quantity: {}", min_mint_quantity);
+        quasar_group.leverage_tokens[leverage_token_index].min_mint_quantity = min_mint_quantity;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Escrow leverage tokens and queue them for redeem
+    /// Permissionless
+    fn request_redeem<'a>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'a>],
+        quantity: u64,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::REQUEST_REDEEM_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, owner_leverage_token_account_ai, owner_ai, redeem_escrow_ai, pda_ai, token_program_ai, system_program_ai, rent_program_ai, hold_record_ai] =
+            accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        check!(owner_ai.is_signer, QuasarErrorCode::SignerNecessary)?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            *token_program_ai.key,
+            spl_token::id(),
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check!(quantity > 0, QuasarErrorCode::RedeemTooSmall)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        // Each request takes a slot of the token's queue until it's settled, the minimum keeps
+        // anyone from filling them with dust
+        check!(
+            quantity >= quasar_group.leverage_tokens[leverage_token_index].min_mint_quantity,
+            QuasarErrorCode::RedeemTooSmall
+        )?;
+        check_hold_record(
+            program_id,
+            &quasar_group,
+            quasar_group_ai,
+            token_mint_ai,
+            owner_ai,
+            hold_record_ai,
+        )?;
+
+        let (redeem_escrow_key, redeem_escrow_bump) =
+            gen_redeem_escrow_key(quasar_group_ai.key, token_mint_ai.key, program_id);
+        check_eq!(
+            *redeem_escrow_ai.key,
+            redeem_escrow_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        // The first request of a leverage token creates its escrow, held by the group signer
+        if redeem_escrow_ai.data_is_empty() {
+            create_account(
+                owner_ai,
+                redeem_escrow_ai,
+                TokenAccount::LEN,
+                token_program_ai.key,
+                system_program_ai,
+                &[&[
+                    quasar_group_ai.key.as_ref(),
+                    token_mint_ai.key.as_ref(),
+                    REDEEM_ESCROW_SEED,
+                    &[redeem_escrow_bump],
+                ]],
+            )?;
+            invoke_initialize_token_account(
+                token_program_ai,
+                redeem_escrow_ai,
+                token_mint_ai,
+                pda_ai,
+                rent_program_ai,
+            )?;
+        }
+
+        invoke_transfer(
+            token_program_ai,
+            owner_leverage_token_account_ai,
+            redeem_escrow_ai,
+            owner_ai,
+            &[],
+            quantity,
+        )?;
+
+        quasar_group.push_redeem_request(
+            leverage_token_index,
+            RedeemRequest {
+                owner: *owner_ai.key,
+                mint: *token_mint_ai.key,
+                quantity,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Settle as much of the oldest queued redeem of the leverage token as the perp markets can
+    /// take, or move it behind the others when they take none of it
+    /// Permissionless
+    fn process_redeem_queue<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::PROCESS_REDEEM_QUEUE_ACCOUNTS.len();
+        const NUM_LEG: usize = 4;
+        let (fixed_ais, mango_open_orders_ais, leg_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+        let [quasar_group_ai, token_mint_ai, redeem_escrow_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai, root_bank_ai, node_bank_ai, vault_ai, token_program_ai, owner_quote_token_account_ai, pda_ai, mango_signer_ai, fee_token_account_ai] =
+            fixed_ais;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let request = *quasar_group
+            .front_redeem_request_mut(leverage_token_index)
+            .ok_or(throw_err!(QuasarErrorCode::RedeemQueueEmpty))?;
+        let (redeem_escrow_key, _) =
+            gen_redeem_escrow_key(quasar_group_ai.key, token_mint_ai.key, program_id);
+        check_eq!(
+            *redeem_escrow_ai.key,
+            redeem_escrow_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let owner_quote_token_account =
+            load_token_account(owner_quote_token_account_ai, &request.owner)?;
+        check_eq!(
+            owner_quote_token_account.mint,
+            quasar_group.quote_mint,
+            QuasarErrorCode::InvalidQuoteMint
+        )?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        accrue_management_fee(
+            &mut quasar_group.leverage_tokens[leverage_token_index],
+            token_program_ai,
+            token_mint_ai,
+            fee_token_account_ai,
+            pda_ai,
+            &[&signer_seeds],
+            now_ts,
+        )?;
+        let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
+
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            leg_ais.len(),
+            leverage_token.num_perp_markets * NUM_LEG,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        // Price in quote lots and quantity in base lots of the perp order of each leg, along
+        // with the exposure change it is meant to make
+        let mut orders = [(ZERO_I80F48, ZERO_I80F48); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        let mut intended_deltas = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        let mut exposures_before = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        let native_price;
+        {
+            let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(&mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                &mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+
+            native_price = leverage_token.get_native_price(
+                token_mint_ai,
+                &mango_group,
+                &mango_account,
+                &mango_cache,
+            )?;
+
+            // Escrowed tokens are still part of the supply until they are burnt
+            let supply = leverage_token.current_supply(token_mint_ai)?;
+            for leg in 0..leverage_token.num_perp_markets {
+                let mango_perp_market = leverage_token.mango_perp_markets[leg];
+                check_eq!(
+                    *leg_ais[leg * NUM_LEG].key,
+                    mango_perp_market,
+                    QuasarErrorCode::InvalidAccount
+                )?;
+
+                let market_index = mango_group
+                    .find_perp_market_index(&mango_perp_market)
+                    .unwrap();
+                let price = get_mango_cache_price(&mango_group, &mango_cache, market_index)?;
+
+                let (current_exposure, _) = get_mango_perp_value(
+                    &mango_group,
+                    &mango_account,
+                    &mango_cache,
+                    price,
+                    market_index,
+                )?;
+
+                let exposure_delta =
+                    -redeemed_exposure(current_exposure, request.quantity, supply)?;
+                orders[leg] = perp_order_lots(&mango_group, market_index, price, exposure_delta)?;
+                exposures_before[leg] = current_exposure;
+                // A leg too small to trade doesn't hold the redeem back
+                if orders[leg].1.abs().to_num::<i64>() != 0 {
+                    intended_deltas[leg] = exposure_delta;
+                }
+            }
+        }
+
+        for leg in 0..leverage_token.num_perp_markets {
+            let (price, quantity) = orders[leg];
+            let [mango_perp_market_ai, mango_bids_ai, mango_asks_ai, mango_event_queue_ai] =
+                array_ref![leg_ais, leg * NUM_LEG, NUM_LEG];
+
+            if quantity.abs().to_num::<i64>() == 0 {
+                continue;
+            }
+
+            place_mango_perp_order(
+                &quasar_group.mango_program_id,
+                mango_program_ai,
+                mango_group_ai,
+                mango_account_ai,
+                pda_ai,
+                mango_cache_ai,
+                mango_perp_market_ai,
+                mango_bids_ai,
+                mango_asks_ai,
+                mango_event_queue_ai,
+                mango_open_orders_ais,
+                &[&signer_seeds],
+                price.to_num::<i64>(),
+                quantity.abs().to_num::<i64>(),
+                0,
+                if quantity > ZERO_I80F48 {
+                    Side::Bid
+                } else {
+                    Side::Ask
+                },
+                OrderType::ImmediateOrCancel,
+            )?;
+        }
+
+        // Only settle the share of the request the thinnest leg managed to unwind
+        let mut settled = request.quantity;
+        {
+            let mango_group = MangoGroup::load_checked(&mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(&mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                &mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+
+            for leg in 0..leverage_token.num_perp_markets {
+                let market_index = mango_group
+                    .find_perp_market_index(&leverage_token.mango_perp_markets[leg])
+                    .unwrap();
+                let price = get_mango_cache_price(&mango_group, &mango_cache, market_index)?;
+                let (exposure, _) = get_mango_perp_value(
+                    &mango_group,
+                    &mango_account,
+                    &mango_cache,
+                    price,
+                    market_index,
+                )?;
+
+                let achieved = exposure.checked_sub(exposures_before[leg]).unwrap();
+                settled = settled.min(filled_quantity(
+                    request.quantity,
+                    intended_deltas[leg],
+                    achieved,
+                )?);
+            }
+        }
+
+        if settled == 0 {
+            msg!(
+                "no liquidity to settle the redeem of {}, moved to the back of the queue",
+                request.owner
+            );
+            quasar_group.requeue_front_redeem_request(leverage_token_index)?;
+            return Ok(());
+        }
+
+        let collateral = collateral_for_redeem(native_price, settled, leverage_token.decimals)?;
+        let supply = leverage_token.current_supply(token_mint_ai)?;
+
+        invoke_burn(
+            token_program_ai,
+            redeem_escrow_ai,
+            token_mint_ai,
+            pda_ai,
+            &[&signer_seeds],
+            settled,
+        )?;
+        quasar_group.leverage_tokens[leverage_token_index].record_burn(settled);
+        quasar_group.redeem_settled_pnl(leverage_token_index, settled, supply)?;
+
+        withdraw_from_mango_account(
+            &quasar_group.mango_program_id,
+            mango_program_ai,
+            mango_group_ai,
+            mango_account_ai,
+            pda_ai,
+            mango_cache_ai,
+            root_bank_ai,
+            node_bank_ai,
+            vault_ai,
+            owner_quote_token_account_ai,
+            mango_signer_ai,
+            token_program_ai,
+            mango_open_orders_ais,
+            &[&signer_seeds],
+            collateral,
+            false,
+        )?;
+
+        if settled == request.quantity {
+            quasar_group.pop_redeem_request(leverage_token_index)?;
+        } else {
+            quasar_group
+                .front_redeem_request_mut(leverage_token_index)
+                .unwrap()
+                .quantity -= settled;
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Remove a base token without dependent leverage tokens, freeing its slot
+    /// Only allow admin
+    fn remove_base_token(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::REMOVE_BASE_TOKEN_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, mint_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        let base_token_index = quasar_group
+            .find_base_token_index(mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        check!(
+            !quasar_group.is_base_in_use(mint_ai.key),
+            QuasarErrorCode::BaseTokenInUse
+        )?;
+
+        // Base tokens are looked up by mint, so the last one can fill the hole to keep the
+        // first `num_base_tokens` slots taken
+        let last_index = quasar_group.num_base_tokens - 1;
+        quasar_group.base_tokens[base_token_index] = quasar_group.base_tokens[last_index];
+        quasar_group.base_tokens[last_index] = BaseToken::zeroed();
+        quasar_group.num_base_tokens -= 1;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Settle the pnl of a perp leg of a leverage token
+    /// Permissionless
+    fn settle_token_pnl<'a>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'a>],
+        leg_index: usize,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SETTLE_TOKEN_PNL_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, counterparty_mango_account_ai, mango_cache_ai, root_bank_ai, node_bank_ai] =
+            accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = &quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check!(
+            leg_index < leverage_token.num_perp_markets,
+            QuasarErrorCode::InvalidParam
+        )?;
+        check!(
+            counterparty_mango_account_ai.key != mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let market_index = {
+            let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+            mango_group
+                .find_perp_market_index(&leverage_token.mango_perp_markets[leg_index])
+                .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?
+        };
+
+        // Quote the Mango account holds less its quote borrows. Settling moves pnl between the
+        // perp position and the quote deposits, and funding into the perp position, so the
+        // deposits are what tells the settled pnl apart
+        let quote_value = || -> QuasarResult<I80F48> {
+            let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+            get_mango_quote_value(&mango_account, &mango_cache)
+        };
+        let quote_before = quote_value()?;
+
+        invoke_settle_pnl(
+            &quasar_group.mango_program_id,
+            mango_program_ai,
+            mango_group_ai,
+            mango_account_ai,
+            counterparty_mango_account_ai,
+            mango_cache_ai,
+            root_bank_ai,
+            node_bank_ai,
+            market_index,
+        )?;
+
+        let settled = quote_value()?
+            .checked_sub(quote_before)
+            .ok_or(math_err!())?;
+        msg!("settled pnl: {}", settled);
+        quasar_group.record_settled_pnl(leverage_token_index, settled)
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn update_price_sample(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::UPDATE_PRICE_SAMPLE_ACCOUNTS.len();
+        let (fixed_ais, oracle_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai, mint_ai] = fixed_ais;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        let base_token_index = quasar_group
+            .find_base_token_index(mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+
+        let clock = Clock::get()?;
+        let base_token = &mut quasar_group.base_tokens[base_token_index];
+        let price = read_oracle(base_token, oracle_ais, &clock)?;
+        base_token.push_price_sample(price, clock.unix_timestamp as u64)
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn quote_mint(program_id: &Pubkey, accounts: &[AccountInfo], quantity: u64) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::QUOTE_MINT_ACCOUNTS.len();
+        let (fixed_ais, book_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai, token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai] =
+            fixed_ais;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = &quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+        let mango_cache =
+            MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, mango_program_ai.key, mango_group_ai.key)?;
+
+        let mut quote = leverage_token.quote_mint(
+            leverage_token.current_supply(token_mint_ai)?,
+            quantity,
+            Clock::get()?.unix_timestamp as u64,
+            quasar_group.quote_decimals,
+            &mango_group,
+            &mango_account,
+            &mango_cache,
+        )?;
+        add_mint_price_impact(
+            &mut quote,
+            leverage_token,
+            mango_program_ai.key,
+            mango_group_ai.key,
+            &mango_group,
+            &mango_cache,
+            book_ais,
+        )?;
+        emit(&quote);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn reconcile_token<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::RECONCILE_TOKEN_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, fee_token_account_ai, pda_ai, token_program_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai] =
+            accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            *fee_token_account_ai.key,
+            leverage_token.fee_token_account(pda_ai.key),
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let fee_token_account = load_token_account(fee_token_account_ai, pda_ai.key)?;
+
+        let supply = leverage_token.current_supply(token_mint_ai)?;
+        let expected_backing = {
+            let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+
+            let native_price =
+                leverage_token.native_price(supply, &mango_group, &mango_account, &mango_cache)?;
+            native_price
+                .checked_mul(native_to_ui(
+                    leverage_token.tracked_supply,
+                    leverage_token.decimals,
+                ))
+                .ok_or(math_err!())?
+        };
+        let backing = mango_account_equity(
+            mango_program_ai.key,
+            mango_group_ai,
+            mango_account_ai,
+            mango_cache_ai,
+        )?;
+
+        let mut event = ReconcileEvent {
+            mint: leverage_token.mint,
+            tracked_supply: leverage_token.tracked_supply,
+            supply,
+            drift: supply as i128 - leverage_token.tracked_supply as i128,
+            expected_backing: expected_backing.to_bits(),
+            backing: backing.to_bits(),
+            quote_decimals: quasar_group.quote_decimals,
+            minted: 0,
+            burnt: 0,
+            flagged: false,
+        };
+
+        // Corrections leave the mint's supply equal to `tracked_supply`, which needs no update
+        match leverage_token.supply_correction(supply, fee_token_account.amount) {
+            SupplyCorrection::None => {}
+            SupplyCorrection::MintToFeeTokenAccount(quantity) => {
+                invoke_mint_to(
+                    token_program_ai,
+                    token_mint_ai,
+                    fee_token_account_ai,
+                    pda_ai,
+                    &[&signer_seeds],
+                    quantity,
+                )?;
+                event.minted = quantity;
+            }
+            SupplyCorrection::BurnFromFeeTokenAccount(quantity) => {
+                invoke_burn(
+                    token_program_ai,
+                    fee_token_account_ai,
+                    token_mint_ai,
+                    pda_ai,
+                    &[&signer_seeds],
+                    quantity,
+                )?;
+                event.burnt = quantity;
+            }
+            SupplyCorrection::Flag => {
+                msg!("supply drift too large to correct: {}", event.drift);
+                event.flagged = true;
+            }
+        }
+        quasar_group.leverage_tokens[leverage_token_index].supply_drift_flagged = event.flagged;
+        emit(&event);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn settle_funding<'a>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'a>],
+        leg_index: usize,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SETTLE_FUNDING_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai, mango_perp_market_ai, mango_bids_ai, mango_asks_ai] =
+            accounts;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = &quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check!(
+            leg_index < leverage_token.num_perp_markets,
+            QuasarErrorCode::InvalidParam
+        )?;
+        check_eq!(
+            *mango_perp_market_ai.key,
+            leverage_token.mango_perp_markets[leg_index],
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        {
+            let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let market_index = mango_group
+                .find_perp_market_index(mango_perp_market_ai.key)
+                .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?;
+
+            let now_ts = Clock::get()?.unix_timestamp as u64;
+            if mango_cache.perp_market_cache[market_index].last_update >= now_ts {
+                msg!("funding already settled");
+                return Ok(());
+            }
+        }
+
+        invoke_update_funding(
+            &quasar_group.mango_program_id,
+            mango_program_ai,
+            mango_group_ai,
+            mango_cache_ai,
+            mango_perp_market_ai,
+            mango_bids_ai,
+            mango_asks_ai,
+        )?;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Only allow admin
+    fn set_mango_program(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_MANGO_PROGRAM_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, mango_program_ai, mango_group_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        check!(
+            mango_program_ai.executable,
+            QuasarErrorCode::InvalidMangoProgram
+        )?;
+        // Make sure the Mango group belongs to the new program
+        MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+
+        // Every leverage token has a Mango account, with whatever positions it holds, in the
+        // old group. Its holders could only redeem against the new one, so each has to be
+        // closed first
+        check_eq!(
+            quasar_group.num_leverage_tokens,
+            0,
+            QuasarErrorCode::MangoProgramInUse
+        )?;
+
+        msg!("mango program: {}", mango_program_ai.key);
+        quasar_group.mango_program_id = *mango_program_ai.key;
+        quasar_group.mango_group = *mango_group_ai.key;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Report mints and oracles that no longer match what the group stored about them
+    /// Permissionless
+    fn validate_group(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::VALIDATE_GROUP_ACCOUNTS.len();
+        let (fixed_ais, validation_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai] = fixed_ais;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        let validation_accounts = quasar_group.validation_accounts();
+        check_eq!(
+            validation_ais.len(),
+            validation_accounts.len(),
+            QuasarErrorCode::InvalidAccount
+        )?;
+        for (ai, key) in validation_ais.iter().zip(validation_accounts.iter()) {
+            check_eq!(ai.key, key, QuasarErrorCode::InvalidAccount)?;
+        }
+
+        let event = ValidateEvent::new(&quasar_group, validation_ais);
+        msg!("{} discrepancies found", event.discrepancies.len());
+        emit(&event);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Choose between the EMA and the aggregate price of a base token's Pyth oracles
+    /// Only allow admin
+    fn set_use_ema(program_id: &Pubkey, accounts: &[AccountInfo], use_ema: bool) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_USE_EMA_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, base_token_mint_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        let base_token_index = quasar_group
+            .find_base_token_index(base_token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+
+        msg!("use ema: {}", use_ema);
+        quasar_group.base_tokens[base_token_index].use_ema = use_ema;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn log_token_leverage(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::LOG_TOKEN_LEVERAGE_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai] =
+            accounts;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = &quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+        let mango_cache =
+            MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, mango_program_ai.key, mango_group_ai.key)?;
+
+        emit(&TokenLeverage {
+            mint: leverage_token.mint,
+            target_leverage: leverage_token.target_leverage.to_bits(),
+            actual_leverage: leverage_token
+                .actual_leverage(&mango_group, &mango_account, &mango_cache)?
+                .to_bits(),
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Rotate or revoke the freeze authority of a leverage mint
+    /// Only allow admin
+    fn set_mint_freeze_authority<'a>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'a>],
+        freeze_authority: Option<Pubkey>,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_MINT_FREEZE_AUTHORITY_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, pda_ai, token_program_ai, admin_ai] = accounts;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+        check!(
+            quasar_group
+                .find_leverage_token_index_by_mint(token_mint_ai.key)
+                .is_some(),
+            QuasarErrorCode::InvalidToken
+        )?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            *token_program_ai.key,
+            spl_token::id(),
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        // Once rotated or revoked, the signer has nothing left to hand over
+        let mint = load_mint(token_mint_ai)?;
+        check_eq!(
+            mint.freeze_authority,
+            COption::Some(*pda_ai.key),
+            QuasarErrorCode::InvalidMintAuthority
+        )?;
+
+        match freeze_authority {
+            Some(freeze_authority) => msg!("freeze authority: {}", freeze_authority),
+            None => msg!("freeze authority revoked"),
+        }
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
+        invoke_set_freeze_authority(
+            token_program_ai,
+            token_mint_ai,
+            pda_ai,
+            freeze_authority.as_ref(),
+            &[&signer_seeds],
+        )?;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Run a `MintLeverageToken` for each entry, the transaction failing as a whole if any fails
+    /// Permissionless
+    fn batch_mint<'a>(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo<'a>],
+        num_entries: usize,
+        entries: &[BatchMintEntry; MAX_BATCH_MINTS],
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::BATCH_MINT_ACCOUNTS.len();
+        const NUM_TOKEN: usize = instruction::BATCH_MINT_TOKEN_ACCOUNTS.len();
+        const NUM_BOOK: usize = 3;
+        let (fixed_ais, token_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai, mango_program_ai, mango_group_ai, owner_ai, mango_cache_ai, root_bank_ai, node_bank_ai, vault_ai, token_program_ai, owner_quote_token_account_ai, pda_ai, system_program_ai, associated_token_program_ai, rent_program_ai] =
+            fixed_ais;
+
+        check!(
+            num_entries > 0 && num_entries <= MAX_BATCH_MINTS,
+            QuasarErrorCode::InvalidParam
+        )?;
+        let entries = &entries[..num_entries];
+
+        // Check every entry before minting any, so a bad one doesn't waste the compute of the
+        // ones before it. Each entry's accounts are followed by the books of its token's perp
+        // markets, so this also finds where every entry's accounts start
+        let mut entry_ais = Vec::with_capacity(entries.len());
+        {
+            let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+            let mut start = 0;
+            for entry in entries.iter() {
+                check!(entry.quantity > 0, QuasarErrorCode::InvalidQuantity)?;
+                let leverage_token = quasar_group
+                    .leverage_tokens
+                    .get(entry.token_index as usize)
+                    .filter(|leverage_token| !leverage_token.is_empty())
+                    .ok_or(throw_err!(QuasarErrorCode::InvalidIndex))?;
+                let end = start + NUM_TOKEN + NUM_BOOK * leverage_token.num_perp_markets;
+                let ais = token_ais
+                    .get(start..end)
+                    .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?;
+                check_eq!(
+                    ais[0].key,
+                    &leverage_token.mint,
+                    QuasarErrorCode::InvalidAccount
+                )?;
+                entry_ais.push(ais);
+                start = end;
+            }
+            check_eq!(start, token_ais.len(), QuasarErrorCode::InvalidAccount)?;
+        }
+
+        for (entry, token_ais) in entries.iter().zip(entry_ais) {
+            let (token_ais, book_ais) = token_ais.split_at(NUM_TOKEN);
+            let [token_mint_ai, owner_leverage_token_account_ai, mango_account_ai, hold_record_ai, fee_token_account_ai] =
+                array_ref![token_ais, 0, NUM_TOKEN];
+            // In the order of `MINT_LEVERAGE_TOKEN_ACCOUNTS`, then the books
+            let mut mint_ais = vec![
+                quasar_group_ai.clone(),
+                token_mint_ai.clone(),
+                owner_leverage_token_account_ai.clone(),
+                mango_program_ai.clone(),
+                mango_group_ai.clone(),
+                mango_account_ai.clone(),
+                owner_ai.clone(),
+                mango_cache_ai.clone(),
+                root_bank_ai.clone(),
+                node_bank_ai.clone(),
+                vault_ai.clone(),
+                token_program_ai.clone(),
+                owner_quote_token_account_ai.clone(),
+                pda_ai.clone(),
+                system_program_ai.clone(),
+                associated_token_program_ai.clone(),
+                rent_program_ai.clone(),
+                fee_token_account_ai.clone(),
+                hold_record_ai.clone(),
+            ];
+            mint_ais.extend(book_ais.iter().cloned());
+            msg!(
+                "batch mint of {} of token {}",
+                entry.quantity,
+                entry.token_index
+            );
+            Self::mint_leverage_token(
+                program_id,
+                &mint_ais,
+                entry.quantity,
+                Some(entry.max_collateral),
+                false,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Migrate the group to the current layout version
+    /// Only allow admin
+    fn migrate_group<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::MIGRATE_GROUP_ACCOUNTS.len();
+        let (fixed_ais, token_mint_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai, admin_ai, mango_group_ai, quote_mint_ai, system_program_ai] =
+            fixed_ais;
+        check_eq!(
+            quasar_group_ai.owner,
+            program_id,
+            QuasarErrorCode::InvalidOwner
+        )?;
+
+        let v0 = {
+            let data = quasar_group_ai.try_borrow_data()?;
+            if QuasarGroup::is_v0_layout(&data) {
+                Some((
+                    QuasarGroup::v0_admin_and_mango_program(&data),
+                    QuasarGroup::v0_leverage_token_mints(&data),
+                ))
+            } else {
+                None
+            }
+        };
+        if let Some(((admin_key, mango_program_id), leverage_token_mints)) = v0 {
+            check!(admin_ai.is_signer, QuasarErrorCode::SignerNecessary)?;
+            check_eq!(admin_ai.key, &admin_key, QuasarErrorCode::InvalidAdminKey)?;
+
+            // The current layout is more than a single instruction can grow a version 0 group
+            // by, it's rewritten by the call growing it the rest of the way
+            let len = size_of::<QuasarGroup>();
+            grow_account(admin_ai, quasar_group_ai, system_program_ai, len)?;
+            if quasar_group_ai.data_len() < len {
+                msg!(
+                    "group grown to {} of {} bytes, migrate again to finish",
+                    quasar_group_ai.data_len(),
+                    len
+                );
+                return Ok(());
+            }
+
+            // Version 0 stored neither the Mango group nor the quote mint, the quote mint has
+            // to be the quote token of the Mango group
+            let mango_group = MangoGroup::load_checked(mango_group_ai, &mango_program_id)?;
+            check_eq!(
+                quote_mint_ai.key,
+                &mango_group.tokens[QUOTE_INDEX].mint,
+                QuasarErrorCode::InvalidQuoteMint
+            )?;
+            check_eq!(
+                token_mint_ais.len(),
+                leverage_token_mints.len(),
+                QuasarErrorCode::InvalidAccount
+            )?;
+            let mut tracked_supplies = Vec::with_capacity(leverage_token_mints.len());
+            for (token_mint_ai, token_mint) in token_mint_ais.iter().zip(&leverage_token_mints) {
+                check_eq!(
+                    token_mint_ai.key,
+                    token_mint,
+                    QuasarErrorCode::InvalidAccount
+                )?;
+                tracked_supplies.push(load_mint(token_mint_ai)?.supply);
+            }
+
+            QuasarGroup::convert_v0(
+                &mut quasar_group_ai.try_borrow_mut_data()?[..],
+                mango_group_ai.key,
+                quote_mint_ai.key,
+                &tracked_supplies,
+                Clock::get()?.unix_timestamp as u64,
+            )?;
+        } else {
+            // Later groups only lack the fields appended to the layout since. Should the admin
+            // check below fail, the growth fails along with the instruction
+            grow_account(
+                admin_ai,
+                quasar_group_ai,
+                system_program_ai,
+                size_of::<QuasarGroup>(),
+            )?;
+        }
+
+        let mut quasar_group = QuasarGroup::load_mut_any_version(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+        check_eq!(
+            mango_group_ai.key,
+            &quasar_group.mango_group,
+            QuasarErrorCode::InvalidMangoGroup
+        )?;
+        check_eq!(
+            quote_mint_ai.key,
+            &quasar_group.quote_mint,
+            QuasarErrorCode::InvalidQuoteMint
+        )?;
+        let quote_mint = load_mint(quote_mint_ai)?;
+
+        msg!(
+            "migrating group from version {} to {}",
+            quasar_group.meta_data.version,
+            QUASAR_GROUP_VERSION
+        );
+        quasar_group.migrate(quote_mint.decimals)
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn quote_redeem(program_id: &Pubkey, accounts: &[AccountInfo], quantity: u64) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::QUOTE_REDEEM_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai] =
+            accounts;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = &quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+        let mango_cache =
+            MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, mango_program_ai.key, mango_group_ai.key)?;
+
+        let quote = leverage_token.quote_redeem(
+            leverage_token.current_supply(token_mint_ai)?,
+            quantity,
+            Clock::get()?.unix_timestamp as u64,
+            quasar_group.quote_decimals,
+            &mango_group,
+            &mango_account,
+            &mango_cache,
+        )?;
+        emit(&quote);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn sweep_dust<'a>(program_id: &Pubkey, accounts: &[AccountInfo<'a>]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SWEEP_DUST_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, mango_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let [quasar_group_ai, token_mint_ai, mango_program_ai, mango_group_ai, mango_account_ai, mango_cache_ai, root_bank_ai, node_bank_ai, vault_ai, fee_vault_ai, pda_ai, mango_signer_ai, token_program_ai] =
+            fixed_ais;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        check_eq!(
+            pda_ai.key,
+            &quasar_group.signer_key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check!(
+            quasar_group.fee_vault != Pubkey::default(),
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            fee_vault_ai.key,
+            &quasar_group.fee_vault,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+        let leverage_token = &quasar_group.leverage_tokens[leverage_token_index];
+        check_eq!(
+            leverage_token.mango_account,
+            *mango_account_ai.key,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let supply = leverage_token.current_supply(token_mint_ai)?;
+
+        let dust = {
+            let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+            let mango_cache =
+                MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+            let mango_account = MangoAccount::load_checked(
+                mango_account_ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?;
+            leverage_token.sweepable_dust(supply, &mango_group, &mango_account, &mango_cache)?
+        };
+        if dust == 0 {
+            msg!("no dust to sweep");
+            return Ok(());
+        }
+        msg!("sweeping {} of dust to the fee vault", dust);
+
+        let signer_seeds_parts = quasar_group.signer_seeds_parts(quasar_group_ai.key);
+        let signer_seeds = signer_seeds_parts.as_seeds();
+        withdraw_from_mango_account(
+            &quasar_group.mango_program_id,
+            mango_program_ai,
+            mango_group_ai,
+            mango_account_ai,
+            pda_ai,
+            mango_cache_ai,
+            root_bank_ai,
+            node_bank_ai,
+            vault_ai,
+            fee_vault_ai,
+            mango_signer_ai,
+            token_program_ai,
+            mango_open_orders_ais,
+            &[&signer_seeds],
+            dust,
+            false,
+        )?;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Change the order type of the rebalance perp orders of a leverage token
+    /// Only allow admin
+    fn set_rebalance_order_type(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_type: u8,
+        offset_bps: u16,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_REBALANCE_ORDER_TYPE_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+
+        let order_type = check_rebalance_order(order_type, offset_bps)?;
+        msg!(
+            "rebalance order type: {:?} / offset bps: {}",
+            order_type,
+            offset_bps
+        );
+        let leverage_token = &mut quasar_group.leverage_tokens[leverage_token_index];
+        leverage_token.rebalance_order_type = order_type.into();
+        leverage_token.rebalance_order_offset_bps = offset_bps;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Permissionless
+    fn log_total_tvl(program_id: &Pubkey, accounts: &[AccountInfo]) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::LOG_TOTAL_TVL_ACCOUNTS.len();
+        let (fixed_ais, mango_account_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [quasar_group_ai, mango_program_ai, mango_group_ai, mango_cache_ai] = fixed_ais;
+
+        let quasar_group = QuasarGroup::load_checked(quasar_group_ai, program_id)?;
+        quasar_group.check_mango_group(mango_program_ai.key, mango_group_ai.key)?;
+        let tvl_accounts = quasar_group.tvl_accounts();
+        check_eq!(
+            mango_account_ais.len(),
+            tvl_accounts.len(),
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+        let mango_cache =
+            MangoCache::load_checked(mango_cache_ai, mango_program_ai.key, &mango_group)?;
+        let mut mango_accounts = Vec::with_capacity(mango_account_ais.len());
+        for (ai, key) in mango_account_ais.iter().zip(tvl_accounts.iter()) {
+            check_eq!(ai.key, key, QuasarErrorCode::InvalidAccount)?;
+            mango_accounts.push(MangoAccount::load_checked(
+                ai,
+                mango_program_ai.key,
+                mango_group_ai.key,
+            )?);
+        }
+
+        let event = TotalTvl::new(
+            quasar_group.quote_decimals,
+            &mango_group,
+            &mango_cache,
+            mango_accounts.iter().map(|mango_account| &**mango_account),
+        )?;
+        msg!("total tvl: {}", I80F48::from_bits(event.tvl));
+        emit(&event);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Only allow admin
+    fn set_compound(program_id: &Pubkey, accounts: &[AccountInfo], compound: bool) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_COMPOUND_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, token_mint_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        let leverage_token_index = quasar_group
+            .find_leverage_token_index_by_mint(token_mint_ai.key)
+            .ok_or(throw_err!(QuasarErrorCode::InvalidToken))?;
+
+        msg!("compound: {}", compound);
+        quasar_group.leverage_tokens[leverage_token_index].compound = compound;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Only allow admin
+    fn set_risk_admin(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        risk_admin: Option<Pubkey>,
+    ) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_RISK_ADMIN_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin(&quasar_group, admin_ai)?;
+
+        match risk_admin {
+            Some(risk_admin) => msg!("risk admin: {}", risk_admin),
+            None => msg!("risk admin removed"),
+        }
+        quasar_group.risk_admin_key = risk_admin.unwrap_or_default();
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Only allow admin or risk admin
+    fn set_paused(program_id: &Pubkey, accounts: &[AccountInfo], paused: bool) -> QuasarResult {
+        const NUM_FIXED: usize = instruction::SET_PAUSED_ACCOUNTS.len();
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [quasar_group_ai, admin_ai] = accounts;
+
+        let mut quasar_group = QuasarGroup::load_mut_checked(quasar_group_ai, program_id)?;
+        require_admin_or_risk_admin(&quasar_group, admin_ai)?;
+
+        msg!("paused: {}", paused);
+        quasar_group.paused = paused;
+
+        Ok(())
+    }
+}
+
+/// Make sure a Mango account collateral is minted from is one of `owner`'s in the group, and not
+/// the Mango account of the leverage token itself
+fn check_mango_balance_source(
+    source_mango_account_ai: &AccountInfo,
+    mango_program_id: &Pubkey,
+    mango_group_key: &Pubkey,
+    owner_key: &Pubkey,
+    leverage_token_mango_account: &Pubkey,
+) -> QuasarResult {
+    check!(
+        source_mango_account_ai.key != leverage_token_mango_account,
+        QuasarErrorCode::InvalidAccount
+    )?;
+    let source_mango_account =
+        MangoAccount::load_checked(source_mango_account_ai, mango_program_id, mango_group_key)?;
+    check_eq!(
+        source_mango_account.owner,
+        *owner_key,
+        QuasarErrorCode::InvalidAccount
+    )
+}
+
+/// Stamp a mint of `token_mint_ai` by `owner_ai` on their hold record, creating it on their
+/// first mint. Nothing is kept while the group has no `min_hold_secs`
+fn record_hold<'a>(
+    program_id: &Pubkey,
+    quasar_group: &QuasarGroup,
+    quasar_group_ai: &AccountInfo<'a>,
+    token_mint_ai: &AccountInfo<'a>,
+    owner_ai: &AccountInfo<'a>,
+    hold_record_ai: &AccountInfo<'a>,
+    system_program_ai: &AccountInfo<'a>,
+    now_ts: u64,
+) -> QuasarResult {
+    if quasar_group.min_hold_secs == 0 {
+        return Ok(());
+    }
+    let (hold_record_key, hold_record_bump) = gen_hold_record_key(
+        quasar_group_ai.key,
+        token_mint_ai.key,
+        owner_ai.key,
+        program_id,
+    );
+    check_eq!(
+        *hold_record_ai.key,
+        hold_record_key,
+        QuasarErrorCode::InvalidAccount
+    )?;
+
+    if hold_record_ai.data_is_empty() {
+        create_account(
+            owner_ai,
+            hold_record_ai,
+            size_of::<HoldRecord>(),
+            program_id,
+            system_program_ai,
+            &[&[
+                quasar_group_ai.key.as_ref(),
+                token_mint_ai.key.as_ref(),
+                owner_ai.key.as_ref(),
+                HOLD_RECORD_SEED,
+                &[hold_record_bump],
+            ]],
+        )?;
+        let mut hold_record = HoldRecord::load_mut(hold_record_ai)?;
+        hold_record.meta_data = MetaData::new(DataType::HoldRecord, 0, true);
+    }
+
+    let mut hold_record = HoldRecord::load_mut_checked(hold_record_ai, program_id)?;
+    hold_record.last_mint_ts = now_ts;
+    Ok(())
+}
+
+/// Make sure `owner_ai` isn't redeeming `token_mint_ai` within the group's `min_hold_secs` of
+/// their last mint of it. An owner without a hold record never minted the token, they got it
+/// by transfer, so they have to wait out a mint of their own as well
+fn check_hold_record(
+    program_id: &Pubkey,
+    quasar_group: &QuasarGroup,
+    quasar_group_ai: &AccountInfo,
+    token_mint_ai: &AccountInfo,
+    owner_ai: &AccountInfo,
+    hold_record_ai: &AccountInfo,
+) -> QuasarResult {
+    if quasar_group.min_hold_secs == 0 {
+        return Ok(());
+    }
+    let (hold_record_key, _) = gen_hold_record_key(
+        quasar_group_ai.key,
+        token_mint_ai.key,
+        owner_ai.key,
+        program_id,
+    );
+    check_eq!(
+        *hold_record_ai.key,
+        hold_record_key,
+        QuasarErrorCode::InvalidAccount
+    )?;
+    check!(
+        !hold_record_ai.data_is_empty(),
+        QuasarErrorCode::HoldPeriodNotElapsed
+    )?;
+
+    let hold_record = HoldRecord::load_checked(hold_record_ai, program_id)?;
+    let now_ts = Clock::get()?.unix_timestamp as u64;
+    quasar_group.check_hold_elapsed(hold_record.last_mint_ts, now_ts)
+}
+
+/// Make sure a leverage token's Mango account is owned by the group signer, which
+/// `init_mango_account` made it, so an account someone else controls can't stand in for it
+fn check_mango_account_owner(
+    mango_account_ai: &AccountInfo,
+    mango_program_id: &Pubkey,
+    mango_group_key: &Pubkey,
+    signer_key: &Pubkey,
+) -> QuasarResult {
+    let mango_account =
+        MangoAccount::load_checked(mango_account_ai, mango_program_id, mango_group_key)?;
+    check_eq!(
+        mango_account.owner,
+        *signer_key,
+        QuasarErrorCode::InvalidMangoAccountOwner
+    )
+}
+
+/// Make sure a leverage token being closed has no supply left, and no perp position or order a
+/// rebalance still has to unwind with nothing left to back them
+fn check_wound_down(
+    leverage_token: &LeverageToken,
+    token_mint_ai: &AccountInfo,
+    mango_program_ai: &AccountInfo,
+    mango_group_ai: &AccountInfo,
+    mango_account_ai: &AccountInfo,
+) -> QuasarResult {
+    check_eq!(
+        leverage_token.current_supply(token_mint_ai)?,
+        0,
+        QuasarErrorCode::TokenSupplyNonzero
+    )?;
+
+    let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_ai.key)?;
+    let mango_account =
+        MangoAccount::load_checked(mango_account_ai, mango_program_ai.key, mango_group_ai.key)?;
+
+    for mango_perp_market in
+        leverage_token.mango_perp_markets[..leverage_token.num_perp_markets].iter()
+    {
+        let market_index = mango_group
+            .find_perp_market_index(mango_perp_market)
+            .unwrap();
+        let perp_account = &mango_account.perp_accounts[market_index];
+        check!(
+            perp_account.base_position == 0
+                && perp_account.bids_quantity == 0
+                && perp_account.asks_quantity == 0,
+            QuasarErrorCode::OpenPositionRemaining
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Make sure the mint of a closed leverage token can back the token again: still minted by
+/// the group signer, with nothing in circulation and the `decimals` the token is added with
+fn check_reusable_mint(mint_ai: &AccountInfo, signer_key: &Pubkey, decimals: u8) -> QuasarResult {
+    let mint = load_mint(mint_ai)?;
+    check_eq!(
+        mint.mint_authority,
+        COption::Some(*signer_key),
+        QuasarErrorCode::InvalidAccount
+    )?;
+    check_eq!(mint.supply, 0, QuasarErrorCode::TokenSupplyNonzero)?;
+    check_eq!(mint.decimals, decimals, QuasarErrorCode::InvalidParam)
+}
+
+/// Make sure the Mango account of a closed leverage token can back the token again: owned by
+/// the group signer in the group's Mango group, without a perp position or order left on any
+/// market, as the token may now trade others
+fn check_reusable_mango_account(
+    mango_program_ai: &AccountInfo,
+    mango_group_ai: &AccountInfo,
+    mango_account_ai: &AccountInfo,
+    signer_key: &Pubkey,
+) -> QuasarResult {
+    let mango_account =
+        MangoAccount::load_checked(mango_account_ai, mango_program_ai.key, mango_group_ai.key)?;
+    check_eq!(
+        mango_account.owner,
+        *signer_key,
+        QuasarErrorCode::InvalidAccount
+    )?;
+    check!(
+        mango_account.perp_accounts.iter().all(|perp_account| {
+            perp_account.base_position == 0
+                && perp_account.bids_quantity == 0
+                && perp_account.asks_quantity == 0
+        }),
+        QuasarErrorCode::OpenPositionRemaining
+    )
+}
+
+/// Make sure the bank accounts collateral is deposited through are the ones of the quote token
+/// on the Mango group, and the quote token is the group's collateral. Mango would take a deposit
+/// into any of its tokens' banks
+fn check_quote_bank(
+    mango_program_id: &Pubkey,
+    mango_group_ai: &AccountInfo,
+    quote_mint: &Pubkey,
+    root_bank_ai: &AccountInfo,
+    node_bank_ai: &AccountInfo,
+    vault_ai: &AccountInfo,
+) -> QuasarResult {
+    let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_id)?;
+    let quote_token = &mango_group.tokens[QUOTE_INDEX];
+    check_eq!(
+        quote_token.mint,
+        *quote_mint,
+        QuasarErrorCode::TokenMintMismatch
+    )?;
+    check_eq!(
+        *root_bank_ai.key,
+        quote_token.root_bank,
+        QuasarErrorCode::TokenMintMismatch
+    )?;
+
+    let root_bank = RootBank::load_checked(root_bank_ai, mango_program_id)?;
+    check!(
+        root_bank.node_banks[..root_bank.num_node_banks].contains(node_bank_ai.key),
+        QuasarErrorCode::TokenMintMismatch
+    )?;
+    let node_bank = NodeBank::load_checked(node_bank_ai, mango_program_id)?;
+    check_eq!(
+        *vault_ai.key,
+        node_bank.vault,
+        QuasarErrorCode::TokenMintMismatch
+    )
+}
+
+/// Make sure the fixed accounts of an instruction are writable and signed as its `*_ACCOUNTS`
+/// table says, so a misconfigured client is told which account is off rather than failing
+/// somewhere down a CPI
+fn check_account_roles(
+    accounts: &[AccountInfo],
+    descriptors: &[AccountDescriptor],
+) -> QuasarResult {
+    if accounts.len() < descriptors.len() {
+        return Err(ProgramError::NotEnoughAccountKeys.into());
+    }
+
+    for (account, descriptor) in accounts.iter().zip(descriptors) {
+        if descriptor.is_writable && !account.is_writable {
+            msg!("{} must be writable", descriptor.name);
+            return Err(throw_err!(QuasarErrorCode::AccountNotWritable));
+        }
+        if descriptor.is_signer && !account.is_signer {
+            msg!("{} must sign", descriptor.name);
+            return Err(throw_err!(QuasarErrorCode::AccountNotSigner));
+        }
+    }
+
+    Ok(())
+}
+
+/// Mint the management fee accrued since the last accrual to the token's fee token account.
+/// Nothing is minted while the supply is zero, and the accrual time only moves forward once some
+/// fee was minted so that frequent calls can't round the fee away.
+fn accrue_management_fee<'a>(
+    leverage_token: &mut LeverageToken,
+    token_program_ai: &AccountInfo<'a>,
+    token_mint_ai: &AccountInfo<'a>,
+    fee_token_account_ai: &AccountInfo<'a>,
+    pda_ai: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+    now_ts: u64,
+) -> QuasarResult {
+    check_eq!(
+        *fee_token_account_ai.key,
+        leverage_token.fee_token_account(pda_ai.key),
+        QuasarErrorCode::InvalidAccount
+    )?;
+
+    let supply = leverage_token.current_supply(token_mint_ai)?;
+    if leverage_token.management_fee_bps_annual == 0 || supply == 0 {
+        leverage_token.last_fee_accrual_ts = now_ts;
+        return Ok(());
+    }
+
+    let fee_tokens = leverage_token.pending_management_fee(supply, now_ts)?;
+    if fee_tokens == 0 {
+        return Ok(());
+    }
+
+    msg!("management fee: {}", fee_tokens);
+    invoke_mint_to(
+        token_program_ai,
+        token_mint_ai,
+        fee_token_account_ai,
+        pda_ai,
+        signer_seeds,
+        fee_tokens,
+    )?;
+    leverage_token.record_mint(fee_tokens)?;
+    leverage_token.last_fee_accrual_ts = now_ts;
+
+    Ok(())
+}
+
+/// Pay the crank of a rebalance that traded `traded_notional` native quote units its reward
+/// out of the group's fee vault. Nothing is paid while the group has no `crank_reward_bps` or
+/// the fee vault is empty
+fn pay_crank_reward<'a>(
+    quasar_group: &QuasarGroup,
+    token_program_ai: &AccountInfo<'a>,
+    fee_vault_ai: &AccountInfo<'a>,
+    crank_quote_token_account_ai: &AccountInfo<'a>,
+    pda_ai: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+    traded_notional: I80F48,
+    quote_decimals: u8,
+) -> QuasarResult {
+    if quasar_group.crank_reward_bps == 0 {
+        return Ok(());
+    }
+    check!(
+        quasar_group.fee_vault != Pubkey::default(),
+        QuasarErrorCode::InvalidAccount
+    )?;
+    check_eq!(
+        fee_vault_ai.key,
+        &quasar_group.fee_vault,
+        QuasarErrorCode::InvalidAccount
+    )?;
+    let fee_vault = load_token_account(fee_vault_ai, &quasar_group.signer_key)?;
+    let crank_quote_token_account =
+        TokenAccount::unpack(&crank_quote_token_account_ai.try_borrow_data()?)?;
+    check_eq!(
+        crank_quote_token_account.mint,
+        quasar_group.quote_mint,
+        QuasarErrorCode::InvalidQuoteMint
+    )?;
+
+    let reward = quasar_group.crank_reward(traded_notional, quote_decimals, fee_vault.amount)?;
+    if reward == 0 {
+        return Ok(());
+    }
+
+    msg!("crank reward: {}", reward);
+    invoke_transfer(
+        token_program_ai,
+        fee_vault_ai,
+        crank_quote_token_account_ai,
+        pda_ai,
+        signer_seeds,
+        reward,
+    )?;
+
+    Ok(())
+}
 
-            price = mango_cache.price_cache[market_index].price;
-            msg!("price: {}", price);
-            let target_exposure = net_asset_value
-                .checked_mul(leverage_token.target_leverage)
-                .unwrap();
-            msg!("target leverage: {}", leverage_token.target_leverage);
-            msg!("target exposure: {}", target_exposure);
-            msg!("current exposure: {}", perp_asset_value);
+/// Convert an oracle price and an exposure delta in native quote units into the price in
+/// quote lots and the quantity in base lots of the perp order closing the delta
+fn perp_order_lots(
+    mango_group: &MangoGroup,
+    market_index: usize,
+    price: OraclePrice,
+    exposure_delta: I80F48,
+) -> QuasarResult<(I80F48, I80F48)> {
+    let base_lot_size = I80F48::from_num(mango_group.perp_markets[market_index].base_lot_size);
+    let quote_lot_size = I80F48::from_num(mango_group.perp_markets[market_index].quote_lot_size);
 
-            let base_decimals = mango_group.tokens[market_index].decimals;
-            let base_unit = 10u64.pow(base_decimals.into());
-            let base_lot_size =
-                I80F48::from_num(mango_group.perp_markets[market_index].base_lot_size);
+    msg!("exposure delta in native quote unit: {}", exposure_delta);
 
-            let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
-            let quote_unit = 10u64.pow(quote_decimals.into());
-            let quote_lot_size =
-                I80F48::from_num(mango_group.perp_markets[market_index].quote_lot_size);
+    // Lots are of native units, so the price has to be per native unit before it is per lot
+    let price = price
+        .native_price(mango_group.tokens[QUOTE_INDEX].decimals)?
+        .checked_mul(base_lot_size)
+        .and_then(|v| v.checked_div(quote_lot_size))
+        .ok_or(math_err!())?;
+    msg!("price in quote lot unit: {}", price);
 
-            let exposure_delta = target_exposure.checked_sub(perp_asset_value).unwrap();
-            msg!("exposure delta in native quote unit: {}", exposure_delta);
+    let exposure_delta = exposure_delta.checked_div(quote_lot_size).unwrap();
+    msg!("exposure delta in quote lot unit: {}", exposure_delta);
 
-            price = price
-                .checked_mul(I80F48::from_num(quote_unit))
-                .unwrap()
-                .checked_mul(base_lot_size)
-                .unwrap()
-                .checked_div(quote_lot_size)
-                .unwrap()
-                .checked_div(I80F48::from_num(base_unit))
-                .unwrap();
-            msg!("price in quote lot unit: {}", price);
+    let quantity = exposure_delta.checked_div(price).unwrap();
+    msg!("perp quantity to adjust in base lot unit: {}", quantity);
 
-            let exposure_delta = exposure_delta
-                .checked_div(I80F48::from_num(quote_lot_size))
-                .unwrap();
-            msg!("exposure delta in quote lot unit: {}", exposure_delta);
+    Ok((price, quantity))
+}
 
-            quantity = exposure_delta.checked_div(price).unwrap();
-            msg!("perp quantity to adjust in base lot unit: {}", quantity);
-        }
+/// Charge a mint `quote` the slippage the perp orders levering up its collateral would take on
+/// the books of `book_ais`, three accounts for each perp market of `leverage_token`. Rebalance
+/// only opens the exposure later, but it is the minter's, so the minter pays for it rather
+/// than the existing holders. The extra collateral is itself levered up once deposited, that
+/// second order slippage is left out
+fn add_mint_price_impact(
+    quote: &mut MintQuote,
+    leverage_token: &LeverageToken,
+    mango_program_id: &Pubkey,
+    mango_group_key: &Pubkey,
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    book_ais: &[AccountInfo],
+) -> QuasarResult {
+    const NUM_BOOK: usize = 3;
+    check_eq!(
+        book_ais.len(),
+        leverage_token.num_perp_markets * NUM_BOOK,
+        QuasarErrorCode::InvalidAccount
+    )?;
 
-        let signer_seeds = gen_signer_seeds(&quasar_group.signer_nonce, quasar_group_ai.key);
+    // Long tokens take the asks to open their exposure, short ones the bids
+    let is_buy = leverage_token.target_leverage.is_positive();
+    let exposure = I80F48::from_num(quote.collateral)
+        .checked_mul(leverage_token.target_leverage.abs())
+        .ok_or(math_err!())?;
+    let mut price_impact = ZERO_I80F48;
+    for leg in 0..leverage_token.num_perp_markets {
+        let [perp_market_ai, bids_ai, asks_ai] = array_ref![book_ais, leg * NUM_BOOK, NUM_BOOK];
+        check_eq!(
+            *perp_market_ai.key,
+            leverage_token.mango_perp_markets[leg],
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let perp_market =
+            PerpMarket::load_checked(perp_market_ai, mango_program_id, mango_group_key)?;
+        check_eq!(
+            *bids_ai.key,
+            perp_market.bids,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            *asks_ai.key,
+            perp_market.asks,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        let book_ai = if is_buy { asks_ai } else { bids_ai };
+        check_eq!(
+            book_ai.owner,
+            mango_program_id,
+            QuasarErrorCode::InvalidOwner
+        )?;
+        let book_side = BookSide::load(book_ai)?;
 
-        msg!(
-            "price: {}, quantity: {}",
-            price.to_num::<i64>(),
-            quantity.abs().to_num::<i64>()
-        );
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .unwrap();
+        let base_lot_size = I80F48::from_num(perp_market.base_lot_size);
+        let quote_lot_size = I80F48::from_num(perp_market.quote_lot_size);
+        // Native quote units per native base unit, and quote lots per base lot like the book
+        let price = get_mango_cache_price(mango_group, mango_cache, market_index)?
+            .native_price(mango_group.tokens[QUOTE_INDEX].decimals)?;
+        let lot_price = price
+            .checked_mul(base_lot_size)
+            .and_then(|v| v.checked_div(quote_lot_size))
+            .ok_or(math_err!())?;
 
-        if (quantity > ZERO_I80F48) {
-            place_mango_perp_order(
-                mango_program_ai,
-                mango_group_ai,
-                mango_account_ai,
-                pda_ai,
-                mango_cache_ai,
-                mango_perp_market_ai,
-                mango_bids_ai,
-                mango_asks_ai,
-                mango_event_queue_ai,
-                mango_open_orders_ais,
-                &[&signer_seeds],
-                price.to_num::<i64>(),
-                quantity.abs().to_num::<i64>(),
-                0,
-                if quantity > ZERO_I80F48 {
-                    Side::Bid
-                } else {
-                    Side::Ask
-                },
-                OrderType::Limit,
-            )?;
+        let notional = exposure
+            .checked_mul(leverage_token.perp_market_weights[leg])
+            .ok_or(math_err!())?;
+        let base_lots = notional
+            .checked_div(price)
+            .and_then(|v| v.checked_div(base_lot_size))
+            .ok_or(math_err!())?;
+        if !base_lots.is_positive() {
+            continue;
         }
 
-        Ok(())
+        let levels = book_side.iter().map(|leaf| (leaf.price(), leaf.quantity));
+        let fill_price = average_fill_price(levels, base_lots)?
+            .ok_or(throw_err!(QuasarErrorCode::InsufficientBookDepth))?;
+        price_impact = price_impact_cost(notional, lot_price, fill_price, is_buy)?
+            .checked_add(price_impact)
+            .ok_or(math_err!())?;
     }
+
+    quote.price_impact = price_impact
+        .checked_ceil()
+        .and_then(|v| v.checked_to_num::<u64>())
+        .ok_or(math_err!())?;
+    quote.collateral = quote
+        .collateral
+        .checked_add(quote.price_impact)
+        .ok_or(math_err!())?;
+    msg!("mint price impact: {}", quote.price_impact);
+
+    Ok(())
+}
+
+/// Load a Mango account together with its group and cache and return the account's total
+/// equity in native quote units, refusing a stale cache. `mango_program_id` has to be the one
+/// `QuasarGroup::check_mango_group` checked, `load_checked` then makes sure the group, the
+/// cache and the account all belong to it
+#[inline(never)]
+fn mango_account_equity(
+    mango_program_id: &Pubkey,
+    mango_group_ai: &AccountInfo,
+    mango_account_ai: &AccountInfo,
+    mango_cache_ai: &AccountInfo,
+) -> QuasarResult<I80F48> {
+    let mango_group = MangoGroup::load_checked(mango_group_ai, mango_program_id)?;
+    let mango_cache = MangoCache::load_checked(mango_cache_ai, mango_program_id, &mango_group)?;
+    let mango_account =
+        MangoAccount::load_checked(mango_account_ai, mango_program_id, mango_group_ai.key)?;
+
+    let now_ts = Clock::get()?.unix_timestamp as u64;
+    check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts)?;
+
+    get_mango_account_equity(&mango_group, &mango_account, &mango_cache)
 }
 
 fn create_account<'a>(
     signer_ai: &AccountInfo<'a>,
     new_account_ai: &AccountInfo<'a>,
     space: usize,
-    owner_ai: &AccountInfo<'a>,
+    owner: &Pubkey,
     system_program_ai: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
 ) -> ProgramResult {
-    let rent = Rent::default().minimum_balance(space);
-
     check_eq!(
         *system_program_ai.key,
         solana_program::system_program::id(),
         QuasarErrorCode::InvalidAccount
     )?;
+    check_eq!(
+        *new_account_ai.owner,
+        solana_program::system_program::id(),
+        QuasarErrorCode::InvalidAccount
+    )?;
+    check!(
+        new_account_ai.data_is_empty(),
+        QuasarErrorCode::InvalidAccount
+    )?;
 
-    let instruction = solana_program::system_instruction::create_account(
-        signer_ai.key,
-        new_account_ai.key,
-        rent,
-        space as u64,
-        owner_ai.key,
-    );
+    // Use the cluster's actual rent rather than the default one
+    let rent = Rent::get()?.minimum_balance(space);
+    let top_up = rent.saturating_sub(new_account_ai.lamports());
+    check!(
+        signer_ai.lamports() >= top_up,
+        QuasarErrorCode::InsufficientRent
+    )?;
 
     let account_infos = [
         system_program_ai.clone(),
         signer_ai.clone(),
         new_account_ai.clone(),
     ];
+    if new_account_ai.lamports() == 0 {
+        let instruction = solana_program::system_instruction::create_account(
+            signer_ai.key,
+            new_account_ai.key,
+            rent,
+            space as u64,
+            owner,
+        );
+        return invoke_signed(&instruction, &account_infos, signer_seeds);
+    }
+
+    // The system program refuses to create an account holding lamports, which anyone can send
+    // to a PDA before it's created. Such an account is topped up, allocated and assigned instead
+    if top_up > 0 {
+        invoke(
+            &system_instruction::transfer(signer_ai.key, new_account_ai.key, top_up),
+            &account_infos,
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(new_account_ai.key, space as u64),
+        &account_infos,
+        signer_seeds,
+    )?;
+    invoke_signed(
+        &system_instruction::assign(new_account_ai.key, owner),
+        &account_infos,
+        signer_seeds,
+    )
+}
+
+/// Grow `account_ai`, owned by this program, towards `len` bytes, by no more than the
+/// `MAX_PERMITTED_DATA_INCREASE` a single instruction may grow an account by. The new bytes are
+/// zero, and `payer_ai` tops up the lamports to the rent exemption of the new size.
+///
+/// solana-program 1.7 has no `AccountInfo::realloc`, so this does what it would: the runtime
+/// leaves `MAX_PERMITTED_DATA_INCREASE` bytes free after the data of every account it passes a
+/// program, and takes the new length of the account from the `u64` right before the data
+fn grow_account<'a>(
+    payer_ai: &AccountInfo<'a>,
+    account_ai: &AccountInfo<'a>,
+    system_program_ai: &AccountInfo<'a>,
+    len: usize,
+) -> QuasarResult {
+    check_eq!(
+        *system_program_ai.key,
+        system_program::id(),
+        QuasarErrorCode::InvalidAccount
+    )?;
+    let old_len = account_ai.data_len();
+    let new_len = len.min(old_len.saturating_add(MAX_PERMITTED_DATA_INCREASE));
+    if new_len <= old_len {
+        return Ok(());
+    }
+
+    let top_up = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(account_ai.lamports());
+    if top_up > 0 {
+        invoke(
+            &system_instruction::transfer(payer_ai.key, account_ai.key, top_up),
+            &[
+                payer_ai.clone(),
+                account_ai.clone(),
+                system_program_ai.clone(),
+            ],
+        )?;
+    }
+
+    let mut data = account_ai.try_borrow_mut_data()?;
+    // Safe as long as the account came through the entrypoint, which serialized its length
+    // before the data and left the room after it
+    unsafe {
+        let data_ptr = data.as_mut_ptr();
+        (data_ptr.sub(size_of::<u64>()) as *mut u64).write_unaligned(new_len as u64);
+        *data = std::slice::from_raw_parts_mut(data_ptr, new_len);
+    }
+    data[old_len..].fill(0);
+    Ok(())
+}
+
+fn invoke_create_associated_token_account<'a>(
+    associated_token_program_ai: &AccountInfo<'a>,
+    funder_ai: &AccountInfo<'a>,
+    wallet_ai: &AccountInfo<'a>,
+    associated_token_account_ai: &AccountInfo<'a>,
+    mint_ai: &AccountInfo<'a>,
+    system_program_ai: &AccountInfo<'a>,
+    token_program_ai: &AccountInfo<'a>,
+    rent_program_ai: &AccountInfo<'a>,
+) -> ProgramResult {
+    check_eq!(
+        *associated_token_program_ai.key,
+        spl_associated_token_account::id(),
+        QuasarErrorCode::InvalidAccount
+    )?;
+
+    let instruction = create_associated_token_account(funder_ai.key, wallet_ai.key, mint_ai.key);
+
+    let account_infos = [
+        associated_token_program_ai.clone(),
+        funder_ai.clone(),
+        associated_token_account_ai.clone(),
+        wallet_ai.clone(),
+        mint_ai.clone(),
+        system_program_ai.clone(),
+        token_program_ai.clone(),
+        rent_program_ai.clone(),
+    ];
 
     invoke(&instruction, &account_infos)
 }
@@ -677,7 +4085,172 @@ fn invoke_burn<'a>(
     solana_program::program::invoke_signed(&instruction, &account_infos, signer_seeds)
 }
 
+fn invoke_transfer<'a>(
+    token_program_ai: &AccountInfo<'a>,
+    source_ai: &AccountInfo<'a>,
+    destination_ai: &AccountInfo<'a>,
+    owner_ai: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+    quantity: u64,
+) -> ProgramResult {
+    let instruction = spl_token::instruction::transfer(
+        &spl_token::ID,
+        source_ai.key,
+        destination_ai.key,
+        owner_ai.key,
+        &[],
+        quantity,
+    )?;
+
+    let account_infos = [
+        token_program_ai.clone(),
+        source_ai.clone(),
+        destination_ai.clone(),
+        owner_ai.clone(),
+    ];
+
+    solana_program::program::invoke_signed(&instruction, &account_infos, signer_seeds)
+}
+
+fn invoke_set_freeze_authority<'a>(
+    token_program_ai: &AccountInfo<'a>,
+    mint_ai: &AccountInfo<'a>,
+    authority_ai: &AccountInfo<'a>,
+    new_authority: Option<&Pubkey>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = spl_token::instruction::set_authority(
+        &spl_token::ID,
+        mint_ai.key,
+        new_authority,
+        spl_token::instruction::AuthorityType::FreezeAccount,
+        authority_ai.key,
+        &[],
+    )?;
+
+    let account_infos = [
+        token_program_ai.clone(),
+        mint_ai.clone(),
+        authority_ai.clone(),
+    ];
+
+    solana_program::program::invoke_signed(&instruction, &account_infos, signer_seeds)
+}
+
+fn invoke_initialize_token_account<'a>(
+    token_program_ai: &AccountInfo<'a>,
+    account_ai: &AccountInfo<'a>,
+    mint_ai: &AccountInfo<'a>,
+    owner_ai: &AccountInfo<'a>,
+    rent_program_ai: &AccountInfo<'a>,
+) -> ProgramResult {
+    let instruction = spl_token::instruction::initialize_account(
+        &spl_token::ID,
+        account_ai.key,
+        mint_ai.key,
+        owner_ai.key,
+    )?;
+
+    let account_infos = [
+        token_program_ai.clone(),
+        account_ai.clone(),
+        mint_ai.clone(),
+        owner_ai.clone(),
+        rent_program_ai.clone(),
+    ];
+
+    invoke(&instruction, &account_infos)
+}
+
+/// `invoke_signed` an instruction for Mango, refusing to if it isn't addressed to the group's
+/// Mango program. The program account comes from the caller, a fake one could otherwise be
+/// handed the group signer and our deposits
+fn invoke_mango<'a>(
+    mango_program_id: &Pubkey,
+    instruction: &Instruction,
+    account_infos: &[AccountInfo<'a>],
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    check_eq!(
+        instruction.program_id,
+        *mango_program_id,
+        QuasarErrorCode::InvalidMangoProgram
+    )?;
+    invoke_signed(instruction, account_infos, signers_seeds)
+}
+
+fn invoke_settle_pnl<'a>(
+    mango_program_id: &Pubkey,
+    mango_program_ai: &AccountInfo<'a>,
+    mango_group_ai: &AccountInfo<'a>,
+    mango_account_a_ai: &AccountInfo<'a>,
+    mango_account_b_ai: &AccountInfo<'a>,
+    mango_cache_ai: &AccountInfo<'a>,
+    root_bank_ai: &AccountInfo<'a>,
+    node_bank_ai: &AccountInfo<'a>,
+    market_index: usize,
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *mango_program_ai.key,
+        data: mango::instruction::MangoInstruction::SettlePnl { market_index }.pack(),
+        accounts: vec![
+            AccountMeta::new_readonly(*mango_group_ai.key, false),
+            AccountMeta::new(*mango_account_a_ai.key, false),
+            AccountMeta::new(*mango_account_b_ai.key, false),
+            AccountMeta::new_readonly(*mango_cache_ai.key, false),
+            AccountMeta::new_readonly(*root_bank_ai.key, false),
+            AccountMeta::new(*node_bank_ai.key, false),
+        ],
+    };
+
+    let account_infos = [
+        mango_program_ai.clone(),
+        mango_group_ai.clone(),
+        mango_account_a_ai.clone(),
+        mango_account_b_ai.clone(),
+        mango_cache_ai.clone(),
+        root_bank_ai.clone(),
+        node_bank_ai.clone(),
+    ];
+
+    invoke_mango(mango_program_id, &instruction, &account_infos, &[])
+}
+
+fn invoke_update_funding<'a>(
+    mango_program_id: &Pubkey,
+    mango_program_ai: &AccountInfo<'a>,
+    mango_group_ai: &AccountInfo<'a>,
+    mango_cache_ai: &AccountInfo<'a>,
+    mango_perp_market_ai: &AccountInfo<'a>,
+    mango_bids_ai: &AccountInfo<'a>,
+    mango_asks_ai: &AccountInfo<'a>,
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *mango_program_ai.key,
+        data: mango::instruction::MangoInstruction::UpdateFunding.pack(),
+        accounts: vec![
+            AccountMeta::new_readonly(*mango_group_ai.key, false),
+            AccountMeta::new(*mango_cache_ai.key, false),
+            AccountMeta::new(*mango_perp_market_ai.key, false),
+            AccountMeta::new_readonly(*mango_bids_ai.key, false),
+            AccountMeta::new_readonly(*mango_asks_ai.key, false),
+        ],
+    };
+
+    let account_infos = [
+        mango_program_ai.clone(),
+        mango_group_ai.clone(),
+        mango_cache_ai.clone(),
+        mango_perp_market_ai.clone(),
+        mango_bids_ai.clone(),
+        mango_asks_ai.clone(),
+    ];
+
+    invoke_mango(mango_program_id, &instruction, &account_infos, &[])
+}
+
 fn init_mango_account<'a>(
+    mango_program_id: &Pubkey,
     mango_program_ai: &AccountInfo<'a>,
     mango_group_ai: &AccountInfo<'a>,
     mango_account_ai: &AccountInfo<'a>,
@@ -701,10 +4274,16 @@ fn init_mango_account<'a>(
         owner_ai.clone(),
     ];
 
-    invoke_signed(&instruction, &account_infos, signers_seeds)
+    invoke_mango(
+        mango_program_id,
+        &instruction,
+        &account_infos,
+        signers_seeds,
+    )
 }
 
 fn deposit_to_mango_account<'a>(
+    mango_program_id: &Pubkey,
     mango_program_ai: &AccountInfo<'a>,
     mango_group_ai: &AccountInfo<'a>,
     mango_account_ai: &AccountInfo<'a>,
@@ -717,7 +4296,7 @@ fn deposit_to_mango_account<'a>(
     owner_token_account_ai: &AccountInfo<'a>,
     signers_seeds: &[&[&[u8]]],
     quantity: u64,
-) -> ProgramResult {
+) -> QuasarResult {
     let instruction = Instruction {
         program_id: *mango_program_ai.key,
         data: mango::instruction::MangoInstruction::Deposit { quantity }.pack(),
@@ -747,10 +4326,22 @@ fn deposit_to_mango_account<'a>(
         owner_token_account_ai.clone(),
     ];
 
-    invoke_signed(&instruction, &account_infos, signers_seeds)
+    // Most failures inside Mango abort the transaction outright, but those reported back to
+    // us are tagged so they can't be mistaken for a failed check of our own
+    invoke_mango(
+        mango_program_id,
+        &instruction,
+        &account_infos,
+        signers_seeds,
+    )
+    .map_err(|e| {
+        msg!("mango deposit failed: {}", e);
+        throw_err!(QuasarErrorCode::MangoDepositFailed)
+    })
 }
 
 fn withdraw_from_mango_account<'a>(
+    mango_program_id: &Pubkey,
     mango_program_ai: &AccountInfo<'a>,
     mango_group_ai: &AccountInfo<'a>,
     mango_account_ai: &AccountInfo<'a>,
@@ -812,10 +4403,11 @@ fn withdraw_from_mango_account<'a>(
     account_infos.extend(mango_open_orders_ais.iter().map(|ai| ai.clone()));
     let account_infos = account_infos.as_slice();
 
-    invoke_signed(&instruction, account_infos, signers_seeds)
+    invoke_mango(mango_program_id, &instruction, account_infos, signers_seeds)
 }
 
 fn place_mango_perp_order<'a>(
+    mango_program_id: &Pubkey,
     mango_program_ai: &AccountInfo<'a>,
     mango_group_ai: &AccountInfo<'a>,
     mango_account_ai: &AccountInfo<'a>,
@@ -877,7 +4469,12 @@ fn place_mango_perp_order<'a>(
         accounts: accounts,
     };
 
-    invoke_signed(&instruction, &account_infos, signers_seeds)
+    invoke_mango(
+        mango_program_id,
+        &instruction,
+        &account_infos,
+        signers_seeds,
+    )
 }
 
 fn create_and_initialize_mint_account<'a>(
@@ -888,6 +4485,7 @@ fn create_and_initialize_mint_account<'a>(
     system_program_ai: &AccountInfo<'a>,
     rent_program_ai: &AccountInfo<'a>,
     signer_seeds: &[&[&[u8]]],
+    mint_seeds: &[&[&[u8]]],
     decimals: u8,
 ) -> QuasarResult {
     check_eq!(
@@ -912,12 +4510,15 @@ fn create_and_initialize_mint_account<'a>(
         &signer_ai,
         mint_ai,
         Mint::LEN,
-        &token_program_ai,
+        token_program_ai.key,
         &system_program_ai,
+        mint_seeds,
     )?;
 
     msg!("mint account {} created", mint_ai.key.to_string());
 
+    // The signer also gets the freeze authority, see `SetMintFreezeAuthority` for giving it up
+
     let instruction = spl_token::instruction::initialize_mint(
         token_program_ai.key,
         mint_ai.key,
@@ -939,35 +4540,78 @@ fn create_and_initialize_mint_account<'a>(
     Ok(())
 }
 
+/// Price of a base token as the median of its oracles, `oracle_ais` being all of them in the
+/// order they were added. Oracles that are stale or not trading are left out, and the price
+/// is only trusted if a majority of them are left
 #[inline(never)]
-fn read_oracle(base_token: &BaseToken, oracle_ai: &AccountInfo) -> QuasarResult<I80F48> {
-    let quote_decimals: u8 = base_token.decimals;
-    let oracle_type = determine_oracle_type(oracle_ai);
-    let price = match oracle_type {
-        OracleType::Pyth => {
-            let price_account = Price::get_price(oracle_ai).unwrap();
-            let value = I80F48::from_num(price_account.agg.price);
+fn read_oracle(
+    base_token: &BaseToken,
+    oracle_ais: &[AccountInfo],
+    clock: &Clock,
+) -> QuasarResult<OraclePrice> {
+    check_eq!(
+        oracle_ais.len(),
+        base_token.num_oracles as usize,
+        QuasarErrorCode::InvalidAccount
+    )?;
 
-            let decimals = (quote_decimals as i32)
-                .checked_add(price_account.expo)
-                .unwrap()
-                .checked_sub(quote_decimals as i32)
-                .unwrap();
+    for (i, oracle_ai) in oracle_ais.iter().enumerate() {
+        check_eq!(
+            oracle_ai.key,
+            &base_token.oracles[i],
+            QuasarErrorCode::InvalidAccount
+        )?;
+    }
 
-            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-            if decimals < 0 {
-                value.checked_div(decimal_adj).unwrap()
-            } else {
-                value.checked_mul(decimal_adj).unwrap()
+    let price = read_quorum_price(oracle_ais.len(), |i| {
+        read_oracle_price(base_token, i, &oracle_ais[i], clock)
+    })?;
+    Ok(OraclePrice::new(price, base_token.decimals))
+}
+
+/// Price of the `index`th oracle of a base token, `None` if it can't be trusted right now
+fn read_oracle_price(
+    base_token: &BaseToken,
+    index: usize,
+    oracle_ai: &AccountInfo,
+    clock: &Clock,
+) -> QuasarResult<Option<I80F48>> {
+    let oracle_type = match base_token.oracle_type(index) {
+        OracleType::Unknown => determine_oracle_type(oracle_ai),
+        oracle_type => oracle_type,
+    };
+    let price = match oracle_type {
+        OracleType::Pyth => {
+            let price_account = Price::get_price(oracle_ai)?;
+            let is_fresh = price_account
+                .agg
+                .pub_slot
+                .checked_add(MAX_PYTH_PRICE_AGE_SLOTS)
+                .map_or(false, |valid_until| clock.slot <= valid_until);
+            if !matches!(price_account.agg.status, PriceStatus::Trading) || !is_fresh {
+                msg!("oracle {} is stale or not trading", oracle_ai.key);
+                return Ok(None);
             }
+            price_account.base_token_price(base_token.use_ema)?
         }
         OracleType::Stub => {
-            let oracle = StubOracle::load(oracle_ai)?;
+            let oracle = StubOracle::load_checked(oracle_ai)?;
+            let is_fresh = oracle
+                .last_update
+                .checked_add(MAX_STUB_PRICE_AGE_SECS)
+                .map_or(false, |valid_until| {
+                    clock.unix_timestamp as u64 <= valid_until
+                });
+            if !is_fresh {
+                msg!("oracle {} is stale", oracle_ai.key);
+                return Ok(None);
+            }
             I80F48::from_num(oracle.price)
         }
         OracleType::Unknown => {
-            panic!("Unknown oracle");
+            msg!("oracle {} has an unknown type", oracle_ai.key);
+            return Ok(None);
         }
     };
-    Ok(price)
+    Ok(Some(price))
 }
@@ -1,32 +1,266 @@
 use fixed::types::I80F48;
-use mango::state::{MangoAccount, MangoCache, MangoGroup, QUOTE_INDEX, ZERO_I80F48};
+use mango::matching::{OrderType, Side};
+use mango::state::{MangoAccount, MangoCache, MangoGroup, ONE_I80F48, QUOTE_INDEX, ZERO_I80F48};
 use mango_common::Loadable;
 use mango_macro::{Loadable, Pod};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use solana_program::{
     account_info::AccountInfo,
+    msg,
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
 };
 
-use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
-use spl_token::state::Mint;
+use arrayref::{array_ref, array_refs};
+use bytemuck::Zeroable;
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::state::{Account as TokenAccount, Mint};
+use static_assertions::{const_assert, const_assert_eq};
 
-use std::cell::{Ref, RefMut};
+use std::{
+    cell::{Ref, RefMut},
+    convert::TryFrom,
+    mem::size_of,
+    ops::Range,
+};
 
+#[cfg(feature = "anchor-discriminator")]
+use crate::utils::account_discriminator;
 use crate::{
     error::{check_assert, QuasarError, QuasarErrorCode, QuasarResult, SourceFileId},
-    utils::get_mango_spot_value,
+    events::{MintQuote, RedeemQuote},
+    math::{
+        collateral_for_redeem, collateral_for_tokens, management_fee_tokens, native_to_ui,
+        ui_to_native, within_tolerance, BPS_UNIT,
+    },
+    oracle::{OraclePrice, OracleType, MAX_ORACLES_PER_READ},
+    utils::{get_mango_cache_price, get_mango_perp_value, get_mango_spot_value},
 };
 
 declare_check_assert_macros!(SourceFileId::State);
 
 pub const MAX_BASE_TOKENS: usize = 16;
 pub const MAX_LEVERAGE_TOKENS: usize = 32;
+// Events and summaries report the token counts as `u8`
+const_assert!(MAX_BASE_TOKENS <= u8::MAX as usize);
+const_assert!(MAX_LEVERAGE_TOKENS <= u8::MAX as usize);
+pub const MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN: usize = 4;
+pub const MAX_ORACLES_PER_BASE_TOKEN: usize = 3;
+// Every oracle of a base token has to fit in the compute budget of one read
+const_assert!(MAX_ORACLES_PER_BASE_TOKEN <= MAX_ORACLES_PER_READ);
+pub const MAX_TARGET_LEVERAGE: u64 = 10;
+/// Smallest target leverage, in percent. Anything closer to 1x is better held as the base
+pub const MIN_TARGET_LEVERAGE_PERCENT: u64 = 101;
+/// Decimals of leverage tokens added without a choice of their own
 pub const LEVERGAE_TOKEN_DECIMALS: u8 = 0;
+/// Highest number of decimals a leverage token can have
+pub const MAX_LEVERAGE_TOKEN_DECIMALS: u8 = 9;
 pub const INITIAL_LEVERAGE_TOKEN_PRICE: u64 = 1;
+/// Minimum quantity for the first mint of a leverage token.
+///
+/// While the supply is zero, tokens are minted 1:1 at `INITIAL_LEVERAGE_TOKEN_PRICE` no matter
+/// how much collateral sits in the Mango account, so collateral donated before the first mint
+/// cannot move the price. Requiring a sizeable first mint makes it expensive to inflate the
+/// NAV of a tiny supply afterwards: a donation only moves the price in proportion to the
+/// supply, and the donor keeps just their share of it.
+pub const MIN_INITIAL_MINT_QUANTITY: u64 = 100;
+/// Deviation from the target exposure of a leg, in bps of the target, past which a rebalance
+/// is considered incomplete and left pending
+pub const REBALANCE_TOLERANCE_BPS: u64 = 100;
+/// Most perp orders a rebalance places on a leg its taking orders only partially fill
+pub const MAX_REBALANCE_ORDER_ATTEMPTS: usize = 3;
+/// Largest deviation from the target exposure, in bps of the target, a rebalance can be set to
+/// leave alone
+pub const MAX_REBALANCE_DEADBAND_BPS: u16 = 2_000;
+/// Compute units a rebalance transaction is assumed to get, and rough estimates of what it
+/// spends before trading and on each leg. Legs that don't fit are left to the next crank
+pub const REBALANCE_COMPUTE_BUDGET: u64 = 200_000;
+pub const REBALANCE_BASE_COMPUTE_UNITS: u64 = 50_000;
+pub const REBALANCE_LEG_COMPUTE_UNITS: u64 = 60_000;
+pub const MAX_LEGS_PER_REBALANCE: usize = ((REBALANCE_COMPUTE_BUDGET
+    - REBALANCE_BASE_COMPUTE_UNITS)
+    / REBALANCE_LEG_COMPUTE_UNITS) as usize;
+/// Wait after the first rebalance falling short of its target, doubled on every further one up
+/// to `MAX_REBALANCE_BACKOFF_SECS`
+pub const REBALANCE_BACKOFF_BASE_SECS: u64 = 30;
+pub const MAX_REBALANCE_BACKOFF_SECS: u64 = 60 * 60;
+/// Smallest time between a deleverage and the rebalance or deleverage before it, so the Mango
+/// cache catches up with the fills before the position can be halved again
+pub const MIN_DELEVERAGE_INTERVAL_SECS: u64 = 60;
+/// Capacity of the queue of pending redeems groups before version 5 shared between all of
+/// their leverage tokens
+pub const MAX_REDEEM_REQUESTS: usize = 32;
+/// Capacity of the queue of pending redeems of each leverage token
+pub const MAX_REDEEM_REQUESTS_PER_TOKEN: usize = 4;
+/// Oracle price samples each base token keeps for its TWAP
+pub const MAX_PRICE_SAMPLES: usize = 8;
+/// Smallest time between two price samples. Without it the buffer could be filled with
+/// samples of a single manipulated slot
+pub const MIN_PRICE_SAMPLE_INTERVAL_SECS: u64 = 60;
+/// Samples older than this don't count towards the TWAP mints and rebalances are checked
+/// against
+pub const TWAP_WINDOW_SECS: u64 = 30 * 60;
+/// Farthest the Mango cache may price a perp market from the TWAP of its base token, in bps of
+/// the TWAP, see `QuasarGroup::check_price_near_twap`
+pub const MAX_TWAP_DEVIATION_BPS: u64 = 500;
+/// Drift between the tracked and the actual supply of a leverage token, in bps of the tracked
+/// supply, `ReconcileToken` leaves alone
+pub const RECONCILE_TOLERANCE_BPS: u64 = 1;
+/// Largest drift, in bps of the tracked supply, `ReconcileToken` corrects through the fee token account.
+/// Anything larger gets the token flagged instead
+pub const MAX_RECONCILE_CORRECTION_BPS: u64 = 100;
+/// Largest distance from the oracle price, in bps of it, post only rebalance orders can rest at
+pub const MAX_REBALANCE_ORDER_OFFSET_BPS: u16 = 500;
+/// Largest share of the notional a rebalance traded, in bps, its crank can be paid
+pub const MAX_CRANK_REWARD_BPS: u16 = 100;
+/// Largest decimals of a quote mint, native quote amounts are only logged for mints up to this
+pub const MAX_QUOTE_DECIMALS: u8 = 9;
+
+/// Make sure a decoded target leverage is within `[1.01, MAX_TARGET_LEVERAGE]`. Any bit
+/// pattern decodes to a valid `I80F48`, so zero, negative or huge values have to be caught here
+pub fn check_target_leverage(target_leverage: I80F48) -> QuasarResult {
+    let min = I80F48::from_num(MIN_TARGET_LEVERAGE_PERCENT) / I80F48::from_num(100);
+    check!(
+        target_leverage >= min && target_leverage <= I80F48::from_num(MAX_TARGET_LEVERAGE),
+        QuasarErrorCode::InvalidLeverage
+    )
+}
+
+/// Make sure a rebalance deadband is at most `MAX_REBALANCE_DEADBAND_BPS`
+pub fn check_rebalance_deadband(rebalance_deadband_bps: u16) -> QuasarResult {
+    check!(
+        rebalance_deadband_bps <= MAX_REBALANCE_DEADBAND_BPS,
+        QuasarErrorCode::InvalidParam
+    )
+}
+
+/// How `Rebalance` places its perp orders on Mango
+#[derive(Copy, Clone, Debug, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RebalanceOrderType {
+    /// Take what the book has up to the oracle price and cancel the rest
+    ImmediateOrCancel = 0,
+    /// Take what the book has at any price
+    Market = 1,
+    /// Rest on the book away from the oracle price, never taking
+    PostOnly = 2,
+}
+
+/// Make sure the decimals of a quote mint fit in `MAX_QUOTE_DECIMALS`
+pub fn check_quote_decimals(quote_decimals: u8) -> QuasarResult {
+    check!(
+        quote_decimals <= MAX_QUOTE_DECIMALS,
+        QuasarErrorCode::InvalidQuoteMint
+    )
+}
+
+/// Make sure `order_type` is a `RebalanceOrderType` and `offset_bps` fits it: within
+/// `(0, MAX_REBALANCE_ORDER_OFFSET_BPS]` for post only orders and zero for the others, which
+/// trade at the oracle price
+pub fn check_rebalance_order(order_type: u8, offset_bps: u16) -> QuasarResult<RebalanceOrderType> {
+    let order_type = RebalanceOrderType::try_from(order_type)
+        .map_err(|_| throw_err!(QuasarErrorCode::InvalidParam))?;
+    match order_type {
+        RebalanceOrderType::PostOnly => check!(
+            offset_bps > 0 && offset_bps <= MAX_REBALANCE_ORDER_OFFSET_BPS,
+            QuasarErrorCode::InvalidParam
+        )?,
+        _ => check_eq!(offset_bps, 0, QuasarErrorCode::InvalidParam)?,
+    }
+    Ok(order_type)
+}
+
+/// Unpack an SPL token account, making sure it is initialized and belongs to `owner`
+pub fn load_token_account(account: &AccountInfo, owner: &Pubkey) -> QuasarResult<TokenAccount> {
+    let token_account = TokenAccount::unpack_unchecked(&account.try_borrow_data()?)?;
+    check!(
+        token_account.is_initialized(),
+        QuasarErrorCode::InvalidTokenAccount
+    )?;
+    check_eq!(
+        &token_account.owner,
+        owner,
+        QuasarErrorCode::InvalidTokenAccount
+    )?;
+    Ok(token_account)
+}
+
+/// Make sure `admin_ai` is the group's admin and signed the transaction
+pub fn require_admin(quasar_group: &QuasarGroup, admin_ai: &AccountInfo) -> QuasarResult {
+    check!(admin_ai.is_signer, QuasarErrorCode::SignerNecessary)?;
+    check_eq!(
+        admin_ai.key,
+        &quasar_group.admin_key,
+        QuasarErrorCode::InvalidAdminKey
+    )
+}
+
+/// Make sure `signer_ai` signed and is either the group's admin or its risk admin
+pub fn require_admin_or_risk_admin(
+    quasar_group: &QuasarGroup,
+    signer_ai: &AccountInfo,
+) -> QuasarResult {
+    check!(signer_ai.is_signer, QuasarErrorCode::SignerNecessary)?;
+    check!(
+        *signer_ai.key == quasar_group.admin_key || quasar_group.is_risk_admin(signer_ai.key),
+        QuasarErrorCode::InvalidAdminKey
+    )
+}
+
+/// Unpack an SPL token mint, making sure `account` is an initialized mint of the token program
+pub fn load_mint(account: &AccountInfo) -> QuasarResult<Mint> {
+    if account.owner != &spl_token::id() {
+        msg!("{} is not owned by the token program", account.key);
+        return Err(throw_err!(QuasarErrorCode::InvalidMint));
+    }
+    let mint = Mint::unpack_unchecked(&account.try_borrow_data()?).map_err(|_| {
+        msg!("{} is not a mint", account.key);
+        throw_err!(QuasarErrorCode::InvalidMint)
+    })?;
+    if !mint.is_initialized() {
+        msg!("mint {} is not initialized", account.key);
+        return Err(throw_err!(QuasarErrorCode::InvalidMint));
+    }
+    Ok(mint)
+}
+
+/// Make sure `token_account` can fund a deposit of `amount` into Mango. Mango would fail an
+/// underfunded deposit with an opaque token program error, which doesn't say which side is at
+/// fault
+pub fn check_deposit_balance(token_account: &TokenAccount, amount: u64) -> QuasarResult {
+    if token_account.amount < amount {
+        msg!(
+            "deposit of {} exceeds the balance of {}",
+            amount,
+            token_account.amount
+        );
+        return Err(throw_err!(QuasarErrorCode::MangoDepositFailed));
+    }
+    Ok(())
+}
+
+/// Layout version of the groups this program reads, bumped along with a step in
+/// `QuasarGroup::migrate` whenever the layout or the meaning of a field changes
+pub const QUASAR_GROUP_VERSION: u8 = 7;
+/// Size of the groups created before the layout was versioned, all of them at version 0
+pub const QUASAR_GROUP_V0_LEN: usize = 5_888;
+/// Sizes of the base and leverage tokens of version 0 groups
+const V0_BASE_TOKEN_LEN: usize = 72;
+const V0_LEVERAGE_TOKEN_LEN: usize = 144;
+/// Size of the groups of version 6, before `perp_market_base_mints` was appended
+pub const QUASAR_GROUP_V6_LEN: usize = size_of::<QuasarGroup>()
+    - size_of::<Pubkey>() * MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN * MAX_LEVERAGE_TOKENS;
+/// Size of the groups of version 5, before `settled_pnl` was appended
+pub const QUASAR_GROUP_V5_LEN: usize =
+    QUASAR_GROUP_V6_LEN - size_of::<I80F48>() * MAX_LEVERAGE_TOKENS;
+/// Size of the groups of version 4, before the `redeem_queues` were appended
+pub const QUASAR_GROUP_V4_LEN: usize =
+    QUASAR_GROUP_V5_LEN - size_of::<RedeemQueue>() * MAX_LEVERAGE_TOKENS;
+/// Size of the groups of versions 1 to 3, before `risk_admin_key` and `paused` were appended
+pub const QUASAR_GROUP_V3_LEN: usize = QUASAR_GROUP_V4_LEN - 40;
 
 #[repr(u8)]
 #[derive(IntoPrimitive, TryFromPrimitive)]
@@ -34,6 +268,7 @@ pub enum DataType {
     QuasarGroup = 0,
     BaseToken = 1,
     LeverageToken = 2,
+    HoldRecord = 3,
 }
 
 #[derive(Copy, Clone, Pod, Default)]
@@ -45,6 +280,7 @@ pub struct MetaData {
     pub is_initialized: bool,
     pub padding: [u8; 5], // This makes explicit the 8 byte alignment padding
 }
+const_assert_eq!(size_of::<MetaData>(), 8);
 
 impl MetaData {
     pub fn new(data_type: DataType, version: u8, is_initialized: bool) -> Self {
@@ -57,9 +293,47 @@ impl MetaData {
     }
 }
 
+/// Owned copy of the group signer seeds, so they don't keep the group borrowed while signing
+pub struct SignerSeeds {
+    quasar_group_key: Pubkey,
+    nonce: [u8; 8],
+}
+
+impl SignerSeeds {
+    /// Seeds in the form `invoke_signed` takes them
+    pub fn as_seeds(&self) -> [&[u8]; 2] {
+        [self.quasar_group_key.as_ref(), &self.nonce]
+    }
+}
+
+/// Index of an initialized slot of `QuasarGroup::base_tokens`, only built by
+/// `QuasarGroup::base_token_index` so it can't be mixed up with other indices
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BaseTokenIndex(u8);
+
+impl BaseTokenIndex {
+    pub fn get(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Index of an initialized slot of `QuasarGroup::leverage_tokens`, only built by
+/// `QuasarGroup::leverage_token_index`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LeverageTokenIndex(u8);
+
+impl LeverageTokenIndex {
+    pub fn get(self) -> usize {
+        self.0 as usize
+    }
+}
+
 #[derive(Copy, Clone, Pod, Loadable)]
 #[repr(C)]
 pub struct QuasarGroup {
+    /// `account_discriminator("QuasarGroup")`, only with the `anchor-discriminator` feature
+    #[cfg(feature = "anchor-discriminator")]
+    pub discriminator: [u8; 8],
     pub meta_data: MetaData,
 
     pub num_base_tokens: usize,
@@ -72,25 +346,94 @@ pub struct QuasarGroup {
     pub signer_key: Pubkey,
     pub admin_key: Pubkey,
     pub mango_program_id: Pubkey,
+    /// Mango group every leverage token's Mango account lives in, set at init
+    pub mango_group: Pubkey,
+    /// Mint of the collateral users deposit to mint leverage tokens
+    pub quote_mint: Pubkey,
+    /// Token account of the group signer collecting group level fees, set by `InitFeeVault`
+    pub fee_vault: Pubkey,
+
+    /// Minimum init health ratio of a leverage token's Mango account after a mint, in percent
+    pub min_health_ratio: I80F48,
+    /// Maint health ratio of a leverage token's Mango account below which anyone can
+    /// deleverage it, in percent
+    pub deleverage_health_ratio: I80F48,
+    /// Minimum number of seconds between two rebalances of the same leverage token
+    pub min_rebalance_interval: u64,
+    /// Maximum number of leverage tokens built on the same base token, zero meaning unlimited
+    pub max_tokens_per_base: u64,
+
+    /// Ring buffer of the redeems groups before version 5 queued for all of their tokens,
+    /// oldest at `legacy_redeem_queue_head`. Migrating moves them to the queues of their
+    /// tokens, those that don't fit follow as the queue of their token drains. Nothing else
+    /// is queued here
+    pub legacy_redeem_queue: [RedeemRequest; MAX_REDEEM_REQUESTS],
+    pub legacy_redeem_queue_head: u64,
+    pub legacy_redeem_queue_len: u64,
+
+    /// Set for good by the admin, leaving the group redeem only
+    pub shutdown: bool,
+    /// Decimals of `quote_mint`, the scale of the native quote amounts NAV events carry
+    pub quote_decimals: u8,
+    /// Share of the notional a rebalance traded, in bps, paid to its crank out of the fee vault
+    pub crank_reward_bps: u16,
+    /// Largest reward a single rebalance pays its crank, in whole quote tokens
+    pub max_crank_reward: u16,
+    /// Deviation from the target exposure, in bps of the target, rebalances of leverage tokens
+    /// without a deadband of their own leave alone
+    pub rebalance_deadband_bps: u16,
+    /// Native quote units anyone but the admin pays into the fee vault to add a leverage
+    /// token, zero leaving it to the admin
+    pub creation_fee: u64,
+    /// Seconds a holder has to wait after minting a leverage token before redeeming it, zero
+    /// meaning no wait
+    pub min_hold_secs: u64,
+
+    /// Key allowed to pause minting and tune rebalances next to the admin, set by `SetRiskAdmin`.
+    /// Left at the default key when there is none
+    pub risk_admin_key: Pubkey,
+    /// Set while the admin or the risk admin has paused minting, see `SetPaused`
+    pub paused: bool,
+    pub padding: [u8; 7],
+
+    /// Redeems waiting for `ProcessRedeemQueue`, a queue per leverage token in the slot of
+    /// the token
+    pub redeem_queues: [RedeemQueue; MAX_LEVERAGE_TOKENS],
+
+    /// Pnl and funding `SettleTokenPnl` moved from the perp positions of each leverage token
+    /// into the quote deposits of its Mango account, in native quote units and in the slot of
+    /// the token. Settled losses count against it and redeems take their share of it along.
+    /// Compounding tokens lever it up, see `get_rebalance_net_asset_value`
+    pub settled_pnl: [I80F48; MAX_LEVERAGE_TOKENS],
+
+    /// Mint of the base token each perp market of each leverage token trades, in the slot of
+    /// the token and in the order of its `mango_perp_markets`. `AddLeverageToken` checks every
+    /// market against its base token, see `leg_base_mints`
+    pub perp_market_base_mints:
+        [[Pubkey; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN]; MAX_LEVERAGE_TOKENS],
 }
 
 impl QuasarGroup {
     pub fn load_mut_checked<'a>(
         account: &'a AccountInfo,
         program_id: &Pubkey,
+    ) -> QuasarResult<RefMut<'a, Self>> {
+        let quasar_group = Self::load_mut_any_version(account, program_id)?;
+        quasar_group.check_version()?;
+
+        Ok(quasar_group)
+    }
+
+    /// `load_mut_checked` of a group of any layout version, for `MigrateGroup`
+    pub fn load_mut_any_version<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
     ) -> QuasarResult<RefMut<'a, Self>> {
         check_eq!(account.owner, program_id, QuasarErrorCode::InvalidOwner)?;
+        Self::check_len(account)?;
 
         let quasar_group: RefMut<'a, Self> = Self::load_mut(account)?;
-        check!(
-            quasar_group.meta_data.is_initialized,
-            QuasarErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            quasar_group.meta_data.data_type,
-            DataType::QuasarGroup as u8,
-            QuasarErrorCode::InvalidAccount
-        )?;
+        quasar_group.check_header()?;
 
         Ok(quasar_group)
     }
@@ -100,19 +443,422 @@ impl QuasarGroup {
         program_id: &Pubkey,
     ) -> QuasarResult<Ref<'a, Self>> {
         check_eq!(account.owner, program_id, QuasarErrorCode::InvalidOwner)?;
+        Self::check_len(account)?;
 
         let quasar_group: Ref<'a, Self> = Self::load(account)?;
+        quasar_group.check_header()?;
+        quasar_group.check_version()?;
+
+        Ok(quasar_group)
+    }
+
+    /// Make sure `account` is the size of the current layout before it's cast. Groups before
+    /// version 7 are smaller, `MigrateGroup` grows them
+    fn check_len(account: &AccountInfo) -> QuasarResult {
+        if account.data_len() != size_of::<Self>() {
+            let code = if account.data_len() == QUASAR_GROUP_V6_LEN
+                || account.data_len() == QUASAR_GROUP_V5_LEN
+                || account.data_len() == QUASAR_GROUP_V4_LEN
+                || account.data_len() == QUASAR_GROUP_V3_LEN
+                || Self::is_v0_layout(&account.try_borrow_data()?)
+            {
+                QuasarErrorCode::GroupNeedsMigration
+            } else {
+                QuasarErrorCode::InvalidAccount
+            };
+            return Err(throw_err!(code));
+        }
+        Ok(())
+    }
+
+    /// Make sure the data is an initialized group, whatever its layout version
+    pub(crate) fn check_header(&self) -> QuasarResult {
+        self.check_discriminator()?;
         check!(
-            quasar_group.meta_data.is_initialized,
+            self.meta_data.is_initialized,
             QuasarErrorCode::InvalidAccount
         )?;
         check_eq!(
-            quasar_group.meta_data.data_type,
+            self.meta_data.data_type,
             DataType::QuasarGroup as u8,
             QuasarErrorCode::InvalidAccount
+        )
+    }
+
+    pub(crate) fn check_version(&self) -> QuasarResult {
+        check_eq!(
+            self.meta_data.version,
+            QUASAR_GROUP_VERSION,
+            QuasarErrorCode::GroupNeedsMigration
+        )
+    }
+
+    /// Whether `data` starts with a group of the layout every group had before the layout was
+    /// versioned: no discriminator, a single oracle per base token and a single perp market per
+    /// leverage token, `QUASAR_GROUP_V0_LEN` bytes in all. Nothing past those is read, so this
+    /// still holds once `MigrateGroup` has grown the account
+    pub fn is_v0_layout(data: &[u8]) -> bool {
+        if data.len() < QUASAR_GROUP_V0_LEN {
+            return false;
+        }
+        #[cfg(feature = "anchor-discriminator")]
+        {
+            if data[..8] == account_discriminator("QuasarGroup") {
+                return false;
+            }
+        }
+        let (data_type, version, is_initialized) = (data[0], data[1], data[2]);
+        data_type == DataType::QuasarGroup as u8 && version == 0 && is_initialized == 1
+    }
+
+    /// Fields of the version 0 group `data` starts with, `data` passing `is_v0_layout`
+    fn v0_fields(data: &[u8]) -> QuasarGroupV0 {
+        let (
+            _meta_data,
+            num_base_tokens,
+            base_tokens,
+            num_leverage_tokens,
+            leverage_tokens,
+            signer_nonce,
+            signer_key,
+            admin_key,
+            mango_program_id,
+        ) = array_refs![
+            array_ref![data, 0, QUASAR_GROUP_V0_LEN],
+            8,
+            8,
+            V0_BASE_TOKEN_LEN * MAX_BASE_TOKENS,
+            8,
+            V0_LEVERAGE_TOKEN_LEN * MAX_LEVERAGE_TOKENS,
+            8,
+            32,
+            32,
+            32
+        ];
+        QuasarGroupV0 {
+            num_base_tokens: usize::from_le_bytes(*num_base_tokens),
+            base_tokens,
+            num_leverage_tokens: usize::from_le_bytes(*num_leverage_tokens),
+            leverage_tokens,
+            signer_nonce: u64::from_le_bytes(*signer_nonce),
+            signer_key: Pubkey::new_from_array(*signer_key),
+            admin_key: Pubkey::new_from_array(*admin_key),
+            mango_program_id: Pubkey::new_from_array(*mango_program_id),
+        }
+    }
+
+    /// Admin and Mango program of the version 0 group `data` starts with, see `v0_fields`
+    pub fn v0_admin_and_mango_program(data: &[u8]) -> (Pubkey, Pubkey) {
+        let v0 = Self::v0_fields(data);
+        (v0.admin_key, v0.mango_program_id)
+    }
+
+    /// Mints of the leverage tokens of the version 0 group `data` starts with, in slot order,
+    /// see `v0_fields`. `MigrateGroup` takes them to count their supply into `tracked_supply`
+    pub fn v0_leverage_token_mints(data: &[u8]) -> Vec<Pubkey> {
+        Self::v0_fields(data)
+            .leverage_tokens
+            .chunks_exact(V0_LEVERAGE_TOKEN_LEN)
+            .map(|lt| Pubkey::new_from_array(*array_ref![lt, 0, 32]))
+            .filter(|mint| *mint != Pubkey::default())
+            .collect()
+    }
+
+    /// Rewrite the version 0 group `data` starts with in the current layout, `data` having
+    /// been grown to the size of it. Fields version 0 didn't have get the values
+    /// `InitQuasarGroup` and `AddLeverageToken` give them, other than the Mango group and quote
+    /// mint it didn't store and the `tracked_supplies` of the leverage tokens, in slot order.
+    /// The version is left at 0 for `migrate` to run the steps of later versions
+    pub fn convert_v0(
+        data: &mut [u8],
+        mango_group: &Pubkey,
+        quote_mint: &Pubkey,
+        tracked_supplies: &[u64],
+        now_ts: u64,
+    ) -> QuasarResult {
+        check!(Self::is_v0_layout(data), QuasarErrorCode::InvalidAccount)?;
+        check_eq!(
+            data.len(),
+            size_of::<Self>(),
+            QuasarErrorCode::InvalidAccount
         )?;
 
-        Ok(quasar_group)
+        // Copied to the heap, the stack has no room for it
+        let v0_data = data[..QUASAR_GROUP_V0_LEN].to_vec();
+        let v0 = Self::v0_fields(&v0_data);
+
+        data.fill(0);
+        let quasar_group: &mut Self = bytemuck::from_bytes_mut(data);
+        quasar_group.init_discriminator();
+        quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, 0, true);
+
+        quasar_group.num_base_tokens = v0.num_base_tokens;
+        for (base_token, v0_base_token) in quasar_group
+            .base_tokens
+            .iter_mut()
+            .zip(v0.base_tokens.chunks_exact(V0_BASE_TOKEN_LEN))
+        {
+            let (mint, decimals, oracle, _padding) = array_refs![
+                array_ref![v0_base_token, 0, V0_BASE_TOKEN_LEN],
+                32,
+                1,
+                32,
+                7
+            ];
+            base_token.mint = Pubkey::new_from_array(*mint);
+            if base_token.is_empty() {
+                continue;
+            }
+            base_token.decimals = decimals[0];
+            // Version 0 didn't record the oracle type, it's detected whenever the oracle is read
+            base_token.oracles[0] = Pubkey::new_from_array(*oracle);
+            base_token.num_oracles = 1;
+        }
+
+        let mut tracked_supplies = tracked_supplies.iter();
+        quasar_group.num_leverage_tokens = v0.num_leverage_tokens;
+        for (leverage_token, v0_leverage_token) in quasar_group
+            .leverage_tokens
+            .iter_mut()
+            .zip(v0.leverage_tokens.chunks_exact(V0_LEVERAGE_TOKEN_LEN))
+        {
+            let (mint, base_token_mint, target_leverage, mango_account, mango_perp_market) = array_refs![
+                array_ref![v0_leverage_token, 0, V0_LEVERAGE_TOKEN_LEN],
+                32,
+                32,
+                16,
+                32,
+                32
+            ];
+            if *mint == [0; 32] {
+                continue;
+            }
+            let mut perp_market_weights = [ZERO_I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+            perp_market_weights[0] = ONE_I80F48;
+            let mut mango_perp_markets = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+            mango_perp_markets[0] = Pubkey::new_from_array(*mango_perp_market);
+            *leverage_token = LeverageToken {
+                mint: Pubkey::new_from_array(*mint),
+                base_token_mint: Pubkey::new_from_array(*base_token_mint),
+                target_leverage: I80F48::from_le_bytes(*target_leverage),
+                mango_account: Pubkey::new_from_array(*mango_account),
+                perp_market_weights,
+                mango_perp_markets,
+                num_perp_markets: 1,
+                last_fee_accrual_ts: now_ts,
+                decimals: LEVERGAE_TOKEN_DECIMALS,
+                tracked_supply: *tracked_supplies
+                    .next()
+                    .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?,
+                ..LeverageToken::zeroed()
+            };
+        }
+        check!(
+            tracked_supplies.next().is_none(),
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        quasar_group.signer_nonce = v0.signer_nonce;
+        quasar_group.signer_key = v0.signer_key;
+        quasar_group.admin_key = v0.admin_key;
+        quasar_group.mango_program_id = v0.mango_program_id;
+        quasar_group.mango_group = *mango_group;
+        quasar_group.quote_mint = *quote_mint;
+        Ok(())
+    }
+
+    /// Bring a group of an older layout version up to `QUASAR_GROUP_VERSION`. A group runs the
+    /// steps of every version after its own, so it can skip versions. `quote_decimals` are the
+    /// decimals of the group's quote mint. Version 0 groups have to be rewritten in the current
+    /// layout by `convert_v0` first
+    pub fn migrate(&mut self, quote_decimals: u8) -> QuasarResult {
+        check!(
+            self.meta_data.version < QUASAR_GROUP_VERSION,
+            QuasarErrorCode::InvalidParam
+        )?;
+
+        // Groups of version 1 and later have the current layout, the fields the steps below
+        // leave alone are zero, which is their default, unless the admin has set them. Later
+        // versions add their steps here, each behind an `if self.meta_data.version < N`
+        if self.meta_data.version < 2 {
+            // Taken from a padding byte, which was always zero
+            check_quote_decimals(quote_decimals)?;
+            self.quote_decimals = quote_decimals;
+        }
+        if self.meta_data.version == 3 {
+            self.move_risk_admin_out_of_redeem_queue();
+        }
+        if self.meta_data.version < 5 {
+            for token_index in 0..MAX_LEVERAGE_TOKENS {
+                if !self.leverage_tokens[token_index].is_empty() {
+                    self.take_legacy_redeem_requests(token_index);
+                }
+            }
+        }
+        // Version 6 appended `settled_pnl`. What was settled before isn't known, so it starts
+        // at zero and compounding tokens only lever what they settle from then on
+        if self.meta_data.version < 7 {
+            // Earlier versions had every perp market of a leverage token trade its base token
+            for token_index in 0..MAX_LEVERAGE_TOKENS {
+                let leverage_token = self.leverage_tokens[token_index];
+                if !leverage_token.is_empty() {
+                    self.perp_market_base_mints[token_index][..leverage_token.num_perp_markets]
+                        .fill(leverage_token.base_token_mint);
+                }
+            }
+        }
+        self.meta_data.version = QUASAR_GROUP_VERSION;
+        Ok(())
+    }
+
+    /// Version 3 took the last slot of the redeem queue for `risk_admin_key` and `paused`,
+    /// which version 4 appends to the group instead. Move them to their fields and the queued
+    /// redeems, in a ring of one slot less, to the front of the queue. Groups before version 3
+    /// already queue in every slot
+    fn move_risk_admin_out_of_redeem_queue(&mut self) {
+        const V3_MAX_REDEEM_REQUESTS: usize = MAX_REDEEM_REQUESTS - 1;
+        let taken_slot = self.legacy_redeem_queue[V3_MAX_REDEEM_REQUESTS];
+        self.risk_admin_key = taken_slot.owner;
+        self.paused = taken_slot.mint.as_ref()[0] != 0;
+
+        let requests: Vec<RedeemRequest> = (0..self.legacy_redeem_queue_len as usize)
+            .map(|i| {
+                self.legacy_redeem_queue
+                    [(self.legacy_redeem_queue_head as usize + i) % V3_MAX_REDEEM_REQUESTS]
+            })
+            .collect();
+        self.set_legacy_redeem_requests(&requests);
+    }
+
+    /// Move the oldest redeems the group queued for the leverage token at `token_index` before
+    /// version 5 to the token's queue, as many as it has room for. Done before anything else
+    /// touches the token's queue, so they stay ahead of the ones queued since
+    fn take_legacy_redeem_requests(&mut self, token_index: usize) {
+        if self.legacy_redeem_queue_len == 0 {
+            return;
+        }
+        let mint = self.leverage_tokens[token_index].mint;
+        let mut kept = Vec::with_capacity(self.legacy_redeem_queue_len as usize);
+        for i in 0..self.legacy_redeem_queue_len as usize {
+            let request = self.legacy_redeem_queue
+                [(self.legacy_redeem_queue_head as usize + i) % MAX_REDEEM_REQUESTS];
+            let queue = &mut self.redeem_queues[token_index];
+            if request.mint != mint || queue.push(request).is_err() {
+                kept.push(request);
+            }
+        }
+        self.set_legacy_redeem_requests(&kept);
+    }
+
+    /// Leave only `requests` in the legacy queue, from its first slot
+    fn set_legacy_redeem_requests(&mut self, requests: &[RedeemRequest]) {
+        for slot in self.legacy_redeem_queue.iter_mut() {
+            *slot = RedeemRequest::zeroed();
+        }
+        self.legacy_redeem_queue[..requests.len()].copy_from_slice(requests);
+        self.legacy_redeem_queue_head = 0;
+        self.legacy_redeem_queue_len = requests.len() as u64;
+    }
+
+    /// Stamp the Anchor discriminator on a group being initialized, a no-op without the
+    /// `anchor-discriminator` feature
+    pub fn init_discriminator(&mut self) {
+        #[cfg(feature = "anchor-discriminator")]
+        {
+            self.discriminator = account_discriminator("QuasarGroup");
+        }
+    }
+
+    fn check_discriminator(&self) -> QuasarResult {
+        #[cfg(feature = "anchor-discriminator")]
+        check_eq!(
+            self.discriminator,
+            account_discriminator("QuasarGroup"),
+            QuasarErrorCode::InvalidAccount
+        )?;
+        Ok(())
+    }
+
+    /// Seeds signing for the group signer, checked against `signer_key` at init. Every handler
+    /// signing for the group goes through this rather than assembling the seeds itself
+    pub fn signer_seeds_parts(&self, quasar_group_key: &Pubkey) -> SignerSeeds {
+        SignerSeeds {
+            quasar_group_key: *quasar_group_key,
+            nonce: self.signer_nonce.to_le_bytes(),
+        }
+    }
+
+    /// Error out once the group has been shut down, for everything but redeeming
+    pub fn check_not_shutdown(&self) -> QuasarResult {
+        check!(!self.shutdown, QuasarErrorCode::GroupShutdown)
+    }
+
+    /// Whether `key` is the risk admin, never true of the default key the group has without one
+    pub fn is_risk_admin(&self, key: &Pubkey) -> bool {
+        self.risk_admin_key != Pubkey::default() && *key == self.risk_admin_key
+    }
+
+    /// Error out while the admin or the risk admin has paused minting
+    pub fn check_not_paused(&self) -> QuasarResult {
+        check!(!self.paused, QuasarErrorCode::GroupPaused)
+    }
+
+    /// Native quote units `creator` has to pay to add a leverage token. The admin adds them
+    /// for free, anyone else only once the group charges a creation fee
+    pub fn creation_fee_for(&self, creator: &Pubkey) -> QuasarResult<u64> {
+        if *creator == self.admin_key {
+            return Ok(0);
+        }
+        check!(self.creation_fee > 0, QuasarErrorCode::InvalidAdminKey)?;
+        Ok(self.creation_fee)
+    }
+
+    /// Native quote units a rebalance that traded `notional` native quote units pays its crank,
+    /// `crank_reward_bps` of it up to `max_crank_reward` whole tokens of `quote_decimals`, and
+    /// never more than the `vault_balance` the fee vault holds
+    pub fn crank_reward(
+        &self,
+        notional: I80F48,
+        quote_decimals: u8,
+        vault_balance: u64,
+    ) -> QuasarResult<u64> {
+        let reward = notional
+            .abs()
+            .checked_mul(I80F48::from_num(self.crank_reward_bps))
+            .ok_or(math_err!())?
+            .checked_div(I80F48::from_num(10_000))
+            .ok_or(math_err!())?
+            .checked_floor()
+            .ok_or(math_err!())?
+            .checked_to_num::<u64>()
+            .ok_or(math_err!())?;
+        let max_reward = 10u64
+            .checked_pow(quote_decimals as u32)
+            .and_then(|unit| unit.checked_mul(self.max_crank_reward as u64))
+            .ok_or(math_err!())?;
+        Ok(reward.min(max_reward).min(vault_balance))
+    }
+
+    /// Make sure the group's `min_hold_secs` have passed since a mint at `last_mint_ts`
+    pub fn check_hold_elapsed(&self, last_mint_ts: u64, now_ts: u64) -> QuasarResult {
+        check!(
+            now_ts >= last_mint_ts.saturating_add(self.min_hold_secs),
+            QuasarErrorCode::HoldPeriodNotElapsed
+        )
+    }
+
+    /// Make sure a handler is talking to the Mango program and group the group was set up with
+    pub fn check_mango_group(&self, mango_program: &Pubkey, mango_group: &Pubkey) -> QuasarResult {
+        check_eq!(
+            *mango_program,
+            self.mango_program_id,
+            QuasarErrorCode::InvalidMangoProgram
+        )?;
+        check_eq!(
+            *mango_group,
+            self.mango_group,
+            QuasarErrorCode::InvalidMangoGroup
+        )
     }
 
     pub fn find_leverage_token_index(
@@ -120,21 +866,344 @@ impl QuasarGroup {
         base_token_mint: &Pubkey,
         target_leverage: I80F48,
     ) -> Option<usize> {
-        self.leverage_tokens.iter().position(|lt| {
-            lt.base_token_mint == *base_token_mint && lt.target_leverage == target_leverage
-        })
+        self.active_leverage_tokens()
+            .find(|(_, lt)| {
+                lt.base_token_mint == *base_token_mint && lt.target_leverage == target_leverage
+            })
+            .map(|(i, _)| i)
     }
 
     pub fn find_leverage_token_index_by_mint(&self, token_mint: &Pubkey) -> Option<usize> {
-        self.leverage_tokens
-            .iter()
-            .position(|lt| lt.mint == *token_mint)
+        self.active_leverage_tokens()
+            .find(|(_, lt)| lt.mint == *token_mint)
+            .map(|(i, _)| i)
     }
 
     pub fn find_base_token_index(&self, base_token_mint: &Pubkey) -> Option<usize> {
+        self.active_base_tokens()
+            .find(|(_, bt)| bt.mint == *base_token_mint)
+            .map(|(i, _)| i)
+    }
+
+    /// Make sure the Mango cache prices every perp market of the leverage token at
+    /// `token_index` within `MAX_TWAP_DEVIATION_BPS` of the TWAP of the base token it trades,
+    /// so a price pushed away for a few slots can't be minted or rebalanced against. Redeems
+    /// aren't checked so holders are never kept from getting out. Only markets of base tokens
+    /// `UpdatePriceSample` has sampled within `TWAP_WINDOW_SECS` are checked, there is nothing to
+    /// compare to before
+    pub fn check_price_near_twap(
+        &self,
+        token_index: usize,
+        mango_group: &MangoGroup,
+        mango_cache: &MangoCache,
+        now_ts: u64,
+    ) -> QuasarResult {
+        let leverage_token = &self.leverage_tokens[token_index];
+        for (mango_perp_market, base_mint) in leverage_token.mango_perp_markets
+            [..leverage_token.num_perp_markets]
+            .iter()
+            .zip(self.leg_base_mints(token_index))
+        {
+            let twap = match self
+                .find_base_token_index(base_mint)
+                .and_then(|i| self.base_tokens[i].twap(now_ts, TWAP_WINDOW_SECS))
+            {
+                Some(twap) => twap.native_price(self.quote_decimals)?,
+                None => continue,
+            };
+            let market_index = mango_group
+                .find_perp_market_index(mango_perp_market)
+                .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?;
+            let price = mango_cache.price_cache[market_index].price;
+            if !within_tolerance(price, twap, MAX_TWAP_DEVIATION_BPS) {
+                msg!("price {} is too far from the twap {}", price, twap);
+                return Err(throw_err!(QuasarErrorCode::PriceDeviatesFromTwap));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure one more leverage token can be built on `base_mint` without going over
+    /// `max_tokens_per_base`
+    pub fn check_room_for_base(&self, base_mint: &Pubkey) -> QuasarResult {
+        check!(
+            self.max_tokens_per_base == 0
+                || (self.leverage_tokens_for_base(base_mint).len() as u64)
+                    < self.max_tokens_per_base,
+            QuasarErrorCode::TooManyTokensForBase
+        )
+    }
+
+    /// Mint of the base token each perp market of the leverage token at `token_index` trades,
+    /// in the order of its `mango_perp_markets`
+    pub fn leg_base_mints(&self, token_index: usize) -> &[Pubkey] {
+        let num_perp_markets = self.leverage_tokens[token_index].num_perp_markets;
+        &self.perp_market_base_mints[token_index][..num_perp_markets]
+    }
+
+    /// Whether a leverage token is built on `base_mint`, or has a perp market trading it
+    pub fn is_base_in_use(&self, base_mint: &Pubkey) -> bool {
+        self.active_leverage_tokens().any(|(i, lt)| {
+            lt.base_token_mint == *base_mint || self.leg_base_mints(i).contains(base_mint)
+        })
+    }
+
+    /// Indices of every leverage token built on `base_mint`
+    pub fn leverage_tokens_for_base(&self, base_mint: &Pubkey) -> Vec<usize> {
+        self.active_leverage_tokens()
+            .filter(|(_, lt)| lt.base_token_mint == *base_mint)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Iterate over the initialized base token slots along with their index
+    pub fn active_base_tokens(&self) -> impl Iterator<Item = (usize, &BaseToken)> {
         self.base_tokens
             .iter()
-            .position(|bt| bt.mint == *base_token_mint)
+            .enumerate()
+            .filter(|(_, bt)| !bt.is_empty())
+    }
+
+    /// Iterate over the initialized leverage token slots along with their index
+    pub fn active_leverage_tokens(&self) -> impl Iterator<Item = (usize, &LeverageToken)> {
+        self.leverage_tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, lt)| !lt.is_empty())
+    }
+
+    /// Accounts `ValidateGroup` checks the group against: the mint of every leverage token, then
+    /// the mint of every base token followed by its oracles, all in slot order
+    pub fn validation_accounts(&self) -> Vec<Pubkey> {
+        let leverage_mints = self.active_leverage_tokens().map(|(_, lt)| lt.mint);
+        let base_accounts = self.active_base_tokens().flat_map(|(_, bt)| {
+            std::iter::once(bt.mint).chain(bt.oracles[..bt.num_oracles as usize].iter().copied())
+        });
+        leverage_mints.chain(base_accounts).collect()
+    }
+
+    /// Mango accounts `LogTotalTvl` sums up, the one of each active leverage token in order
+    pub fn tvl_accounts(&self) -> Vec<Pubkey> {
+        self.active_leverage_tokens()
+            .map(|(_, lt)| lt.mango_account)
+            .collect()
+    }
+
+    /// Queue a redeem of the leverage token at `token_index` behind the ones already waiting
+    pub fn push_redeem_request(
+        &mut self,
+        token_index: usize,
+        request: RedeemRequest,
+    ) -> QuasarResult {
+        self.take_legacy_redeem_requests(token_index);
+        self.redeem_queues[token_index].push(request)
+    }
+
+    /// Oldest queued redeem of the leverage token at `token_index`, the one
+    /// `ProcessRedeemQueue` works on
+    pub fn front_redeem_request_mut(&mut self, token_index: usize) -> Option<&mut RedeemRequest> {
+        self.take_legacy_redeem_requests(token_index);
+        self.redeem_queues[token_index].front_mut()
+    }
+
+    /// Drop the oldest queued redeem of the leverage token at `token_index` once it has been
+    /// settled in full
+    pub fn pop_redeem_request(&mut self, token_index: usize) -> QuasarResult<RedeemRequest> {
+        let request = self.redeem_queues[token_index].pop()?;
+        self.take_legacy_redeem_requests(token_index);
+        Ok(request)
+    }
+
+    /// Move the oldest queued redeem of the leverage token at `token_index` behind the others,
+    /// for when it couldn't be settled at all, so it doesn't hold them up
+    pub fn requeue_front_redeem_request(&mut self, token_index: usize) -> QuasarResult {
+        let request = self.redeem_queues[token_index].pop()?;
+        self.redeem_queues[token_index].push(request)
+    }
+
+    /// Count `settled` native quote units `SettleTokenPnl` moved into the quote deposits of the
+    /// leverage token at `token_index`, negative for settled losses
+    pub fn record_settled_pnl(&mut self, token_index: usize, settled: I80F48) -> QuasarResult {
+        let settled_pnl = &mut self.settled_pnl[token_index];
+        *settled_pnl = settled_pnl.checked_add(settled).ok_or(math_err!())?;
+        Ok(())
+    }
+
+    /// Take the share of the settled pnl of the leverage token at `token_index` a redeem of
+    /// `quantity` out of `supply` pays out, the rest staying with the tokens left
+    pub fn redeem_settled_pnl(
+        &mut self,
+        token_index: usize,
+        quantity: u64,
+        supply: u64,
+    ) -> QuasarResult {
+        if quantity >= supply {
+            self.settled_pnl[token_index] = ZERO_I80F48;
+            return Ok(());
+        }
+        let settled_pnl = &mut self.settled_pnl[token_index];
+        *settled_pnl = I80F48::from_num(supply - quantity)
+            .checked_div(I80F48::from_num(supply))
+            .and_then(|kept| settled_pnl.checked_mul(kept))
+            .ok_or(math_err!())?;
+        Ok(())
+    }
+
+    /// Check an index taken from instruction data against the group. Slots free up when tokens
+    /// are removed, so being below `num_base_tokens` isn't enough, the slot has to be in use
+    pub fn base_token_index(&self, index: u8) -> QuasarResult<BaseTokenIndex> {
+        check!(
+            self.base_token(index as usize).is_some(),
+            QuasarErrorCode::InvalidIndex
+        )?;
+        Ok(BaseTokenIndex(index))
+    }
+
+    /// Same as `base_token_index`, for leverage tokens
+    pub fn leverage_token_index(&self, index: u8) -> QuasarResult<LeverageTokenIndex> {
+        check!(
+            self.leverage_token(index as usize).is_some(),
+            QuasarErrorCode::InvalidIndex
+        )?;
+        Ok(LeverageTokenIndex(index))
+    }
+
+    pub fn base_token(&self, index: usize) -> Option<&BaseToken> {
+        self.base_tokens.get(index).filter(|bt| !bt.is_empty())
+    }
+
+    pub fn leverage_token(&self, index: usize) -> Option<&LeverageToken> {
+        self.leverage_tokens.get(index).filter(|lt| !lt.is_empty())
+    }
+}
+
+/// When an owner last minted a leverage token, kept in a PDA of the group, the mint and the owner
+/// while the group has a `min_hold_secs`. Only mints are recorded, so an owner who only
+/// received tokens by transfer has no record and can't redeem until a mint of their own has
+/// been held for `min_hold_secs`. Otherwise minting into one wallet and redeeming from another
+/// would skip the wait
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct HoldRecord {
+    pub meta_data: MetaData,
+    pub last_mint_ts: u64,
+}
+const_assert_eq!(size_of::<HoldRecord>(), 16);
+
+impl HoldRecord {
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> QuasarResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, QuasarErrorCode::InvalidOwner)?;
+
+        let hold_record: RefMut<'a, Self> = Self::load_mut(account)?;
+        check!(
+            hold_record.meta_data.is_initialized,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            hold_record.meta_data.data_type,
+            DataType::HoldRecord as u8,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        Ok(hold_record)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> QuasarResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, QuasarErrorCode::InvalidOwner)?;
+
+        let hold_record: Ref<'a, Self> = Self::load(account)?;
+        check!(
+            hold_record.meta_data.is_initialized,
+            QuasarErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            hold_record.meta_data.data_type,
+            DataType::HoldRecord as u8,
+            QuasarErrorCode::InvalidAccount
+        )?;
+
+        Ok(hold_record)
+    }
+}
+
+/// Fields of a version 0 group, read straight from its bytes. Its layout is only ever read
+/// by `QuasarGroup::convert_v0`, so it has no struct of its own to be cast to
+struct QuasarGroupV0<'a> {
+    num_base_tokens: usize,
+    /// `V0_BASE_TOKEN_LEN` bytes per slot: mint, decimals, oracle and padding
+    base_tokens: &'a [u8; V0_BASE_TOKEN_LEN * MAX_BASE_TOKENS],
+    num_leverage_tokens: usize,
+    /// `V0_LEVERAGE_TOKEN_LEN` bytes per slot: mint, base token mint, target leverage, Mango
+    /// account and perp market
+    leverage_tokens: &'a [u8; V0_LEVERAGE_TOKEN_LEN * MAX_LEVERAGE_TOKENS],
+    signer_nonce: u64,
+    signer_key: Pubkey,
+    admin_key: Pubkey,
+    mango_program_id: Pubkey,
+}
+
+/// Leverage tokens escrowed by `owner` to be unwound and paid out as liquidity allows
+#[derive(Copy, Clone, Debug, PartialEq, Pod)]
+#[repr(C)]
+pub struct RedeemRequest {
+    pub owner: Pubkey,
+    /// Mint of the leverage token being redeemed
+    pub mint: Pubkey,
+    /// Quantity still waiting to be settled, in native units
+    pub quantity: u64,
+}
+const_assert_eq!(size_of::<RedeemRequest>(), 72);
+
+/// Ring buffer of the redeems of a leverage token waiting for `ProcessRedeemQueue`, oldest at
+/// `head`
+#[derive(Copy, Clone, Pod)]
+#[repr(C)]
+pub struct RedeemQueue {
+    pub requests: [RedeemRequest; MAX_REDEEM_REQUESTS_PER_TOKEN],
+    pub head: u64,
+    pub len: u64,
+}
+const_assert_eq!(size_of::<RedeemQueue>(), 304);
+
+impl RedeemQueue {
+    pub fn push(&mut self, request: RedeemRequest) -> QuasarResult {
+        check!(
+            (self.len as usize) < MAX_REDEEM_REQUESTS_PER_TOKEN,
+            QuasarErrorCode::RedeemQueueFull
+        )?;
+        let tail = (self.head + self.len) as usize % MAX_REDEEM_REQUESTS_PER_TOKEN;
+        self.requests[tail] = request;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn front_mut(&mut self) -> Option<&mut RedeemRequest> {
+        if self.len == 0 {
+            return None;
+        }
+        Some(&mut self.requests[self.head as usize])
+    }
+
+    pub fn pop(&mut self) -> QuasarResult<RedeemRequest> {
+        check!(self.len > 0, QuasarErrorCode::RedeemQueueEmpty)?;
+        let head = self.head as usize;
+        let request = self.requests[head];
+        self.requests[head] = RedeemRequest::zeroed();
+        self.head = ((head + 1) % MAX_REDEEM_REQUESTS_PER_TOKEN) as u64;
+        self.len -= 1;
+        Ok(request)
+    }
+
+    /// Queued redeems, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &RedeemRequest> {
+        (0..self.len as usize)
+            .map(move |i| &self.requests[(self.head as usize + i) % MAX_REDEEM_REQUESTS_PER_TOKEN])
     }
 }
 
@@ -143,16 +1212,122 @@ impl QuasarGroup {
 pub struct BaseToken {
     pub mint: Pubkey,
     pub decimals: u8,
-    pub oracle: Pubkey,
-    pub padding: [u8; 7],
+    /// Oracles the price is the median of, the first `num_oracles` are set
+    pub oracles: [Pubkey; MAX_ORACLES_PER_BASE_TOKEN],
+    /// `OracleType` of each oracle, detected when the base token was added
+    pub oracle_types: [u8; MAX_ORACLES_PER_BASE_TOKEN],
+    pub num_oracles: u8,
+    /// Slot of `price_samples` the next sample is written to
+    pub next_price_sample: u8,
+    pub num_price_samples: u8,
+    /// Read Pyth oracles at their EMA price instead of their aggregate one, set by `SetUseEma`
+    pub use_ema: bool,
+    pub padding: [u8; 8],
+    /// Ring buffer of the latest oracle prices, see `twap`
+    pub price_samples: [PriceSample; MAX_PRICE_SAMPLES],
 }
+const_assert_eq!(size_of::<BaseToken>(), 400);
 
 impl BaseToken {
     pub fn is_empty(&self) -> bool {
         self.mint == Pubkey::default()
     }
+
+    pub fn oracle_type(&self, index: usize) -> OracleType {
+        OracleType::try_from(self.oracle_types[index]).unwrap_or(OracleType::Unknown)
+    }
+
+    /// Whether the perp market at `market_index` of the Mango group trades this base token.
+    /// Mango lists a perp market at the index of its asset, so either the spot token there is
+    /// this mint, or the oracle there is one of ours for markets without a spot token
+    pub fn matches_perp_market(&self, mango_group: &MangoGroup, market_index: usize) -> bool {
+        mango_group.tokens[market_index].mint == self.mint
+            || self.oracles[..self.num_oracles as usize]
+                .contains(&mango_group.oracles[market_index])
+    }
+
+    pub fn last_price_sample(&self) -> Option<&PriceSample> {
+        if self.num_price_samples == 0 {
+            return None;
+        }
+        let index = (self.next_price_sample as usize + MAX_PRICE_SAMPLES - 1) % MAX_PRICE_SAMPLES;
+        Some(&self.price_samples[index])
+    }
+
+    /// Record an oracle `price` read at `timestamp`, overwriting the oldest sample once the
+    /// buffer is full. Samples closer than `MIN_PRICE_SAMPLE_INTERVAL_SECS` are rejected, so
+    /// are prices scaled for other decimals than the ones of this base token
+    pub fn push_price_sample(&mut self, price: OraclePrice, timestamp: u64) -> QuasarResult {
+        check_eq!(
+            price.base_decimals,
+            self.decimals,
+            QuasarErrorCode::InvalidParam
+        )?;
+        if let Some(last) = self.last_price_sample() {
+            let earliest = last
+                .timestamp
+                .checked_add(MIN_PRICE_SAMPLE_INTERVAL_SECS)
+                .ok_or(math_err!())?;
+            check!(timestamp >= earliest, QuasarErrorCode::PriceSampleTooSoon)?;
+        }
+
+        self.price_samples[self.next_price_sample as usize] = PriceSample {
+            price: price.value,
+            timestamp,
+            padding: [0; 8],
+        };
+        self.next_price_sample = ((self.next_price_sample as usize + 1) % MAX_PRICE_SAMPLES) as u8;
+        self.num_price_samples = (self.num_price_samples + 1).min(MAX_PRICE_SAMPLES as u8);
+        Ok(())
+    }
+
+    /// Average of the price samples taken in the last `window_secs` seconds before `now_ts`,
+    /// `None` if there are none. A spike in a single sample only moves it by its share of the
+    /// samples in the window
+    pub fn twap(&self, now_ts: u64, window_secs: u64) -> Option<OraclePrice> {
+        let since = now_ts.saturating_sub(window_secs);
+        let (sum, count) = self.price_samples[..self.num_price_samples as usize]
+            .iter()
+            .filter(|sample| sample.timestamp >= since && sample.timestamp <= now_ts)
+            .fold((ZERO_I80F48, 0u32), |(sum, count), sample| {
+                (sum + sample.price, count + 1)
+            });
+        if count == 0 {
+            None
+        } else {
+            Some(OraclePrice::new(
+                sum / I80F48::from_num(count),
+                self.decimals,
+            ))
+        }
+    }
 }
 
+/// Outcome of reconciling the tracked supply of a leverage token with its mint, see
+/// `LeverageToken::supply_correction`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SupplyCorrection {
+    /// Within `RECONCILE_TOLERANCE_BPS`
+    None,
+    /// Mint the missing native units to the fee token account
+    MintToFeeTokenAccount(u64),
+    /// Burn the excess native units from the fee token account
+    BurnFromFeeTokenAccount(u64),
+    /// Too far off to correct
+    Flag,
+}
+
+/// An oracle price of a base token and when it was read
+#[derive(Copy, Clone, Debug, PartialEq, Pod)]
+#[repr(C)]
+pub struct PriceSample {
+    /// `OraclePrice::value`, the decimals are the ones of the base token
+    pub price: I80F48,
+    pub timestamp: u64,
+    pub padding: [u8; 8],
+}
+const_assert_eq!(size_of::<PriceSample>(), 32);
+
 #[derive(Copy, Clone, Pod)]
 #[repr(C)]
 pub struct LeverageToken {
@@ -160,14 +1335,385 @@ pub struct LeverageToken {
     pub base_token_mint: Pubkey,
     pub target_leverage: I80F48,
     pub mango_account: Pubkey,
-    pub mango_perp_market: Pubkey,
+
+    /// Share of the target exposure taken in each perp market, summing up to one
+    pub perp_market_weights: [I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN],
+    pub mango_perp_markets: [Pubkey; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN],
+    pub num_perp_markets: usize,
+    pub last_rebalance_ts: u64,
+    /// Last time the management fee was minted to the fee token account
+    pub last_fee_accrual_ts: u64,
+    /// Maximum equity of the Mango account mints can take it to, in native quote units.
+    /// Zero means unlimited
+    pub deposit_cap: u64,
+    /// Smallest quantity a single mint can create, in native leverage token units
+    pub min_mint_quantity: u64,
+    /// Yearly management fee, taken by minting leverage tokens to the fee token account
+    pub management_fee_bps_annual: u16,
+    /// Set when the target changed and the position has yet to be rebalanced to it
+    pub pending_rebalance: bool,
+    /// Bump of `mango_account`, a PDA of the group and the token mint
+    pub mango_account_bump: u8,
+    /// First leg the next `Rebalance` works on, non zero while a multi leg rebalance is under way
+    pub next_rebalance_leg: u8,
+    /// Whether a leg of the rebalance under way fell short of its target
+    pub rebalance_legs_missed: bool,
+    /// Decimals of the mint, which `nav`s are per whole token of
+    pub decimals: u8,
+    /// Set by `ReconcileToken` when the supply drifted further than it could correct, mints
+    /// are refused until a later reconciliation finds it back within tolerance
+    pub supply_drift_flagged: bool,
+    /// Supply this program minted less what it burnt, in native units. Drifts from the mint's
+    /// supply when tokens are burnt, or minted, behind its back
+    pub tracked_supply: u64,
+    /// Rebalances in a row that fell short of the target, reset by one that didn't
+    pub rebalance_fail_count: u64,
+    /// No rebalance goes through before this while backing off from failed ones
+    pub next_rebalance_allowed_ts: u64,
+    /// Deviation from the target exposure, in bps of the target, a rebalance leaves alone. Zero
+    /// falls back to the group's
+    pub rebalance_deadband_bps: u16,
+    /// Distance of post only rebalance orders from the oracle price, in bps of it
+    pub rebalance_order_offset_bps: u16,
+    /// `RebalanceOrderType` of the perp orders `Rebalance` places, zero being immediate or cancel
+    pub rebalance_order_type: u8,
+    /// Whether `Rebalance` levers the token's `QuasarGroup::settled_pnl` too, see
+    /// `get_rebalance_net_asset_value`
+    pub compound: bool,
+    pub padding: [u8; 2],
 }
+// Pod structs are read straight from account data, so their layout must not have implicit
+// padding the derive can't see. `I80F48` fields are kept at multiples of 16 bytes and
+// `padding` rounds the struct up to the 16 byte alignment of `i128`
+const_assert_eq!(size_of::<LeverageToken>(), 400);
 
 impl LeverageToken {
     pub fn is_empty(&self) -> bool {
         self.mint == Pubkey::default()
     }
 
+    /// Order type of the rebalance perp orders, immediate or cancel if the stored one is unknown
+    pub fn rebalance_order_type(&self) -> RebalanceOrderType {
+        RebalanceOrderType::try_from(self.rebalance_order_type)
+            .unwrap_or(RebalanceOrderType::ImmediateOrCancel)
+    }
+
+    /// Price in quote lots and Mango order type of a rebalance order on `side`, with the oracle
+    /// at `price` quote lots. Post only orders rest `rebalance_order_offset_bps` away from the
+    /// oracle on the passive side of the book
+    pub fn rebalance_order(&self, price: i64, side: Side) -> (i64, OrderType) {
+        match self.rebalance_order_type() {
+            RebalanceOrderType::ImmediateOrCancel => (price, OrderType::ImmediateOrCancel),
+            RebalanceOrderType::Market => (price, OrderType::Market),
+            RebalanceOrderType::PostOnly => {
+                let offset = price as i128 * self.rebalance_order_offset_bps as i128;
+                let offset = ((offset + 9_999) / 10_000) as i64;
+                let price = match side {
+                    Side::Bid => price - offset,
+                    Side::Ask => price + offset,
+                };
+                (price.max(1), OrderType::PostOnly)
+            }
+        }
+    }
+
+    /// Legs the next `Rebalance` works on, picking up where the last one stopped
+    pub fn next_rebalance_legs(&self) -> Range<usize> {
+        let first = self.next_rebalance_leg as usize;
+        first..(first + MAX_LEGS_PER_REBALANCE).min(self.num_perp_markets)
+    }
+
+    /// Record that `legs` were rebalanced, `incomplete` if any fell short of its target. The
+    /// rebalance is only over, and the cooldown only restarts, once the last leg went through
+    pub fn record_rebalance_legs(&mut self, legs: Range<usize>, incomplete: bool, now_ts: u64) {
+        let missed = (legs.start != 0 && self.rebalance_legs_missed) || incomplete;
+        if legs.end >= self.num_perp_markets {
+            self.next_rebalance_leg = 0;
+            self.rebalance_legs_missed = false;
+            self.last_rebalance_ts = now_ts;
+            self.pending_rebalance = missed;
+        } else {
+            self.next_rebalance_leg = legs.end as u8;
+            self.rebalance_legs_missed = missed;
+            self.pending_rebalance = true;
+        }
+    }
+
+    /// Refuse to rebalance while backing off from rebalances that fell short, so a crank can't
+    /// keep trading into a halted or empty market
+    pub fn check_rebalance_backoff(&self, now_ts: u64) -> QuasarResult {
+        check!(
+            now_ts >= self.next_rebalance_allowed_ts,
+            QuasarErrorCode::RebalanceBackoff
+        )
+    }
+
+    /// Back off exponentially after a rebalance that fell short of its target, and reset once
+    /// one reaches it. A rebalance erroring out reverts with everything else, so only those
+    /// that went through count
+    pub fn record_rebalance_outcome(&mut self, failed: bool, now_ts: u64) {
+        if !failed {
+            self.rebalance_fail_count = 0;
+            self.next_rebalance_allowed_ts = 0;
+            return;
+        }
+
+        self.rebalance_fail_count = self.rebalance_fail_count.saturating_add(1);
+        // Capped well before the doubling could overflow
+        let doublings = (self.rebalance_fail_count - 1).min(32);
+        let backoff = REBALANCE_BACKOFF_BASE_SECS
+            .saturating_mul(1 << doublings)
+            .min(MAX_REBALANCE_BACKOFF_SECS);
+        self.next_rebalance_allowed_ts = now_ts.saturating_add(backoff);
+    }
+
+    /// Deadband rebalances of this token use, given the group's `group_deadband_bps`
+    pub fn effective_rebalance_deadband_bps(&self, group_deadband_bps: u16) -> u64 {
+        if self.rebalance_deadband_bps == 0 {
+            group_deadband_bps as u64
+        } else {
+            self.rebalance_deadband_bps as u64
+        }
+    }
+
+    pub fn find_perp_market_index(&self, perp_market: &Pubkey) -> Option<usize> {
+        self.mango_perp_markets[..self.num_perp_markets]
+            .iter()
+            .position(|pm| pm == perp_market)
+    }
+
+    /// Make sure the collateral backing the token, once a mint went through, stays within
+    /// `deposit_cap`
+    pub fn check_deposit_cap(&self, collateral: I80F48) -> QuasarResult {
+        if self.deposit_cap != 0 {
+            check!(
+                collateral <= I80F48::from_num(self.deposit_cap),
+                QuasarErrorCode::DepositCapExceeded
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Count `quantity` native units minted by this program into `tracked_supply`
+    pub fn record_mint(&mut self, quantity: u64) -> QuasarResult {
+        self.tracked_supply = self
+            .tracked_supply
+            .checked_add(quantity)
+            .ok_or(math_err!())?;
+        Ok(())
+    }
+
+    /// Take `quantity` native units burnt by this program off `tracked_supply`. A burn never
+    /// fails because of the tracking, `ReconcileToken` picks up any mismatch
+    pub fn record_burn(&mut self, quantity: u64) {
+        self.tracked_supply = self.tracked_supply.saturating_sub(quantity);
+    }
+
+    /// What `ReconcileToken` should do about the actual `supply` of the mint given
+    /// `tracked_supply` and the `fee_token_account_balance` a correction can burn from
+    pub fn supply_correction(
+        &self,
+        supply: u64,
+        fee_token_account_balance: u64,
+    ) -> SupplyCorrection {
+        let tracked_supply = self.tracked_supply as u128;
+        let drift = (supply as u128).max(tracked_supply) - (supply as u128).min(tracked_supply);
+        let bps_of_tracked = |bps: u64| tracked_supply * bps as u128 / BPS_UNIT as u128;
+
+        if drift <= bps_of_tracked(RECONCILE_TOLERANCE_BPS) {
+            SupplyCorrection::None
+        } else if drift > bps_of_tracked(MAX_RECONCILE_CORRECTION_BPS) {
+            SupplyCorrection::Flag
+        } else if supply < self.tracked_supply {
+            // Burnt outside the program, the collateral they left behind goes to the fee token account
+            SupplyCorrection::MintToFeeTokenAccount(drift as u64)
+        } else if drift <= fee_token_account_balance as u128 {
+            SupplyCorrection::BurnFromFeeTokenAccount(drift as u64)
+        } else {
+            SupplyCorrection::Flag
+        }
+    }
+
+    /// Reject mints too small to be worth the perp position they add
+    pub fn check_mint_quantity(&self, quantity: u64) -> QuasarResult {
+        check!(
+            quantity > 0 && quantity >= self.min_mint_quantity,
+            QuasarErrorCode::MintTooSmall
+        )
+    }
+
+    /// Token account of the group signer collecting the management fee, the signer's associated
+    /// token account of the leverage token. Not to be confused with the group's quote `fee_vault`
+    pub fn fee_token_account(&self, signer_key: &Pubkey) -> Pubkey {
+        get_associated_token_address(signer_key, &self.mint)
+    }
+
+    /// Read the circulating supply from the token's mint rather than tracking it separately
+    pub fn current_supply(&self, mint_ai: &AccountInfo) -> QuasarResult<u64> {
+        check_eq!(mint_ai.key, &self.mint, QuasarErrorCode::InvalidAccount)?;
+        // Mint::unpack fails on uninitialized mints
+        let mint = Mint::unpack(&mint_ai.try_borrow_data()?)?;
+        Ok(mint.supply)
+    }
+
+    /// Management fee tokens due on a `supply` of native units between the last accrual and
+    /// `now_ts`
+    pub fn pending_management_fee(&self, supply: u64, now_ts: u64) -> QuasarResult<u64> {
+        if self.management_fee_bps_annual == 0 || supply == 0 {
+            return Ok(0);
+        }
+        let elapsed = now_ts.saturating_sub(self.last_fee_accrual_ts);
+        management_fee_tokens(supply, self.management_fee_bps_annual, elapsed)
+    }
+
+    /// Collateral a mint of `quantity` native units would take at `now_ts`, from a `supply`
+    /// of native units. The fee due is accrued first, diluting the supply, the same as
+    /// `MintLeverageToken` does. The price impact needs the books, it is left for the caller
+    /// to add
+    pub fn quote_mint(
+        &self,
+        supply: u64,
+        quantity: u64,
+        now_ts: u64,
+        quote_decimals: u8,
+        mango_group: &MangoGroup,
+        mango_account: &MangoAccount,
+        mango_cache: &MangoCache,
+    ) -> QuasarResult<MintQuote> {
+        let management_fee_tokens = self.pending_management_fee(supply, now_ts)?;
+        let supply = supply
+            .checked_add(management_fee_tokens)
+            .ok_or(math_err!())?;
+        let native_price = self.native_price(supply, mango_group, mango_account, mango_cache)?;
+        Ok(MintQuote {
+            mint: self.mint,
+            quantity,
+            collateral: collateral_for_tokens(native_price, quantity, self.decimals, 0)?,
+            native_price: native_price.to_bits(),
+            quote_decimals,
+            management_fee_tokens,
+            price_impact: 0,
+        })
+    }
+
+    /// Collateral a redeem of `quantity` native units would pay out at `now_ts`, from a `supply`
+    /// of native units. The fee due is accrued first, the same as `BurnLeverageToken` does, so
+    /// the redeeming holder pays their share of it
+    pub fn quote_redeem(
+        &self,
+        supply: u64,
+        quantity: u64,
+        now_ts: u64,
+        quote_decimals: u8,
+        mango_group: &MangoGroup,
+        mango_account: &MangoAccount,
+        mango_cache: &MangoCache,
+    ) -> QuasarResult<RedeemQuote> {
+        let management_fee_tokens = self.pending_management_fee(supply, now_ts)?;
+        let supply = supply
+            .checked_add(management_fee_tokens)
+            .ok_or(math_err!())?;
+        let native_price = self.native_price(supply, mango_group, mango_account, mango_cache)?;
+        Ok(RedeemQuote {
+            mint: self.mint,
+            quantity,
+            collateral: collateral_for_redeem(native_price, quantity, self.decimals)?,
+            native_price: native_price.to_bits(),
+            quote_decimals,
+            management_fee_tokens,
+        })
+    }
+
+    /// Native quote units left on deposit in the Mango account of a token with a `supply` of
+    /// zero, which no holder has a claim on. The first mint after the supply runs out is priced
+    /// at the bootstrap price, so leaving the dust would only make it unreachable. Refused
+    /// while there is a supply, or while a perp account of the token holds a position, orders
+    /// or unsettled quote that the deposit may still be needed for
+    pub fn sweepable_dust(
+        &self,
+        supply: u64,
+        mango_group: &MangoGroup,
+        mango_account: &MangoAccount,
+        mango_cache: &MangoCache,
+    ) -> QuasarResult<u64> {
+        check_eq!(supply, 0, QuasarErrorCode::TokenSupplyNonzero)?;
+        for mango_perp_market in self.mango_perp_markets[..self.num_perp_markets].iter() {
+            let market_index = mango_group
+                .find_perp_market_index(mango_perp_market)
+                .ok_or(throw_err!(QuasarErrorCode::InvalidAccount))?;
+            let perp_account = &mango_account.perp_accounts[market_index];
+            check!(
+                perp_account.base_position == 0
+                    && perp_account.quote_position == ZERO_I80F48
+                    && perp_account.bids_quantity == 0
+                    && perp_account.asks_quantity == 0,
+                QuasarErrorCode::OpenPositionRemaining
+            )?;
+        }
+
+        // Rounded down, Mango refuses to withdraw more than is on deposit without a borrow
+        mango_account.deposits[QUOTE_INDEX]
+            .checked_mul(mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index)
+            .map(|v| v.floor())
+            .and_then(|v| v.checked_to_num::<u64>())
+            .ok_or(math_err!())
+    }
+
+    /// Leverage the Mango account actually runs at, the notional of its perp positions over its
+    /// net asset value, valued the same way `Rebalance` does. Zero without any position
+    pub fn actual_leverage(
+        &self,
+        mango_group: &MangoGroup,
+        mango_account: &MangoAccount,
+        mango_cache: &MangoCache,
+    ) -> QuasarResult<I80F48> {
+        let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
+        let mut net_asset_value = ZERO_I80F48;
+        let mut perp_notional = ZERO_I80F48;
+
+        for i in 0..mango_group.num_oracles {
+            let price = get_mango_cache_price(mango_group, mango_cache, i)?;
+            let spot_value = get_mango_spot_value(
+                mango_account,
+                &mango_cache.root_bank_cache[i],
+                price,
+                quote_decimals,
+                i,
+            )?;
+
+            let (perp_base_value, perp_quote_value) =
+                get_mango_perp_value(mango_group, mango_account, mango_cache, price, i)?;
+
+            net_asset_value = net_asset_value
+                .checked_add(spot_value)
+                .and_then(|v| v.checked_add(perp_base_value))
+                .and_then(|v| v.checked_add(perp_quote_value))
+                .ok_or(math_err!())?;
+            perp_notional = perp_notional
+                .checked_add(perp_base_value)
+                .ok_or(math_err!())?;
+        }
+
+        if perp_notional == ZERO_I80F48 {
+            return Ok(ZERO_I80F48);
+        }
+        perp_notional
+            .checked_div(net_asset_value)
+            .ok_or(math_err!())
+    }
+
+    /// Make sure only the group signer can mint more of the token. The mint is created with
+    /// the signer as its authority, which nothing should ever change
+    pub fn check_mint_authority(&self, mint_ai: &AccountInfo, signer_key: &Pubkey) -> QuasarResult {
+        check_eq!(mint_ai.key, &self.mint, QuasarErrorCode::InvalidAccount)?;
+        let mint = Mint::unpack(&mint_ai.try_borrow_data()?)?;
+        check_eq!(
+            mint.mint_authority,
+            COption::Some(*signer_key),
+            QuasarErrorCode::InvalidMintAuthority
+        )
+    }
+
     pub fn get_native_price(
         &self,
         mint_ai: &AccountInfo,
@@ -175,30 +1721,41 @@ impl LeverageToken {
         mango_account: &MangoAccount,
         mango_cache: &MangoCache,
     ) -> Result<I80F48, QuasarError> {
-        let mint = Mint::unpack(&mint_ai.try_borrow_data()?)?;
-        let supply = mint.supply;
+        let supply = self.current_supply(mint_ai)?;
+        self.native_price(supply, mango_group, mango_account, mango_cache)
+    }
 
+    /// Native quote units per whole leverage token for a `supply` of native units
+    pub fn native_price(
+        &self,
+        supply: u64,
+        mango_group: &MangoGroup,
+        mango_account: &MangoAccount,
+        mango_cache: &MangoCache,
+    ) -> QuasarResult<I80F48> {
+        let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
         if supply == 0 {
-            let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
-            let quote_unit = 10u64.pow(quote_decimals.into());
-            return Ok(I80F48::from_num(INITIAL_LEVERAGE_TOKEN_PRICE * quote_unit));
+            let price = ui_to_native(
+                I80F48::from_num(INITIAL_LEVERAGE_TOKEN_PRICE),
+                quote_decimals,
+            )?;
+            return Ok(I80F48::from_num(price));
         }
 
         let mut net_asset_value = ZERO_I80F48;
 
         for i in 0..mango_group.num_oracles {
+            let price = get_mango_cache_price(mango_group, mango_cache, i)?;
             let spot_value = get_mango_spot_value(
                 &mango_account,
                 &mango_cache.root_bank_cache[i],
-                mango_cache.price_cache[i].price,
+                price,
+                quote_decimals,
                 i,
             )?;
 
-            let (perp_base_value, perp_quote_value) = mango_account.perp_accounts[i].get_val(
-                &mango_group.perp_markets[i],
-                &mango_cache.perp_market_cache[i],
-                mango_cache.price_cache[i].price,
-            )?;
+            let (perp_base_value, perp_quote_value) =
+                get_mango_perp_value(mango_group, mango_account, mango_cache, price, i)?;
 
             net_asset_value = net_asset_value
                 .checked_add(
@@ -209,8 +1766,9 @@ impl LeverageToken {
                 .unwrap();
         }
 
+        // Per whole token, not per native unit
         Ok(net_asset_value
-            .checked_div(I80F48::from_num(supply))
+            .checked_div(native_to_ui(supply, self.decimals))
             .unwrap())
     }
 }
@@ -1,16 +1,45 @@
 use fixed::types::I80F48;
-use mango::state::{MangoAccount, RootBankCache, ZERO_I80F48};
+use mango::state::{
+    HealthType, MangoAccount, MangoCache, MangoGroup, RootBankCache, ONE_I80F48, QUOTE_INDEX,
+    ZERO_I80F48,
+};
+use solana_program::hash::hashv;
+use solana_program::msg;
 use solana_program::program_error::ProgramError;
 use solana_program::pubkey::Pubkey;
 
 use bytemuck::{bytes_of, cast_slice_mut, from_bytes_mut, Contiguous, Pod};
 
-use crate::error::QuasarResult;
+use crate::error::{check_assert, QuasarError, QuasarErrorCode, QuasarResult, SourceFileId};
+use crate::oracle::OraclePrice;
 
+declare_check_assert_macros!(SourceFileId::Utils);
+
+/// Bytes of Anchor discriminator in front of the data of group and stub oracle accounts, zero
+/// unless built with the `anchor-discriminator` feature
+pub const ACCOUNT_DISCRIMINATOR_LEN: usize = if cfg!(feature = "anchor-discriminator") {
+    8
+} else {
+    0
+};
+
+/// Discriminator Anchor gives accounts of type `name`, the first 8 bytes of the sha256 of
+/// `account:<name>`
+pub fn account_discriminator(name: &str) -> [u8; 8] {
+    let hash = hashv(&[b"account:", name.as_bytes()]);
+    let mut discriminator = [0u8; 8];
+    discriminator.copy_from_slice(&hash.as_ref()[..8]);
+    discriminator
+}
+
+/// Seeds of the group signer for a given nonce. Only used to derive the signer at init, handlers
+/// take the seeds from `QuasarGroup::signer_seeds_parts`
 pub fn gen_signer_seeds<'a>(nonce: &'a u64, acc_pk: &'a Pubkey) -> [&'a [u8]; 2] {
     [acc_pk.as_ref(), bytes_of(nonce)]
 }
 
+/// Derive the group signer key. This costs a `create_program_address`, so it is only done when
+/// initializing a group, afterwards the stored `signer_key` is compared against instead
 pub fn gen_signer_key(
     nonce: u64,
     acc_pk: &Pubkey,
@@ -20,10 +49,120 @@ pub fn gen_signer_key(
     Ok(Pubkey::create_program_address(&seeds, program_id)?)
 }
 
+/// Group signer of `group` for `nonce`, for clients and other programs to derive the PDA without
+/// repeating its seeds. Errors if `nonce` doesn't give a valid program address
+pub fn quasar_group_signer(
+    program_id: &Pubkey,
+    group: &Pubkey,
+    nonce: u64,
+) -> QuasarResult<Pubkey> {
+    Ok(gen_signer_key(nonce, group, program_id)?)
+}
+
+/// Seed tag of the Mango account PDA of a leverage token
+pub const MANGO_ACCOUNT_SEED: &[u8] = b"mango_account";
+
+/// Derive the Mango account of the leverage token `mint` of a quasar group, along with its bump
+pub fn gen_mango_account_key(
+    quasar_group_pk: &Pubkey,
+    mint_pk: &Pubkey,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            quasar_group_pk.as_ref(),
+            mint_pk.as_ref(),
+            MANGO_ACCOUNT_SEED,
+        ],
+        program_id,
+    )
+}
+
+/// Seed tag of the mint PDA of a leverage token
+pub const LEVERAGE_TOKEN_MINT_SEED: &[u8] = b"leverage_token_mint";
+
+/// Derive the mint of the leverage token of a quasar group tracking `base_token_mint` in slot
+/// `token_index` of the group, along with its bump. The slot rather than the target leverage
+/// goes into the seeds since `SetTargetLeverage` moves the leverage, and a token never changes
+/// slots
+pub fn gen_leverage_token_mint_key(
+    quasar_group_pk: &Pubkey,
+    base_token_mint_pk: &Pubkey,
+    token_index: u8,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            quasar_group_pk.as_ref(),
+            base_token_mint_pk.as_ref(),
+            &[token_index],
+            LEVERAGE_TOKEN_MINT_SEED,
+        ],
+        program_id,
+    )
+}
+
+/// Seed tag of the token account escrowing the queued redeems of a leverage token
+pub const REDEEM_ESCROW_SEED: &[u8] = b"redeem_escrow";
+
+/// Derive the redeem escrow of the leverage token `mint` of a quasar group, along with its bump
+pub fn gen_redeem_escrow_key(
+    quasar_group_pk: &Pubkey,
+    mint_pk: &Pubkey,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            quasar_group_pk.as_ref(),
+            mint_pk.as_ref(),
+            REDEEM_ESCROW_SEED,
+        ],
+        program_id,
+    )
+}
+
+/// Seed tag of the hold record of an owner of a leverage token
+pub const HOLD_RECORD_SEED: &[u8] = b"hold_record";
+
+/// Derive the hold record of `owner_pk` for the leverage token `mint` of a quasar group, along
+/// with its bump
+pub fn gen_hold_record_key(
+    quasar_group_pk: &Pubkey,
+    mint_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            quasar_group_pk.as_ref(),
+            mint_pk.as_ref(),
+            owner_pk.as_ref(),
+            HOLD_RECORD_SEED,
+        ],
+        program_id,
+    )
+}
+
+/// Price Mango caches for the oracle of `market_index`, with the decimals of its token. Mango
+/// keeps it in native quote units per native base unit
+pub fn get_mango_cache_price(
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    market_index: usize,
+) -> QuasarResult<OraclePrice> {
+    OraclePrice::from_native(
+        mango_cache.price_cache[market_index].price,
+        mango_group.tokens[market_index].decimals,
+        mango_group.tokens[QUOTE_INDEX].decimals,
+    )
+}
+
+/// Value of the spot balance of `market_index` at `price`, in native quote units
 pub fn get_mango_spot_value(
     mango_account: &MangoAccount,
     bank_cache: &RootBankCache,
-    price: I80F48,
+    price: OraclePrice,
+    quote_decimals: u8,
     market_index: usize,
 ) -> QuasarResult<I80F48> {
     let base_net = if mango_account.deposits[market_index].is_positive() {
@@ -38,5 +177,274 @@ pub fn get_mango_spot_value(
         ZERO_I80F48
     };
 
-    Ok(base_net * price)
+    base_net
+        .checked_mul(price.native_price(quote_decimals)?)
+        .ok_or(math_err!())
+}
+
+/// Quote deposits of a Mango account net of its quote borrows, in native quote units
+pub fn get_mango_quote_value(
+    mango_account: &MangoAccount,
+    mango_cache: &MangoCache,
+) -> QuasarResult<I80F48> {
+    // A whole quote token is worth one, whatever its decimals
+    get_mango_spot_value(
+        mango_account,
+        &mango_cache.root_bank_cache[QUOTE_INDEX],
+        OraclePrice::new(ONE_I80F48, 0),
+        0,
+        QUOTE_INDEX,
+    )
+}
+
+/// Value of the base and the quote position of the perp account of `market_index` at `price`,
+/// in native quote units, as Mango's `PerpAccount::get_val` puts it
+pub fn get_mango_perp_value(
+    mango_group: &MangoGroup,
+    mango_account: &MangoAccount,
+    mango_cache: &MangoCache,
+    price: OraclePrice,
+    market_index: usize,
+) -> QuasarResult<(I80F48, I80F48)> {
+    let native_price = price.native_price(mango_group.tokens[QUOTE_INDEX].decimals)?;
+    Ok(mango_account.perp_accounts[market_index].get_val(
+        &mango_group.perp_markets[market_index],
+        &mango_cache.perp_market_cache[market_index],
+        native_price,
+    )?)
+}
+
+/// Total equity of a Mango account in native quote units: quote deposits net of borrows,
+/// the value of every spot balance, and the value of every perp position including its
+/// unsettled quote position.
+pub fn get_mango_account_equity(
+    mango_group: &MangoGroup,
+    mango_account: &MangoAccount,
+    mango_cache: &MangoCache,
+) -> QuasarResult<I80F48> {
+    let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
+    let mut equity = get_mango_quote_value(mango_account, mango_cache)?;
+
+    for i in 0..mango_group.num_oracles {
+        let price = get_mango_cache_price(mango_group, mango_cache, i)?;
+        let spot_value = get_mango_spot_value(
+            mango_account,
+            &mango_cache.root_bank_cache[i],
+            price,
+            quote_decimals,
+            i,
+        )?;
+
+        let (perp_base_value, perp_quote_value) =
+            get_mango_perp_value(mango_group, mango_account, mango_cache, price, i)?;
+
+        equity = equity
+            .checked_add(spot_value)
+            .unwrap()
+            .checked_add(perp_base_value)
+            .unwrap()
+            .checked_add(perp_quote_value)
+            .unwrap();
+    }
+
+    Ok(equity)
+}
+
+/// Net asset value `Rebalance` sizes a leverage token's exposure on and the value of its perp
+/// positions, in native quote units. Funding is part of the perp positions' value until it is
+/// settled, as is their pnl. `SettleTokenPnl` moves them into the quote deposits of the Mango
+/// account, which the NAV leaves out along with the collateral deposited there, and counts
+/// them into the token's `settled_pnl`. A token that compounds passes it as `compounded_pnl`
+/// so it keeps counting, and the rebalance levers it up with the rest of the backing. For
+/// any other token it sits in the Mango account unlevered.
+///
+/// Turning compounding on moves the NAV up by the settled pnl, and the next rebalance trades
+/// it into exposure at the market price
+pub fn get_rebalance_net_asset_value(
+    mango_group: &MangoGroup,
+    mango_account: &MangoAccount,
+    mango_cache: &MangoCache,
+    compounded_pnl: I80F48,
+) -> QuasarResult<(I80F48, I80F48)> {
+    let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
+    let mut net_asset_value = ZERO_I80F48;
+    let mut perp_asset_value = ZERO_I80F48;
+
+    for i in 0..mango_group.num_oracles {
+        let price = get_mango_cache_price(mango_group, mango_cache, i)?;
+        let spot_value = get_mango_spot_value(
+            mango_account,
+            &mango_cache.root_bank_cache[i],
+            price,
+            quote_decimals,
+            i,
+        )?;
+
+        let (perp_base_value, perp_quote_value) =
+            get_mango_perp_value(mango_group, mango_account, mango_cache, price, i)?;
+
+        msg!(
+            "market {}: spot {} / perp_base {} / perp_quote {}",
+            i,
+            spot_value,
+            perp_base_value,
+            perp_quote_value,
+        );
+
+        net_asset_value = net_asset_value
+            .checked_add(
+                spot_value
+                    .checked_add(perp_base_value.checked_add(perp_quote_value).unwrap())
+                    .unwrap(),
+            )
+            .unwrap();
+
+        perp_asset_value = perp_asset_value.checked_add(perp_base_value).unwrap();
+    }
+
+    if compounded_pnl != ZERO_I80F48 {
+        msg!("compounded pnl: {}", compounded_pnl);
+        net_asset_value = net_asset_value
+            .checked_add(compounded_pnl)
+            .ok_or(math_err!())?;
+    }
+
+    Ok((net_asset_value, perp_asset_value))
+}
+
+/// Make sure every cache entry the Mango account's value depends on has been updated
+/// within the Mango group's valid interval
+pub fn check_mango_cache_fresh(
+    mango_group: &MangoGroup,
+    mango_account: &MangoAccount,
+    mango_cache: &MangoCache,
+    now_ts: u64,
+) -> QuasarResult {
+    let is_fresh = |last_update: u64| {
+        last_update
+            .checked_add(mango_group.valid_interval)
+            .map_or(false, |valid_until| now_ts <= valid_until)
+    };
+
+    check!(
+        is_fresh(mango_cache.root_bank_cache[QUOTE_INDEX].last_update),
+        QuasarErrorCode::MangoCacheStale
+    )?;
+
+    for i in 0..mango_group.num_oracles {
+        let has_spot =
+            mango_account.deposits[i].is_positive() || mango_account.borrows[i].is_positive();
+        let has_perp = mango_account.perp_accounts[i].base_position != 0
+            || mango_account.perp_accounts[i].quote_position != ZERO_I80F48;
+
+        if !has_spot && !has_perp {
+            continue;
+        }
+
+        check!(
+            is_fresh(mango_cache.price_cache[i].last_update),
+            QuasarErrorCode::MangoCacheStale
+        )?;
+        if has_spot {
+            check!(
+                is_fresh(mango_cache.root_bank_cache[i].last_update),
+                QuasarErrorCode::MangoCacheStale
+            )?;
+        }
+        if has_perp {
+            check!(
+                is_fresh(mango_cache.perp_market_cache[i].last_update),
+                QuasarErrorCode::MangoCacheStale
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Weighted assets and liabilities of a Mango account in native quote units, following Mango's
+/// health rules for spot balances and perp positions. Serum open orders are not taken into
+/// account as leverage tokens never trade spot.
+pub fn get_mango_account_health_components(
+    mango_group: &MangoGroup,
+    mango_account: &MangoAccount,
+    mango_cache: &MangoCache,
+    health_type: HealthType,
+) -> QuasarResult<(I80F48, I80F48)> {
+    let mut assets = ZERO_I80F48;
+    let mut liabs = ZERO_I80F48;
+
+    let mut add_weighted = |value: I80F48, asset_weight: I80F48, liab_weight: I80F48| {
+        if value.is_positive() {
+            assets = assets.checked_add(value * asset_weight).unwrap();
+        } else {
+            liabs = liabs.checked_add(-value * liab_weight).unwrap();
+        }
+    };
+
+    let quote_value = get_mango_quote_value(mango_account, mango_cache)?;
+    add_weighted(quote_value, ONE_I80F48, ONE_I80F48);
+
+    let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals;
+
+    for i in 0..mango_group.num_oracles {
+        let spot_market = &mango_group.spot_markets[i];
+        let perp_market = &mango_group.perp_markets[i];
+        let (spot_asset_weight, spot_liab_weight, perp_asset_weight, perp_liab_weight) =
+            match health_type {
+                HealthType::Init => (
+                    spot_market.init_asset_weight,
+                    spot_market.init_liab_weight,
+                    perp_market.init_asset_weight,
+                    perp_market.init_liab_weight,
+                ),
+                HealthType::Maint => (
+                    spot_market.maint_asset_weight,
+                    spot_market.maint_liab_weight,
+                    perp_market.maint_asset_weight,
+                    perp_market.maint_liab_weight,
+                ),
+            };
+
+        let price = get_mango_cache_price(mango_group, mango_cache, i)?;
+        let spot_value = get_mango_spot_value(
+            mango_account,
+            &mango_cache.root_bank_cache[i],
+            price,
+            quote_decimals,
+            i,
+        )?;
+        add_weighted(spot_value, spot_asset_weight, spot_liab_weight);
+
+        let (perp_base_value, perp_quote_value) =
+            get_mango_perp_value(mango_group, mango_account, mango_cache, price, i)?;
+        add_weighted(perp_base_value, perp_asset_weight, perp_liab_weight);
+        add_weighted(perp_quote_value, ONE_I80F48, ONE_I80F48);
+    }
+
+    Ok((assets, liabs))
+}
+
+/// Health ratio of a Mango account in percent, as displayed by Mango: how much the weighted
+/// assets exceed the weighted liabilities. `I80F48::MAX` if the account has no liabilities.
+pub fn get_mango_account_health_ratio(
+    mango_group: &MangoGroup,
+    mango_account: &MangoAccount,
+    mango_cache: &MangoCache,
+    health_type: HealthType,
+) -> QuasarResult<I80F48> {
+    let (assets, liabs) =
+        get_mango_account_health_components(mango_group, mango_account, mango_cache, health_type)?;
+
+    if liabs == ZERO_I80F48 {
+        return Ok(I80F48::MAX);
+    }
+
+    Ok(assets
+        .checked_sub(liabs)
+        .unwrap()
+        .checked_div(liabs)
+        .unwrap()
+        .checked_mul(I80F48::from_num(100))
+        .unwrap())
 }
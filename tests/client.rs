@@ -0,0 +1,123 @@
+use assert_matches::*;
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use quasar::{
+    error::{QuasarError, QuasarErrorCode},
+    state::{
+        DataType, LeverageToken, MetaData, QuasarGroup, RedeemRequest, MAX_REDEEM_REQUESTS,
+        MAX_REDEEM_REQUESTS_PER_TOKEN, QUASAR_GROUP_VERSION,
+    },
+};
+use solana_program::{account_info::AccountInfo, pubkey::Pubkey};
+
+macro_rules! assert_quasar_err {
+    ($result:expr, $code:pat) => {
+        assert_matches!(
+            $result,
+            Err(QuasarError::QuasarErrorCode {
+                quasar_error_code: $code,
+                ..
+            })
+        )
+    };
+}
+
+#[test]
+fn test_unpack_from_slice_matches_load_checked() {
+    let program_id = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.init_discriminator();
+    quasar_group.admin_key = Pubkey::new_unique();
+    quasar_group.min_health_ratio = I80F48::from_num(120);
+    let mint = Pubkey::new_unique();
+    quasar_group.leverage_tokens[1] = LeverageToken {
+        mint,
+        target_leverage: I80F48::from_num(3),
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    // Redeems wrapping around the end of the token's ring buffer, then one left over in the
+    // queue of an older group
+    quasar_group.redeem_queues[1].head = MAX_REDEEM_REQUESTS_PER_TOKEN as u64 - 1;
+    for quantity in 1..=2 {
+        quasar_group
+            .push_redeem_request(
+                1,
+                RedeemRequest {
+                    owner: Pubkey::new_unique(),
+                    mint,
+                    quantity,
+                },
+            )
+            .unwrap();
+    }
+    quasar_group.legacy_redeem_queue_head = MAX_REDEEM_REQUESTS as u64 - 1;
+    quasar_group.legacy_redeem_queue_len = 2;
+    for (i, quantity) in [3, 4].iter().enumerate() {
+        quasar_group.legacy_redeem_queue[(MAX_REDEEM_REQUESTS - 1 + i) % MAX_REDEEM_REQUESTS] =
+            RedeemRequest {
+                owner: Pubkey::new_unique(),
+                mint: if i == 0 { mint } else { Pubkey::new_unique() },
+                quantity: *quantity,
+            };
+    }
+
+    let key = Pubkey::new_unique();
+    let mut lamports = 0;
+    let mut data = bytemuck::bytes_of(&quasar_group).to_vec();
+    let account_info = AccountInfo::new(
+        &key,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &program_id,
+        false,
+        0,
+    );
+    let loaded = QuasarGroup::load_checked(&account_info, &program_id).unwrap();
+    let unpacked = QuasarGroup::unpack_from_slice(&account_info.data.borrow()).unwrap();
+    assert_eq!(bytemuck::bytes_of(&*loaded), bytemuck::bytes_of(&unpacked));
+
+    assert_eq!(unpacked.admin_key, quasar_group.admin_key);
+    assert_eq!(unpacked.min_health_ratio, I80F48::from_num(120));
+    assert_eq!(
+        unpacked
+            .leverage_token_by_mint(&mint)
+            .unwrap()
+            .target_leverage,
+        I80F48::from_num(3)
+    );
+    assert!(unpacked
+        .leverage_token_by_mint(&Pubkey::new_unique())
+        .is_none());
+    let quantities: Vec<u64> = unpacked.redeem_requests(1).map(|r| r.quantity).collect();
+    assert_eq!(quantities, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_unpack_from_slice_rejects_other_data() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let data = bytemuck::bytes_of(&quasar_group).to_vec();
+    assert_quasar_err!(
+        QuasarGroup::unpack_from_slice(&data).map(|_| ()),
+        QuasarErrorCode::InvalidAccount
+    );
+
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.init_discriminator();
+    let data = bytemuck::bytes_of(&quasar_group).to_vec();
+    assert_quasar_err!(
+        QuasarGroup::unpack_from_slice(&data[..data.len() - 1]).map(|_| ()),
+        QuasarErrorCode::InvalidAccount
+    );
+
+    // Groups of an older layout have to be migrated before they decode
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION - 1, true);
+    let data = bytemuck::bytes_of(&quasar_group).to_vec();
+    assert_quasar_err!(
+        QuasarGroup::unpack_from_slice(&data).map(|_| ()),
+        QuasarErrorCode::GroupNeedsMigration
+    );
+}
@@ -0,0 +1,295 @@
+#![cfg(feature = "test-bpf")]
+
+use bytemuck::Zeroable;
+use mango::state::{
+    DataType as MangoDataType, MangoAccount, MangoCache, MangoGroup, MetaData as MangoMetaData,
+    NodeBank, RootBank, QUOTE_INDEX,
+};
+use quasar::{
+    instruction::{
+        self, ADD_BASE_TOKEN_COMPUTE_UNITS, INIT_QUASAR_GROUP_COMPUTE_UNITS,
+        MINT_LEVERAGE_TOKEN_COMPUTE_UNITS,
+    },
+    quasar_group_signer,
+    state::{DataType, LeverageToken, MetaData, QuasarGroup, QUASAR_GROUP_VERSION},
+    utils::gen_hold_record_key,
+};
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program_option::COption,
+    program_pack::Pack, pubkey::Pubkey, rent::Rent, system_program, sysvar,
+};
+use solana_program_test::{processor, ProgramTest};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::state::{Account as TokenAccount, AccountState, Mint};
+use std::mem::size_of;
+
+/// The BPF build of the program, failing any instruction that spends more than `compute_units`
+fn program_test(compute_units: u64) -> (ProgramTest, Pubkey) {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("quasar", program_id, None);
+    program_test.set_bpf_compute_max_units(compute_units);
+    (program_test, program_id)
+}
+
+/// Stands in for the Mango program, taking every instruction without doing anything. Mango's
+/// own compute isn't part of what these tests bound, only what the handlers spend around it
+fn process_mango_stub(
+    _program_id: &Pubkey,
+    _accounts: &[AccountInfo],
+    _instruction_data: &[u8],
+) -> ProgramResult {
+    Ok(())
+}
+
+fn rent_exempt_account(data: Vec<u8>, owner: Pubkey) -> Account {
+    Account {
+        lamports: Rent::default().minimum_balance(data.len()),
+        data,
+        owner,
+        ..Account::default()
+    }
+}
+
+fn mint_account(decimals: u8) -> Account {
+    let mut data = vec![0; Mint::LEN];
+    let mint = Mint {
+        mint_authority: COption::Some(Pubkey::new_unique()),
+        decimals,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut data).unwrap();
+    rent_exempt_account(data, spl_token::id())
+}
+
+fn signer_nonce(program_id: &Pubkey, quasar_group: &Pubkey) -> (u64, Pubkey) {
+    (0..)
+        .find_map(|nonce| {
+            quasar_group_signer(program_id, quasar_group, nonce)
+                .ok()
+                .map(|signer| (nonce, signer))
+        })
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_init_quasar_group_compute_units() {
+    let (mut program_test, program_id) = program_test(INIT_QUASAR_GROUP_COMPUTE_UNITS);
+    let (quasar_group, mango_program, mango_group, quote_mint) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    program_test.add_account(
+        quasar_group,
+        rent_exempt_account(vec![0; size_of::<QuasarGroup>()], program_id),
+    );
+    let mut mango_group_data = MangoGroup::zeroed();
+    mango_group_data.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    program_test.add_account(
+        mango_group,
+        rent_exempt_account(
+            bytemuck::bytes_of(&mango_group_data).to_vec(),
+            mango_program,
+        ),
+    );
+    program_test.add_account(quote_mint, mint_account(6));
+    let (signer_nonce, signer) = signer_nonce(&program_id, &quasar_group);
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let ix = instruction::init_quasar_group(
+        &program_id,
+        &[
+            quasar_group,
+            signer,
+            payer.pubkey(),
+            mango_program,
+            quote_mint,
+            mango_group,
+        ],
+        signer_nonce,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_add_base_token_compute_units() {
+    let (mut program_test, program_id) = program_test(ADD_BASE_TOKEN_COMPUTE_UNITS);
+    let admin = Keypair::new();
+    let (quasar_group, mint, oracle) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    let mut quasar_group_data = QuasarGroup::zeroed();
+    quasar_group_data.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group_data.admin_key = admin.pubkey();
+    program_test.add_account(
+        quasar_group,
+        rent_exempt_account(bytemuck::bytes_of(&quasar_group_data).to_vec(), program_id),
+    );
+    program_test.add_account(mint, mint_account(9));
+    // A Pyth magic number is all adding a Pyth oracle reads
+    let mut oracle_data = vec![0; 3312];
+    oracle_data[..4].copy_from_slice(&[212, 195, 178, 161]);
+    program_test.add_account(
+        oracle,
+        rent_exempt_account(oracle_data, system_program::id()),
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let ix = instruction::add_base_token(
+        &program_id,
+        &[quasar_group, mint, oracle, admin.pubkey()],
+        &[],
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &admin], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_mint_leverage_token_compute_units() {
+    let (mut program_test, program_id) = program_test(MINT_LEVERAGE_TOKEN_COMPUTE_UNITS);
+    let mango_program = Pubkey::new_unique();
+    program_test.add_program("mango_stub", mango_program, processor!(process_mango_stub));
+    let owner = Keypair::new();
+    let (quasar_group, token_mint, quote_mint) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    let (mango_group, mango_account, mango_cache, root_bank, node_bank, vault) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    let owner_quote_token_account = Pubkey::new_unique();
+    let (signer_nonce, signer) = signer_nonce(&program_id, &quasar_group);
+
+    let mut quasar_group_data = QuasarGroup::zeroed();
+    quasar_group_data.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group_data.signer_nonce = signer_nonce;
+    quasar_group_data.signer_key = signer;
+    quasar_group_data.mango_program_id = mango_program;
+    quasar_group_data.mango_group = mango_group;
+    quasar_group_data.quote_mint = quote_mint;
+    quasar_group_data.leverage_tokens[0] = LeverageToken {
+        mint: token_mint,
+        mango_account,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group_data.num_leverage_tokens = 1;
+    program_test.add_account(
+        quasar_group,
+        rent_exempt_account(bytemuck::bytes_of(&quasar_group_data).to_vec(), program_id),
+    );
+
+    let mut data = vec![0; Mint::LEN];
+    let mint = Mint {
+        mint_authority: COption::Some(signer),
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut data).unwrap();
+    program_test.add_account(token_mint, rent_exempt_account(data, spl_token::id()));
+    let mut data = vec![0; TokenAccount::LEN];
+    let quote_token_account = TokenAccount {
+        mint: quote_mint,
+        owner: owner.pubkey(),
+        amount: u64::MAX,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+    TokenAccount::pack(quote_token_account, &mut data).unwrap();
+    program_test.add_account(
+        owner_quote_token_account,
+        rent_exempt_account(data, spl_token::id()),
+    );
+    program_test.add_account(
+        owner.pubkey(),
+        Account {
+            lamports: 1_000_000_000,
+            ..Account::default()
+        },
+    );
+
+    // An empty Mango account on a group whose cache never goes stale, so the mint goes through
+    // on the stub's deposit
+    let mut mango_group_data = MangoGroup::zeroed();
+    mango_group_data.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group_data.tokens[QUOTE_INDEX].mint = quote_mint;
+    mango_group_data.tokens[QUOTE_INDEX].root_bank = root_bank;
+    mango_group_data.mango_cache = mango_cache;
+    mango_group_data.valid_interval = u64::MAX;
+    let mut mango_cache_data = MangoCache::zeroed();
+    mango_cache_data.meta_data = MangoMetaData::new(MangoDataType::MangoCache, 0, true);
+    let mut mango_account_data = MangoAccount::zeroed();
+    mango_account_data.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    mango_account_data.mango_group = mango_group;
+    mango_account_data.owner = signer;
+    let mut root_bank_data = RootBank::zeroed();
+    root_bank_data.meta_data = MangoMetaData::new(MangoDataType::RootBank, 0, true);
+    root_bank_data.node_banks[0] = node_bank;
+    root_bank_data.num_node_banks = 1;
+    let mut node_bank_data = NodeBank::zeroed();
+    node_bank_data.meta_data = MangoMetaData::new(MangoDataType::NodeBank, 0, true);
+    node_bank_data.vault = vault;
+    for (key, data) in [
+        (mango_group, bytemuck::bytes_of(&mango_group_data).to_vec()),
+        (mango_cache, bytemuck::bytes_of(&mango_cache_data).to_vec()),
+        (
+            mango_account,
+            bytemuck::bytes_of(&mango_account_data).to_vec(),
+        ),
+        (root_bank, bytemuck::bytes_of(&root_bank_data).to_vec()),
+        (node_bank, bytemuck::bytes_of(&node_bank_data).to_vec()),
+    ]
+    .iter()
+    {
+        program_test.add_account(*key, rent_exempt_account(data.clone(), mango_program));
+    }
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    // The owner's first mint, paying for their leverage token account too
+    let ix = instruction::mint_leverage_token(
+        &program_id,
+        &[
+            quasar_group,
+            token_mint,
+            get_associated_token_address(&owner.pubkey(), &token_mint),
+            mango_program,
+            mango_group,
+            mango_account,
+            owner.pubkey(),
+            mango_cache,
+            root_bank,
+            node_bank,
+            vault,
+            spl_token::id(),
+            owner_quote_token_account,
+            signer,
+            system_program::id(),
+            spl_associated_token_account::id(),
+            sysvar::rent::id(),
+            get_associated_token_address(&signer, &token_mint),
+            gen_hold_record_key(&quasar_group, &token_mint, &owner.pubkey(), &program_id).0,
+        ],
+        &[],
+        1_000,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+}
@@ -0,0 +1,31 @@
+use quasar::error::{QuasarError, QuasarErrorCode, SourceFileId};
+use solana_program::program_error::ProgramError;
+
+#[test]
+fn test_error_code_to_program_error() {
+    let error = QuasarError::QuasarErrorCode {
+        quasar_error_code: QuasarErrorCode::InvalidAdminKey,
+        line: 42,
+        source_file_id: SourceFileId::Processor,
+    };
+    assert_eq!(
+        error.to_string(),
+        "QuasarErrorCode::InvalidAdminKey; src/processor.rs:42"
+    );
+    // Clients match on the code, which is the variant's position in QuasarErrorCode
+    assert_eq!(ProgramError::from(error), ProgramError::Custom(4));
+
+    let error = QuasarError::QuasarErrorCode {
+        quasar_error_code: QuasarErrorCode::Default,
+        line: 0,
+        source_file_id: SourceFileId::State,
+    };
+    assert_eq!(ProgramError::from(error), ProgramError::Custom(u32::MAX));
+
+    // Errors from the runtime or other programs are passed through untouched
+    let error = QuasarError::from(ProgramError::InvalidInstructionData);
+    assert_eq!(
+        ProgramError::from(error),
+        ProgramError::InvalidInstructionData
+    );
+}
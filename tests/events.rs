@@ -0,0 +1,207 @@
+use borsh::BorshSerialize;
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use mango::state::{MangoAccount, MangoCache, MangoGroup, QUOTE_INDEX};
+use quasar::{
+    events::{Discrepancy, GroupSummary, TotalTvl, ValidateEvent},
+    oracle::OracleType,
+    state::{BaseToken, LeverageToken, QuasarGroup},
+    utils::ACCOUNT_DISCRIMINATOR_LEN,
+};
+use solana_program::{
+    account_info::AccountInfo, program_option::COption, program_pack::Pack, pubkey::Pubkey,
+};
+use spl_token::state::Mint;
+
+fn mint_data(mint_authority: Pubkey, decimals: u8) -> Vec<u8> {
+    let mut data = vec![0; Mint::LEN];
+    let mint = Mint {
+        mint_authority: COption::Some(mint_authority),
+        decimals,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut data).unwrap();
+    data
+}
+
+#[test]
+fn test_group_summary_lists_active_leverage_tokens() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.admin_key = Pubkey::new_unique();
+    quasar_group.min_rebalance_interval = 60;
+    let mint = Pubkey::new_unique();
+    quasar_group.leverage_tokens[3] = LeverageToken {
+        mint,
+        base_token_mint: Pubkey::new_unique(),
+        target_leverage: I80F48::from_num(3),
+        num_perp_markets: 1,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+
+    let summary = GroupSummary::new(&quasar_group);
+    assert_eq!(summary.admin_key, quasar_group.admin_key);
+    assert_eq!(summary.min_rebalance_interval, 60);
+    assert_eq!(summary.leverage_tokens.len(), 1);
+    assert_eq!(summary.leverage_tokens[0].index, 3);
+    assert_eq!(summary.leverage_tokens[0].mint, mint);
+    assert_eq!(
+        summary.leverage_tokens[0].target_leverage,
+        I80F48::from_num(3).to_bits()
+    );
+
+    // Fixed part of the summary, then a u32 length prefixed list of tokens
+    let data = summary.try_to_vec().unwrap();
+    let fixed_len = 1 + 1 + 32 * 5 + 16 * 2 + 8 + 1 + 1;
+    let token_len = 1 + 32 * 3 + 16 + 1 + 1;
+    assert_eq!(data.len(), fixed_len + 4 + token_len);
+    assert_eq!(&data[fixed_len..fixed_len + 4], &1u32.to_le_bytes());
+}
+
+#[test]
+fn test_validate_event_reports_tampered_mint() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.signer_key = Pubkey::new_unique();
+    let (mint, tampered_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.leverage_tokens[1] = LeverageToken {
+        mint: tampered_mint,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 2;
+    let (base_token_mint, oracle) = (Pubkey::new_unique(), Pubkey::new_unique());
+    quasar_group.base_tokens[0] = BaseToken {
+        mint: base_token_mint,
+        decimals: 6,
+        oracles: [oracle, Pubkey::default(), Pubkey::default()],
+        oracle_types: [OracleType::Stub.into(), 0, 0],
+        num_oracles: 1,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 1;
+
+    let validation_accounts = quasar_group.validation_accounts();
+    assert_eq!(
+        validation_accounts,
+        vec![mint, tampered_mint, base_token_mint, oracle]
+    );
+
+    // The authority of the second leverage mint was handed to someone else
+    let intruder = Pubkey::new_unique();
+    let mut data = vec![
+        mint_data(quasar_group.signer_key, 6),
+        mint_data(intruder, 6),
+        mint_data(Pubkey::new_unique(), 6),
+        [
+            &[0; ACCOUNT_DISCRIMINATOR_LEN][..],
+            &[77, 110, 103, 111, 0, 0, 0, 0],
+        ]
+        .concat(),
+    ];
+    let mut lamports = vec![0; validation_accounts.len()];
+    let owners = [
+        spl_token::id(),
+        spl_token::id(),
+        spl_token::id(),
+        Pubkey::default(),
+    ];
+    let account_infos: Vec<AccountInfo> = validation_accounts
+        .iter()
+        .zip(lamports.iter_mut())
+        .zip(data.iter_mut())
+        .zip(owners.iter())
+        .map(|(((key, lamports), data), owner)| {
+            AccountInfo::new(key, false, false, lamports, data, owner, false, 0)
+        })
+        .collect();
+
+    let event = ValidateEvent::new(&quasar_group, &account_infos);
+    assert_eq!(event.num_leverage_tokens, 2);
+    assert_eq!(
+        event.discrepancies,
+        vec![Discrepancy::MintAuthority {
+            mint: tampered_mint,
+            mint_authority: Some(intruder),
+        }]
+    );
+}
+
+#[test]
+fn test_total_tvl_sums_up_leverage_tokens() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let mango_accounts = [Pubkey::new_unique(), Pubkey::new_unique()];
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: Pubkey::new_unique(),
+        mango_account: mango_accounts[0],
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.leverage_tokens[2] = LeverageToken {
+        mint: Pubkey::new_unique(),
+        mango_account: mango_accounts[1],
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 2;
+    assert_eq!(quasar_group.tvl_accounts(), mango_accounts.to_vec());
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index = I80F48::from_num(1);
+    let mut first = MangoAccount::zeroed();
+    first.deposits[QUOTE_INDEX] = I80F48::from_num(1_000);
+    // The second token has perp losses it hasn't settled yet, they count against it
+    let mut second = MangoAccount::zeroed();
+    second.deposits[QUOTE_INDEX] = I80F48::from_num(2_500);
+    second.perp_accounts[0].quote_position = I80F48::from_num(-200);
+
+    let event = TotalTvl::new(
+        6,
+        &mango_group,
+        &mango_cache,
+        [&first, &second].iter().copied(),
+    )
+    .unwrap();
+    assert_eq!(event.num_leverage_tokens, 2);
+    assert_eq!(event.tvl, I80F48::from_num(3_300).to_bits());
+    assert_eq!(event.quote_decimals, 6);
+}
+
+#[test]
+fn test_nav_events_carry_quote_decimals() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    let mango_cache = MangoCache::zeroed();
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(2_000_000);
+    let leverage_token = LeverageToken {
+        mint: Pubkey::new_unique(),
+        ..LeverageToken::zeroed()
+    };
+
+    let mint_quote = leverage_token
+        .quote_mint(1, 1, 0, 6, &mango_group, &mango_account, &mango_cache)
+        .unwrap();
+    let redeem_quote = leverage_token
+        .quote_redeem(1, 1, 0, 6, &mango_group, &mango_account, &mango_cache)
+        .unwrap();
+    // 2 whole quote tokens per leverage token at 6 decimals
+    let whole_price = I80F48::from_num(2);
+    assert_eq!(mint_quote.quote_decimals, 6);
+    assert_eq!(
+        I80F48::from_bits(mint_quote.native_price) / I80F48::from_num(1_000_000),
+        whole_price
+    );
+    assert_eq!(redeem_quote.quote_decimals, 6);
+    assert_eq!(
+        I80F48::from_bits(redeem_quote.native_price) / I80F48::from_num(1_000_000),
+        whole_price
+    );
+
+    // Clients find the decimals right after the price in the encoding
+    let data = mint_quote.try_to_vec().unwrap();
+    assert_eq!(data[32 + 8 + 8 + 16], 6);
+}
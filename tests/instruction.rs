@@ -0,0 +1,646 @@
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use mango::state::MAX_PAIRS;
+use quasar::{
+    instruction::{
+        self, AccountDescriptor, BatchMintAccounts, BatchMintEntry, CrankRewardAccounts,
+        PerpBookAccounts, PerpMarketAccounts, QuasarInstruction, MAX_BATCH_MINTS,
+    },
+    state::{QuasarGroup, RebalanceOrderType, MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN},
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+
+fn assert_round_trip(instruction: QuasarInstruction) {
+    let packed = instruction.pack();
+    assert_eq!(QuasarInstruction::unpack(&packed), Some(instruction));
+}
+
+fn weights(weights: &[I80F48]) -> [I80F48; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN] {
+    let mut perp_market_weights = [I80F48::from_num(0); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    perp_market_weights[..weights.len()].copy_from_slice(weights);
+    perp_market_weights
+}
+
+fn base_mints(base_mints: &[Pubkey]) -> [Pubkey; MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN] {
+    let mut perp_market_base_mints = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    perp_market_base_mints[..base_mints.len()].copy_from_slice(base_mints);
+    perp_market_base_mints
+}
+
+/// Every instruction, with the edge values of their fields
+fn sample_instructions() -> Vec<QuasarInstruction> {
+    vec![
+        QuasarInstruction::InitQuasarGroup { signer_nonce: 0 },
+        QuasarInstruction::InitQuasarGroup {
+            signer_nonce: u64::MAX,
+        },
+        QuasarInstruction::AddBaseToken,
+        QuasarInstruction::AddLeverageToken {
+            target_leverage: I80F48::from_num(3),
+            num_perp_markets: 1,
+            perp_market_weights: weights(&[I80F48::from_num(1)]),
+            perp_market_base_mints: base_mints(&[Pubkey::new_unique()]),
+            decimals: None,
+            rebalance_deadband_bps: 0,
+        },
+        QuasarInstruction::AddLeverageToken {
+            target_leverage: I80F48::from_bits(i128::MIN),
+            num_perp_markets: 2,
+            perp_market_weights: weights(&[I80F48::from_bits(1), I80F48::MAX]),
+            perp_market_base_mints: base_mints(&[Pubkey::new_unique(), Pubkey::new_unique()]),
+            decimals: Some(9),
+            rebalance_deadband_bps: u16::MAX,
+        },
+        QuasarInstruction::MintLeverageToken { quantity: 1 },
+        QuasarInstruction::MintLeverageToken { quantity: u64::MAX },
+        QuasarInstruction::BurnLeverageToken {
+            quantity: 0,
+            output_mint: None,
+        },
+        QuasarInstruction::BurnLeverageToken {
+            quantity: u64::MAX,
+            output_mint: Some(Pubkey::new_unique()),
+        },
+        QuasarInstruction::Rebalance,
+        QuasarInstruction::CloseLeverageToken,
+        QuasarInstruction::ChangeGroupParams {
+            min_health_ratio: None,
+            deleverage_health_ratio: None,
+            min_rebalance_interval: None,
+            max_tokens_per_base: None,
+            rebalance_deadband_bps: None,
+            creation_fee: None,
+            min_hold_secs: None,
+            crank_reward_bps: None,
+            max_crank_reward: None,
+        },
+        QuasarInstruction::ChangeGroupParams {
+            min_health_ratio: Some(I80F48::from_num(-12.5)),
+            deleverage_health_ratio: Some(I80F48::MIN),
+            min_rebalance_interval: Some(u64::MAX),
+            max_tokens_per_base: Some(3),
+            rebalance_deadband_bps: Some(50),
+            creation_fee: Some(u64::MAX),
+            min_hold_secs: Some(3_600),
+            crank_reward_bps: Some(5),
+            max_crank_reward: Some(u16::MAX),
+        },
+        QuasarInstruction::DeleverageToken,
+        QuasarInstruction::SetTargetLeverage {
+            new_leverage: I80F48::from_num(2.5),
+            rebalance_deadband_bps: None,
+        },
+        QuasarInstruction::SetTargetLeverage {
+            new_leverage: I80F48::from_num(2.5),
+            rebalance_deadband_bps: Some(25),
+        },
+        QuasarInstruction::LogGroupState,
+        QuasarInstruction::SetDepositCap { deposit_cap: 0 },
+        QuasarInstruction::SetDepositCap {
+            deposit_cap: u64::MAX,
+        },
+        QuasarInstruction::SetManagementFee {
+            management_fee_bps_annual: 0,
+        },
+        QuasarInstruction::SetManagementFee {
+            management_fee_bps_annual: u16::MAX,
+        },
+        QuasarInstruction::InitiateShutdown,
+        QuasarInstruction::InitFeeVault,
+        QuasarInstruction::SetMinMintQuantity {
+            min_mint_quantity: 1_000,
+        },
+        QuasarInstruction::RequestRedeem { quantity: 1 },
+        QuasarInstruction::RequestRedeem { quantity: u64::MAX },
+        QuasarInstruction::ProcessRedeemQueue,
+        QuasarInstruction::RebalanceLeg { leg_index: 0 },
+        QuasarInstruction::RebalanceLeg { leg_index: u8::MAX },
+        QuasarInstruction::RemoveBaseToken,
+        QuasarInstruction::SettleTokenPnl { leg_index: 3 },
+        QuasarInstruction::UpdatePriceSample,
+        QuasarInstruction::QuoteMint { quantity: u64::MAX },
+        QuasarInstruction::ReconcileToken,
+        QuasarInstruction::SettleFunding { leg_index: 3 },
+        QuasarInstruction::SetMangoProgram,
+        QuasarInstruction::MintFromMangoBalance { quantity: 7 },
+        QuasarInstruction::ValidateGroup,
+        QuasarInstruction::SetUseEma { use_ema: true },
+        QuasarInstruction::LogTokenLeverage,
+        QuasarInstruction::SetMintFreezeAuthority {
+            freeze_authority: None,
+        },
+        QuasarInstruction::SetMintFreezeAuthority {
+            freeze_authority: Some(Pubkey::new_unique()),
+        },
+        QuasarInstruction::BatchMint {
+            num_entries: 2,
+            entries: [BatchMintEntry {
+                token_index: 3,
+                quantity: 7,
+                max_collateral: u64::MAX,
+            }; MAX_BATCH_MINTS],
+        },
+        QuasarInstruction::MigrateGroup,
+        QuasarInstruction::QuoteRedeem { quantity: 1 },
+        QuasarInstruction::SweepDust,
+        QuasarInstruction::SetRebalanceOrderType {
+            order_type: 2,
+            offset_bps: 25,
+        },
+        QuasarInstruction::LogTotalTvl,
+        QuasarInstruction::SetCompound { compound: true },
+        QuasarInstruction::SetRiskAdmin {
+            risk_admin: Some(Pubkey::new_unique()),
+        },
+        QuasarInstruction::SetRiskAdmin { risk_admin: None },
+        QuasarInstruction::SetPaused { paused: true },
+    ]
+}
+
+#[test]
+fn test_round_trip() {
+    for instruction in sample_instructions() {
+        assert_round_trip(instruction);
+    }
+}
+
+#[test]
+fn test_encoding() {
+    assert_eq!(
+        QuasarInstruction::MintLeverageToken { quantity: u64::MAX }.pack(),
+        vec![3, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255]
+    );
+
+    // I80F48 is encoded as its raw little endian bits
+    let target_leverage = I80F48::from_num(-2.75);
+    let base_mint = Pubkey::new_unique();
+    let packed = QuasarInstruction::AddLeverageToken {
+        target_leverage,
+        num_perp_markets: 1,
+        perp_market_weights: weights(&[I80F48::from_num(1)]),
+        perp_market_base_mints: base_mints(&[base_mint]),
+        decimals: Some(6),
+        rebalance_deadband_bps: 300,
+    }
+    .pack();
+    assert_eq!(
+        packed.len(),
+        4 + 16 + 1 + (16 + 32) * MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN + 2 + 2
+    );
+    assert_eq!(&packed[packed.len() - 4..], &[1, 6, 44, 1]);
+    assert_eq!(&packed[..4], &[2, 0, 0, 0]);
+    assert_eq!(&packed[4..20], &target_leverage.to_le_bytes());
+    assert_eq!(packed[20], 1);
+    // the base token mints follow the weights
+    let base_mints_offset = 21 + 16 * MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN;
+    assert_eq!(
+        &packed[base_mints_offset..base_mints_offset + 32],
+        base_mint.as_ref()
+    );
+}
+
+#[test]
+fn test_unpack_invalid() {
+    assert_eq!(QuasarInstruction::unpack(&[]), None);
+    assert_eq!(QuasarInstruction::unpack(&[5, 0, 0]), None);
+    assert_eq!(QuasarInstruction::unpack(&[255, 255, 255, 255]), None);
+
+    let mut packed = QuasarInstruction::MintLeverageToken { quantity: 42 }.pack();
+    assert_eq!(QuasarInstruction::unpack(&packed[..packed.len() - 1]), None);
+    packed.push(0);
+    assert_eq!(QuasarInstruction::unpack(&packed), None);
+}
+
+/// xorshift64, random enough to throw bytes at `unpack` without a fuzzing crate
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[test]
+fn test_unpack_malformed_input() {
+    // Every truncation of a valid encoding is missing a field, and any byte of it may be garbage
+    for instruction in sample_instructions() {
+        let packed = instruction.pack();
+        for len in 0..packed.len() {
+            assert_eq!(QuasarInstruction::unpack(&packed[..len]), None);
+        }
+        for i in 0..packed.len() {
+            let mut corrupted = packed.clone();
+            corrupted[i] ^= 0xff;
+            QuasarInstruction::unpack(&corrupted);
+        }
+    }
+
+    let mut rng = Xorshift(0x2545_f491_4f6c_dd1d);
+    for _ in 0..20_000 {
+        let len = (rng.next() % 256) as usize;
+        let mut data: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+        // Mostly known discriminants, or random bytes would hardly get past the first field
+        if len >= 4 && rng.next() % 4 != 0 {
+            let discriminant = (rng.next() % 41) as u32;
+            data[..4].copy_from_slice(&discriminant.to_le_bytes());
+        }
+        // Whatever decodes is a canonical encoding, nothing was skipped or made up
+        if let Some(instruction) = QuasarInstruction::unpack(&data) {
+            assert_eq!(instruction.pack(), data);
+        }
+    }
+}
+
+#[test]
+fn test_leverage_survives_pack_unpack() {
+    // 1.01 has no exact binary representation, every one of its bits has to come back
+    let leverages = [
+        I80F48::from_num(101) / I80F48::from_num(100),
+        I80F48::from_num(10) / I80F48::from_num(3),
+        I80F48::from_bits(1),
+        I80F48::from_bits(0x0123_4567_89ab_cdef_fedc_ba98_7654_3210),
+    ];
+    for &leverage in leverages.iter() {
+        let packed = QuasarInstruction::SetTargetLeverage {
+            new_leverage: leverage,
+            rebalance_deadband_bps: None,
+        }
+        .pack();
+        match QuasarInstruction::unpack(&packed) {
+            Some(QuasarInstruction::SetTargetLeverage { new_leverage, .. }) => {
+                assert_eq!(new_leverage.to_bits(), leverage.to_bits())
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
+
+fn keys<const N: usize>() -> [Pubkey; N] {
+    let mut keys = [Pubkey::default(); N];
+    keys.iter_mut().for_each(|key| *key = Pubkey::new_unique());
+    keys
+}
+
+fn perp_market_accounts() -> PerpMarketAccounts {
+    PerpMarketAccounts {
+        perp_market: Pubkey::new_unique(),
+        bids: Pubkey::new_unique(),
+        asks: Pubkey::new_unique(),
+        event_queue: Pubkey::new_unique(),
+    }
+}
+
+fn perp_book_accounts() -> PerpBookAccounts {
+    PerpBookAccounts {
+        perp_market: Pubkey::new_unique(),
+        bids: Pubkey::new_unique(),
+        asks: Pubkey::new_unique(),
+    }
+}
+
+/// The fixed accounts of `ix` follow the descriptor of its instruction, then come
+/// `num_remaining` more
+fn assert_matches_descriptor(ix: &Instruction, fixed_keys: &[Pubkey], num_remaining: usize) {
+    let descriptors: &[AccountDescriptor] = QuasarInstruction::unpack(&ix.data)
+        .unwrap()
+        .fixed_accounts();
+    assert_eq!(descriptors.len(), fixed_keys.len());
+    assert_eq!(ix.accounts.len(), descriptors.len() + num_remaining);
+    for ((meta, descriptor), key) in ix.accounts.iter().zip(descriptors).zip(fixed_keys) {
+        assert_eq!(meta.pubkey, *key, "{}", descriptor.name);
+        assert_eq!(
+            meta.is_writable, descriptor.is_writable,
+            "{}",
+            descriptor.name
+        );
+        assert_eq!(meta.is_signer, descriptor.is_signer, "{}", descriptor.name);
+    }
+}
+
+#[test]
+fn test_builders_match_account_descriptors() {
+    let program_id = Pubkey::new_unique();
+    let open_orders = keys::<MAX_PAIRS>();
+    let perp_markets = [perp_market_accounts(), perp_market_accounts()];
+    let trading = MAX_PAIRS + 4 * perp_markets.len();
+    let books = [perp_book_accounts(), perp_book_accounts()];
+
+    let accounts = keys();
+    let ix = instruction::init_quasar_group(&program_id, &accounts, 3);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::add_base_token(&program_id, &accounts, &keys::<2>());
+    assert_matches_descriptor(&ix, &accounts, 2);
+
+    let accounts = keys();
+    let half = I80F48::from_num(0.5);
+    let ix = instruction::add_leverage_token(
+        &program_id,
+        &accounts,
+        I80F48::from_num(2),
+        &[half, half],
+        &keys::<2>(),
+        &keys::<2>(),
+        Some(6),
+        0,
+        None,
+    );
+    assert_matches_descriptor(&ix, &accounts, 2);
+    // Paying the creation fee adds the fee accounts after the perp markets
+    let fee_accounts = keys();
+    let ix = instruction::add_leverage_token(
+        &program_id,
+        &accounts,
+        I80F48::from_num(2),
+        &[half, half],
+        &keys::<2>(),
+        &keys::<2>(),
+        Some(6),
+        0,
+        Some(&fee_accounts),
+    );
+    assert_matches_descriptor(&ix, &accounts, 4);
+    assert_eq!(ix.accounts[accounts.len() + 2].pubkey, fee_accounts[0]);
+    assert!(ix.accounts[accounts.len() + 3].is_writable);
+
+    let accounts = keys();
+    let ix = instruction::mint_leverage_token(&program_id, &accounts, &books, 1);
+    assert_matches_descriptor(&ix, &accounts, 6);
+    assert_eq!(ix.accounts[accounts.len() + 3].pubkey, books[1].perp_market);
+    assert!(ix.accounts[accounts.len()..]
+        .iter()
+        .all(|meta| !meta.is_writable && !meta.is_signer));
+
+    let accounts = keys();
+    let ix = instruction::burn_leverage_token(
+        &program_id,
+        &accounts,
+        &open_orders,
+        &perp_markets,
+        1,
+        None,
+    );
+    assert_matches_descriptor(&ix, &accounts, trading);
+    assert!(ix.accounts[accounts.len()..accounts.len() + MAX_PAIRS]
+        .iter()
+        .all(|meta| !meta.is_writable));
+    assert!(ix.accounts[accounts.len() + MAX_PAIRS..]
+        .iter()
+        .all(|meta| meta.is_writable));
+
+    let accounts = keys();
+    let ix = instruction::rebalance(&program_id, &accounts, &open_orders, &perp_markets, None);
+    assert_matches_descriptor(&ix, &accounts, trading);
+
+    // The crank reward accounts go after every leg
+    let accounts = keys();
+    let crank_reward = CrankRewardAccounts {
+        fee_vault: Pubkey::new_unique(),
+        crank_quote_token_account: Pubkey::new_unique(),
+    };
+    let ix = instruction::rebalance(
+        &program_id,
+        &accounts,
+        &open_orders,
+        &perp_markets,
+        Some(&crank_reward),
+    );
+    assert_matches_descriptor(&ix, &accounts, trading + 2);
+    let reward_metas = &ix.accounts[accounts.len() + trading..];
+    assert_eq!(reward_metas[0].pubkey, crank_reward.fee_vault);
+    assert_eq!(
+        reward_metas[1].pubkey,
+        crank_reward.crank_quote_token_account
+    );
+    assert!(reward_metas.iter().all(|meta| meta.is_writable));
+
+    let accounts = keys();
+    let ix = instruction::close_leverage_token(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::change_group_params(
+        &program_id,
+        &accounts,
+        None,
+        None,
+        Some(60),
+        Some(2),
+        None,
+        Some(1_000_000),
+        Some(60),
+        Some(5),
+        None,
+    );
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::deleverage_token(&program_id, &accounts, &open_orders, &perp_markets);
+    assert_matches_descriptor(&ix, &accounts, trading);
+
+    let accounts = keys();
+    let ix =
+        instruction::set_target_leverage(&program_id, &accounts, I80F48::from_num(2), Some(50));
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::log_group_state(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::set_deposit_cap(&program_id, &accounts, 1);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::set_management_fee(&program_id, &accounts, 100);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::initiate_shutdown(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::init_fee_vault(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::set_min_mint_quantity(&program_id, &accounts, 1);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::request_redeem(&program_id, &accounts, 1);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::process_redeem_queue(&program_id, &accounts, &open_orders, &perp_markets);
+    assert_matches_descriptor(&ix, &accounts, trading);
+
+    let accounts = keys();
+    let ix = instruction::rebalance_leg(
+        &program_id,
+        &accounts,
+        &open_orders,
+        &perp_markets[1],
+        1,
+        None,
+    );
+    assert_matches_descriptor(&ix, &accounts, MAX_PAIRS + 4);
+    assert_eq!(
+        ix.accounts[accounts.len() + MAX_PAIRS].pubkey,
+        perp_markets[1].perp_market
+    );
+
+    let accounts = keys();
+    let ix = instruction::remove_base_token(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::settle_token_pnl(&program_id, &accounts, 0);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::update_price_sample(&program_id, &accounts, &keys::<3>());
+    assert_matches_descriptor(&ix, &accounts, 3);
+
+    let accounts = keys();
+    let ix = instruction::quote_mint(&program_id, &accounts, &books, 10);
+    assert_matches_descriptor(&ix, &accounts, 6);
+    assert!(ix.accounts.iter().all(|meta| !meta.is_writable));
+
+    let accounts = keys();
+    let ix = instruction::reconcile_token(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::settle_funding(&program_id, &accounts, 1);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::set_mango_program(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::mint_from_mango_balance(
+        &program_id,
+        &accounts,
+        &keys::<MAX_PAIRS>(),
+        &books,
+        10,
+    );
+    assert_matches_descriptor(&ix, &accounts, MAX_PAIRS + 6);
+    assert_eq!(
+        ix.accounts[accounts.len() + MAX_PAIRS].pubkey,
+        books[0].perp_market
+    );
+    assert!(ix.accounts[accounts.len()..]
+        .iter()
+        .all(|meta| !meta.is_writable && !meta.is_signer));
+
+    let accounts = keys();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.leverage_tokens[0].mint = Pubkey::new_unique();
+    let ix = instruction::validate_group(&program_id, &accounts, &quasar_group);
+    assert_matches_descriptor(&ix, &accounts, 1);
+    assert_eq!(ix.accounts[1].pubkey, quasar_group.leverage_tokens[0].mint);
+
+    let accounts = keys();
+    let ix = instruction::set_use_ema(&program_id, &accounts, true);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::log_token_leverage(&program_id, &accounts);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::set_mint_freeze_authority(&program_id, &accounts, None);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let entry = BatchMintEntry {
+        token_index: 1,
+        quantity: 100,
+        max_collateral: 1_000,
+    };
+    let [token_mint, owner_leverage_token_account, mango_account, hold_record, fee_token_account] =
+        keys();
+    let token_accounts = BatchMintAccounts {
+        token_mint,
+        owner_leverage_token_account,
+        mango_account,
+        hold_record,
+        fee_token_account,
+        books: books[..1].to_vec(),
+    };
+    let ix = instruction::batch_mint(
+        &program_id,
+        &accounts,
+        &[(entry, token_accounts.clone()), (entry, token_accounts)],
+    );
+    assert_matches_descriptor(&ix, &accounts, 16);
+    assert_eq!(ix.accounts[accounts.len() + 4].pubkey, fee_token_account);
+    assert_eq!(ix.accounts[accounts.len() + 5].pubkey, books[0].perp_market);
+    assert_eq!(ix.accounts[accounts.len() + 8].pubkey, token_mint);
+    for entry_accounts in ix.accounts[accounts.len()..].chunks(8) {
+        assert!(entry_accounts[..5]
+            .iter()
+            .all(|meta| meta.is_writable && !meta.is_signer));
+        assert!(entry_accounts[5..].iter().all(|meta| !meta.is_writable));
+    }
+
+    let accounts = keys();
+    let token_mint = Pubkey::new_unique();
+    let ix = instruction::migrate_group(&program_id, &accounts, &[token_mint]);
+    assert_matches_descriptor(&ix, &accounts, 1);
+    assert_eq!(
+        ix.accounts[accounts.len()],
+        AccountMeta::new_readonly(token_mint, false)
+    );
+
+    let accounts = keys();
+    let ix = instruction::quote_redeem(&program_id, &accounts, 10);
+    assert_matches_descriptor(&ix, &accounts, 0);
+    assert!(ix.accounts.iter().all(|meta| !meta.is_writable));
+
+    let accounts = keys();
+    let ix = instruction::sweep_dust(&program_id, &accounts, &open_orders);
+    assert_matches_descriptor(&ix, &accounts, MAX_PAIRS);
+
+    let accounts = keys();
+    let ix = instruction::set_rebalance_order_type(
+        &program_id,
+        &accounts,
+        RebalanceOrderType::PostOnly,
+        25,
+    );
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.leverage_tokens[0].mint = Pubkey::new_unique();
+    quasar_group.leverage_tokens[0].mango_account = Pubkey::new_unique();
+    let ix = instruction::log_total_tvl(&program_id, &accounts, &quasar_group);
+    assert_matches_descriptor(&ix, &accounts, 1);
+    assert_eq!(
+        ix.accounts[accounts.len()].pubkey,
+        quasar_group.leverage_tokens[0].mango_account
+    );
+
+    let accounts = keys();
+    let ix = instruction::set_compound(&program_id, &accounts, true);
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::set_risk_admin(&program_id, &accounts, Some(Pubkey::new_unique()));
+    assert_matches_descriptor(&ix, &accounts, 0);
+
+    let accounts = keys();
+    let ix = instruction::set_paused(&program_id, &accounts, true);
+    assert_matches_descriptor(&ix, &accounts, 0);
+}
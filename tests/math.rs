@@ -0,0 +1,575 @@
+use assert_matches::*;
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use quasar::{
+    error::{QuasarError, QuasarErrorCode},
+    math::{
+        average_fill_price, collateral_for_redeem, collateral_for_tokens, filled_quantity,
+        management_fee_tokens, native_to_ui, position_deviation, price_impact_cost,
+        rebalance_exposure_delta, redeemed_exposure, target_position_size, tokens_for_collateral,
+        ui_to_native, within_tolerance, SECONDS_PER_YEAR,
+    },
+    state::{LeverageToken, LEVERGAE_TOKEN_DECIMALS, MAX_LEVERAGE_TOKEN_DECIMALS},
+};
+
+const DECIMALS: u8 = LEVERGAE_TOKEN_DECIMALS;
+
+macro_rules! assert_quasar_err {
+    ($result:expr, $code:pat) => {
+        assert_matches!(
+            $result,
+            Err(QuasarError::QuasarErrorCode {
+                quasar_error_code: $code,
+                ..
+            })
+        )
+    };
+}
+
+#[test]
+fn test_collateral_for_tokens() {
+    let nav = I80F48::from_num(1_000_000);
+
+    assert_eq!(
+        collateral_for_tokens(nav, 5, DECIMALS, 0).unwrap(),
+        5_000_000
+    );
+    assert_eq!(
+        collateral_for_tokens(nav, 5, DECIMALS, 30).unwrap(),
+        5_015_000
+    );
+    // Rounds up in favor of the protocol
+    assert_eq!(
+        collateral_for_tokens(I80F48::from_num(1.5), 1, DECIMALS, 0).unwrap(),
+        2
+    );
+    assert_eq!(collateral_for_tokens(nav, 0, DECIMALS, 30).unwrap(), 0);
+}
+
+#[test]
+fn test_tokens_for_collateral() {
+    let nav = I80F48::from_num(1_000_000);
+
+    assert_eq!(
+        tokens_for_collateral(nav, 5_000_000, DECIMALS, 0).unwrap(),
+        5
+    );
+    assert_eq!(
+        tokens_for_collateral(nav, 5_015_000, DECIMALS, 30).unwrap(),
+        5
+    );
+    // Rounds down in favor of the protocol
+    assert_eq!(
+        tokens_for_collateral(nav, 4_999_999, DECIMALS, 0).unwrap(),
+        4
+    );
+    assert_eq!(tokens_for_collateral(nav, 0, DECIMALS, 30).unwrap(), 0);
+}
+
+#[test]
+fn test_round_trip_never_gains() {
+    let nav = I80F48::from_num(1_234_567);
+    for tokens in [1u64, 7, 1_000, 123_456] {
+        let collateral = collateral_for_tokens(nav, tokens, DECIMALS, 25).unwrap();
+        assert!(tokens_for_collateral(nav, collateral, DECIMALS, 25).unwrap() <= tokens);
+    }
+}
+
+#[test]
+fn test_collateral_for_redeem() {
+    let nav = I80F48::from_num(1_000_000);
+
+    assert_eq!(
+        collateral_for_redeem(nav, 5_000_000, DECIMALS).unwrap(),
+        5_000_000
+    );
+    // Rounds down in favor of the protocol
+    assert_eq!(
+        collateral_for_redeem(I80F48::from_num(1_500_000), 1, DECIMALS).unwrap(),
+        1
+    );
+    assert_eq!(
+        collateral_for_redeem(nav, 999_999, DECIMALS).unwrap(),
+        999_999
+    );
+    assert_eq!(
+        collateral_for_redeem(I80F48::from_num(0.5), 1, DECIMALS).unwrap(),
+        0
+    );
+    assert_eq!(collateral_for_redeem(nav, 0, DECIMALS).unwrap(), 0);
+    assert_quasar_err!(
+        collateral_for_redeem(I80F48::from_num(0), 1, DECIMALS),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+/// Deterministic spread of values over several orders of magnitude, with awkward fractions
+fn sample_values(seed: u64, count: usize) -> Vec<u64> {
+    let mut x = seed;
+    (0..count)
+        .map(|i| {
+            // xorshift
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x % 10u64.pow(1 + (i % 10) as u32)
+        })
+        .collect()
+}
+
+#[test]
+fn test_mint_then_redeem_never_returns_more() {
+    let navs: Vec<I80F48> = sample_values(0x9e37_79b9_7f4a_7c15, 40)
+        .into_iter()
+        .map(|v| I80F48::from_num(v + 1) / I80F48::from_num(7))
+        .collect();
+    for nav in navs {
+        for tokens in sample_values(nav.to_bits() as u64 | 1, 40) {
+            for &fee_bps in [0u16, 1, 30].iter() {
+                let deposited = collateral_for_tokens(nav, tokens, DECIMALS, fee_bps).unwrap();
+                let redeemed = collateral_for_redeem(nav, tokens, DECIMALS).unwrap();
+                assert!(
+                    redeemed <= deposited,
+                    "nav {} tokens {} fee {}: deposited {} redeemed {}",
+                    nav,
+                    tokens,
+                    fee_bps,
+                    deposited,
+                    redeemed
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dust_round_trips_never_gain() {
+    // One native unit at a time is where rounding matters most
+    let nav = I80F48::from_num(1_000_001) / I80F48::from_num(3);
+    let mut deposited = 0u64;
+    let mut redeemed = 0u64;
+    for _ in 0..10_000 {
+        deposited += collateral_for_tokens(nav, 1, DECIMALS, 0).unwrap();
+        redeemed += collateral_for_redeem(nav, 1, DECIMALS).unwrap();
+    }
+    assert!(redeemed <= deposited);
+    // A dust mint always costs a native unit while the redeem pays nothing
+    assert_eq!(deposited, 10_000);
+    assert_eq!(redeemed, 0);
+}
+
+#[test]
+fn test_conversions_with_token_decimals() {
+    // 2.5 native quote units per whole token
+    let nav = I80F48::from_num(2.5);
+
+    // 6 decimals: a whole token is 1_000_000 native units
+    assert_eq!(collateral_for_tokens(nav, 1_000_000, 6, 0).unwrap(), 3);
+    assert_eq!(collateral_for_tokens(nav, 2_000_000, 6, 0).unwrap(), 5);
+    assert_eq!(tokens_for_collateral(nav, 5, 6, 0).unwrap(), 2_000_000);
+    assert_eq!(collateral_for_redeem(nav, 2_000_000, 6).unwrap(), 5);
+    assert_eq!(collateral_for_redeem(nav, 1_000_000, 6).unwrap(), 2);
+
+    // 9 decimals
+    let nav = I80F48::from_num(1_000_000);
+    assert_eq!(collateral_for_tokens(nav, 1, 9, 0).unwrap(), 1);
+    assert_eq!(collateral_for_redeem(nav, 1, 9).unwrap(), 0);
+    assert_eq!(
+        tokens_for_collateral(nav, 3_000_000, MAX_LEVERAGE_TOKEN_DECIMALS, 0).unwrap(),
+        3_000_000_000
+    );
+    assert_eq!(
+        collateral_for_redeem(nav, 3_000_000_000, MAX_LEVERAGE_TOKEN_DECIMALS).unwrap(),
+        3_000_000
+    );
+}
+
+#[test]
+fn test_invalid_nav() {
+    assert_quasar_err!(
+        collateral_for_tokens(I80F48::from_num(0), 1, DECIMALS, 0),
+        QuasarErrorCode::InvalidParam
+    );
+    assert_quasar_err!(
+        tokens_for_collateral(I80F48::from_num(-1), 1, DECIMALS, 0),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_u64_boundary() {
+    let one = I80F48::from_num(1);
+
+    assert_eq!(
+        collateral_for_tokens(one, u64::MAX, DECIMALS, 0).unwrap(),
+        u64::MAX
+    );
+    assert_eq!(
+        tokens_for_collateral(one, u64::MAX, DECIMALS, 0).unwrap(),
+        u64::MAX
+    );
+
+    // The fee pushes the collateral past u64::MAX
+    assert_quasar_err!(
+        collateral_for_tokens(one, u64::MAX, DECIMALS, 1),
+        QuasarErrorCode::MathOverflow
+    );
+    // The intermediate product overflows I80F48
+    assert_quasar_err!(
+        collateral_for_tokens(I80F48::from_num(1_000_000), u64::MAX, DECIMALS, 0),
+        QuasarErrorCode::MathOverflow
+    );
+    // A tiny NAV makes the token amount overflow
+    assert_quasar_err!(
+        tokens_for_collateral(I80F48::from_bits(1), u64::MAX, DECIMALS, 0),
+        QuasarErrorCode::MathOverflow
+    );
+}
+
+#[test]
+fn test_target_position_size() {
+    let collateral = I80F48::from_num(1_000_000);
+
+    assert_eq!(
+        target_position_size(collateral, I80F48::from_num(3), I80F48::from_num(40)).unwrap(),
+        I80F48::from_num(75_000)
+    );
+    assert_eq!(
+        target_position_size(collateral, I80F48::from_num(-2), I80F48::from_num(40)).unwrap(),
+        I80F48::from_num(-50_000)
+    );
+    assert_eq!(
+        target_position_size(collateral, I80F48::from_num(0), I80F48::from_num(40)).unwrap(),
+        I80F48::from_num(0)
+    );
+}
+
+#[test]
+fn test_target_position_size_overflow() {
+    let collateral = I80F48::from_num(1_000_000);
+
+    assert_quasar_err!(
+        target_position_size(collateral, I80F48::from_num(3), I80F48::from_num(0)),
+        QuasarErrorCode::MathOverflow
+    );
+    assert_quasar_err!(
+        target_position_size(collateral, I80F48::MAX, I80F48::from_num(40)),
+        QuasarErrorCode::MathOverflow
+    );
+    assert_quasar_err!(
+        target_position_size(
+            I80F48::from_num(1_000_000_000_000u64),
+            I80F48::from_num(3),
+            I80F48::from_bits(1)
+        ),
+        QuasarErrorCode::MathOverflow
+    );
+}
+
+#[test]
+fn test_position_deviation() {
+    let target = I80F48::from_num(200);
+
+    assert_eq!(position_deviation(target, target), I80F48::from_num(0));
+    assert_eq!(
+        position_deviation(I80F48::from_num(150), target),
+        I80F48::from_num(0.25)
+    );
+    assert_eq!(
+        position_deviation(I80F48::from_num(250), target),
+        I80F48::from_num(0.25)
+    );
+    assert_eq!(
+        position_deviation(I80F48::from_num(-150), I80F48::from_num(-200)),
+        I80F48::from_num(0.25)
+    );
+    assert_eq!(
+        position_deviation(I80F48::from_num(0), I80F48::from_num(0)),
+        I80F48::from_num(0)
+    );
+    assert_eq!(
+        position_deviation(I80F48::from_num(1), I80F48::from_num(0)),
+        I80F48::MAX
+    );
+    assert_eq!(position_deviation(I80F48::MAX, I80F48::MIN), I80F48::MAX);
+}
+
+#[test]
+fn test_ui_to_native() {
+    let amount = I80F48::from_num(1.5);
+    assert_eq!(ui_to_native(amount, 0).unwrap(), 1);
+    assert_eq!(ui_to_native(amount, 6).unwrap(), 1_500_000);
+    assert_eq!(ui_to_native(amount, 9).unwrap(), 1_500_000_000);
+
+    // Fractions of a native unit are dropped
+    let amount = I80F48::from_num(1.0000015);
+    assert_eq!(ui_to_native(amount, 6).unwrap(), 1_000_001);
+    assert_eq!(ui_to_native(I80F48::DELTA, 9).unwrap(), 0);
+    assert_eq!(ui_to_native(I80F48::from_num(0), 6).unwrap(), 0);
+}
+
+#[test]
+fn test_ui_to_native_errors() {
+    assert_quasar_err!(
+        ui_to_native(I80F48::from_num(-1), 6),
+        QuasarErrorCode::InvalidParam
+    );
+    assert_quasar_err!(
+        ui_to_native(I80F48::from_num(u64::MAX), 6),
+        QuasarErrorCode::MathOverflow
+    );
+    assert_quasar_err!(
+        ui_to_native(I80F48::from_num(1), 30),
+        QuasarErrorCode::MathOverflow
+    );
+}
+
+#[test]
+fn test_native_to_ui() {
+    assert_eq!(native_to_ui(1_500_000, 0), I80F48::from_num(1_500_000));
+    assert_eq!(native_to_ui(1_500_000, 6), I80F48::from_num(1.5));
+    assert_eq!(native_to_ui(1_500_000_000, 9), I80F48::from_num(1.5));
+    assert_eq!(native_to_ui(0, 9), I80F48::from_num(0));
+
+    // Inexact divisions round down, so converting back never gains a native unit
+    for &decimals in [0u8, 6, 9].iter() {
+        for &amount in [1u64, 3, 7, 999_999_999, u64::MAX].iter() {
+            let ui = native_to_ui(amount, decimals);
+            assert!(ui_to_native(ui, decimals).unwrap() <= amount);
+        }
+    }
+}
+
+#[test]
+fn test_management_fee_tokens() {
+    let supply = 1_000_000_000;
+
+    // 2% a year on the supply, pro rata over time
+    assert_eq!(
+        management_fee_tokens(supply, 200, SECONDS_PER_YEAR).unwrap(),
+        20_000_000
+    );
+    assert_eq!(
+        management_fee_tokens(supply, 200, SECONDS_PER_YEAR / 2).unwrap(),
+        10_000_000
+    );
+    let mut vault = 0;
+    for _ in 0..4 {
+        vault += management_fee_tokens(supply, 200, SECONDS_PER_YEAR / 4).unwrap();
+    }
+    assert_eq!(vault, 20_000_000);
+
+    // Rounds down, nothing accrues without time, supply or a fee
+    assert_eq!(management_fee_tokens(supply, 200, 1).unwrap(), 0);
+    assert_eq!(management_fee_tokens(supply, 200, 0).unwrap(), 0);
+    assert_eq!(management_fee_tokens(0, 200, SECONDS_PER_YEAR).unwrap(), 0);
+    assert_eq!(
+        management_fee_tokens(supply, 0, SECONDS_PER_YEAR).unwrap(),
+        0
+    );
+
+    assert_quasar_err!(
+        management_fee_tokens(u64::MAX, u16::MAX, u64::MAX),
+        QuasarErrorCode::MathOverflow
+    );
+}
+
+#[test]
+fn test_rebalance_deadband() {
+    let target = I80F48::from_num(10_000);
+
+    // Tokens without a deadband of their own use the group's
+    let mut leverage_token = LeverageToken::zeroed();
+    assert_eq!(leverage_token.effective_rebalance_deadband_bps(50), 50);
+    leverage_token.rebalance_deadband_bps = 200;
+    assert_eq!(leverage_token.effective_rebalance_deadband_bps(50), 200);
+    let deadband_bps = leverage_token.effective_rebalance_deadband_bps(50);
+
+    // 1.5% off is within the 2% deadband, nothing to trade
+    assert_eq!(
+        rebalance_exposure_delta(I80F48::from_num(9_850), target, deadband_bps).unwrap(),
+        I80F48::from_num(0)
+    );
+    assert_eq!(
+        rebalance_exposure_delta(I80F48::from_num(10_100), target, deadband_bps).unwrap(),
+        I80F48::from_num(0)
+    );
+    // 3% off trades all the way back to the target
+    assert_eq!(
+        rebalance_exposure_delta(I80F48::from_num(9_700), target, deadband_bps).unwrap(),
+        I80F48::from_num(300)
+    );
+    assert_eq!(
+        rebalance_exposure_delta(I80F48::from_num(10_300), target, deadband_bps).unwrap(),
+        I80F48::from_num(-300)
+    );
+    // No deadband at all trades any deviation
+    assert_eq!(
+        rebalance_exposure_delta(I80F48::from_num(9_999), target, 0).unwrap(),
+        I80F48::from_num(1)
+    );
+}
+
+#[test]
+fn test_within_tolerance() {
+    let target = I80F48::from_num(10_000);
+
+    assert!(within_tolerance(target, target, 0));
+    assert!(within_tolerance(I80F48::from_num(9_900), target, 100));
+    assert!(within_tolerance(I80F48::from_num(10_100), target, 100));
+    // A partial fill leaving the position 2% short
+    assert!(!within_tolerance(I80F48::from_num(9_800), target, 100));
+    // Nothing filled at all
+    assert!(!within_tolerance(I80F48::from_num(0), target, 100));
+    // Short targets compare by magnitude
+    assert!(within_tolerance(
+        I80F48::from_num(-9_950),
+        I80F48::from_num(-10_000),
+        100
+    ));
+    assert!(within_tolerance(
+        I80F48::from_num(0),
+        I80F48::from_num(0),
+        0
+    ));
+    assert!(!within_tolerance(
+        I80F48::from_num(1),
+        I80F48::from_num(0),
+        100
+    ));
+}
+
+#[test]
+fn test_redeemed_exposure() {
+    // Redeeming 30% of the supply takes 30% of the position off
+    let exposure = I80F48::from_num(-10_000);
+    let reduced = redeemed_exposure(exposure, 300, 1_000).unwrap();
+    assert_eq!(reduced, I80F48::from_num(-3_000));
+
+    // The 700 tokens left back the same exposure per token as before
+    let remaining = exposure - reduced;
+    assert_eq!(
+        remaining / I80F48::from_num(700),
+        exposure / I80F48::from_num(1_000)
+    );
+
+    // Last holder out closes the whole position
+    assert_eq!(redeemed_exposure(exposure, 1_000, 1_000).unwrap(), exposure);
+    assert_eq!(
+        redeemed_exposure(exposure, 0, 1_000).unwrap(),
+        I80F48::from_num(0)
+    );
+    assert_quasar_err!(
+        redeemed_exposure(exposure, 1_001, 1_000),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_filled_quantity() {
+    let intended = I80F48::from_num(-1_000);
+    assert_eq!(filled_quantity(300, intended, intended).unwrap(), 300);
+    // Overfills don't pay out more than requested
+    assert_eq!(
+        filled_quantity(300, intended, I80F48::from_num(-1_200)).unwrap(),
+        300
+    );
+    // Rounded down
+    assert_eq!(
+        filled_quantity(300, intended, I80F48::from_num(-333)).unwrap(),
+        99
+    );
+    assert_eq!(
+        filled_quantity(300, intended, I80F48::from_num(0)).unwrap(),
+        0
+    );
+    // Moving the wrong way settles nothing
+    assert_eq!(
+        filled_quantity(300, intended, I80F48::from_num(10)).unwrap(),
+        0
+    );
+    // Nothing to unwind on the leg
+    assert_eq!(
+        filled_quantity(300, I80F48::from_num(0), I80F48::from_num(0)).unwrap(),
+        300
+    );
+}
+
+#[test]
+fn test_average_fill_price() {
+    let asks = [(100, 10), (110, 10), (150, 5)];
+    assert_eq!(
+        average_fill_price(asks.iter().copied(), I80F48::from_num(5)).unwrap(),
+        Some(I80F48::from_num(100))
+    );
+    // 10 lots at 100 and 5 at 110
+    assert_eq!(
+        average_fill_price(asks.iter().copied(), I80F48::from_num(15)).unwrap(),
+        Some(I80F48::from_num(1_550) / I80F48::from_num(15))
+    );
+    assert_eq!(
+        average_fill_price(asks.iter().copied(), I80F48::from_num(25)).unwrap(),
+        Some(I80F48::from_num(2_850) / I80F48::from_num(25))
+    );
+    // Deeper than the book
+    assert_eq!(
+        average_fill_price(asks.iter().copied(), I80F48::from_num(26)).unwrap(),
+        None
+    );
+    assert_quasar_err!(
+        average_fill_price(asks.iter().copied(), I80F48::from_num(0)),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_price_impact_cost() {
+    let (notional, price) = (I80F48::from_num(2_000), I80F48::from_num(100));
+    assert_eq!(
+        price_impact_cost(notional, price, I80F48::from_num(110), true).unwrap(),
+        I80F48::from_num(200)
+    );
+    assert_eq!(
+        price_impact_cost(notional, price, I80F48::from_num(90), false).unwrap(),
+        I80F48::from_num(200)
+    );
+    // Better fills than the price aren't credited
+    assert_eq!(
+        price_impact_cost(notional, price, I80F48::from_num(90), true).unwrap(),
+        I80F48::from_num(0)
+    );
+    assert_quasar_err!(
+        price_impact_cost(notional, I80F48::from_num(0), price, true),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_large_mint_on_shallow_book_gets_fewer_tokens() {
+    // 10 lots at the oracle price, then the book thins out. One native unit per lot, so lot
+    // prices are native prices and notionals divide into base lots directly
+    let asks = [(100, 10), (120, 10), (200, 100)];
+    let (nav, leverage, price) = (
+        I80F48::from_num(10),
+        I80F48::from_num(2),
+        I80F48::from_num(100),
+    );
+    let mint_cost = |tokens: u64| {
+        let collateral = collateral_for_tokens(nav, tokens, DECIMALS, 0).unwrap();
+        let notional = I80F48::from_num(collateral) * leverage;
+        let fill_price = average_fill_price(asks.iter().copied(), notional / price)
+            .unwrap()
+            .unwrap();
+        let price_impact = price_impact_cost(notional, price, fill_price, true).unwrap();
+        collateral + price_impact.ceil().to_num::<u64>()
+    };
+
+    // A small mint fills at the oracle price and costs its NAV
+    assert_eq!(mint_cost(10), 100);
+
+    // A large one takes 10 lots at 100 and 10 at 120, paying the slippage on top
+    let cost = mint_cost(100);
+    assert_eq!(cost, 1_200);
+    // which at spot NAV would have minted more
+    assert_eq!(tokens_for_collateral(nav, cost, DECIMALS, 0).unwrap(), 120);
+}
@@ -0,0 +1,288 @@
+use assert_matches::*;
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+#[cfg(feature = "anchor-discriminator")]
+use quasar::utils::account_discriminator;
+use quasar::{
+    error::{QuasarError, QuasarErrorCode},
+    oracle::{
+        cast, median_price, oracle_quorum, read_quorum_price, OraclePrice, Price, StubOracle,
+        MAGIC, MAX_ORACLES_PER_READ, STUB_ORACLE_MAGIC,
+    },
+};
+use solana_program::{account_info::AccountInfo, pubkey::Pubkey};
+use std::mem::size_of;
+
+macro_rules! assert_quasar_err {
+    ($result:expr, $code:pat) => {
+        assert_matches!(
+            $result,
+            Err(QuasarError::QuasarErrorCode {
+                quasar_error_code: $code,
+                ..
+            })
+        )
+    };
+}
+
+fn price(value: f64) -> Option<I80F48> {
+    Some(I80F48::from_num(value))
+}
+
+#[test]
+fn test_oracle_quorum() {
+    assert_eq!(oracle_quorum(1), 1);
+    assert_eq!(oracle_quorum(2), 2);
+    assert_eq!(oracle_quorum(3), 2);
+}
+
+#[test]
+fn test_median_price() {
+    assert_eq!(
+        median_price(&[price(101.0), price(99.0), price(250.0)]).unwrap(),
+        I80F48::from_num(101)
+    );
+    assert_eq!(median_price(&[price(42.0)]).unwrap(), I80F48::from_num(42));
+
+    // One of three oracles is stale, the other two still make a quorum
+    assert_eq!(
+        median_price(&[price(100.0), None, price(102.0)]).unwrap(),
+        I80F48::from_num(101)
+    );
+}
+
+#[test]
+fn test_median_price_needs_quorum() {
+    assert_quasar_err!(
+        median_price(&[price(100.0), None, None]),
+        QuasarErrorCode::OracleQuorumNotMet
+    );
+    assert_quasar_err!(median_price(&[None]), QuasarErrorCode::OracleQuorumNotMet);
+    assert_quasar_err!(median_price(&[]), QuasarErrorCode::OracleQuorumNotMet);
+}
+
+#[test]
+fn test_read_quorum_price_stops_at_quorum() {
+    let prices = [price(100.0), price(100.5), price(250.0)];
+    let mut read = vec![];
+    let median = read_quorum_price(3, |i| {
+        read.push(i);
+        Ok(prices[i])
+    })
+    .unwrap();
+    // Two of three fresh prices that agree are a quorum, the third oracle is left alone
+    assert_eq!(read, vec![0, 1]);
+    assert_eq!(median, I80F48::from_num(100.25));
+
+    // With the first one stale, the last one is needed
+    let prices = [None, price(102.0), price(250.0)];
+    let mut read = vec![];
+    let median = read_quorum_price(3, |i| {
+        read.push(i);
+        Ok(prices[i])
+    })
+    .unwrap();
+    assert_eq!(read, vec![0, 1, 2]);
+    assert_eq!(median, I80F48::from_num(176));
+
+    // Two stale oracles leave no quorum to wait for
+    let prices = [None, None, price(250.0)];
+    let mut read = vec![];
+    assert_quasar_err!(
+        read_quorum_price(3, |i| {
+            read.push(i);
+            Ok(prices[i])
+        }),
+        QuasarErrorCode::OracleQuorumNotMet
+    );
+    assert_eq!(read, vec![0, 1]);
+
+    assert_quasar_err!(
+        read_quorum_price(MAX_ORACLES_PER_READ + 1, |_| Ok(price(100.0))),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_read_quorum_price_reads_past_an_outlier() {
+    // The second oracle is off, so the third one settles the median instead of the outlier
+    // pulling it halfway
+    let prices = [price(100.0), price(250.0), price(101.0)];
+    let mut read = vec![];
+    let median = read_quorum_price(3, |i| {
+        read.push(i);
+        Ok(prices[i])
+    })
+    .unwrap();
+    assert_eq!(read, vec![0, 1, 2]);
+    assert_eq!(median, I80F48::from_num(101));
+
+    // A quorum of every oracle can't read any further and takes the median as it is
+    let prices = [price(100.0), price(250.0)];
+    assert_eq!(
+        read_quorum_price(2, |i| Ok(prices[i])).unwrap(),
+        I80F48::from_num(175)
+    );
+}
+
+#[test]
+fn test_pyth_ema_price() {
+    // Zeroed words keep the account data aligned for the cast
+    let words = vec![0u64; size_of::<Price>() / 8 + 1];
+    let mut price = *cast::<Price>(bytemuck::cast_slice(&words));
+    price.expo = -6;
+    price.agg.price = 101_500_000;
+    price.twap = 100_250_000;
+
+    // Both are scaled by the same exponent
+    assert_eq!(price.scaled_price(false).unwrap(), I80F48::from_num(101.5));
+    assert_eq!(price.scaled_price(true).unwrap(), I80F48::from_num(100.25));
+
+    price.expo = 2;
+    price.agg.price = 3;
+    price.twap = 4;
+    assert_eq!(price.scaled_price(false).unwrap(), I80F48::from_num(300));
+    assert_eq!(price.scaled_price(true).unwrap(), I80F48::from_num(400));
+}
+
+#[test]
+fn test_pyth_price_overflow_is_an_error() {
+    let words = vec![0u64; size_of::<Price>() / 8 + 1];
+    let mut price = *cast::<Price>(bytemuck::cast_slice(&words));
+    price.agg.price = i64::MAX;
+    price.expo = 15;
+    assert_quasar_err!(price.scaled_price(false), QuasarErrorCode::MathOverflow);
+    // Past what 10 to the power of the exponent fits in
+    price.agg.price = 1;
+    price.expo = -20;
+    assert_quasar_err!(price.scaled_price(false), QuasarErrorCode::MathOverflow);
+    assert_quasar_err!(price.base_token_price(false), QuasarErrorCode::MathOverflow);
+}
+
+#[cfg(feature = "debug-errors")]
+#[test]
+fn test_pyth_price_failure_logs_intermediates() {
+    use solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+    use std::sync::{Arc, Mutex};
+
+    struct LogCapture(Arc<Mutex<Vec<String>>>);
+    impl SyscallStubs for LogCapture {
+        fn sol_log(&self, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    set_syscall_stubs(Box::new(LogCapture(logs.clone())));
+
+    let words = vec![0u64; size_of::<Price>() / 8 + 1];
+    let mut price = *cast::<Price>(bytemuck::cast_slice(&words));
+    price.agg.price = i64::MAX;
+    price.twap = 7;
+    price.expo = 15;
+    assert_quasar_err!(price.base_token_price(false), QuasarErrorCode::MathOverflow);
+
+    let expected = format!(
+        "pyth price failed: price {} twap 7 expo 15 use_ema false",
+        i64::MAX
+    );
+    assert!(logs.lock().unwrap().contains(&expected));
+}
+
+#[test]
+fn test_oracle_price_conversions() {
+    // 25.5 USDC (6 decimals) per SOL (9 decimals)
+    let price = OraclePrice::new(I80F48::from_num(25.5), 9);
+
+    assert_eq!(
+        price.native_price(6).unwrap(),
+        I80F48::from_num(25_500_000) / I80F48::from_num(1_000_000_000u64)
+    );
+    // 2 SOL are worth 51 USDC
+    assert_eq!(price.native_value(2_000_000_000, 6).unwrap(), 51_000_000);
+    // Rounded down to the native unit
+    assert_eq!(price.native_value(1, 6).unwrap(), 0);
+    assert_eq!(
+        price.base_for_collateral(51_000_000, 6).unwrap(),
+        2_000_000_000
+    );
+    assert_eq!(price.base_for_collateral(0, 6).unwrap(), 0);
+
+    // Same price for a base token with no decimals
+    let price = OraclePrice::new(I80F48::from_num(25.5), 0);
+    assert_eq!(price.native_value(2, 6).unwrap(), 51_000_000);
+    assert_eq!(price.base_for_collateral(50_999_999, 6).unwrap(), 1);
+
+    assert_quasar_err!(
+        OraclePrice::new(I80F48::from_num(0), 9).base_for_collateral(1, 6),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_oracle_price_from_native() {
+    // Mango caches 25.5 USDC per SOL as 0.0255 native USDC per lamport
+    let native = I80F48::from_num(25_500_000) / I80F48::from_num(1_000_000_000u64);
+    let price = OraclePrice::from_native(native, 9, 6).unwrap();
+    assert_eq!(price.base_decimals, 9);
+    assert_eq!(price.native_price(6).unwrap(), native);
+    assert!((price.value - I80F48::from_num(25.5)).abs() < I80F48::from_num(0.000_001));
+
+    // A base token with fewer decimals than the quote token
+    let price = OraclePrice::from_native(I80F48::from_num(25_500_000), 0, 6).unwrap();
+    assert_eq!(price, OraclePrice::new(I80F48::from_num(25.5), 0));
+    assert_eq!(price.native_price(6).unwrap(), I80F48::from_num(25_500_000));
+
+    let price = OraclePrice::from_native(I80F48::from_num(42), 6, 6).unwrap();
+    assert_eq!(price, OraclePrice::new(I80F48::from_num(42), 6));
+}
+
+#[test]
+fn test_stub_oracle_load_checks_magic() {
+    let key = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let mut lamports = 0;
+    let mut oracle = StubOracle::zeroed();
+    #[cfg(feature = "anchor-discriminator")]
+    {
+        oracle.discriminator = account_discriminator("StubOracle");
+    }
+    oracle.price = I80F48::from_num(42);
+
+    oracle.magic = STUB_ORACLE_MAGIC;
+    let mut data = bytemuck::bytes_of(&oracle).to_vec();
+    let oracle_ai = AccountInfo::new(
+        &key,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &owner,
+        false,
+        0,
+    );
+    assert_eq!(
+        StubOracle::load_checked(&oracle_ai).unwrap().price,
+        I80F48::from_num(42)
+    );
+
+    // A zeroed account, or a Pyth one of the same size, is no stub with a price of zero
+    for &magic in &[0, MAGIC] {
+        oracle.magic = magic;
+        let mut data = bytemuck::bytes_of(&oracle).to_vec();
+        let mut lamports = 0;
+        let oracle_ai = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+        assert_quasar_err!(
+            StubOracle::load_checked(&oracle_ai).map(|_| ()),
+            QuasarErrorCode::InvalidStubOracle
+        );
+    }
+}
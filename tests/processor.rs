@@ -0,0 +1,4298 @@
+use assert_matches::*;
+use borsh::BorshSerialize;
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use mango::{
+    instruction::MangoInstruction,
+    matching::Side,
+    state::{
+        DataType as MangoDataType, MangoAccount, MangoCache, MangoGroup, MetaData as MangoMetaData,
+        NodeBank, RootBank, MAX_PAIRS, QUOTE_INDEX,
+    },
+};
+use quasar::{
+    error::{QuasarError, QuasarErrorCode, SourceFileId},
+    events::{GroupSummary, EVENT_LOG_PREFIX},
+    instruction::{BatchMintEntry, QuasarInstruction, MAX_BATCH_MINTS},
+    oracle::OraclePrice,
+    processor::Processor,
+    state::{
+        BaseToken, DataType, HoldRecord, LeverageToken, MetaData, QuasarGroup, RedeemRequest,
+        INITIAL_LEVERAGE_TOKEN_PRICE, MAX_BASE_TOKENS, MAX_CRANK_REWARD_BPS,
+        MAX_LEGS_PER_REBALANCE, MAX_LEVERAGE_TOKEN_DECIMALS, MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN,
+        MAX_REBALANCE_ORDER_ATTEMPTS, MAX_REDEEM_REQUESTS, MIN_DELEVERAGE_INTERVAL_SECS,
+        MIN_INITIAL_MINT_QUANTITY, QUASAR_GROUP_V0_LEN, QUASAR_GROUP_V3_LEN, QUASAR_GROUP_VERSION,
+        REBALANCE_BACKOFF_BASE_SECS,
+    },
+    utils::{
+        gen_hold_record_key, gen_leverage_token_mint_key, gen_mango_account_key,
+        gen_redeem_escrow_key, gen_signer_key, quasar_group_signer,
+    },
+};
+use solana_program::{
+    account_info::AccountInfo,
+    clock::Clock,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE, SUCCESS},
+    instruction::Instruction,
+    program_error::ProgramError,
+    program_option::COption,
+    program_pack::Pack,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_program,
+};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::state::{Account as TokenAccount, AccountState, Mint};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    convert::TryInto,
+    mem::size_of,
+    sync::Once,
+};
+
+macro_rules! assert_quasar_err {
+    ($result:expr, $code:pat) => {
+        assert_matches!(
+            $result,
+            Err(QuasarError::QuasarErrorCode {
+                quasar_error_code: $code,
+                ..
+            })
+        )
+    };
+}
+
+thread_local! {
+    /// Program running the instruction, the one the PDAs of its signer seeds are derived from
+    static PROGRAM_ID: Cell<Pubkey> = Cell::new(Pubkey::default());
+    static UNIX_TIMESTAMP: Cell<i64> = Cell::new(1_600_000_000);
+    static LOGS: RefCell<Vec<String>> = RefCell::new(vec![]);
+    /// Every instruction the handlers called into, Mango's included
+    static INVOKED: RefCell<Vec<Instruction>> = RefCell::new(vec![]);
+    /// Base lots the next perp orders placed on Mango fill, one entry per order, at the order's
+    /// price. Orders past the last entry don't fill
+    static PERP_FILLS: RefCell<VecDeque<i64>> = RefCell::new(VecDeque::new());
+}
+
+/// The runtime as the handlers see it off chain. The clock and rent sysvars are served, logs
+/// kept, and calls into the system, token and associated token programs run, signatures
+/// checked. Calls into any other program are recorded, see `invoked`, and those into Mango
+/// play out on the Mango account as far as `process_mango_instruction` goes
+struct TestRuntime;
+
+impl SyscallStubs for TestRuntime {
+    fn sol_log(&self, message: &str) {
+        LOGS.with(|logs| logs.borrow_mut().push(message.to_string()));
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        INVOKED.with(|invoked| invoked.borrow_mut().push(instruction.clone()));
+        let stubbed_programs = [
+            system_program::id(),
+            spl_token::id(),
+            spl_associated_token_account::id(),
+        ];
+        if !stubbed_programs.contains(&instruction.program_id) {
+            process_mango_instruction(instruction, account_infos);
+            return Ok(());
+        }
+
+        let caller = PROGRAM_ID.with(Cell::get);
+        let pdas: Vec<Pubkey> = signers_seeds
+            .iter()
+            .filter_map(|seeds| Pubkey::create_program_address(seeds, &caller).ok())
+            .collect();
+        let mut accounts = vec![];
+        for meta in instruction.accounts.iter() {
+            let mut account = account_infos
+                .iter()
+                .find(|account| *account.key == meta.pubkey)
+                .unwrap_or_else(|| panic!("{} not passed along", meta.pubkey))
+                .clone();
+            if meta.is_signer && !account.is_signer && !pdas.contains(account.key) {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            account.is_signer = meta.is_signer;
+            accounts.push(account);
+        }
+
+        PROGRAM_ID.with(|program_id| program_id.set(instruction.program_id));
+        let result = if instruction.program_id == system_program::id() {
+            process_system_instruction(&accounts, &instruction.data)
+        } else if instruction.program_id == spl_token::id() {
+            spl_token::processor::Processor::process(
+                &instruction.program_id,
+                &accounts,
+                &instruction.data,
+            )
+        } else {
+            spl_associated_token_account::processor::process_instruction(
+                &instruction.program_id,
+                &accounts,
+                &instruction.data,
+            )
+        };
+        PROGRAM_ID.with(|program_id| program_id.set(caller));
+        result
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let clock = Clock {
+            unix_timestamp: UNIX_TIMESTAMP.with(Cell::get),
+            ..Clock::default()
+        };
+        unsafe { *(var_addr as *mut Clock) = clock };
+        SUCCESS
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        SUCCESS
+    }
+}
+
+/// What the stubbed Mango program does with an instruction: a `Deposit` is credited to the
+/// quote deposits of the Mango account and a `Withdraw` debited, a `PlacePerpOrder` fills the
+/// next of `PERP_FILLS` on the perp market it's placed on, a `SettlePnl` moves the whole quote
+/// position of its market into the quote deposits. Anything else does nothing
+fn process_mango_instruction(instruction: &Instruction, account_infos: &[AccountInfo]) {
+    let mango_account_ai = match instruction.accounts.get(1).and_then(|meta| {
+        account_infos
+            .iter()
+            .find(|account| *account.key == meta.pubkey)
+    }) {
+        Some(account) if account.data_len() == size_of::<MangoAccount>() => account,
+        _ => return,
+    };
+    let mut data = mango_account_ai.data.borrow_mut();
+    let mut mango_account = MangoAccount::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_account).copy_from_slice(&data);
+
+    match MangoInstruction::unpack(&instruction.data) {
+        Some(MangoInstruction::Deposit { quantity }) => {
+            mango_account.deposits[QUOTE_INDEX] += I80F48::from_num(quantity);
+        }
+        Some(MangoInstruction::Withdraw { quantity, .. }) => {
+            mango_account.deposits[QUOTE_INDEX] -= I80F48::from_num(quantity);
+        }
+        Some(MangoInstruction::PlacePerpOrder { price, side, .. }) => {
+            let fill = match PERP_FILLS.with(|fills| fills.borrow_mut().pop_front()) {
+                Some(fill) => fill,
+                None => return,
+            };
+            let base_change = match side {
+                Side::Bid => fill,
+                Side::Ask => -fill,
+            };
+            let mango_group_ai = account_infos
+                .iter()
+                .find(|account| *account.key == instruction.accounts[0].pubkey)
+                .unwrap();
+            let mut mango_group = MangoGroup::zeroed();
+            bytemuck::bytes_of_mut(&mut mango_group).copy_from_slice(&mango_group_ai.data.borrow());
+            let market_index = mango_group
+                .find_perp_market_index(&instruction.accounts[4].pubkey)
+                .unwrap();
+            let perp_account = &mut mango_account.perp_accounts[market_index];
+            perp_account.base_position += base_change;
+            perp_account.quote_position -= I80F48::from_num(base_change * price);
+        }
+        Some(MangoInstruction::SettlePnl { market_index }) => {
+            let perp_account = &mut mango_account.perp_accounts[market_index];
+            mango_account.deposits[QUOTE_INDEX] += perp_account.quote_position;
+            perp_account.quote_position = I80F48::from_num(0);
+        }
+        _ => return,
+    }
+    data.copy_from_slice(bytemuck::bytes_of(&mango_account));
+}
+
+/// What the system program does with the instructions the handlers send it
+fn process_system_instruction(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    // Accounts already in use, and lamports the sender doesn't have
+    const ACCOUNT_ALREADY_IN_USE: u32 = 0;
+    const RESULT_WITH_NEGATIVE_LAMPORTS: u32 = 1;
+    let transfer = |from: &AccountInfo, to: &AccountInfo, lamports: u64| {
+        if !from.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if from.lamports() < lamports {
+            return Err(ProgramError::Custom(RESULT_WITH_NEGATIVE_LAMPORTS));
+        }
+        **from.lamports.borrow_mut() -= lamports;
+        **to.lamports.borrow_mut() += lamports;
+        Ok(())
+    };
+    let allocate = |account: &AccountInfo, space: usize| {
+        if !account.data_is_empty() || *account.owner != system_program::id() {
+            return Err(ProgramError::Custom(ACCOUNT_ALREADY_IN_USE));
+        }
+        *account.data.borrow_mut() = Box::leak(vec![0; space].into_boxed_slice());
+        Ok(())
+    };
+    // The owner of an `AccountInfo` is behind a shared reference, solana-program-test writes
+    // through it the same way
+    let assign = |account: &AccountInfo, owner: Pubkey| unsafe {
+        std::ptr::write(account.owner as *const Pubkey as *mut Pubkey, owner)
+    };
+
+    let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let pubkey_at = |offset: usize| Pubkey::new(&data[offset..offset + 32]);
+    match u32::from_le_bytes(data[..4].try_into().unwrap()) {
+        // CreateAccount { lamports, space, owner }
+        0 => {
+            if accounts[1].lamports() > 0 {
+                return Err(ProgramError::Custom(ACCOUNT_ALREADY_IN_USE));
+            }
+            transfer(&accounts[0], &accounts[1], u64_at(4))?;
+            allocate(&accounts[1], u64_at(12) as usize)?;
+            assign(&accounts[1], pubkey_at(20));
+        }
+        // Assign { owner }
+        1 => assign(&accounts[0], pubkey_at(4)),
+        // Transfer { lamports }
+        2 => transfer(&accounts[0], &accounts[1], u64_at(4))?,
+        // Allocate { space }
+        8 => allocate(&accounts[0], u64_at(4) as usize)?,
+        variant => panic!("system instruction {} isn't stubbed", variant),
+    }
+    Ok(())
+}
+
+/// `Processor::process` on `TestRuntime`. Every test of this file goes through it, once
+/// installed the stubs are there for all of them
+fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> Result<(), QuasarError> {
+    static INSTALL_RUNTIME: Once = Once::new();
+    INSTALL_RUNTIME.call_once(|| {
+        set_syscall_stubs(Box::new(TestRuntime));
+    });
+    PROGRAM_ID.with(|id| id.set(*program_id));
+    Processor::process(program_id, accounts, instruction_data)
+}
+
+fn set_unix_timestamp(unix_timestamp: i64) {
+    UNIX_TIMESTAMP.with(|ts| ts.set(unix_timestamp));
+}
+
+/// Instructions the handlers called into since the last call, in order
+fn invoked() -> Vec<Instruction> {
+    INVOKED.with(|invoked| invoked.replace(vec![]))
+}
+
+/// Lines logged since the last call
+fn logs() -> Vec<String> {
+    LOGS.with(|logs| logs.replace(vec![]))
+}
+
+/// The rent sysvar account, read by the token program
+fn rent_sysvar_account() -> TestAccount {
+    let rent = Rent::default();
+    // Its bincode serialization
+    let mut data = rent.lamports_per_byte_year.to_le_bytes().to_vec();
+    data.extend_from_slice(&rent.exemption_threshold.to_le_bytes());
+    data.push(rent.burn_percent);
+    TestAccount {
+        data,
+        owner: solana_program::sysvar::id(),
+        ..TestAccount::new(solana_program::sysvar::rent::id())
+    }
+}
+
+struct TestAccount {
+    key: Pubkey,
+    lamports: u64,
+    data: Vec<u8>,
+    owner: Pubkey,
+    is_signer: bool,
+    is_writable: bool,
+    executable: bool,
+}
+
+impl TestAccount {
+    fn new(key: Pubkey) -> Self {
+        Self {
+            key,
+            lamports: 0,
+            data: vec![],
+            owner: Pubkey::default(),
+            is_signer: false,
+            is_writable: true,
+            executable: false,
+        }
+    }
+
+    fn info(&mut self) -> AccountInfo {
+        AccountInfo::new(
+            &self.key,
+            self.is_signer,
+            self.is_writable,
+            &mut self.lamports,
+            &mut self.data,
+            &self.owner,
+            self.executable,
+            0,
+        )
+    }
+}
+
+/// An account laid out the way the entrypoint hands accounts to the program, its length right
+/// before its data and `MAX_PERMITTED_DATA_INCREASE` bytes of room after it, so it can be grown
+struct SerializedAccount {
+    key: Pubkey,
+    lamports: u64,
+    /// Length, data and room to grow
+    buffer: Vec<u8>,
+    owner: Pubkey,
+}
+
+impl SerializedAccount {
+    fn new(account: TestAccount) -> Self {
+        let mut buffer = (account.data.len() as u64).to_le_bytes().to_vec();
+        buffer.extend_from_slice(&account.data);
+        buffer.resize(buffer.len() + MAX_PERMITTED_DATA_INCREASE, 0);
+        Self {
+            key: account.key,
+            lamports: account.lamports,
+            buffer,
+            owner: account.owner,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        let len = u64::from_le_bytes(self.buffer[..8].try_into().unwrap()) as usize;
+        &self.buffer[8..8 + len]
+    }
+
+    fn info(&mut self) -> AccountInfo {
+        let len = self.data().len();
+        AccountInfo::new(
+            &self.key,
+            false,
+            true,
+            &mut self.lamports,
+            &mut self.buffer[8..8 + len],
+            &self.owner,
+            false,
+            0,
+        )
+    }
+}
+
+/// Accounts with the roles the fixed accounts of `instruction` document, followed by `extra`
+/// more
+fn accounts_for(instruction: &QuasarInstruction, extra: usize) -> Vec<TestAccount> {
+    let fixed = instruction
+        .fixed_accounts()
+        .iter()
+        .map(|descriptor| TestAccount {
+            is_signer: descriptor.is_signer,
+            ..TestAccount::new(Pubkey::new_unique())
+        });
+    let extra = (0..extra).map(|_| TestAccount::new(Pubkey::new_unique()));
+    fixed.chain(extra).collect()
+}
+
+/// Initialized group owned by `program_id`, with the signer its key derives, for handlers
+/// whose calls into the token program sign as it
+fn group_account_with_signer(program_id: &Pubkey, quasar_group: &mut QuasarGroup) -> TestAccount {
+    let key = Pubkey::new_unique();
+    let (signer_nonce, signer_key) = (0..=u64::from(u8::MAX))
+        .find_map(|nonce| {
+            gen_signer_key(nonce, &key, program_id)
+                .ok()
+                .map(|signer_key| (nonce, signer_key))
+        })
+        .unwrap();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.signer_nonce = signer_nonce;
+    quasar_group.signer_key = signer_key;
+    quasar_group.init_discriminator();
+    TestAccount {
+        data: bytemuck::bytes_of(quasar_group).to_vec(),
+        owner: *program_id,
+        ..TestAccount::new(key)
+    }
+}
+
+/// A Mango account of `mango_group` owned by `owner`
+fn mango_account(mango_program_id: &Pubkey, mango_group: &Pubkey, owner: &Pubkey) -> TestAccount {
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    mango_account.mango_group = *mango_group;
+    mango_account.owner = *owner;
+    TestAccount {
+        data: bytemuck::bytes_of(&mango_account).to_vec(),
+        owner: *mango_program_id,
+        ..TestAccount::new(Pubkey::new_unique())
+    }
+}
+
+/// Accounts for a mint `instruction`, with a group holding the leverage token being minted and
+/// the owner's quote token account filled in, followed by `extra` more
+fn mint_accounts(
+    program_id: &Pubkey,
+    instruction: &QuasarInstruction,
+    extra: usize,
+) -> Vec<TestAccount> {
+    let mut accounts = accounts_for(instruction, extra);
+    let (mint_key, mango_program_id, mango_group_key, mango_account_key, owner_key, signer_key) = (
+        accounts[1].key,
+        accounts[3].key,
+        accounts[4].key,
+        accounts[5].key,
+        accounts[6].key,
+        accounts[13].key,
+    );
+    let quote_mint = Pubkey::new_unique();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.mango_program_id = mango_program_id;
+    quasar_group.mango_group = mango_group_key;
+    quasar_group.signer_key = signer_key;
+    quasar_group.quote_mint = quote_mint;
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: mint_key,
+        mango_account: mango_account_key,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_from(program_id, &quasar_group);
+
+    accounts[1].data = vec![0; Mint::LEN];
+    let mint = Mint {
+        mint_authority: COption::Some(signer_key),
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+
+    accounts[12].data = vec![0; TokenAccount::LEN];
+    let quote_token_account = TokenAccount {
+        mint: quote_mint,
+        owner: owner_key,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+    TokenAccount::pack(quote_token_account, &mut accounts[12].data).unwrap();
+
+    accounts[5] = TestAccount {
+        key: mango_account_key,
+        ..mango_account(&mango_program_id, &mango_group_key, &signer_key)
+    };
+
+    // The quote token's banks on the Mango group, the collateral is deposited into its vault
+    let (root_bank_key, node_bank_key, vault_key) =
+        (accounts[8].key, accounts[9].key, accounts[10].key);
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.tokens[QUOTE_INDEX].mint = quote_mint;
+    mango_group.tokens[QUOTE_INDEX].root_bank = root_bank_key;
+    let mut root_bank = RootBank::zeroed();
+    root_bank.meta_data = MangoMetaData::new(MangoDataType::RootBank, 0, true);
+    root_bank.node_banks[0] = node_bank_key;
+    root_bank.num_node_banks = 1;
+    let mut node_bank = NodeBank::zeroed();
+    node_bank.meta_data = MangoMetaData::new(MangoDataType::NodeBank, 0, true);
+    node_bank.vault = vault_key;
+    for (i, data) in [
+        (4, bytemuck::bytes_of(&mango_group).to_vec()),
+        (8, bytemuck::bytes_of(&root_bank).to_vec()),
+        (9, bytemuck::bytes_of(&node_bank).to_vec()),
+    ]
+    .iter()
+    {
+        accounts[*i].data = data.clone();
+        accounts[*i].owner = mango_program_id;
+    }
+
+    accounts
+}
+
+/// Accounts of a `MintLeverageToken` that goes through on the stubbed runtime: the owner has
+/// `MINTABLE_QUOTE` to pay from and a leverage token account to mint to, the token has no perp
+/// markets and a `supply` minted already. The Mango account's NAV is its perp quote position on
+/// market 0, which the test sets, and the quote token has no decimals, so every token of a supply
+/// costs a share of that NAV in native quote
+fn mintable_accounts(
+    program_id: &Pubkey,
+    instruction: &QuasarInstruction,
+    supply: u64,
+) -> Vec<TestAccount> {
+    let mut accounts = mint_accounts(program_id, instruction, 0);
+    let (mint_key, mango_program_id, mango_group_key, owner_key) = (
+        accounts[1].key,
+        accounts[3].key,
+        accounts[4].key,
+        accounts[6].key,
+    );
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    accounts[0] = group_account_with_signer(program_id, &mut quasar_group);
+    let signer_key = quasar_group.signer_key;
+
+    let mint = Mint {
+        mint_authority: COption::Some(signer_key),
+        supply,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+    accounts[1].owner = spl_token::id();
+
+    accounts[2].key = get_associated_token_address(&owner_key, &mint_key);
+    accounts[2].data = vec![0; TokenAccount::LEN];
+    let leverage_token_account = TokenAccount {
+        mint: mint_key,
+        owner: owner_key,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+    TokenAccount::pack(leverage_token_account, &mut accounts[2].data).unwrap();
+    accounts[2].owner = spl_token::id();
+
+    accounts[5] = TestAccount {
+        key: accounts[5].key,
+        ..mango_account(&mango_program_id, &mango_group_key, &signer_key)
+    };
+    accounts[6].lamports = 1_000_000_000;
+
+    // A Mango group of one market with unit lots, and a cache of it that never goes stale
+    let mut mango_group = MangoGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_group).copy_from_slice(&accounts[4].data);
+    mango_group.num_oracles = 1;
+    mango_group.perp_markets[0].base_lot_size = 1;
+    mango_group.perp_markets[0].quote_lot_size = 1;
+    mango_group.mango_cache = accounts[7].key;
+    mango_group.valid_interval = u64::MAX;
+    accounts[4].data = bytemuck::bytes_of(&mango_group).to_vec();
+    accounts[4].owner = mango_program_id;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.meta_data = MangoMetaData::new(MangoDataType::MangoCache, 0, true);
+    mango_cache.price_cache[0].price = I80F48::from_num(1);
+    mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index = I80F48::from_num(1);
+    accounts[7].data = bytemuck::bytes_of(&mango_cache).to_vec();
+    accounts[7].owner = mango_program_id;
+
+    accounts[11].key = spl_token::id();
+    let mut quote_token_account = TokenAccount::unpack(&accounts[12].data).unwrap();
+    quote_token_account.amount = MINTABLE_QUOTE;
+    TokenAccount::pack(quote_token_account, &mut accounts[12].data).unwrap();
+    accounts[12].owner = spl_token::id();
+    accounts[13].key = signer_key;
+    accounts[14].key = system_program::id();
+    accounts[15].key = spl_associated_token_account::id();
+    accounts[16] = rent_sysvar_account();
+    accounts[17].key = get_associated_token_address(&signer_key, &mint_key);
+    accounts
+}
+
+/// Quote the owner of `mintable_accounts` has to mint with
+const MINTABLE_QUOTE: u64 = 1_000_000;
+
+/// Change the group in `accounts[0]`
+fn update_group(accounts: &mut [TestAccount], update: impl FnOnce(&mut QuasarGroup)) {
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    update(&mut quasar_group);
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+}
+
+/// Change the Mango account in `accounts[5]`
+fn update_mango_account(accounts: &mut [TestAccount], update: impl FnOnce(&mut MangoAccount)) {
+    let mut mango_account = MangoAccount::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_account).copy_from_slice(&accounts[5].data);
+    update(&mut mango_account);
+    accounts[5].data = bytemuck::bytes_of(&mango_account).to_vec();
+}
+
+/// Quote deposited into Mango, as passed to the Mango program since the last call
+fn deposited(mango_program_id: &Pubkey) -> Vec<u64> {
+    invoked()
+        .iter()
+        .filter(|instruction| instruction.program_id == *mango_program_id)
+        .filter_map(
+            |instruction| match MangoInstruction::unpack(&instruction.data) {
+                Some(MangoInstruction::Deposit { quantity }) => Some(quantity),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Quote withdrawn from Mango, as passed to the Mango program since the last call
+fn withdrawn(mango_program_id: &Pubkey) -> Vec<u64> {
+    invoked()
+        .iter()
+        .filter(|instruction| instruction.program_id == *mango_program_id)
+        .filter_map(
+            |instruction| match MangoInstruction::unpack(&instruction.data) {
+                Some(MangoInstruction::Withdraw { quantity, .. }) => Some(quantity),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Accounts of a `BurnLeverageToken` by the owner of `mintable_accounts`, paid out to their
+/// quote token account, taken out of `accounts`
+fn burnable_accounts(accounts: &mut [TestAccount]) -> Vec<TestAccount> {
+    let mut take =
+        |i: usize| std::mem::replace(&mut accounts[i], TestAccount::new(Pubkey::default()));
+    let mut burn_accounts: Vec<TestAccount> = (0..14).map(&mut take).collect();
+    burn_accounts.push(TestAccount::new(Pubkey::new_unique())); // mango_signer
+    burn_accounts.push(take(17));
+    burn_accounts.push(take(18));
+    burn_accounts.extend((0..MAX_PAIRS).map(|_| TestAccount::new(Pubkey::new_unique())));
+    burn_accounts
+}
+
+/// `mintable_accounts` once the owner minted `quantity` of the token at the bootstrap price,
+/// its collateral carried on the perp quote position the stubbed NAV counts
+fn minted_accounts(program_id: &Pubkey, quantity: u64) -> Vec<TestAccount> {
+    let instruction = QuasarInstruction::MintLeverageToken { quantity };
+    let mut accounts = mintable_accounts(program_id, &instruction, 0);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(program_id, &account_infos, &instruction.pack()).unwrap();
+    }
+    update_mango_account(&mut accounts, |mango_account| {
+        mango_account.perp_accounts[0].quote_position =
+            I80F48::from_num(quantity * INITIAL_LEVERAGE_TOKEN_PRICE)
+    });
+    accounts
+}
+
+#[test]
+fn test_burn_pays_out_in_the_quote_mint() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts = minted_accounts(&program_id, 2 * MIN_INITIAL_MINT_QUANTITY);
+    let mango_program_id = accounts[3].key;
+    let quote_mint = TokenAccount::unpack(&accounts[12].data).unwrap().mint;
+    let base_mint = Pubkey::new_unique();
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.base_tokens[0] = BaseToken {
+            mint: base_mint,
+            ..BaseToken::zeroed()
+        };
+        quasar_group.num_base_tokens = 1;
+    });
+    let mut accounts = burnable_accounts(&mut accounts);
+    let quantity = MIN_INITIAL_MINT_QUANTITY;
+    let burn = |output_mint: Pubkey| {
+        QuasarInstruction::BurnLeverageToken {
+            quantity,
+            output_mint: Some(output_mint),
+        }
+        .pack()
+    };
+    invoked();
+
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        // A base token of the group, which the quote collateral would have to be swapped into
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &burn(base_mint)),
+            QuasarErrorCode::UnsupportedOutputMint
+        );
+        // and a mint the group doesn't know at all
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &burn(Pubkey::new_unique())),
+            QuasarErrorCode::InvalidToken
+        );
+        assert!(invoked().is_empty());
+
+        // The quote mint asked for explicitly, same as leaving it out
+        process_instruction(&program_id, &account_infos, &burn(quote_mint)).unwrap();
+    }
+    assert_eq!(
+        withdrawn(&mango_program_id),
+        vec![quantity * INITIAL_LEVERAGE_TOKEN_PRICE]
+    );
+    assert_eq!(
+        TokenAccount::unpack(&accounts[2].data).unwrap().amount,
+        quantity
+    );
+    assert_eq!(Mint::unpack(&accounts[1].data).unwrap().supply, quantity);
+}
+
+#[test]
+fn test_mint_and_burn_refuse_dust() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts = minted_accounts(&program_id, MIN_INITIAL_MINT_QUANTITY);
+    let mango_program_id = accounts[3].key;
+    // The token lost half its NAV, a native unit of it is worth half a native quote unit
+    update_mango_account(&mut accounts, |mango_account| {
+        mango_account.perp_accounts[0].quote_position =
+            I80F48::from_num(MIN_INITIAL_MINT_QUANTITY / 2)
+    });
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.leverage_tokens[0].min_mint_quantity = 10
+    });
+
+    // Mints under the token's floor
+    let instruction = QuasarInstruction::MintLeverageToken { quantity: 9 };
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::MintTooSmall
+        );
+    }
+
+    // and burns whose collateral rounds down to nothing
+    let burn = |quantity: u64| {
+        QuasarInstruction::BurnLeverageToken {
+            quantity,
+            output_mint: None,
+        }
+        .pack()
+    };
+    let mut accounts = burnable_accounts(&mut accounts);
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &burn(1)),
+            QuasarErrorCode::RedeemTooSmall
+        );
+        assert!(invoked().is_empty());
+        process_instruction(&program_id, &account_infos, &burn(2)).unwrap();
+    }
+    assert_eq!(withdrawn(&mango_program_id), vec![1]);
+    assert_eq!(
+        Mint::unpack(&accounts[1].data).unwrap().supply,
+        MIN_INITIAL_MINT_QUANTITY - 2
+    );
+}
+
+#[test]
+fn test_mint_and_burn_reject_spoofed_mango_accounts() {
+    let program_id = Pubkey::new_unique();
+    let check_spoofs = |new_accounts: &dyn Fn() -> Vec<TestAccount>, data: &[u8]| {
+        // Some other program standing in for Mango, with the group copied over to it
+        let mut accounts = new_accounts();
+        let spoofed_program = Pubkey::new_unique();
+        accounts[3].key = spoofed_program;
+        accounts[4].owner = spoofed_program;
+        invoked();
+        {
+            let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+            assert_quasar_err!(
+                process_instruction(&program_id, &account_infos, data),
+                QuasarErrorCode::InvalidMangoProgram
+            );
+        }
+        assert!(invoked().is_empty());
+
+        // The right program, but a group of Mango's other than the one the group is set up on
+        let mut accounts = new_accounts();
+        accounts[4].key = Pubkey::new_unique();
+        invoked();
+        {
+            let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+            assert_quasar_err!(
+                process_instruction(&program_id, &account_infos, data),
+                QuasarErrorCode::InvalidMangoGroup
+            );
+        }
+        assert!(invoked().is_empty());
+    };
+
+    let quantity = MIN_INITIAL_MINT_QUANTITY;
+    let mint = QuasarInstruction::MintLeverageToken { quantity };
+    check_spoofs(&|| mintable_accounts(&program_id, &mint, 0), &mint.pack());
+    let burn = QuasarInstruction::BurnLeverageToken {
+        quantity,
+        output_mint: None,
+    };
+    check_spoofs(
+        &|| burnable_accounts(&mut minted_accounts(&program_id, quantity)),
+        &burn.pack(),
+    );
+}
+
+#[test]
+fn test_mint_creates_the_owner_token_account_once() {
+    let program_id = Pubkey::new_unique();
+    let quantity = MIN_INITIAL_MINT_QUANTITY;
+    let instruction = QuasarInstruction::MintLeverageToken { quantity };
+    let mut accounts = mintable_accounts(&program_id, &instruction, 0);
+    let (mint_key, owner_key) = (accounts[1].key, accounts[6].key);
+    let creates_token_account = |invoked: &[Instruction]| {
+        invoked
+            .iter()
+            .filter(|instruction| instruction.program_id == spl_associated_token_account::id())
+            .count()
+    };
+
+    // A brand-new owner has no leverage token account yet, it's created the first time
+    accounts[2].data = vec![];
+    accounts[2].owner = system_program::id();
+    invoked();
+    accounts[2].data = {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+        let data = account_infos[2].data.borrow().to_vec();
+        data
+    };
+    assert_eq!(creates_token_account(&invoked()), 1);
+    assert_eq!(accounts[2].owner, spl_token::id());
+    let token_account = TokenAccount::unpack(&accounts[2].data).unwrap();
+    assert_eq!(token_account.mint, mint_key);
+    assert_eq!(token_account.owner, owner_key);
+    assert_eq!(token_account.amount, quantity);
+
+    // and minted into as it is after that
+    update_mango_account(&mut accounts, |mango_account| {
+        mango_account.perp_accounts[0].quote_position =
+            I80F48::from_num(quantity * INITIAL_LEVERAGE_TOKEN_PRICE)
+    });
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    assert_eq!(creates_token_account(&invoked()), 0);
+    let token_account = TokenAccount::unpack(&account_infos[2].data.borrow()).unwrap();
+    assert_eq!(token_account.amount, 2 * quantity);
+}
+
+#[test]
+fn test_mint_redeem_and_close_a_leverage_token() {
+    let program_id = Pubkey::new_unique();
+    let quantity = MIN_INITIAL_MINT_QUANTITY;
+    let collateral = quantity * INITIAL_LEVERAGE_TOKEN_PRICE;
+    let mint = QuasarInstruction::MintLeverageToken { quantity };
+    let mut accounts = mintable_accounts(&program_id, &mint, 0);
+    let (mint_key, mango_program_id) = (accounts[1].key, accounts[3].key);
+    let admin_key = Pubkey::new_unique();
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.admin_key = admin_key
+    });
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &mint.pack()).unwrap();
+    }
+    assert_eq!(deposited(&mango_program_id), vec![collateral]);
+    assert_eq!(
+        TokenAccount::unpack(&accounts[2].data).unwrap().amount,
+        quantity
+    );
+    assert_eq!(Mint::unpack(&accounts[1].data).unwrap().supply, quantity);
+
+    // The stubbed Mango account's NAV doesn't count quote deposits, so carry the collateral
+    // on the perp quote position it does count
+    update_mango_account(&mut accounts, |mango_account| {
+        mango_account.perp_accounts[0].quote_position = I80F48::from_num(collateral)
+    });
+
+    // The whole holding is redeemed for the collateral it was minted with
+    let mut accounts = burnable_accounts(&mut accounts);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::BurnLeverageToken {
+                quantity,
+                output_mint: None,
+            }
+            .pack(),
+        )
+        .unwrap();
+    }
+    assert_eq!(withdrawn(&mango_program_id), vec![collateral]);
+    assert_eq!(TokenAccount::unpack(&accounts[2].data).unwrap().amount, 0);
+    assert_eq!(Mint::unpack(&accounts[1].data).unwrap().supply, 0);
+    let mut mango_account = MangoAccount::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_account).copy_from_slice(&accounts[5].data);
+    assert_eq!(mango_account.deposits[QUOTE_INDEX], I80F48::from_num(0));
+
+    // With the supply gone the admin can close the token, its Mango account left open
+    let mut take =
+        |i: usize| std::mem::replace(&mut accounts[i], TestAccount::new(Pubkey::default()));
+    let mut accounts: Vec<TestAccount> = [0, 1, 3, 4, 5].iter().map(|i| take(*i)).collect();
+    accounts.push(TestAccount {
+        is_signer: true,
+        ..TestAccount::new(admin_key)
+    });
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        // CloseLeverageToken
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::CloseLeverageToken.pack(),
+        )
+        .unwrap();
+    }
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert_eq!(quasar_group.num_leverage_tokens, 0);
+    assert!(quasar_group
+        .find_leverage_token_index_by_mint(&mint_key)
+        .is_none());
+    assert_eq!(accounts[4].owner, mango_program_id);
+}
+
+#[test]
+fn test_donation_before_the_first_mint_doesnt_move_its_price() {
+    let program_id = Pubkey::new_unique();
+    // Too small a first mint to bootstrap the token
+    let too_small = QuasarInstruction::MintLeverageToken {
+        quantity: MIN_INITIAL_MINT_QUANTITY - 1,
+    };
+    let mut accounts = mintable_accounts(&program_id, &too_small, 0);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &too_small.pack()),
+            QuasarErrorCode::InitialMintTooSmall
+        );
+    }
+
+    // Collateral donated to the Mango account ahead of the first mint
+    let instruction = QuasarInstruction::MintLeverageToken {
+        quantity: MIN_INITIAL_MINT_QUANTITY,
+    };
+    let mut accounts = mintable_accounts(&program_id, &instruction, 0);
+    let mango_program_id = accounts[3].key;
+    update_mango_account(&mut accounts, |mango_account| {
+        mango_account.perp_accounts[0].quote_position = I80F48::from_num(10_000)
+    });
+    invoked();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+
+    // Minted 1:1 at the bootstrap price, not at the NAV the donation would make it
+    assert_eq!(
+        deposited(&mango_program_id),
+        vec![MIN_INITIAL_MINT_QUANTITY * INITIAL_LEVERAGE_TOKEN_PRICE]
+    );
+    let minted = TokenAccount::unpack(&account_infos[2].data.borrow()).unwrap();
+    assert_eq!(minted.amount, MIN_INITIAL_MINT_QUANTITY);
+}
+
+#[test]
+fn test_account_roles_are_checked() {
+    let program_id = Pubkey::new_unique();
+    let instructions = [
+        (QuasarInstruction::AddBaseToken, 0),
+        (QuasarInstruction::MintLeverageToken { quantity: 1 }, 0),
+        (
+            QuasarInstruction::BurnLeverageToken {
+                quantity: 1,
+                output_mint: None,
+            },
+            MAX_PAIRS,
+        ),
+        (QuasarInstruction::ProcessRedeemQueue, MAX_PAIRS),
+        (QuasarInstruction::ReconcileToken, 0),
+        (QuasarInstruction::SetMangoProgram, 0),
+    ];
+
+    for (instruction, extra) in instructions.iter() {
+        let data = instruction.pack();
+        let descriptors = instruction.fixed_accounts();
+        for (i, descriptor) in descriptors.iter().enumerate() {
+            if descriptor.is_writable {
+                let mut accounts = accounts_for(instruction, *extra);
+                accounts[i].is_writable = false;
+                let account_infos: Vec<AccountInfo> =
+                    accounts.iter_mut().map(|a| a.info()).collect();
+                assert_quasar_err!(
+                    process_instruction(&program_id, &account_infos, &data),
+                    QuasarErrorCode::AccountNotWritable
+                );
+            }
+            if descriptor.is_signer {
+                let mut accounts = accounts_for(instruction, *extra);
+                accounts[i].is_signer = false;
+                let account_infos: Vec<AccountInfo> =
+                    accounts.iter_mut().map(|a| a.info()).collect();
+                assert_quasar_err!(
+                    process_instruction(&program_id, &account_infos, &data),
+                    QuasarErrorCode::AccountNotSigner
+                );
+            }
+        }
+
+        let mut accounts = accounts_for(instruction, 0);
+        accounts.pop();
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_matches!(
+            process_instruction(&program_id, &account_infos, &data),
+            Err(QuasarError::ProgramError(
+                ProgramError::NotEnoughAccountKeys
+            ))
+        );
+    }
+}
+
+#[test]
+fn test_zero_quantity_mint_and_burn_are_rejected() {
+    let program_id = Pubkey::new_unique();
+    let instructions = [
+        (QuasarInstruction::MintLeverageToken { quantity: 0 }, 0),
+        (
+            QuasarInstruction::BurnLeverageToken {
+                quantity: 0,
+                output_mint: None,
+            },
+            MAX_PAIRS,
+        ),
+    ];
+    for (instruction, extra) in instructions.iter() {
+        let mut accounts = accounts_for(instruction, *extra);
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::InvalidQuantity
+        );
+    }
+}
+
+#[test]
+fn test_failed_check_logs_where_it_fired() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::MintLeverageToken {
+        quantity: MIN_INITIAL_MINT_QUANTITY - 1,
+    };
+    let mut accounts = mintable_accounts(&program_id, &instruction, 0);
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    logs();
+
+    let line = match process_instruction(&program_id, &account_infos, &instruction.pack()) {
+        Err(QuasarError::QuasarErrorCode {
+            quasar_error_code: QuasarErrorCode::InitialMintTooSmall,
+            line,
+            source_file_id: SourceFileId::Processor,
+        }) => line,
+        result => panic!("unexpected {:?}", result),
+    };
+    assert_eq!(
+        logs(),
+        vec![
+            "Instruction: MintLeverageToken".to_string(),
+            format!(
+                "check failed: QuasarErrorCode::InitialMintTooSmall; src/processor.rs:{}",
+                line
+            )
+        ]
+    );
+    // and the line is the check of the first mint's quantity
+    let source = include_str!("../src/processor.rs");
+    let mut source_lines = source.lines().skip(line as usize - 1);
+    assert!(source_lines.next().unwrap().contains("check!("));
+    assert!(source_lines
+        .next()
+        .unwrap()
+        .contains("quantity >= MIN_INITIAL_MINT_QUANTITY"));
+}
+
+#[test]
+fn test_log_group_state_emits_a_decodable_summary() {
+    let program_id = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.admin_key = Pubkey::new_unique();
+    quasar_group.leverage_tokens[1] = LeverageToken {
+        mint: Pubkey::new_unique(),
+        target_leverage: I80F48::from_num(2),
+        num_perp_markets: 1,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    let mut accounts = vec![group_account_with_signer(&program_id, &mut quasar_group)];
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    logs();
+    process_instruction(
+        &program_id,
+        &account_infos,
+        &QuasarInstruction::LogGroupState.pack(),
+    )
+    .unwrap();
+
+    // The one event line, base64 behind the prefix, decodes to the group's summary
+    let events: Vec<Vec<u8>> = logs()
+        .iter()
+        .filter_map(|log| log.strip_prefix(EVENT_LOG_PREFIX))
+        .map(|event| base64::decode(event).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![GroupSummary::new(&quasar_group).try_to_vec().unwrap()]
+    );
+}
+
+#[test]
+fn test_donation_after_the_first_mint_doesnt_dilute_the_next_minter() {
+    let program_id = Pubkey::new_unique();
+    // The attacker minted the minimum for 100 and donated 10_000 on top
+    let supply = MIN_INITIAL_MINT_QUANTITY;
+    let nav = 100 + 10_000;
+    let quantity = 50;
+    let instruction = QuasarInstruction::MintLeverageToken { quantity };
+    let mut accounts = mintable_accounts(&program_id, &instruction, supply);
+    let mango_program_id = accounts[3].key;
+    update_mango_account(&mut accounts, |mango_account| {
+        mango_account.perp_accounts[0].quote_position = I80F48::from_num(nav)
+    });
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    }
+
+    // The next minter pays the inflated NAV per token, so what they get is worth what they
+    // paid and the donation stays with the attacker's tokens
+    let paid = deposited(&mango_program_id);
+    assert_eq!(paid, vec![quantity * nav / supply]);
+    assert_eq!((nav + paid[0]) * quantity / (supply + quantity), paid[0]);
+}
+
+#[test]
+fn test_mint_refuses_to_leave_the_mango_account_below_min_health() {
+    let program_id = Pubkey::new_unique();
+    // A short of 10_000 at 1 on quote of 10_400, 11_000 of liabilities at the init weight for
+    // 10_400 of assets, and the NAV of 400 a supply of 100 leaves makes a token 4
+    let leveraged_accounts = |instruction: &QuasarInstruction| {
+        let mut accounts = mintable_accounts(&program_id, instruction, 100);
+        update_group(&mut accounts, |quasar_group| {
+            quasar_group.min_health_ratio = I80F48::from_num(10)
+        });
+        let mut mango_group = MangoGroup::zeroed();
+        bytemuck::bytes_of_mut(&mut mango_group).copy_from_slice(&accounts[4].data);
+        mango_group.perp_markets[0].init_liab_weight = I80F48::from_num(1.1);
+        accounts[4].data = bytemuck::bytes_of(&mango_group).to_vec();
+        update_mango_account(&mut accounts, |mango_account| {
+            mango_account.perp_accounts[0].base_position = -10_000;
+            mango_account.perp_accounts[0].quote_position = I80F48::from_num(10_400);
+        });
+        accounts
+    };
+
+    // 40 more collateral leaves the account still well below the minimum
+    let instruction = QuasarInstruction::MintLeverageToken { quantity: 10 };
+    let mut accounts = leveraged_accounts(&instruction);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::InsufficientHealth
+        );
+    }
+
+    // 2_000 more takes the ratio to 12.7%
+    let instruction = QuasarInstruction::MintLeverageToken { quantity: 500 };
+    let mut accounts = leveraged_accounts(&instruction);
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+}
+
+#[test]
+fn test_mint_up_to_the_deposit_cap() {
+    let program_id = Pubkey::new_unique();
+    let quantity = MIN_INITIAL_MINT_QUANTITY;
+    let collateral = quantity * INITIAL_LEVERAGE_TOKEN_PRICE;
+    let instruction = QuasarInstruction::MintLeverageToken { quantity };
+    let capped_accounts = |deposit_cap: u64| {
+        let mut accounts = mintable_accounts(&program_id, &instruction, 0);
+        update_group(&mut accounts, |quasar_group| {
+            quasar_group.leverage_tokens[0].deposit_cap = deposit_cap
+        });
+        accounts
+    };
+
+    // A unit over what the Mango account may hold
+    let mut accounts = capped_accounts(collateral - 1);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::DepositCapExceeded
+        );
+    }
+
+    // Right at the cap
+    let mut accounts = capped_accounts(collateral);
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    let minted = TokenAccount::unpack(&account_infos[2].data.borrow()).unwrap();
+    assert_eq!(minted.amount, quantity);
+}
+
+#[test]
+fn test_mint_refuses_collateral_in_another_mint() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::MintLeverageToken { quantity: 10 };
+    let mut accounts = mintable_accounts(&program_id, &instruction, 0);
+    let mango_program_id = accounts[3].key;
+
+    // The owner's account of some token other than the group's quote mint
+    let mut token_account = TokenAccount::unpack(&accounts[12].data).unwrap();
+    token_account.mint = Pubkey::new_unique();
+    token_account.amount = 1_000;
+    TokenAccount::pack(token_account, &mut accounts[12].data).unwrap();
+    invoked();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidQuoteMint
+    );
+    assert!(deposited(&mango_program_id).is_empty());
+}
+
+#[test]
+fn test_mint_checks_quote_bank() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::MintLeverageToken { quantity: 10 };
+    let mut accounts = mint_accounts(&program_id, &instruction, 0);
+
+    // The vault of another token's node bank, the deposit would be made in the wrong token
+    accounts[10] = TestAccount::new(Pubkey::new_unique());
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::TokenMintMismatch
+        );
+    }
+
+    // Nor can the banks be ones the Mango group doesn't list for the quote token
+    let mut accounts = mint_accounts(&program_id, &instruction, 0);
+    accounts[8].key = Pubkey::new_unique();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::TokenMintMismatch
+    );
+}
+
+#[test]
+fn test_request_redeem_checks_hold_record() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::RequestRedeem { quantity: 10 };
+    let mut accounts = accounts_for(&instruction, 0);
+    accounts[6].key = spl_token::id();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.signer_key = accounts[5].key;
+    quasar_group.min_hold_secs = 60;
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: accounts[1].key,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_from(&program_id, &quasar_group);
+
+    // Someone else's hold record, which they may never have minted into
+    let (hold_record_key, _) = gen_hold_record_key(
+        &accounts[0].key,
+        &accounts[1].key,
+        &Pubkey::new_unique(),
+        &program_id,
+    );
+    accounts[9].key = hold_record_key;
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+/// Accounts of RequestRedeem by a fresh `owner` holding `amount` of the only leverage token of
+/// `quasar_group`, its redeem escrow not created yet. Whatever else the token has set in the
+/// first slot is kept
+fn request_redeem_accounts(
+    program_id: &Pubkey,
+    quasar_group: &mut QuasarGroup,
+    amount: u64,
+) -> Vec<TestAccount> {
+    let instruction = QuasarInstruction::RequestRedeem { quantity: amount };
+    let mut accounts = accounts_for(&instruction, 0);
+    let mint_key = accounts[1].key;
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.leverage_tokens[0].mint = mint_key;
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_with_signer(program_id, quasar_group);
+    let group_key = accounts[0].key;
+
+    let mint = Mint {
+        mint_authority: COption::Some(quasar_group.signer_key),
+        supply: amount,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    accounts[1].data = vec![0; Mint::LEN];
+    accounts[1].owner = spl_token::id();
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+    let owner_key = accounts[3].key;
+    let token_account = TokenAccount {
+        mint: mint_key,
+        owner: owner_key,
+        amount,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+    accounts[2].data = vec![0; TokenAccount::LEN];
+    accounts[2].owner = spl_token::id();
+    TokenAccount::pack(token_account, &mut accounts[2].data).unwrap();
+    accounts[3].lamports = 1_000_000_000;
+    accounts[4].key = gen_redeem_escrow_key(&group_key, &mint_key, program_id).0;
+    accounts[5].key = quasar_group.signer_key;
+    accounts[6].key = spl_token::id();
+    accounts[7].key = system_program::id();
+    accounts[8] = rent_sysvar_account();
+    accounts[9].key = gen_hold_record_key(&group_key, &mint_key, &owner_key, program_id).0;
+    accounts
+}
+
+#[test]
+fn test_request_redeem_of_transferred_tokens_waits_for_a_mint() {
+    let program_id = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.min_hold_secs = 60;
+    // The owner got their tokens from the wallet that minted them, so has no hold record
+    let mut accounts = request_redeem_accounts(&program_id, &mut quasar_group, 10);
+    let instruction = QuasarInstruction::RequestRedeem { quantity: 10 };
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::HoldPeriodNotElapsed
+        );
+    }
+
+    // Until a mint of their own has been held long enough
+    let hold_record = HoldRecord {
+        meta_data: MetaData::new(DataType::HoldRecord, 0, true),
+        last_mint_ts: 1_600_000_000 - 60,
+    };
+    accounts[9].data = bytemuck::bytes_of(&hold_record).to_vec();
+    accounts[9].owner = program_id;
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    let escrow = TokenAccount::unpack(&account_infos[4].data.borrow()).unwrap();
+    assert_eq!(escrow.amount, 10);
+    assert_eq!(escrow.owner, quasar_group.signer_key);
+}
+
+#[test]
+fn test_request_redeem_refuses_less_than_min_mint_quantity() {
+    let program_id = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.leverage_tokens[0].min_mint_quantity = 10;
+    let mut accounts = request_redeem_accounts(&program_id, &mut quasar_group, 10);
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::RequestRedeem { quantity: 9 }.pack()
+        ),
+        QuasarErrorCode::RedeemTooSmall
+    );
+    process_instruction(
+        &program_id,
+        &account_infos,
+        &QuasarInstruction::RequestRedeem { quantity: 10 }.pack(),
+    )
+    .unwrap();
+}
+
+/// Accounts of ProcessRedeemQueue once an owner has queued the whole supply of a leverage token
+/// by a RequestRedeem of each of `quantities`. The token has a single perp market priced at 40,
+/// on which its Mango account holds `base_position` lots against `quote_position`
+fn queued_redeem_accounts(
+    program_id: &Pubkey,
+    quantities: &[u64],
+    base_position: i64,
+    quote_position: i64,
+) -> Vec<TestAccount> {
+    let (mango_program_id, mango_group_key, mango_cache_key, mango_account_key) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    let (perp_market_key, quote_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.mango_program_id = mango_program_id;
+    quasar_group.mango_group = mango_group_key;
+    quasar_group.quote_mint = quote_mint;
+    let leverage_token = &mut quasar_group.leverage_tokens[0];
+    leverage_token.mango_account = mango_account_key;
+    leverage_token.num_perp_markets = 1;
+    leverage_token.mango_perp_markets[0] = perp_market_key;
+    leverage_token.perp_market_weights[0] = I80F48::from_num(1);
+
+    let mut request_accounts =
+        request_redeem_accounts(program_id, &mut quasar_group, quantities.iter().sum());
+    let owner_key = request_accounts[3].key;
+    let escrow = {
+        let account_infos: Vec<AccountInfo> =
+            request_accounts.iter_mut().map(|a| a.info()).collect();
+        for quantity in quantities {
+            let instruction = QuasarInstruction::RequestRedeem {
+                quantity: *quantity,
+            };
+            process_instruction(program_id, &account_infos, &instruction.pack()).unwrap();
+        }
+        // The escrow's data was allocated by the system program, it's only behind the info
+        TestAccount {
+            lamports: account_infos[4].lamports(),
+            data: account_infos[4].data.borrow().to_vec(),
+            owner: *account_infos[4].owner,
+            ..TestAccount::new(*account_infos[4].key)
+        }
+    };
+
+    let mut accounts = accounts_for(&QuasarInstruction::ProcessRedeemQueue, MAX_PAIRS + 4);
+    let mint_key = request_accounts[1].key;
+    accounts[0] = std::mem::replace(
+        &mut request_accounts[0],
+        TestAccount::new(Pubkey::default()),
+    );
+    accounts[1] = std::mem::replace(
+        &mut request_accounts[1],
+        TestAccount::new(Pubkey::default()),
+    );
+    accounts[2] = escrow;
+    accounts[3].key = mango_program_id;
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.num_oracles = 1;
+    mango_group.perp_markets[0].perp_market = perp_market_key;
+    mango_group.perp_markets[0].base_lot_size = 1;
+    mango_group.perp_markets[0].quote_lot_size = 1;
+    mango_group.tokens[QUOTE_INDEX].mint = quote_mint;
+    mango_group.mango_cache = mango_cache_key;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.meta_data = MangoMetaData::new(MangoDataType::MangoCache, 0, true);
+    mango_cache.price_cache[0].price = I80F48::from_num(40);
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    mango_account.mango_group = mango_group_key;
+    mango_account.owner = quasar_group.signer_key;
+    mango_account.perp_accounts[0].base_position = base_position;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(quote_position);
+    for (i, key, data) in [
+        (
+            4,
+            mango_group_key,
+            bytemuck::bytes_of(&mango_group).to_vec(),
+        ),
+        (
+            5,
+            mango_account_key,
+            bytemuck::bytes_of(&mango_account).to_vec(),
+        ),
+        (
+            6,
+            mango_cache_key,
+            bytemuck::bytes_of(&mango_cache).to_vec(),
+        ),
+    ]
+    .iter()
+    {
+        accounts[*i].key = *key;
+        accounts[*i].data = data.clone();
+        accounts[*i].owner = mango_program_id;
+    }
+
+    accounts[10].key = spl_token::id();
+    let quote_token_account = TokenAccount {
+        mint: quote_mint,
+        owner: owner_key,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+    accounts[11].data = vec![0; TokenAccount::LEN];
+    accounts[11].owner = spl_token::id();
+    TokenAccount::pack(quote_token_account, &mut accounts[11].data).unwrap();
+    accounts[12].key = quasar_group.signer_key;
+    accounts[14].key = get_associated_token_address(&quasar_group.signer_key, &mint_key);
+    accounts[15 + MAX_PAIRS].key = perp_market_key;
+    accounts
+}
+
+#[test]
+fn test_process_redeem_queue_settles_a_queued_redeem() {
+    let program_id = Pubkey::new_unique();
+    // Only quote in the Mango account, nothing to unwind: 100 of NAV for 10 tokens
+    let mut accounts = queued_redeem_accounts(&program_id, &[10], 0, 100);
+    let (mint_key, mango_program_id) = (accounts[1].key, accounts[3].key);
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::ProcessRedeemQueue.pack(),
+        )
+        .unwrap();
+    }
+
+    // The escrowed tokens are burnt and their NAV withdrawn to the owner
+    assert_eq!(Mint::unpack(&accounts[1].data).unwrap().supply, 0);
+    assert_eq!(TokenAccount::unpack(&accounts[2].data).unwrap().amount, 0);
+    let withdrawn: Vec<u64> = invoked()
+        .iter()
+        .filter(|instruction| instruction.program_id == mango_program_id)
+        .filter_map(
+            |instruction| match MangoInstruction::unpack(&instruction.data) {
+                Some(MangoInstruction::Withdraw { quantity, .. }) => Some(quantity),
+                _ => None,
+            },
+        )
+        .collect();
+    assert_eq!(withdrawn, vec![100]);
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    let index = quasar_group
+        .find_leverage_token_index_by_mint(&mint_key)
+        .unwrap();
+    assert!(quasar_group.front_redeem_request_mut(index).is_none());
+
+    // Nothing left to process
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::ProcessRedeemQueue.pack(),
+        ),
+        QuasarErrorCode::RedeemQueueEmpty
+    );
+}
+
+#[test]
+fn test_process_redeem_queue_goes_through_off_the_twap() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts = queued_redeem_accounts(&program_id, &[10], 0, 100);
+    let now_ts = 1_600_000_000;
+    set_unix_timestamp(now_ts);
+
+    // The base token has been sampled at 50 while the Mango cache has it at 40
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    let base_mint = Pubkey::new_unique();
+    quasar_group.base_tokens[0] = BaseToken {
+        mint: base_mint,
+        num_oracles: 1,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 1;
+    quasar_group.base_tokens[0]
+        .push_price_sample(OraclePrice::new(I80F48::from_num(50), 0), now_ts as u64)
+        .unwrap();
+    quasar_group.leverage_tokens[0].base_token_mint = base_mint;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+
+    // A queued redeem can't be held up by the price wandering off, it would never get out
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::ProcessRedeemQueue.pack(),
+        )
+        .unwrap();
+    }
+    assert_eq!(Mint::unpack(&accounts[1].data).unwrap().supply, 0);
+}
+
+#[test]
+fn test_process_redeem_queue_moves_an_unfilled_redeem_to_the_back() {
+    let program_id = Pubkey::new_unique();
+    // 10 long at 40 against 300 of quote. The orders unwinding the front request don't fill
+    let mut accounts = queued_redeem_accounts(&program_id, &[4, 6], 10, -300);
+    let (mint_key, mango_program_id) = (accounts[1].key, accounts[3].key);
+    invoked();
+    logs();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::ProcessRedeemQueue.pack(),
+        )
+        .unwrap();
+    }
+
+    let placed = invoked()
+        .iter()
+        .filter(|instruction| instruction.program_id == mango_program_id)
+        .filter(|instruction| {
+            matches!(
+                MangoInstruction::unpack(&instruction.data),
+                Some(MangoInstruction::PlacePerpOrder { .. })
+            )
+        })
+        .count();
+    assert_eq!(placed, 1);
+    assert!(logs()
+        .iter()
+        .any(|log| log.contains("moved to the back of the queue")));
+
+    // Nothing is burnt, the other request is next
+    assert_eq!(Mint::unpack(&accounts[1].data).unwrap().supply, 10);
+    assert_eq!(TokenAccount::unpack(&accounts[2].data).unwrap().amount, 10);
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    let index = quasar_group
+        .find_leverage_token_index_by_mint(&mint_key)
+        .unwrap();
+    let quantities: Vec<u64> = quasar_group.redeem_queues[index]
+        .iter()
+        .map(|request| request.quantity)
+        .collect();
+    assert_eq!(quantities, vec![6, 4]);
+}
+
+#[test]
+fn test_mint_from_mango_balance_checks_source_account() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::MintFromMangoBalance { quantity: 10 };
+    let mut accounts = mint_accounts(&program_id, &instruction, MAX_PAIRS);
+    let (mango_program_id, mango_group_key, mango_account_key, owner_key) = (
+        accounts[3].key,
+        accounts[4].key,
+        accounts[5].key,
+        accounts[6].key,
+    );
+
+    // Funded by someone else's deposits
+    accounts[19] = mango_account(&mango_program_id, &mango_group_key, &Pubkey::new_unique());
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::InvalidAccount
+        );
+    }
+
+    // Nor can the leverage token's own Mango account back the mint
+    accounts[19] = TestAccount {
+        key: mango_account_key,
+        ..mango_account(&mango_program_id, &mango_group_key, &owner_key)
+    };
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+#[test]
+fn test_mint_from_mango_balance_moves_the_collateral_between_mango_accounts() {
+    let program_id = Pubkey::new_unique();
+    let quantity = MIN_INITIAL_MINT_QUANTITY;
+    let collateral = quantity * INITIAL_LEVERAGE_TOKEN_PRICE;
+    let instruction = QuasarInstruction::MintFromMangoBalance { quantity };
+    let mut accounts = mintable_accounts(&program_id, &instruction, 0);
+    let (mango_program_id, mango_group_key, mango_account_key, owner_key) = (
+        accounts[3].key,
+        accounts[4].key,
+        accounts[5].key,
+        accounts[6].key,
+    );
+
+    // Everything the owner has is already on Mango, none of it in their token account
+    let mut quote_token_account = TokenAccount::unpack(&accounts[12].data).unwrap();
+    quote_token_account.amount = 0;
+    TokenAccount::pack(quote_token_account, &mut accounts[12].data).unwrap();
+    let source_key = accounts[19].key;
+    let mut source_mango_account = MangoAccount::zeroed();
+    source_mango_account.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    source_mango_account.mango_group = mango_group_key;
+    source_mango_account.owner = owner_key;
+    source_mango_account.deposits[QUOTE_INDEX] = I80F48::from_num(collateral + 1);
+    accounts[19].data = bytemuck::bytes_of(&source_mango_account).to_vec();
+    accounts[19].owner = mango_program_id;
+    accounts.extend((0..MAX_PAIRS).map(|_| TestAccount::new(Pubkey::new_unique())));
+    invoked();
+
+    let minted = {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+        let minted = TokenAccount::unpack(&account_infos[2].data.borrow())
+            .unwrap()
+            .amount;
+        minted
+    };
+    assert_eq!(minted, quantity);
+
+    // Out of the owner's Mango account, through their token account, into the token's
+    let mango_instructions: Vec<(Pubkey, &str, u64)> = invoked()
+        .iter()
+        .filter(|instruction| instruction.program_id == mango_program_id)
+        .map(|instruction| {
+            let (name, quantity) = match MangoInstruction::unpack(&instruction.data) {
+                Some(MangoInstruction::Withdraw {
+                    quantity,
+                    allow_borrow: false,
+                }) => ("withdraw", quantity),
+                Some(MangoInstruction::Deposit { quantity }) => ("deposit", quantity),
+                _ => ("other", 0),
+            };
+            (instruction.accounts[1].pubkey, name, quantity)
+        })
+        .collect();
+    assert_eq!(
+        mango_instructions,
+        vec![
+            (source_key, "withdraw", collateral),
+            (mango_account_key, "deposit", collateral)
+        ]
+    );
+
+    let mut source_mango_account = MangoAccount::zeroed();
+    bytemuck::bytes_of_mut(&mut source_mango_account).copy_from_slice(&accounts[19].data);
+    assert_eq!(
+        source_mango_account.deposits[QUOTE_INDEX],
+        I80F48::from_num(1)
+    );
+}
+
+#[test]
+fn test_mint_checks_mango_account_owner() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::MintLeverageToken { quantity: 10 };
+    let mut accounts = mint_accounts(&program_id, &instruction, 0);
+    let (mango_program_id, mango_group_key, mango_account_key) =
+        (accounts[3].key, accounts[4].key, accounts[5].key);
+
+    // The leverage token's Mango account, but owned by someone other than the group signer
+    accounts[5] = TestAccount {
+        key: mango_account_key,
+        ..mango_account(&mango_program_id, &mango_group_key, &Pubkey::new_unique())
+    };
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidMangoAccountOwner
+    );
+}
+
+/// Accounts for a `BatchMint` `instruction` of `num_entries` entries, with a group holding a
+/// leverage token at index 0 for the first entry's accounts
+fn batch_mint_accounts(
+    program_id: &Pubkey,
+    instruction: &QuasarInstruction,
+    num_entries: usize,
+) -> Vec<TestAccount> {
+    let mut accounts = accounts_for(instruction, num_entries * 5);
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: accounts[14].key,
+        mango_account: accounts[16].key,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_from(program_id, &quasar_group);
+    accounts
+}
+
+#[test]
+fn test_batch_mint_checks_every_entry_before_minting() {
+    let program_id = Pubkey::new_unique();
+    let entry = BatchMintEntry {
+        token_index: 0,
+        quantity: 10,
+        max_collateral: u64::MAX,
+    };
+    let mut entries = [entry; MAX_BATCH_MINTS];
+    // No leverage token at the second entry's index
+    entries[1].token_index = 1;
+    let instruction = QuasarInstruction::BatchMint {
+        num_entries: 2,
+        entries,
+    };
+    let mut accounts = batch_mint_accounts(&program_id, &instruction, 2);
+    let group_data = accounts[0].data.clone();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::InvalidIndex
+        );
+    }
+    // The first entry wasn't minted either
+    assert_eq!(accounts[0].data, group_data);
+
+    // Nor a batch whose second entry names the wrong mint
+    entries[1].token_index = 0;
+    let instruction = QuasarInstruction::BatchMint {
+        num_entries: 2,
+        entries,
+    };
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+#[test]
+fn test_batch_mint_is_bounded() {
+    let program_id = Pubkey::new_unique();
+    let entries = [BatchMintEntry {
+        token_index: 0,
+        quantity: 10,
+        max_collateral: u64::MAX,
+    }; MAX_BATCH_MINTS];
+    for &num_entries in [0, MAX_BATCH_MINTS + 1].iter() {
+        let instruction = QuasarInstruction::BatchMint {
+            num_entries: num_entries as u8,
+            entries,
+        };
+        let mut accounts = batch_mint_accounts(&program_id, &instruction, num_entries);
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::InvalidParam
+        );
+    }
+
+    // Every entry needs all of its accounts
+    let instruction = QuasarInstruction::BatchMint {
+        num_entries: 2,
+        entries,
+    };
+    let mut accounts = batch_mint_accounts(&program_id, &instruction, 1);
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+/// Single leg of `rebalance_accounts`, on a perp market priced at 40
+const REBALANCE_LEG: (f64, i64) = (1.0, 40);
+
+/// Accounts of Rebalance for a leverage token at 2x, its Mango account holding `quote_position`
+/// and no base position yet. Each of `legs` is the weight of a perp market and its price, the
+/// markets listed on the Mango group in the same order
+fn rebalance_accounts(
+    program_id: &Pubkey,
+    quote_position: i64,
+    legs: &[(f64, i64)],
+) -> Vec<TestAccount> {
+    let mut accounts: Vec<TestAccount> = (0..10 + MAX_PAIRS + 4 * legs.len())
+        .map(|_| TestAccount::new(Pubkey::new_unique()))
+        .collect();
+    let (mint_key, mango_program_id, mango_group_key, mango_account_key, mango_cache_key) = (
+        accounts[1].key,
+        accounts[3].key,
+        accounts[4].key,
+        accounts[5].key,
+        accounts[7].key,
+    );
+    let perp_market_keys: Vec<Pubkey> = (0..legs.len())
+        .map(|leg| accounts[10 + MAX_PAIRS + 4 * leg].key)
+        .collect();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.mango_program_id = mango_program_id;
+    quasar_group.mango_group = mango_group_key;
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: mint_key,
+        mango_account: mango_account_key,
+        target_leverage: I80F48::from_num(2),
+        num_perp_markets: legs.len(),
+        ..LeverageToken::zeroed()
+    };
+    for (leg, (weight, _)) in legs.iter().enumerate() {
+        quasar_group.leverage_tokens[0].mango_perp_markets[leg] = perp_market_keys[leg];
+        quasar_group.leverage_tokens[0].perp_market_weights[leg] = I80F48::from_num(*weight);
+    }
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_with_signer(program_id, &mut quasar_group);
+
+    let mint = Mint {
+        mint_authority: COption::Some(quasar_group.signer_key),
+        supply: 10,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    accounts[1].data = vec![0; Mint::LEN];
+    accounts[1].owner = spl_token::id();
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+    accounts[2].key = quasar_group.signer_key;
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.num_oracles = legs.len();
+    mango_group.mango_cache = mango_cache_key;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.meta_data = MangoMetaData::new(MangoDataType::MangoCache, 0, true);
+    for (leg, (_, price)) in legs.iter().enumerate() {
+        mango_group.perp_markets[leg].perp_market = perp_market_keys[leg];
+        mango_group.perp_markets[leg].base_lot_size = 1;
+        mango_group.perp_markets[leg].quote_lot_size = 1;
+        mango_cache.price_cache[leg].price = I80F48::from_num(*price);
+    }
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    mango_account.mango_group = mango_group_key;
+    mango_account.owner = quasar_group.signer_key;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(quote_position);
+    for (i, data) in [
+        (4, bytemuck::bytes_of(&mango_group).to_vec()),
+        (5, bytemuck::bytes_of(&mango_account).to_vec()),
+        (7, bytemuck::bytes_of(&mango_cache).to_vec()),
+    ]
+    .iter()
+    {
+        accounts[*i].data = data.clone();
+        accounts[*i].owner = mango_program_id;
+    }
+    accounts[8].key = spl_token::id();
+    accounts[9].key = get_associated_token_address(&quasar_group.signer_key, &mint_key);
+    accounts
+}
+
+/// Base lots of the perp orders placed on `mango_program_id` since the last call
+fn placed_perp_orders(mango_program_id: &Pubkey) -> Vec<i64> {
+    invoked()
+        .iter()
+        .filter(|instruction| instruction.program_id == *mango_program_id)
+        .filter_map(
+            |instruction| match MangoInstruction::unpack(&instruction.data) {
+                Some(MangoInstruction::PlacePerpOrder { quantity, .. }) => Some(quantity),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Rebalance `accounts` with the perp orders filling `fills`, returning whether the token was
+/// left pending
+fn rebalance_with_fills(program_id: &Pubkey, accounts: &mut [TestAccount], fills: &[i64]) -> bool {
+    PERP_FILLS.with(|perp_fills| *perp_fills.borrow_mut() = fills.iter().copied().collect());
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            program_id,
+            &account_infos,
+            &QuasarInstruction::Rebalance.pack(),
+        )
+        .unwrap();
+    }
+    PERP_FILLS.with(|perp_fills| perp_fills.borrow_mut().clear());
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.leverage_tokens[0].pending_rebalance
+}
+
+#[test]
+fn test_rebalance_orders_the_rest_of_a_partial_fill() {
+    let program_id = Pubkey::new_unique();
+    // 1_000 of NAV at 2x is 2_000 of exposure, 50 lots at 40
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[REBALANCE_LEG]);
+    let mango_program_id = accounts[3].key;
+    invoked();
+
+    // The shallow book only takes 20 lots, the rest fills on the second order
+    assert!(!rebalance_with_fills(&program_id, &mut accounts, &[20, 30]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![50, 30]);
+}
+
+#[test]
+fn test_rebalance_leaves_a_short_leg_pending() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[REBALANCE_LEG]);
+    let mango_program_id = accounts[3].key;
+    invoked();
+
+    // Nothing left on the book after the first fill, no point ordering a third time
+    assert!(rebalance_with_fills(&program_id, &mut accounts, &[20, 0]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![50, 30]);
+
+    // Every order filling a little takes as many orders as are allowed
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[REBALANCE_LEG]);
+    let mango_program_id = accounts[3].key;
+    let fills = vec![10; MAX_REBALANCE_ORDER_ATTEMPTS];
+    assert!(rebalance_with_fills(&program_id, &mut accounts, &fills));
+    let mut expected = vec![50];
+    for _ in 1..MAX_REBALANCE_ORDER_ATTEMPTS {
+        expected.push(expected[expected.len() - 1] - 10);
+    }
+    assert_eq!(placed_perp_orders(&mango_program_id), expected);
+}
+
+#[test]
+fn test_settle_token_pnl_counts_what_it_settles() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::SettleTokenPnl { leg_index: 0 };
+    let mut accounts = accounts_for(&instruction, 0);
+    let (mint_key, mango_program_id, mango_group_key, mango_account_key, mango_cache_key) = (
+        accounts[1].key,
+        accounts[2].key,
+        accounts[3].key,
+        accounts[4].key,
+        accounts[6].key,
+    );
+    let perp_market_key = Pubkey::new_unique();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.mango_program_id = mango_program_id;
+    quasar_group.mango_group = mango_group_key;
+    quasar_group.leverage_tokens[1] = LeverageToken {
+        mint: mint_key,
+        mango_account: mango_account_key,
+        num_perp_markets: 1,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.leverage_tokens[1].mango_perp_markets[0] = perp_market_key;
+    quasar_group.num_leverage_tokens = 1;
+    quasar_group.settled_pnl[1] = I80F48::from_num(50);
+    accounts[0] = group_account_with_signer(&program_id, &mut quasar_group);
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.num_oracles = 1;
+    mango_group.perp_markets[0].perp_market = perp_market_key;
+    mango_group.mango_cache = mango_cache_key;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.meta_data = MangoMetaData::new(MangoDataType::MangoCache, 0, true);
+    mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index = I80F48::from_num(1);
+    // 1_000 of collateral on deposit and 300 of pnl on the perp position
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    mango_account.mango_group = mango_group_key;
+    mango_account.owner = quasar_group.signer_key;
+    mango_account.deposits[QUOTE_INDEX] = I80F48::from_num(1_000);
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(300);
+    for (i, data) in [
+        (3, bytemuck::bytes_of(&mango_group).to_vec()),
+        (4, bytemuck::bytes_of(&mango_account).to_vec()),
+        (6, bytemuck::bytes_of(&mango_cache).to_vec()),
+    ]
+    .iter()
+    {
+        accounts[*i].data = data.clone();
+        accounts[*i].owner = mango_program_id;
+    }
+
+    // Only the settled pnl counts, on top of what was settled before
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    }
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert_eq!(quasar_group.settled_pnl[1], I80F48::from_num(350));
+}
+
+#[test]
+fn test_rebalance_targets_the_leverage_set_since_the_last_one() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[REBALANCE_LEG]);
+    let mango_program_id = accounts[3].key;
+    let admin_key = Pubkey::new_unique();
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.admin_key = admin_key;
+        quasar_group.min_rebalance_interval = 60;
+    });
+    invoked();
+    assert!(!rebalance_with_fills(&program_id, &mut accounts, &[50]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![50]);
+
+    // The admin takes the token from 2x to 3x
+    let instruction = QuasarInstruction::SetTargetLeverage {
+        new_leverage: I80F48::from_num(3),
+        rebalance_deadband_bps: None,
+    };
+    let mut set_accounts = vec![
+        std::mem::replace(&mut accounts[0], TestAccount::new(Pubkey::default())),
+        std::mem::replace(&mut accounts[1], TestAccount::new(Pubkey::default())),
+        TestAccount {
+            is_signer: true,
+            ..TestAccount::new(admin_key)
+        },
+    ];
+    {
+        let account_infos: Vec<AccountInfo> = set_accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    }
+    accounts[1] = set_accounts.remove(1);
+    accounts[0] = set_accounts.remove(0);
+
+    // Without waiting out the interval, the next rebalance takes the 2_000 of exposure on the
+    // 1_000 of NAV to 3_000, 25 more lots at 40
+    assert!(!rebalance_with_fills(&program_id, &mut accounts, &[25]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![25]);
+}
+
+#[test]
+fn test_rebalance_carries_on_with_the_legs_left_on_the_next_crank() {
+    let program_id = Pubkey::new_unique();
+    assert_eq!(MAX_LEGS_PER_REBALANCE, 2);
+    // 2_000 of exposure split 1_000 at 40, 500 at 25 and 500 at 50
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[(0.5, 40), (0.25, 25), (0.25, 50)]);
+    let mango_program_id = accounts[3].key;
+    invoked();
+
+    // The first crank trades as many legs as fit, the token stays pending for the last one
+    assert!(rebalance_with_fills(&program_id, &mut accounts, &[25, 20]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![25, 20]);
+
+    assert!(!rebalance_with_fills(&program_id, &mut accounts, &[10]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![10]);
+    let mut mango_account = MangoAccount::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_account).copy_from_slice(&accounts[5].data);
+    let base_positions: Vec<i64> = mango_account.perp_accounts[..3]
+        .iter()
+        .map(|perp_account| perp_account.base_position)
+        .collect();
+    assert_eq!(base_positions, vec![25, 20, 10]);
+}
+
+#[test]
+fn test_rebalance_sizes_each_leg_by_its_weight() {
+    let program_id = Pubkey::new_unique();
+    // 2_000 of exposure split 1_500 at 50 and 500 at 25
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[(0.75, 50), (0.25, 25)]);
+    let mango_program_id = accounts[3].key;
+    invoked();
+
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    process_instruction(
+        &program_id,
+        &account_infos,
+        &QuasarInstruction::Rebalance.pack(),
+    )
+    .unwrap();
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![30, 20]);
+}
+
+#[test]
+fn test_rebalance_stays_pending_until_its_orders_fill() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[REBALANCE_LEG]);
+    let mango_program_id = accounts[3].key;
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.min_rebalance_interval = 60
+    });
+    let now_ts = 1_600_000_000;
+    set_unix_timestamp(now_ts);
+    invoked();
+
+    // 20 of the 50 lots fill and nothing is left on the book for the rest, the token is left
+    // pending
+    assert!(rebalance_with_fills(&program_id, &mut accounts, &[20]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![50, 30]);
+
+    // A crank straight after backs off from the market that fell short
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &QuasarInstruction::Rebalance.pack()
+            ),
+            QuasarErrorCode::RebalanceBackoff
+        );
+    }
+
+    // but the next one doesn't wait out the interval to order the rest
+    set_unix_timestamp(now_ts + REBALANCE_BACKOFF_BASE_SECS as i64);
+    assert!(!rebalance_with_fills(&program_id, &mut accounts, &[30]));
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![30]);
+}
+
+#[test]
+fn test_rebalance_waits_for_the_min_rebalance_interval() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts = rebalance_accounts(&program_id, 1_000, &[REBALANCE_LEG]);
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.min_rebalance_interval = 60
+    });
+    let now_ts = 1_600_000_000;
+    set_unix_timestamp(now_ts);
+    assert!(!rebalance_with_fills(&program_id, &mut accounts, &[50]));
+
+    // Straight after a rebalance that completed, the next one has to wait
+    set_unix_timestamp(now_ts + 59);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &QuasarInstruction::Rebalance.pack()
+            ),
+            QuasarErrorCode::RebalanceTooSoon
+        );
+    }
+
+    set_unix_timestamp(now_ts + 60);
+    assert!(!rebalance_with_fills(&program_id, &mut accounts, &[]));
+}
+
+/// Initialized group owned by `program_id`, administered by `admin_key` and set up on the given
+/// Mango program and group
+fn group_account(
+    program_id: &Pubkey,
+    admin_key: &Pubkey,
+    mango_program_id: &Pubkey,
+    mango_group: &Pubkey,
+) -> TestAccount {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.admin_key = *admin_key;
+    quasar_group.mango_program_id = *mango_program_id;
+    quasar_group.mango_group = *mango_group;
+
+    group_account_from(program_id, &quasar_group)
+}
+
+fn group_account_from(program_id: &Pubkey, quasar_group: &QuasarGroup) -> TestAccount {
+    let mut quasar_group = *quasar_group;
+    quasar_group.init_discriminator();
+    TestAccount {
+        data: bytemuck::bytes_of(&quasar_group).to_vec(),
+        owner: *program_id,
+        ..TestAccount::new(Pubkey::new_unique())
+    }
+}
+
+/// Instruction data of an `AddLeverageToken` at 3x on a single perp market of `base_mint`
+fn add_leverage_token_data(base_mint: &Pubkey) -> Vec<u8> {
+    add_leverage_token_data_with_decimals(base_mint, None)
+}
+
+/// Instruction data of an `AddLeverageToken` at 3x on a single perp market of `base_mint`,
+/// with a mint of `decimals`
+fn add_leverage_token_data_with_decimals(base_mint: &Pubkey, decimals: Option<u8>) -> Vec<u8> {
+    let mut perp_market_weights = [I80F48::from_num(0); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    perp_market_weights[0] = I80F48::from_num(1);
+    let mut perp_market_base_mints = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    perp_market_base_mints[0] = *base_mint;
+    QuasarInstruction::AddLeverageToken {
+        target_leverage: I80F48::from_num(3),
+        num_perp_markets: 1,
+        perp_market_weights,
+        perp_market_base_mints,
+        decimals,
+        rebalance_deadband_bps: 0,
+    }
+    .pack()
+}
+
+/// Accounts of AddLeverageToken with the given mints, in instruction order
+fn add_leverage_token_accounts(
+    program_id: &Pubkey,
+    mint_key: Pubkey,
+    base_token_mint_key: Pubkey,
+) -> Vec<TestAccount> {
+    let admin_key = Pubkey::new_unique();
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    let mango_program_id = Pubkey::new_unique();
+    let mango_group = Pubkey::new_unique();
+
+    vec![
+        group_account(program_id, &admin_key, &mango_program_id, &mango_group),
+        TestAccount::new(mint_key),
+        TestAccount::new(base_token_mint_key),
+        TestAccount::new(mango_program_id),
+        TestAccount::new(mango_group),
+        TestAccount::new(Pubkey::new_unique()), // mango_account
+        TestAccount::new(solana_program::system_program::id()),
+        TestAccount::new(spl_token::id()),
+        TestAccount::new(solana_program::sysvar::rent::id()),
+        admin,
+        TestAccount::new(Pubkey::new_unique()), // pda
+        TestAccount::new(Pubkey::new_unique()), // mango_perp_market
+    ]
+}
+
+#[test]
+fn test_add_leverage_token_rejects_base_token_mint_as_mint() {
+    let program_id = Pubkey::new_unique();
+    let mint_key = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_accounts(&program_id, mint_key, mint_key);
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+#[test]
+fn test_add_leverage_token_rejects_existing_mint() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts =
+        add_leverage_token_accounts(&program_id, Pubkey::new_unique(), Pubkey::new_unique());
+    // Mint account already funded and allocated
+    accounts[1].lamports = 1;
+    accounts[1].data = vec![0; spl_token::state::Mint::LEN];
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+#[test]
+fn test_add_leverage_token_rejects_spoofed_mango_group() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts =
+        add_leverage_token_accounts(&program_id, Pubkey::new_unique(), Pubkey::new_unique());
+    accounts[4].key = Pubkey::new_unique();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::InvalidMangoGroup
+    );
+}
+
+#[test]
+fn test_add_leverage_token_rejects_spoofed_mango_program() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts =
+        add_leverage_token_accounts(&program_id, Pubkey::new_unique(), Pubkey::new_unique());
+    accounts[3].key = Pubkey::new_unique();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::InvalidMangoProgram
+    );
+}
+
+#[test]
+fn test_add_leverage_token_rejects_too_many_decimals() {
+    let program_id = Pubkey::new_unique();
+    let mut accounts =
+        add_leverage_token_accounts(&program_id, Pubkey::new_unique(), Pubkey::new_unique());
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data_with_decimals(
+                account_infos[2].key,
+                Some(MAX_LEVERAGE_TOKEN_DECIMALS + 1)
+            )
+        ),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_add_leverage_token_refuses_an_underfunded_creator() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_listed_accounts(&program_id, base_mint);
+    derive_leverage_token_accounts(&program_id, &mut accounts);
+    // A lamport short of the rent of the Mango account, the first account created
+    let lamports = Rent::default().minimum_balance(size_of::<MangoAccount>()) - 1;
+    accounts[9].lamports = lamports;
+    invoked();
+
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::InsufficientRent
+    );
+    // Refused before asking the system program to create anything
+    assert!(invoked()
+        .iter()
+        .all(|instruction| instruction.program_id != system_program::id()));
+    assert_eq!(account_infos[9].lamports(), lamports);
+    assert!(account_infos[1].data_is_empty());
+    assert!(account_infos[5].data_is_empty());
+}
+
+#[test]
+fn test_add_leverage_token_rejects_mango_account_off_its_pda() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_listed_accounts(&program_id, base_mint);
+    // Some account of the admin's choosing rather than the one derived for the mint
+    accounts[5].key = Pubkey::new_unique();
+    invoked();
+
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::InvalidAccount
+    );
+    assert!(invoked().is_empty());
+}
+
+#[test]
+fn test_add_leverage_token_on_two_weighted_perp_markets() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_listed_accounts(&program_id, base_mint);
+    derive_leverage_token_accounts(&program_id, &mut accounts);
+    let mint_key = accounts[1].key;
+    // A second base token, with its perp market listed on the Mango group at its own index
+    let second_base_mint = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.base_tokens[1] = BaseToken {
+        mint: second_base_mint,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 2;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+    let second_perp_market = Pubkey::new_unique();
+    let mut mango_group = MangoGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_group).copy_from_slice(&accounts[4].data);
+    mango_group.num_oracles = 2;
+    mango_group.tokens[1].mint = second_base_mint;
+    mango_group.perp_markets[1].perp_market = second_perp_market;
+    accounts[4].data = bytemuck::bytes_of(&mango_group).to_vec();
+    accounts.push(TestAccount::new(second_perp_market));
+    let data = |weights: [f64; 2], base_mints: [Pubkey; 2]| {
+        let mut perp_market_weights = [I80F48::from_num(0); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        perp_market_weights[0] = I80F48::from_num(weights[0]);
+        perp_market_weights[1] = I80F48::from_num(weights[1]);
+        let mut perp_market_base_mints = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+        perp_market_base_mints[..2].copy_from_slice(&base_mints);
+        QuasarInstruction::AddLeverageToken {
+            target_leverage: I80F48::from_num(3),
+            num_perp_markets: 2,
+            perp_market_weights,
+            perp_market_base_mints,
+            decimals: None,
+            rebalance_deadband_bps: 0,
+        }
+        .pack()
+    };
+
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    // The weights have to add up to one
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &data([0.75, 0.5], [base_mint, second_base_mint])
+        ),
+        QuasarErrorCode::InvalidParam
+    );
+    // Each leg is checked against its own base token
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &data([0.75, 0.25], [base_mint, base_mint])
+        ),
+        QuasarErrorCode::PerpMarketMismatch
+    );
+    process_instruction(
+        &program_id,
+        &account_infos,
+        &data([0.75, 0.25], [base_mint, second_base_mint]),
+    )
+    .unwrap();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&account_infos[0].data.borrow());
+    let index = quasar_group
+        .find_leverage_token_index_by_mint(&mint_key)
+        .unwrap();
+    let leverage_token = &quasar_group.leverage_tokens[index];
+    assert_eq!(leverage_token.num_perp_markets, 2);
+    assert_eq!(
+        leverage_token.mango_perp_markets[..2],
+        [*account_infos[11].key, second_perp_market]
+    );
+    assert_eq!(
+        leverage_token.perp_market_weights[..2],
+        [I80F48::from_num(0.75), I80F48::from_num(0.25)]
+    );
+    assert_eq!(
+        quasar_group.leg_base_mints(index),
+        [base_mint, second_base_mint]
+    );
+}
+
+#[test]
+fn test_add_leverage_token_creation_fee() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_accounts(&program_id, Pubkey::new_unique(), base_mint);
+    // Someone other than the admin signs for the creation
+    accounts[9].key = Pubkey::new_unique();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.base_tokens[0] = BaseToken {
+        mint: base_mint,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 1;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+
+    // Only the admin can add leverage tokens while the group charges no fee
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &add_leverage_token_data(account_infos[2].key)
+            ),
+            QuasarErrorCode::InvalidAdminKey
+        );
+    }
+
+    // With a fee, the creator has to pass the accounts paying it after the perp markets
+    quasar_group.creation_fee = 1_000_000;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+#[test]
+fn test_add_base_token_rejects_non_mint() {
+    let program_id = Pubkey::new_unique();
+    let admin_key = Pubkey::new_unique();
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    // A system account where the mint should be
+    let mut mint = TestAccount::new(Pubkey::new_unique());
+    mint.lamports = 1_000_000;
+    mint.owner = solana_program::system_program::id();
+
+    let mut accounts = vec![
+        group_account(
+            &program_id,
+            &admin_key,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+        ),
+        mint,
+        TestAccount::new(Pubkey::new_unique()), // oracle
+        admin,
+    ];
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::AddBaseToken.pack()
+        ),
+        QuasarErrorCode::InvalidMint
+    );
+}
+
+#[test]
+fn test_add_base_token_up_to_capacity() {
+    let program_id = Pubkey::new_unique();
+    let admin_key = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.admin_key = admin_key;
+    for base_token in quasar_group.base_tokens[..MAX_BASE_TOKENS - 1].iter_mut() {
+        base_token.mint = Pubkey::new_unique();
+    }
+    quasar_group.num_base_tokens = MAX_BASE_TOKENS - 1;
+
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    let mut mint = TestAccount {
+        data: vec![0; Mint::LEN],
+        owner: spl_token::id(),
+        ..TestAccount::new(Pubkey::new_unique())
+    };
+    let mint_state = Mint {
+        decimals: 6,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint_state, &mut mint.data).unwrap();
+    // A Pyth magic number is all adding a Pyth oracle reads
+    let mut oracle = TestAccount {
+        data: vec![0; 3312],
+        ..TestAccount::new(Pubkey::new_unique())
+    };
+    oracle.data[..4].copy_from_slice(&[212, 195, 178, 161]);
+    let mut accounts = vec![
+        group_account_from(&program_id, &quasar_group),
+        mint,
+        oracle,
+        admin,
+    ];
+    let data = QuasarInstruction::AddBaseToken.pack();
+
+    // The last free slot can be taken
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &data).unwrap();
+    }
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert_eq!(quasar_group.num_base_tokens, MAX_BASE_TOKENS);
+    assert_eq!(
+        quasar_group.base_tokens[MAX_BASE_TOKENS - 1].mint,
+        accounts[1].key
+    );
+
+    // But not one past it
+    accounts[1].key = Pubkey::new_unique();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &data),
+        QuasarErrorCode::OutOfSpace
+    );
+}
+
+#[test]
+fn test_add_leverage_token_rejects_token_over_base_cap() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_accounts(&program_id, Pubkey::new_unique(), base_mint);
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.base_tokens[0] = BaseToken {
+        mint: base_mint,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 1;
+    for (i, leverage) in [2, 5].iter().enumerate() {
+        quasar_group.leverage_tokens[i] = LeverageToken {
+            mint: Pubkey::new_unique(),
+            base_token_mint: base_mint,
+            target_leverage: I80F48::from_num(*leverage),
+            ..LeverageToken::zeroed()
+        };
+    }
+    quasar_group.num_leverage_tokens = 2;
+    quasar_group.max_tokens_per_base = 2;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::TooManyTokensForBase
+    );
+}
+
+#[test]
+fn test_add_leverage_token_rejects_perp_market_of_other_base() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_accounts(&program_id, Pubkey::new_unique(), base_mint);
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.base_tokens[0] = BaseToken {
+        mint: base_mint,
+        oracles: [Pubkey::new_unique(), Pubkey::default(), Pubkey::default()],
+        num_oracles: 1,
+        ..BaseToken::zeroed()
+    };
+    let other_base_mint = Pubkey::new_unique();
+    quasar_group.base_tokens[1] = BaseToken {
+        mint: other_base_mint,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 2;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+
+    // The only perp market passed is listed on the Mango group, for another asset
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.num_oracles = 1;
+    mango_group.tokens[0].mint = other_base_mint;
+    mango_group.oracles[0] = Pubkey::new_unique();
+    mango_group.perp_markets[0].perp_market = accounts[11].key;
+    accounts[4].data = bytemuck::bytes_of(&mango_group).to_vec();
+    accounts[4].owner = accounts[3].key;
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(&base_mint)
+        ),
+        QuasarErrorCode::PerpMarketMismatch
+    );
+    // Given the base token it does trade, the leverage token still trades none of its own
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(&other_base_mint)
+        ),
+        QuasarErrorCode::PerpMarketMismatch
+    );
+}
+
+/// Accounts of AddLeverageToken for a group with a base token listed on the Mango group, the
+/// mint and Mango account at random keys
+fn add_leverage_token_listed_accounts(program_id: &Pubkey, base_mint: Pubkey) -> Vec<TestAccount> {
+    let mut accounts = add_leverage_token_accounts(program_id, Pubkey::new_unique(), base_mint);
+    let mango_program_id = accounts[3].key;
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.signer_key = accounts[10].key;
+    quasar_group.base_tokens[0] = BaseToken {
+        mint: base_mint,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 1;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.num_oracles = 1;
+    mango_group.tokens[0].mint = base_mint;
+    mango_group.perp_markets[0].perp_market = accounts[11].key;
+    accounts[4].data = bytemuck::bytes_of(&mango_group).to_vec();
+    accounts[4].owner = mango_program_id;
+    accounts
+}
+
+/// Point the mint and Mango account of `add_leverage_token_listed_accounts` at the addresses
+/// derived for a token in the first slot, the mint's key returned
+fn derive_leverage_token_accounts(program_id: &Pubkey, accounts: &mut [TestAccount]) -> Pubkey {
+    let (mint_key, _) =
+        gen_leverage_token_mint_key(&accounts[0].key, &accounts[2].key, 0, program_id);
+    accounts[1].key = mint_key;
+    accounts[5].key = gen_mango_account_key(&accounts[0].key, &mint_key, program_id).0;
+    mint_key
+}
+
+#[test]
+fn test_add_leverage_token_creates_mint_at_derived_address() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_listed_accounts(&program_id, base_mint);
+    let (quasar_group_key, mango_program_id, signer_key) =
+        (accounts[0].key, accounts[3].key, accounts[10].key);
+
+    // A fresh account anywhere else isn't the token's mint
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &add_leverage_token_data(account_infos[2].key)
+            ),
+            QuasarErrorCode::InvalidAccount
+        );
+    }
+
+    // The mint derived from the base token and the first slot, free in the group, and the
+    // Mango account derived from it, get the instruction as far as creating the accounts
+    let mint_key = derive_leverage_token_accounts(&program_id, &mut accounts);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        // which a creator without lamports can't pay the rent of
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &add_leverage_token_data(account_infos[2].key)
+            ),
+            QuasarErrorCode::InsufficientRent
+        );
+    }
+
+    // Someone sent both addresses a lamport first, which only leaves less rent to pay
+    accounts[1].lamports = 1;
+    accounts[5].lamports = 1;
+    accounts[8] = rent_sysvar_account();
+    accounts[9].lamports = 1_000_000_000;
+    invoked();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    process_instruction(
+        &program_id,
+        &account_infos,
+        &add_leverage_token_data(account_infos[2].key),
+    )
+    .unwrap();
+
+    let rent = Rent::default();
+    assert_eq!(*account_infos[1].owner, spl_token::id());
+    assert_eq!(account_infos[1].lamports(), rent.minimum_balance(Mint::LEN));
+    let mint = Mint::unpack(&account_infos[1].data.borrow()).unwrap();
+    assert_eq!(mint.mint_authority, COption::Some(signer_key));
+    assert_eq!(mint.supply, 0);
+    assert_eq!(*account_infos[5].owner, mango_program_id);
+    assert_eq!(
+        account_infos[5].data_len(),
+        std::mem::size_of::<MangoAccount>()
+    );
+    assert_eq!(
+        account_infos[5].lamports(),
+        rent.minimum_balance(std::mem::size_of::<MangoAccount>())
+    );
+    assert!(invoked()
+        .iter()
+        .any(|instruction| instruction.program_id == mango_program_id));
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&account_infos[0].data.borrow());
+    let index = quasar_group
+        .find_leverage_token_index(&base_mint, I80F48::from_num(3))
+        .unwrap();
+    assert_eq!(quasar_group.leverage_tokens[index].mint, mint_key);
+    assert_eq!(
+        quasar_group.leverage_tokens[index].mango_account,
+        gen_mango_account_key(&quasar_group_key, &mint_key, &program_id).0
+    );
+}
+
+#[test]
+fn test_add_leverage_token_again_after_close() {
+    let program_id = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let mut accounts = add_leverage_token_listed_accounts(&program_id, base_mint);
+    let mint_key = derive_leverage_token_accounts(&program_id, &mut accounts);
+    let (mango_program_id, mango_group_key, signer_key) =
+        (accounts[3].key, accounts[4].key, accounts[10].key);
+    accounts[9].lamports = 1_000_000_000;
+
+    // A closed 3x token left its mint and Mango account behind
+    accounts[1].data = vec![0; Mint::LEN];
+    accounts[1].owner = spl_token::id();
+    let mint = Mint {
+        mint_authority: COption::Some(signer_key),
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+    accounts[5] = TestAccount {
+        key: accounts[5].key,
+        ..mango_account(&mango_program_id, &mango_group_key, &signer_key)
+    };
+
+    // Never from under a token still listed with that mint
+    let group_data = accounts[0].data.clone();
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.leverage_tokens[1] = LeverageToken {
+            mint: mint_key,
+            ..LeverageToken::zeroed()
+        };
+        quasar_group.num_leverage_tokens = 1;
+    });
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &add_leverage_token_data(account_infos[2].key)
+            ),
+            QuasarErrorCode::InvalidAccount
+        );
+    }
+    accounts[0].data = group_data;
+
+    // The mint keeps its decimals
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &add_leverage_token_data_with_decimals(account_infos[2].key, Some(2))
+            ),
+            QuasarErrorCode::InvalidParam
+        );
+    }
+
+    // Added again with both taken over as they are
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key),
+        )
+        .unwrap();
+    }
+    assert!(invoked().is_empty());
+    assert_eq!(accounts[9].lamports, 1_000_000_000);
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert!(quasar_group
+        .find_leverage_token_index_by_mint(&mint_key)
+        .is_some());
+}
+
+#[test]
+fn test_remove_base_token_with_dependents() {
+    let program_id = Pubkey::new_unique();
+    let admin_key = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.admin_key = admin_key;
+    quasar_group.base_tokens[0] = BaseToken {
+        mint: base_mint,
+        ..BaseToken::zeroed()
+    };
+    quasar_group.num_base_tokens = 1;
+    quasar_group.leverage_tokens[2] = LeverageToken {
+        mint: Pubkey::new_unique(),
+        base_token_mint: base_mint,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    let mut accounts = vec![
+        group_account_from(&program_id, &quasar_group),
+        TestAccount::new(base_mint),
+        admin,
+    ];
+    let data = QuasarInstruction::RemoveBaseToken.pack();
+
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &data),
+            QuasarErrorCode::BaseTokenInUse
+        );
+    }
+
+    // nor while a leverage token of another base token trades a perp market of it
+    quasar_group.leverage_tokens[2].base_token_mint = Pubkey::new_unique();
+    quasar_group.leverage_tokens[2].num_perp_markets = 2;
+    quasar_group.perp_market_base_mints[2][1] = base_mint;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &data),
+            QuasarErrorCode::BaseTokenInUse
+        );
+    }
+
+    // Without the leverage token the base token goes
+    quasar_group.leverage_tokens[2] = LeverageToken::zeroed();
+    quasar_group.num_leverage_tokens = 0;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &data).unwrap();
+    }
+    let quasar_group: &QuasarGroup = bytemuck::from_bytes(&accounts[0].data);
+    assert_eq!(quasar_group.num_base_tokens, 0);
+    assert!(quasar_group.find_base_token_index(&base_mint).is_none());
+}
+
+#[test]
+fn test_init_quasar_group_checks_mango_program_is_executable() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::InitQuasarGroup { signer_nonce: 0 };
+    let mut accounts = accounts_for(&instruction, 0);
+    accounts[0].owner = program_id;
+
+    // The Mango group's key instead of the program's
+    accounts[3] = TestAccount {
+        data: vec![0; 64],
+        ..TestAccount::new(accounts[5].key)
+    };
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidMangoProgram
+    );
+}
+
+#[test]
+fn test_close_leverage_token_after_wind_down() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::CloseLeverageToken;
+    let mut accounts = accounts_for(&instruction, 0);
+    let (mint_key, mango_program_id, mango_group_key, mango_account_key) = (
+        accounts[1].key,
+        accounts[2].key,
+        accounts[3].key,
+        accounts[4].key,
+    );
+
+    let perp_market = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.admin_key = accounts[5].key;
+    quasar_group.mango_program_id = mango_program_id;
+    quasar_group.mango_group = mango_group_key;
+    let mut mango_perp_markets = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    mango_perp_markets[0] = perp_market;
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: mint_key,
+        mango_account: mango_account_key,
+        mango_perp_markets,
+        num_perp_markets: 1,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_with_signer(&program_id, &mut quasar_group);
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.num_oracles = 1;
+    mango_group.perp_markets[0].perp_market = perp_market;
+    accounts[3].data = bytemuck::bytes_of(&mango_group).to_vec();
+    accounts[3].owner = mango_program_id;
+    // Every position was unwound, the account only holds its rent
+    accounts[4] = TestAccount {
+        key: mango_account_key,
+        lamports: 5_000_000,
+        ..mango_account(
+            &mango_program_id,
+            &mango_group_key,
+            &quasar_group.signer_key,
+        )
+    };
+
+    // Someone still holds some of the token
+    let mut mint = Mint {
+        mint_authority: COption::Some(quasar_group.signer_key),
+        supply: 10,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    accounts[1].data = vec![0; Mint::LEN];
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::TokenSupplyNonzero
+        );
+    }
+
+    // Nor can an open position be left in the account
+    mint.supply = 0;
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    mango_account.mango_group = mango_group_key;
+    mango_account.owner = quasar_group.signer_key;
+    mango_account.perp_accounts[0].base_position = 1;
+    accounts[4].data = bytemuck::bytes_of(&mango_account).to_vec();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::OpenPositionRemaining
+        );
+    }
+
+    // Fully wound down, the token's slot is freed. The Mango account is left open with its
+    // rent, for the token to be added again
+    mango_account.perp_accounts[0].base_position = 0;
+    accounts[4].data = bytemuck::bytes_of(&mango_account).to_vec();
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    }
+    assert!(invoked().is_empty());
+    assert_eq!(accounts[4].lamports, 5_000_000);
+    assert_eq!(accounts[4].owner, mango_program_id);
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert_eq!(quasar_group.num_leverage_tokens, 0);
+    assert!(quasar_group
+        .find_leverage_token_index_by_mint(&mint_key)
+        .is_none());
+}
+
+#[test]
+fn test_set_mango_program() {
+    let program_id = Pubkey::new_unique();
+    let admin_key = Pubkey::new_unique();
+    let (old_mango_program, old_mango_group) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let new_mango_program = Pubkey::new_unique();
+
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    let mut mango_program = TestAccount::new(new_mango_program);
+    mango_program.executable = true;
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    let mango_group = TestAccount {
+        data: bytemuck::bytes_of(&mango_group).to_vec(),
+        owner: new_mango_program,
+        ..TestAccount::new(Pubkey::new_unique())
+    };
+    let new_mango_group = mango_group.key;
+
+    let mut accounts = vec![
+        group_account(
+            &program_id,
+            &admin_key,
+            &old_mango_program,
+            &old_mango_group,
+        ),
+        mango_program,
+        mango_group,
+        admin,
+    ];
+    let data = QuasarInstruction::SetMangoProgram.pack();
+
+    // The new program has to be an actual program
+    accounts[1].executable = false;
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &data),
+            QuasarErrorCode::InvalidMangoProgram
+        );
+    }
+    accounts[1].executable = true;
+
+    // Not while a leverage token is left on the old program
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: Pubkey::new_unique(),
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    let group_data = accounts[0].data.clone();
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &data),
+            QuasarErrorCode::MangoProgramInUse
+        );
+    }
+
+    accounts[0].data = group_data;
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &data).unwrap();
+    }
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert_eq!(quasar_group.mango_program_id, new_mango_program);
+    assert_eq!(quasar_group.mango_group, new_mango_group);
+
+    // Every CPI goes through the group's Mango program and group from now on
+    quasar_group
+        .check_mango_group(&new_mango_program, &new_mango_group)
+        .unwrap();
+    assert_quasar_err!(
+        quasar_group.check_mango_group(&old_mango_program, &new_mango_group),
+        QuasarErrorCode::InvalidMangoProgram
+    );
+    assert_quasar_err!(
+        quasar_group.check_mango_group(&new_mango_program, &old_mango_group),
+        QuasarErrorCode::InvalidMangoGroup
+    );
+}
+
+#[test]
+fn test_migrate_group_from_v0() {
+    let program_id = Pubkey::new_unique();
+    let (admin_key, signer_key, mango_program_id) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    let (base_mint, oracle) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let (token_mint_key, mango_account_key, perp_market) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+
+    // The bytes of a group as the first release of the program wrote them: its header, one
+    // base token and one 3x leverage token, in the third slot
+    let mut v0_data = vec![0; QUASAR_GROUP_V0_LEN];
+    v0_data[..3].copy_from_slice(&[DataType::QuasarGroup as u8, 0, 1]);
+    v0_data[8..16].copy_from_slice(&1usize.to_le_bytes());
+    v0_data[16..48].copy_from_slice(base_mint.as_ref());
+    v0_data[48] = 9;
+    v0_data[49..81].copy_from_slice(oracle.as_ref());
+    v0_data[1168..1176].copy_from_slice(&1usize.to_le_bytes());
+    let leverage_token = 1176 + 2 * 144;
+    for (i, field) in [
+        token_mint_key.to_bytes().to_vec(),
+        base_mint.to_bytes().to_vec(),
+        I80F48::from_num(3).to_le_bytes().to_vec(),
+        mango_account_key.to_bytes().to_vec(),
+        perp_market.to_bytes().to_vec(),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let offset = leverage_token + [0, 32, 64, 80, 112][i];
+        v0_data[offset..offset + field.len()].copy_from_slice(field);
+    }
+    v0_data[5784..5792].copy_from_slice(&255u64.to_le_bytes());
+    v0_data[5792..5824].copy_from_slice(signer_key.as_ref());
+    v0_data[5824..5856].copy_from_slice(admin_key.as_ref());
+    v0_data[5856..5888].copy_from_slice(mango_program_id.as_ref());
+    let mut group = SerializedAccount::new(TestAccount {
+        data: v0_data.clone(),
+        lamports: Rent::default().minimum_balance(QUASAR_GROUP_V0_LEN),
+        owner: program_id,
+        ..TestAccount::new(Pubkey::new_unique())
+    });
+
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    admin.lamports = 1_000_000_000;
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    let quote_mint_key = Pubkey::new_unique();
+    mango_group.tokens[QUOTE_INDEX].mint = quote_mint_key;
+    let mut mango_group = TestAccount {
+        data: bytemuck::bytes_of(&mango_group).to_vec(),
+        owner: mango_program_id,
+        ..TestAccount::new(Pubkey::new_unique())
+    };
+    let mint_account = |key, decimals, supply| {
+        let mut account = TestAccount {
+            data: vec![0; Mint::LEN],
+            owner: spl_token::id(),
+            ..TestAccount::new(key)
+        };
+        let mint = Mint {
+            decimals,
+            supply,
+            is_initialized: true,
+            ..Mint::default()
+        };
+        Mint::pack(mint, &mut account.data).unwrap();
+        account
+    };
+    let mut quote_mint = mint_account(quote_mint_key, 6, 0);
+    let mut system_program = TestAccount::new(system_program::id());
+    let mut token_mint = mint_account(token_mint_key, 0, 500);
+
+    // Nothing else takes the group until it is migrated
+    let change_group_params = QuasarInstruction::ChangeGroupParams {
+        min_health_ratio: None,
+        deleverage_health_ratio: None,
+        min_rebalance_interval: Some(60),
+        max_tokens_per_base: None,
+        rebalance_deadband_bps: None,
+        creation_fee: None,
+        min_hold_secs: None,
+        crank_reward_bps: None,
+        max_crank_reward: None,
+    };
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &[group.info(), admin.info()],
+            &change_group_params.pack()
+        ),
+        QuasarErrorCode::GroupNeedsMigration
+    );
+
+    // The first call grows the group as far as a single instruction may, leaving its bytes be
+    let migrate_group = QuasarInstruction::MigrateGroup.pack();
+    process_instruction(
+        &program_id,
+        &[
+            group.info(),
+            admin.info(),
+            mango_group.info(),
+            quote_mint.info(),
+            system_program.info(),
+            token_mint.info(),
+        ],
+        &migrate_group,
+    )
+    .unwrap();
+    let grown_len = QUASAR_GROUP_V0_LEN + MAX_PERMITTED_DATA_INCREASE;
+    assert_eq!(group.data().len(), grown_len);
+    assert_eq!(&group.data()[..QUASAR_GROUP_V0_LEN], &v0_data[..]);
+    assert!(group.data()[QUASAR_GROUP_V0_LEN..].iter().all(|b| *b == 0));
+    assert_eq!(group.lamports, Rent::default().minimum_balance(grown_len));
+
+    // The next transaction serializes it again, with room to grow. The second call grows it
+    // some more
+    let mut group = SerializedAccount::new(TestAccount {
+        data: group.data().to_vec(),
+        lamports: group.lamports,
+        owner: program_id,
+        ..TestAccount::new(group.key)
+    });
+    process_instruction(
+        &program_id,
+        &[
+            group.info(),
+            admin.info(),
+            mango_group.info(),
+            quote_mint.info(),
+            system_program.info(),
+            token_mint.info(),
+        ],
+        &migrate_group,
+    )
+    .unwrap();
+    let grown_len = grown_len + MAX_PERMITTED_DATA_INCREASE;
+    assert_eq!(group.data().len(), grown_len);
+    assert_eq!(&group.data()[..QUASAR_GROUP_V0_LEN], &v0_data[..]);
+
+    // and the third finishes it. Every leverage token's mint has to be passed for its supply
+    let mut group = SerializedAccount::new(TestAccount {
+        data: group.data().to_vec(),
+        lamports: group.lamports,
+        owner: program_id,
+        ..TestAccount::new(group.key)
+    });
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &[
+                group.info(),
+                admin.info(),
+                mango_group.info(),
+                quote_mint.info(),
+                system_program.info(),
+            ],
+            &migrate_group,
+        ),
+        QuasarErrorCode::InvalidAccount
+    );
+    process_instruction(
+        &program_id,
+        &[
+            group.info(),
+            admin.info(),
+            mango_group.info(),
+            quote_mint.info(),
+            system_program.info(),
+            token_mint.info(),
+        ],
+        &migrate_group,
+    )
+    .unwrap();
+
+    // which rewrites it in the current layout
+    let mut expected = QuasarGroup::zeroed();
+    expected.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    expected.num_base_tokens = 1;
+    expected.base_tokens[0] = BaseToken {
+        mint: base_mint,
+        decimals: 9,
+        num_oracles: 1,
+        ..BaseToken::zeroed()
+    };
+    expected.base_tokens[0].oracles[0] = oracle;
+    let mut perp_market_weights = [I80F48::from_num(0); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    perp_market_weights[0] = I80F48::from_num(1);
+    let mut mango_perp_markets = [Pubkey::default(); MAX_PERP_MARKETS_PER_LEVERAGE_TOKEN];
+    mango_perp_markets[0] = perp_market;
+    expected.num_leverage_tokens = 1;
+    expected.perp_market_base_mints[2][0] = base_mint;
+    expected.leverage_tokens[2] = LeverageToken {
+        mint: token_mint_key,
+        base_token_mint: base_mint,
+        target_leverage: I80F48::from_num(3),
+        mango_account: mango_account_key,
+        perp_market_weights,
+        mango_perp_markets,
+        num_perp_markets: 1,
+        last_fee_accrual_ts: 1_600_000_000,
+        tracked_supply: 500,
+        ..LeverageToken::zeroed()
+    };
+    expected.signer_nonce = 255;
+    expected.signer_key = signer_key;
+    expected.admin_key = admin_key;
+    expected.mango_program_id = mango_program_id;
+    expected.mango_group = mango_group.key;
+    expected.quote_mint = quote_mint_key;
+    expected.quote_decimals = 6;
+    expected.init_discriminator();
+    assert_eq!(group.data().len(), size_of::<QuasarGroup>());
+    assert_eq!(group.data(), bytemuck::bytes_of(&expected));
+    assert_eq!(
+        group.lamports,
+        Rent::default().minimum_balance(size_of::<QuasarGroup>())
+    );
+
+    // Once is enough, after which the group is usable again
+    let mut group = SerializedAccount::new(TestAccount {
+        data: group.data().to_vec(),
+        lamports: group.lamports,
+        owner: program_id,
+        ..TestAccount::new(group.key)
+    });
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &[
+                group.info(),
+                admin.info(),
+                mango_group.info(),
+                quote_mint.info(),
+                system_program.info(),
+            ],
+            &migrate_group,
+        ),
+        QuasarErrorCode::InvalidParam
+    );
+    process_instruction(
+        &program_id,
+        &[group.info(), admin.info()],
+        &change_group_params.pack(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_migrate_group_from_v3() {
+    let program_id = Pubkey::new_unique();
+    let (admin_key, risk_admin_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    admin.lamports = 1_000_000_000;
+    let mut quote_mint = TestAccount {
+        data: vec![0; Mint::LEN],
+        owner: spl_token::id(),
+        ..TestAccount::new(Pubkey::new_unique())
+    };
+    let mint = Mint {
+        decimals: 6,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut quote_mint.data).unwrap();
+    let mut mango_group = TestAccount::new(Pubkey::new_unique());
+    let mut system_program = TestAccount::new(system_program::id());
+
+    // Version 3 kept the risk admin in the last redeem queue slot, and had no room after it
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, 3, true);
+    quasar_group.admin_key = admin_key;
+    quasar_group.mango_group = mango_group.key;
+    quasar_group.quote_mint = quote_mint.key;
+    quasar_group.quote_decimals = 6;
+    quasar_group.legacy_redeem_queue[MAX_REDEEM_REQUESTS - 1].owner = risk_admin_key;
+    let mut data = group_account_from(&program_id, &quasar_group).data;
+    data.truncate(QUASAR_GROUP_V3_LEN);
+    let mut group = SerializedAccount::new(TestAccount {
+        data,
+        lamports: Rent::default().minimum_balance(QUASAR_GROUP_V3_LEN),
+        owner: program_id,
+        ..TestAccount::new(Pubkey::new_unique())
+    });
+
+    let set_paused = QuasarInstruction::SetPaused { paused: true };
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &[group.info(), admin.info()],
+            &set_paused.pack()
+        ),
+        QuasarErrorCode::GroupNeedsMigration
+    );
+
+    // Grown by the fields appended since, which the risk admin is moved to
+    process_instruction(
+        &program_id,
+        &[
+            group.info(),
+            admin.info(),
+            mango_group.info(),
+            quote_mint.info(),
+            system_program.info(),
+        ],
+        &QuasarInstruction::MigrateGroup.pack(),
+    )
+    .unwrap();
+    assert_eq!(group.data().len(), size_of::<QuasarGroup>());
+    assert_eq!(
+        group.lamports,
+        Rent::default().minimum_balance(size_of::<QuasarGroup>())
+    );
+    let mut migrated = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut migrated).copy_from_slice(group.data());
+    assert_eq!(migrated.meta_data.version, QUASAR_GROUP_VERSION);
+    assert_eq!(migrated.risk_admin_key, risk_admin_key);
+    assert!(!migrated.paused);
+    assert_eq!(
+        migrated.legacy_redeem_queue[MAX_REDEEM_REQUESTS - 1],
+        RedeemRequest::zeroed()
+    );
+
+    let mut group = SerializedAccount::new(TestAccount {
+        data: group.data().to_vec(),
+        lamports: group.lamports,
+        owner: program_id,
+        ..TestAccount::new(group.key)
+    });
+    process_instruction(
+        &program_id,
+        &[group.info(), admin.info()],
+        &set_paused.pack(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_set_mint_freeze_authority_needs_the_signer_to_hold_it() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::SetMintFreezeAuthority {
+        freeze_authority: None,
+    };
+    let mut accounts = accounts_for(&instruction, 0);
+    let (mint_key, signer_key, admin_key) = (accounts[1].key, accounts[2].key, accounts[4].key);
+    accounts[3].key = spl_token::id();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.admin_key = admin_key;
+    quasar_group.signer_key = signer_key;
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: mint_key,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_from(&program_id, &quasar_group);
+
+    // Already revoked, the signer has nothing left to hand over
+    accounts[1].data = vec![0; Mint::LEN];
+    accounts[1].owner = spl_token::id();
+    let mint = Mint {
+        mint_authority: COption::Some(signer_key),
+        freeze_authority: COption::None,
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(mint, &mut accounts[1].data).unwrap();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidMintAuthority
+    );
+    drop(account_infos);
+
+    accounts[4].key = Pubkey::new_unique();
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidAdminKey
+    );
+}
+
+#[test]
+fn test_risk_admin_can_pause_but_not_move_funds() {
+    let program_id = Pubkey::new_unique();
+    let (admin_key, risk_admin_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let (mango_program_id, mango_group_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let mut quasar_group =
+        group_account(&program_id, &admin_key, &mango_program_id, &mango_group_key);
+    let signer = |key| TestAccount {
+        is_signer: true,
+        ..TestAccount::new(key)
+    };
+    let (mut admin, mut risk_admin) = (signer(admin_key), signer(risk_admin_key));
+    let process = |quasar_group: &mut TestAccount,
+                   signer: &mut TestAccount,
+                   instruction: QuasarInstruction| {
+        process_instruction(
+            &program_id,
+            &[quasar_group.info(), signer.info()],
+            &instruction.pack(),
+        )
+    };
+
+    // There's no risk admin until the admin appoints one
+    let pause = QuasarInstruction::SetPaused { paused: true };
+    assert_quasar_err!(
+        process(&mut quasar_group, &mut risk_admin, pause),
+        QuasarErrorCode::InvalidAdminKey
+    );
+    let appoint = QuasarInstruction::SetRiskAdmin {
+        risk_admin: Some(risk_admin_key),
+    };
+    assert_quasar_err!(
+        process(&mut quasar_group, &mut risk_admin, appoint),
+        QuasarErrorCode::InvalidAdminKey
+    );
+    process(&mut quasar_group, &mut admin, appoint).unwrap();
+
+    process(&mut quasar_group, &mut risk_admin, pause).unwrap();
+    let change_group_params =
+        |rebalance_deadband_bps, crank_reward_bps| QuasarInstruction::ChangeGroupParams {
+            min_health_ratio: None,
+            deleverage_health_ratio: None,
+            min_rebalance_interval: Some(120),
+            max_tokens_per_base: None,
+            rebalance_deadband_bps: Some(rebalance_deadband_bps),
+            creation_fee: None,
+            min_hold_secs: None,
+            crank_reward_bps,
+            max_crank_reward: None,
+        };
+    process(
+        &mut quasar_group,
+        &mut risk_admin,
+        change_group_params(50, None),
+    )
+    .unwrap();
+    // Crank rewards are paid out of the fee vault
+    assert_quasar_err!(
+        process(
+            &mut quasar_group,
+            &mut risk_admin,
+            change_group_params(50, Some(10))
+        ),
+        QuasarErrorCode::InvalidAdminKey
+    );
+
+    let mut group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut group).copy_from_slice(&quasar_group.data);
+    assert_eq!(group.risk_admin_key, risk_admin_key);
+    assert!(group.paused);
+    assert_eq!(group.min_rebalance_interval, 120);
+    assert_eq!(group.rebalance_deadband_bps, 50);
+    assert_eq!(group.crank_reward_bps, 0);
+
+    // Closing a token stays with the admin
+    let instruction = QuasarInstruction::CloseLeverageToken;
+    let mut accounts = accounts_for(&instruction, 0);
+    accounts[0] = quasar_group;
+    accounts[2].key = mango_program_id;
+    accounts[3].key = mango_group_key;
+    accounts[5] = risk_admin;
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::InvalidAdminKey
+    );
+}
+
+#[test]
+fn test_paused_group_refuses_mints() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::MintLeverageToken { quantity: 10 };
+    let mut accounts = mint_accounts(&program_id, &instruction, 0);
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    quasar_group.paused = true;
+    accounts[0].data = bytemuck::bytes_of(&quasar_group).to_vec();
+
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(&program_id, &account_infos, &instruction.pack()),
+        QuasarErrorCode::GroupPaused
+    );
+}
+
+#[test]
+fn test_change_group_params_caps_crank_reward() {
+    let program_id = Pubkey::new_unique();
+    let admin_key = Pubkey::new_unique();
+    let mut admin = TestAccount::new(admin_key);
+    admin.is_signer = true;
+    let mut accounts = vec![
+        group_account(
+            &program_id,
+            &admin_key,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+        ),
+        admin,
+    ];
+    let change_group_params = |crank_reward_bps| QuasarInstruction::ChangeGroupParams {
+        min_health_ratio: None,
+        deleverage_health_ratio: None,
+        min_rebalance_interval: None,
+        max_tokens_per_base: None,
+        rebalance_deadband_bps: None,
+        creation_fee: None,
+        min_hold_secs: None,
+        crank_reward_bps: Some(crank_reward_bps),
+        max_crank_reward: Some(10),
+    };
+
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &change_group_params(MAX_CRANK_REWARD_BPS + 1).pack()
+        ),
+        QuasarErrorCode::InvalidParam
+    );
+    process_instruction(
+        &program_id,
+        &account_infos,
+        &change_group_params(MAX_CRANK_REWARD_BPS).pack(),
+    )
+    .unwrap();
+    drop(account_infos);
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert_eq!(quasar_group.crank_reward_bps, MAX_CRANK_REWARD_BPS);
+    assert_eq!(quasar_group.max_crank_reward, 10);
+}
+
+#[test]
+fn test_init_fee_vault_takes_the_quote_mint() {
+    let program_id = Pubkey::new_unique();
+    let instruction = QuasarInstruction::InitFeeVault;
+    let mut accounts = accounts_for(&instruction, 0);
+    let (admin_key, signer_key) = (accounts[3].key, accounts[4].key);
+    let quote_mint = Pubkey::new_unique();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    quasar_group.admin_key = admin_key;
+    quasar_group.signer_key = signer_key;
+    quasar_group.quote_mint = quote_mint;
+    accounts[0] = group_account_from(&program_id, &quasar_group);
+
+    // The signer's account of another mint would never receive a fee
+    accounts[1].key = get_associated_token_address(&signer_key, &accounts[2].key);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::InvalidQuoteMint
+        );
+    }
+
+    // The signer's quote token account, which already exists and is reused
+    accounts[2].key = quote_mint;
+    accounts[1] = TestAccount {
+        data: vec![0; TokenAccount::LEN],
+        owner: spl_token::id(),
+        ..TestAccount::new(get_associated_token_address(&signer_key, &quote_mint))
+    };
+    let fee_vault = TokenAccount {
+        mint: quote_mint,
+        owner: signer_key,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+    TokenAccount::pack(fee_vault, &mut accounts[1].data).unwrap();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    }
+    let quasar_group: &QuasarGroup = bytemuck::from_bytes(&accounts[0].data);
+    assert_eq!(quasar_group.fee_vault, accounts[1].key);
+}
+
+#[test]
+fn test_crank_reward_is_paid_from_the_fee_vault() {
+    let signer_key = Pubkey::new_unique();
+    let quote_mint = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.signer_key = signer_key;
+    quasar_group.quote_mint = quote_mint;
+    quasar_group.crank_reward_bps = 5;
+    quasar_group.max_crank_reward = 10;
+
+    let quote_token_account = |owner: Pubkey, amount: u64| {
+        let mut account = TestAccount {
+            data: vec![0; TokenAccount::LEN],
+            owner: spl_token::id(),
+            ..TestAccount::new(Pubkey::new_unique())
+        };
+        let token_account = TokenAccount {
+            mint: quote_mint,
+            owner,
+            amount,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        TokenAccount::pack(token_account, &mut account.data).unwrap();
+        account
+    };
+    let mut fee_vault = quote_token_account(signer_key, 600_000);
+    let mut crank_quote_token_account = quote_token_account(Pubkey::new_unique(), 0);
+    let mut signer = TestAccount {
+        is_signer: true,
+        ..TestAccount::new(signer_key)
+    };
+    let amount = |account: &TestAccount| TokenAccount::unpack(&account.data).unwrap().amount;
+
+    // Filling 2_000 quote tokens earns a token, more than the fee vault has left
+    let traded_notional = I80F48::from_num(2_000_000_000u64);
+    let reward = quasar_group
+        .crank_reward(traded_notional, 6, amount(&fee_vault))
+        .unwrap();
+    assert_eq!(reward, 600_000);
+
+    // The transfer `Rebalance` has the group signer make
+    let transfer = spl_token::instruction::transfer(
+        &spl_token::id(),
+        &fee_vault.key,
+        &crank_quote_token_account.key,
+        &signer_key,
+        &[],
+        reward,
+    )
+    .unwrap();
+    spl_token::processor::process_instruction(
+        &spl_token::id(),
+        &[
+            fee_vault.info(),
+            crank_quote_token_account.info(),
+            signer.info(),
+        ],
+        &transfer.data,
+    )
+    .unwrap();
+    assert_eq!(amount(&crank_quote_token_account), 600_000);
+    assert_eq!(amount(&fee_vault), 0);
+
+    // Once the vault is empty the next crank goes unpaid
+    assert_eq!(
+        quasar_group
+            .crank_reward(traded_notional, 6, amount(&fee_vault))
+            .unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_freezes_fail_once_freeze_authority_is_revoked() {
+    let signer_key = Pubkey::new_unique();
+    let mut mint = TestAccount {
+        data: vec![0; Mint::LEN],
+        owner: spl_token::id(),
+        ..TestAccount::new(Pubkey::new_unique())
+    };
+    let leverage_mint = Mint {
+        mint_authority: COption::Some(signer_key),
+        freeze_authority: COption::Some(signer_key),
+        is_initialized: true,
+        ..Mint::default()
+    };
+    Mint::pack(leverage_mint, &mut mint.data).unwrap();
+    let mut holder_accounts: Vec<TestAccount> = (0..2)
+        .map(|_| {
+            let mut holder_account = TestAccount {
+                data: vec![0; TokenAccount::LEN],
+                owner: spl_token::id(),
+                ..TestAccount::new(Pubkey::new_unique())
+            };
+            let token_account = TokenAccount {
+                mint: mint.key,
+                owner: Pubkey::new_unique(),
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            };
+            TokenAccount::pack(token_account, &mut holder_account.data).unwrap();
+            holder_account
+        })
+        .collect();
+    let mut signer = TestAccount {
+        is_signer: true,
+        ..TestAccount::new(signer_key)
+    };
+
+    let freeze = |mint: &mut TestAccount, holder: &mut TestAccount, signer: &mut TestAccount| {
+        let ix = spl_token::instruction::freeze_account(
+            &spl_token::id(),
+            &holder.key,
+            &mint.key,
+            &signer.key,
+            &[],
+        )
+        .unwrap();
+        spl_token::processor::Processor::process(
+            &spl_token::id(),
+            &[holder.info(), mint.info(), signer.info()],
+            &ix.data,
+        )
+    };
+
+    // While the signer holds the authority it can freeze any holder
+    assert!(freeze(&mut mint, &mut holder_accounts[0], &mut signer).is_ok());
+
+    // The same call SetMintFreezeAuthority has the signer make to revoke it
+    let revoke = spl_token::instruction::set_authority(
+        &spl_token::id(),
+        &mint.key,
+        None,
+        spl_token::instruction::AuthorityType::FreezeAccount,
+        &signer.key,
+        &[],
+    )
+    .unwrap();
+    spl_token::processor::Processor::process(
+        &spl_token::id(),
+        &[mint.info(), signer.info()],
+        &revoke.data,
+    )
+    .unwrap();
+    assert_eq!(
+        Mint::unpack(&mint.data).unwrap().freeze_authority,
+        COption::None
+    );
+
+    assert_eq!(
+        freeze(&mut mint, &mut holder_accounts[1], &mut signer),
+        Err(spl_token::error::TokenError::MintCannotFreeze.into())
+    );
+}
+
+/// Accounts of a `DeleverageToken` of a token long 50 lots of its only perp market at `price`,
+/// against 1_600 of quote, at a 0.9 maintenance weight and a deleverage threshold of 10%. The
+/// Mango cache was updated at the time the test clock starts at, and stays valid for 10 seconds
+fn deleverage_accounts(program_id: &Pubkey, price: i64) -> Vec<TestAccount> {
+    let mut accounts: Vec<TestAccount> = (0..7 + MAX_PAIRS + 4)
+        .map(|_| TestAccount::new(Pubkey::new_unique()))
+        .collect();
+    let (mint_key, mango_program_id, mango_group_key, mango_account_key, mango_cache_key) = (
+        accounts[1].key,
+        accounts[3].key,
+        accounts[4].key,
+        accounts[5].key,
+        accounts[6].key,
+    );
+    let perp_market_key = accounts[7 + MAX_PAIRS].key;
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.mango_program_id = mango_program_id;
+    quasar_group.mango_group = mango_group_key;
+    quasar_group.deleverage_health_ratio = I80F48::from_num(10);
+    quasar_group.leverage_tokens[0] = LeverageToken {
+        mint: mint_key,
+        mango_account: mango_account_key,
+        num_perp_markets: 1,
+        ..LeverageToken::zeroed()
+    };
+    quasar_group.leverage_tokens[0].mango_perp_markets[0] = perp_market_key;
+    quasar_group.leverage_tokens[0].perp_market_weights[0] = I80F48::from_num(1);
+    quasar_group.num_leverage_tokens = 1;
+    accounts[0] = group_account_with_signer(program_id, &mut quasar_group);
+    accounts[2].key = quasar_group.signer_key;
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.meta_data = MangoMetaData::new(MangoDataType::MangoGroup, 0, true);
+    mango_group.num_oracles = 1;
+    mango_group.mango_cache = mango_cache_key;
+    mango_group.perp_markets[0].perp_market = perp_market_key;
+    mango_group.perp_markets[0].base_lot_size = 1;
+    mango_group.perp_markets[0].quote_lot_size = 1;
+    mango_group.perp_markets[0].maint_asset_weight = I80F48::from_num(0.9);
+    mango_group.valid_interval = 10;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.meta_data = MangoMetaData::new(MangoDataType::MangoCache, 0, true);
+    mango_cache.price_cache[0].price = I80F48::from_num(price);
+    set_mango_cache_updated(&mut mango_cache, 1_600_000_000);
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.meta_data = MangoMetaData::new(MangoDataType::MangoAccount, 0, true);
+    mango_account.mango_group = mango_group_key;
+    mango_account.owner = quasar_group.signer_key;
+    mango_account.perp_accounts[0].base_position = 50;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-1_600);
+    for (i, data) in [
+        (4, bytemuck::bytes_of(&mango_group).to_vec()),
+        (5, bytemuck::bytes_of(&mango_account).to_vec()),
+        (6, bytemuck::bytes_of(&mango_cache).to_vec()),
+    ]
+    .iter()
+    {
+        accounts[*i].data = data.clone();
+        accounts[*i].owner = mango_program_id;
+    }
+    accounts
+}
+
+/// Stamp the entries of the quote token and the first perp market of `mango_cache` as updated
+/// at `now_ts`
+fn set_mango_cache_updated(mango_cache: &mut MangoCache, now_ts: u64) {
+    mango_cache.root_bank_cache[QUOTE_INDEX].last_update = now_ts;
+    mango_cache.price_cache[0].last_update = now_ts;
+    mango_cache.perp_market_cache[0].last_update = now_ts;
+}
+
+#[test]
+fn test_deleverage_token_only_below_the_health_threshold() {
+    let program_id = Pubkey::new_unique();
+    // At 40 the position is 1_800 of weighted assets for 1_600 of liabilities, 12.5% healthy
+    let mut accounts = deleverage_accounts(&program_id, 40);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(
+                &program_id,
+                &account_infos,
+                &QuasarInstruction::DeleverageToken.pack()
+            ),
+            QuasarErrorCode::TokenHealthy
+        );
+    }
+
+    // A move to 39 takes it to 1_755 of assets, 9.7%, and half the position is sold off
+    let mut accounts = deleverage_accounts(&program_id, 39);
+    let mango_program_id = accounts[3].key;
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::DeleverageToken.pack(),
+        )
+        .unwrap();
+    }
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![25]);
+}
+
+#[test]
+fn test_deleverage_token_orders_lots_of_native_units() {
+    // Mango caches native quote units per native base unit, whatever the decimals of the tokens
+    let program_id = Pubkey::new_unique();
+    let mut accounts = deleverage_accounts(&program_id, 39);
+    let mut mango_group = MangoGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_group).copy_from_slice(&accounts[4].data);
+    mango_group.tokens[0].decimals = 9;
+    mango_group.tokens[QUOTE_INDEX].decimals = 6;
+    accounts[4].data = bytemuck::bytes_of(&mango_group).to_vec();
+    let mango_program_id = accounts[3].key;
+    invoked();
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::DeleverageToken.pack(),
+        )
+        .unwrap();
+    }
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![25]);
+}
+
+#[test]
+fn test_deleverage_token_waits_for_its_cooldown_and_a_fresh_cache() {
+    let program_id = Pubkey::new_unique();
+    let now_ts = 1_600_000_000;
+    set_unix_timestamp(now_ts);
+    let mut accounts = deleverage_accounts(&program_id, 39);
+    let mango_program_id = accounts[3].key;
+    let deleverage = |accounts: &mut Vec<TestAccount>| {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &QuasarInstruction::DeleverageToken.pack(),
+        )
+    };
+    deleverage(&mut accounts).unwrap();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    bytemuck::bytes_of_mut(&mut quasar_group).copy_from_slice(&accounts[0].data);
+    assert_eq!(
+        quasar_group.leverage_tokens[0].last_rebalance_ts,
+        now_ts as u64
+    );
+
+    // The cache still has the position unhealthy, it can't be halved again right away
+    invoked();
+    set_unix_timestamp(now_ts + MIN_DELEVERAGE_INTERVAL_SECS as i64 - 1);
+    assert_quasar_err!(
+        deleverage(&mut accounts),
+        QuasarErrorCode::DeleverageTooSoon
+    );
+
+    // Once the cooldown is over the cache has to have been updated since
+    let later_ts = now_ts + MIN_DELEVERAGE_INTERVAL_SECS as i64;
+    set_unix_timestamp(later_ts);
+    assert_quasar_err!(deleverage(&mut accounts), QuasarErrorCode::MangoCacheStale);
+    assert!(invoked().is_empty());
+
+    let mut mango_cache = MangoCache::zeroed();
+    bytemuck::bytes_of_mut(&mut mango_cache).copy_from_slice(&accounts[6].data);
+    set_mango_cache_updated(&mut mango_cache, later_ts as u64);
+    accounts[6].data = bytemuck::bytes_of(&mango_cache).to_vec();
+    deleverage(&mut accounts).unwrap();
+    assert_eq!(placed_perp_orders(&mango_program_id), vec![25]);
+}
+
+#[test]
+fn test_rebalance_and_deleverage_only_order_through_the_group_mango_program() {
+    let program_id = Pubkey::new_unique();
+    let new_accounts: [(&dyn Fn() -> Vec<TestAccount>, QuasarInstruction); 2] = [
+        (
+            &|| rebalance_accounts(&program_id, 1_000, &[REBALANCE_LEG]),
+            QuasarInstruction::Rebalance,
+        ),
+        (
+            &|| deleverage_accounts(&program_id, 39),
+            QuasarInstruction::DeleverageToken,
+        ),
+    ];
+    for (new_accounts, instruction) in new_accounts.iter() {
+        // The orders would go to whatever program got passed in, with the group signer on them
+        let mut accounts = new_accounts();
+        let spoofed_program = Pubkey::new_unique();
+        accounts[3].key = spoofed_program;
+        accounts[4].owner = spoofed_program;
+        invoked();
+        {
+            let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+            assert_quasar_err!(
+                process_instruction(&program_id, &account_infos, &instruction.pack()),
+                QuasarErrorCode::InvalidMangoProgram
+            );
+        }
+        assert!(invoked().is_empty());
+
+        // With the group's own program every order is addressed to it
+        let mut accounts = new_accounts();
+        let mango_program_id = accounts[3].key;
+        invoked();
+        {
+            let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+            process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+        }
+        let orders = invoked();
+        assert!(!orders.is_empty());
+        assert!(orders
+            .iter()
+            .all(|instruction| instruction.program_id == mango_program_id));
+    }
+}
+
+#[test]
+fn test_shut_down_group_only_redeems() {
+    let program_id = Pubkey::new_unique();
+    let quantity = MIN_INITIAL_MINT_QUANTITY;
+    let mut accounts = minted_accounts(&program_id, 2 * quantity);
+    let admin_key = Pubkey::new_unique();
+    update_group(&mut accounts, |quasar_group| {
+        quasar_group.admin_key = admin_key
+    });
+
+    // Only the admin can shut the group down, and only once
+    let shutdown = QuasarInstruction::InitiateShutdown.pack();
+    let mut shutdown_accounts = vec![
+        std::mem::replace(&mut accounts[0], TestAccount::new(Pubkey::default())),
+        TestAccount {
+            is_signer: true,
+            ..TestAccount::new(Pubkey::new_unique())
+        },
+    ];
+    {
+        let account_infos: Vec<AccountInfo> =
+            shutdown_accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &shutdown),
+            QuasarErrorCode::InvalidAdminKey
+        );
+    }
+    shutdown_accounts[1].key = admin_key;
+    {
+        let account_infos: Vec<AccountInfo> =
+            shutdown_accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &shutdown).unwrap();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &shutdown),
+            QuasarErrorCode::GroupShutdown
+        );
+    }
+    accounts[0] = shutdown_accounts.remove(0);
+
+    // No more minting
+    let instruction = QuasarInstruction::MintLeverageToken { quantity };
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        assert_quasar_err!(
+            process_instruction(&program_id, &account_infos, &instruction.pack()),
+            QuasarErrorCode::GroupShutdown
+        );
+    }
+
+    // but holders can still get their collateral out
+    let instruction = QuasarInstruction::BurnLeverageToken {
+        quantity,
+        output_mint: None,
+    };
+    let mut accounts = burnable_accounts(&mut accounts);
+    {
+        let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+        process_instruction(&program_id, &account_infos, &instruction.pack()).unwrap();
+    }
+    assert_eq!(Mint::unpack(&accounts[1].data).unwrap().supply, quantity);
+
+    // Nor can tokens be added to the group
+    let mut accounts = add_leverage_token_listed_accounts(&program_id, Pubkey::new_unique());
+    update_group(&mut accounts, |quasar_group| quasar_group.shutdown = true);
+    let account_infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| a.info()).collect();
+    assert_quasar_err!(
+        process_instruction(
+            &program_id,
+            &account_infos,
+            &add_leverage_token_data(account_infos[2].key)
+        ),
+        QuasarErrorCode::GroupShutdown
+    );
+}
@@ -0,0 +1,1833 @@
+use assert_matches::*;
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use mango::{
+    instruction::MangoInstruction,
+    matching::{OrderType, Side},
+    state::{MangoAccount, MangoCache, MangoGroup, QUOTE_INDEX},
+};
+use quasar::{
+    error::{QuasarError, QuasarErrorCode},
+    math::SECONDS_PER_YEAR,
+    oracle::{determine_oracle_type, OraclePrice, OracleType},
+    state::{
+        check_deposit_balance, check_quote_decimals, check_rebalance_order, check_target_leverage,
+        load_mint, load_token_account, require_admin, require_admin_or_risk_admin, BaseToken,
+        DataType, LeverageToken, MetaData, QuasarGroup, RebalanceOrderType, RedeemRequest,
+        SupplyCorrection, MAX_BASE_TOKENS, MAX_LEGS_PER_REBALANCE, MAX_LEVERAGE_TOKENS,
+        MAX_PRICE_SAMPLES, MAX_QUOTE_DECIMALS, MAX_REBALANCE_BACKOFF_SECS,
+        MAX_REBALANCE_ORDER_OFFSET_BPS, MAX_REDEEM_REQUESTS, MAX_REDEEM_REQUESTS_PER_TOKEN,
+        MAX_TARGET_LEVERAGE, MIN_PRICE_SAMPLE_INTERVAL_SECS, QUASAR_GROUP_VERSION,
+        REBALANCE_BACKOFF_BASE_SECS, TWAP_WINDOW_SECS,
+    },
+    utils::{account_discriminator, gen_signer_key, gen_signer_seeds, ACCOUNT_DISCRIMINATOR_LEN},
+};
+use solana_program::{
+    account_info::AccountInfo, program_option::COption, program_pack::Pack, pubkey::Pubkey,
+};
+use spl_token::state::{Account as TokenAccount, AccountState, Mint};
+
+macro_rules! assert_quasar_err {
+    ($result:expr, $code:pat) => {
+        assert_matches!(
+            $result,
+            Err(QuasarError::QuasarErrorCode {
+                quasar_error_code: $code,
+                ..
+            })
+        )
+    };
+}
+
+fn base_token(mint: Pubkey) -> BaseToken {
+    BaseToken {
+        mint,
+        oracles: [Pubkey::new_unique(), Pubkey::default(), Pubkey::default()],
+        num_oracles: 1,
+        ..BaseToken::zeroed()
+    }
+}
+
+fn leverage_token(mint: Pubkey, base_token_mint: Pubkey, target_leverage: I80F48) -> LeverageToken {
+    LeverageToken {
+        mint,
+        base_token_mint,
+        target_leverage,
+        mango_account: Pubkey::new_unique(),
+        ..LeverageToken::zeroed()
+    }
+}
+
+#[test]
+fn test_active_tokens_skip_empty_slots() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let base_mint = Pubkey::new_unique();
+    let (mint_a, mint_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+    quasar_group.base_tokens[0] = base_token(base_mint);
+    quasar_group.num_base_tokens = 1;
+    // Leave a hole at index 1, as closing a leverage token does
+    quasar_group.leverage_tokens[0] = leverage_token(mint_a, base_mint, I80F48::from_num(2));
+    quasar_group.leverage_tokens[2] = leverage_token(mint_b, base_mint, I80F48::from_num(3));
+    quasar_group.num_leverage_tokens = 2;
+
+    let base_tokens: Vec<_> = quasar_group
+        .active_base_tokens()
+        .map(|(i, bt)| (i, bt.mint))
+        .collect();
+    assert_eq!(base_tokens, vec![(0, base_mint)]);
+
+    let leverage_tokens: Vec<_> = quasar_group
+        .active_leverage_tokens()
+        .map(|(i, lt)| (i, lt.mint))
+        .collect();
+    assert_eq!(leverage_tokens, vec![(0, mint_a), (2, mint_b)]);
+
+    assert_eq!(quasar_group.base_token(0).unwrap().mint, base_mint);
+    assert!(quasar_group.base_token(1).is_none());
+    assert_eq!(quasar_group.leverage_token(2).unwrap().mint, mint_b);
+    assert!(quasar_group.leverage_token(1).is_none());
+    assert!(quasar_group.leverage_token(usize::MAX).is_none());
+
+    assert_eq!(
+        quasar_group.find_leverage_token_index(&base_mint, I80F48::from_num(3)),
+        Some(2)
+    );
+    assert_eq!(
+        quasar_group.find_leverage_token_index_by_mint(&mint_b),
+        Some(2)
+    );
+    // Empty slots never match, even when searching for the default key
+    assert_eq!(
+        quasar_group.find_leverage_token_index_by_mint(&Pubkey::default()),
+        None
+    );
+    assert_eq!(quasar_group.find_base_token_index(&Pubkey::default()), None);
+}
+
+#[test]
+fn test_token_indices_are_checked() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let base_mint = Pubkey::new_unique();
+    assert_quasar_err!(
+        quasar_group.base_token_index(0),
+        QuasarErrorCode::InvalidIndex
+    );
+
+    quasar_group.base_tokens[1] = base_token(base_mint);
+    quasar_group.num_base_tokens = 1;
+    quasar_group.leverage_tokens[2] =
+        leverage_token(Pubkey::new_unique(), base_mint, I80F48::from_num(2));
+    quasar_group.num_leverage_tokens = 1;
+
+    let index = quasar_group.base_token_index(1).unwrap();
+    assert_eq!(quasar_group.base_tokens[index.get()].mint, base_mint);
+    assert_eq!(quasar_group.leverage_token_index(2).unwrap().get(), 2);
+
+    // Below the token count but an empty slot
+    assert_quasar_err!(
+        quasar_group.base_token_index(0),
+        QuasarErrorCode::InvalidIndex
+    );
+    assert_quasar_err!(
+        quasar_group.leverage_token_index(0),
+        QuasarErrorCode::InvalidIndex
+    );
+    // Past the end of the slots
+    assert_quasar_err!(
+        quasar_group.base_token_index(MAX_BASE_TOKENS as u8),
+        QuasarErrorCode::InvalidIndex
+    );
+    assert_quasar_err!(
+        quasar_group.leverage_token_index(MAX_LEVERAGE_TOKENS as u8),
+        QuasarErrorCode::InvalidIndex
+    );
+    assert_quasar_err!(
+        quasar_group.leverage_token_index(u8::MAX),
+        QuasarErrorCode::InvalidIndex
+    );
+}
+
+fn mint_data(supply: u64, is_initialized: bool) -> Vec<u8> {
+    mint_data_with_authority(supply, is_initialized, COption::Some(Pubkey::new_unique()))
+}
+
+fn mint_data_with_authority(
+    supply: u64,
+    is_initialized: bool,
+    mint_authority: COption<Pubkey>,
+) -> Vec<u8> {
+    let mut data = vec![0u8; Mint::LEN];
+    let mint = Mint {
+        mint_authority,
+        supply,
+        decimals: 0,
+        is_initialized,
+        freeze_authority: COption::None,
+    };
+    Mint::pack_into_slice(&mint, &mut data);
+    data
+}
+
+#[test]
+fn test_current_supply_reads_mint() {
+    let mint = Pubkey::new_unique();
+    let leverage_token = leverage_token(mint, Pubkey::new_unique(), I80F48::from_num(2));
+
+    for &supply in [0, 1, 1_000_000, u64::MAX].iter() {
+        let mut lamports = 0;
+        let mut data = mint_data(supply, true);
+        let mint_ai = AccountInfo::new(
+            &mint,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &spl_token::ID,
+            false,
+            0,
+        );
+        assert_eq!(leverage_token.current_supply(&mint_ai).unwrap(), supply);
+    }
+}
+
+#[test]
+fn test_current_supply_rejects_invalid_mint() {
+    let mint = Pubkey::new_unique();
+    let leverage_token = leverage_token(mint, Pubkey::new_unique(), I80F48::from_num(2));
+
+    // Mint of another token
+    let other_mint = Pubkey::new_unique();
+    let mut lamports = 0;
+    let mut data = mint_data(10, true);
+    let mint_ai = AccountInfo::new(
+        &other_mint,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &spl_token::ID,
+        false,
+        0,
+    );
+    assert!(leverage_token.current_supply(&mint_ai).is_err());
+
+    // Uninitialized mint
+    let mut lamports = 0;
+    let mut data = mint_data(0, false);
+    let mint_ai = AccountInfo::new(
+        &mint,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &spl_token::ID,
+        false,
+        0,
+    );
+    assert!(leverage_token.current_supply(&mint_ai).is_err());
+}
+
+#[test]
+fn test_require_admin() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.admin_key = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let (mut lamports, mut data) = (0, vec![]);
+    let admin_ai = AccountInfo::new(
+        &quasar_group.admin_key,
+        true,
+        false,
+        &mut lamports,
+        &mut data,
+        &owner,
+        false,
+        0,
+    );
+    assert!(require_admin(&quasar_group, &admin_ai).is_ok());
+
+    // The admin's key, without its signature
+    let (mut lamports, mut data) = (0, vec![]);
+    let unsigned_ai = AccountInfo::new(
+        &quasar_group.admin_key,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &owner,
+        false,
+        0,
+    );
+    assert_quasar_err!(
+        require_admin(&quasar_group, &unsigned_ai),
+        QuasarErrorCode::SignerNecessary
+    );
+
+    // Someone else's signature
+    let impostor = Pubkey::new_unique();
+    let (mut lamports, mut data) = (0, vec![]);
+    let impostor_ai = AccountInfo::new(
+        &impostor,
+        true,
+        false,
+        &mut lamports,
+        &mut data,
+        &owner,
+        false,
+        0,
+    );
+    assert_quasar_err!(
+        require_admin(&quasar_group, &impostor_ai),
+        QuasarErrorCode::InvalidAdminKey
+    );
+}
+
+#[test]
+fn test_require_admin_or_risk_admin() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.admin_key = Pubkey::new_unique();
+    let (risk_admin, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let check = |quasar_group: &QuasarGroup, key: &Pubkey, is_signer| {
+        let (mut lamports, mut data) = (0, vec![]);
+        let signer_ai = AccountInfo::new(
+            key,
+            is_signer,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+        require_admin_or_risk_admin(quasar_group, &signer_ai)
+    };
+
+    // Without a risk admin the default key isn't one either
+    assert!(check(&quasar_group, &quasar_group.admin_key, true).is_ok());
+    assert_quasar_err!(
+        check(&quasar_group, &Pubkey::default(), true),
+        QuasarErrorCode::InvalidAdminKey
+    );
+
+    quasar_group.risk_admin_key = risk_admin;
+    assert!(check(&quasar_group, &quasar_group.admin_key, true).is_ok());
+    assert!(check(&quasar_group, &risk_admin, true).is_ok());
+    assert_quasar_err!(
+        check(&quasar_group, &risk_admin, false),
+        QuasarErrorCode::SignerNecessary
+    );
+    assert_quasar_err!(
+        check(&quasar_group, &Pubkey::new_unique(), true),
+        QuasarErrorCode::InvalidAdminKey
+    );
+    // Admin only instructions still turn the risk admin away
+    let (mut lamports, mut data) = (0, vec![]);
+    let risk_admin_ai = AccountInfo::new(
+        &risk_admin,
+        true,
+        false,
+        &mut lamports,
+        &mut data,
+        &owner,
+        false,
+        0,
+    );
+    assert_quasar_err!(
+        require_admin(&quasar_group, &risk_admin_ai),
+        QuasarErrorCode::InvalidAdminKey
+    );
+}
+
+#[test]
+fn test_deposit_cap() {
+    let mut leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(2),
+    );
+
+    // No cap by default
+    assert!(leverage_token.check_deposit_cap(I80F48::MAX).is_ok());
+
+    leverage_token.deposit_cap = 1_000_000;
+    assert!(leverage_token
+        .check_deposit_cap(I80F48::from_num(999_999))
+        .is_ok());
+    assert!(leverage_token
+        .check_deposit_cap(I80F48::from_num(1_000_000))
+        .is_ok());
+    assert!(leverage_token
+        .check_deposit_cap(I80F48::from_num(1_000_000) + I80F48::DELTA)
+        .is_err());
+}
+
+#[test]
+fn test_stored_oracle_type_matches_detected() {
+    let oracle = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let magics: [(&[u8], OracleType); 3] = [
+        (&0xa1b2c3d4u32.to_le_bytes(), OracleType::Pyth),
+        (&0x6F676E4Du32.to_le_bytes(), OracleType::Stub),
+        (&[0u8; 4], OracleType::Unknown),
+    ];
+
+    for (magic, expected) in magics.iter() {
+        let mut lamports = 0;
+        let mut data = vec![0u8; 64];
+        // Stub oracles carry their magic behind the discriminator
+        let offset = if *expected == OracleType::Stub {
+            ACCOUNT_DISCRIMINATOR_LEN
+        } else {
+            0
+        };
+        data[offset..offset + 4].copy_from_slice(magic);
+        let oracle_ai = AccountInfo::new(
+            &oracle,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+
+        let oracle_type = determine_oracle_type(&oracle_ai);
+        assert_eq!(oracle_type, *expected);
+
+        let base_token = BaseToken {
+            oracle_types: [oracle_type.into(), 0, 0],
+            ..base_token(Pubkey::new_unique())
+        };
+        assert_eq!(base_token.oracle_type(0), oracle_type);
+    }
+
+    // Base tokens stored without a type, or with a garbage one, fall back to detection
+    assert_eq!(BaseToken::zeroed().oracle_type(0), OracleType::Unknown);
+    let base_token = BaseToken {
+        oracle_types: [0xff, 0, 0],
+        ..BaseToken::zeroed()
+    };
+    assert_eq!(base_token.oracle_type(0), OracleType::Unknown);
+}
+
+#[test]
+fn test_account_discriminator() {
+    // Same as Anchor's for accounts of these names
+    assert_eq!(
+        account_discriminator("QuasarGroup"),
+        [89, 160, 58, 141, 23, 166, 209, 107]
+    );
+    assert_eq!(
+        account_discriminator("StubOracle"),
+        [224, 251, 254, 99, 177, 174, 137, 4]
+    );
+}
+
+fn initialized_group_data() -> Vec<u8> {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.init_discriminator();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, QUASAR_GROUP_VERSION, true);
+    bytemuck::bytes_of(&quasar_group).to_vec()
+}
+
+#[cfg(not(feature = "anchor-discriminator"))]
+#[test]
+fn test_group_load_without_discriminator() {
+    let program_id = Pubkey::new_unique();
+    let key = Pubkey::new_unique();
+    let mut lamports = 0;
+    let mut data = initialized_group_data();
+    // The data starts right at the meta data
+    assert_eq!(ACCOUNT_DISCRIMINATOR_LEN, 0);
+    assert_eq!(data[0], DataType::QuasarGroup as u8);
+
+    let group_ai = AccountInfo::new(
+        &key,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &program_id,
+        false,
+        0,
+    );
+    assert!(QuasarGroup::load_checked(&group_ai, &program_id).is_ok());
+}
+
+#[cfg(feature = "anchor-discriminator")]
+#[test]
+fn test_group_load_checks_discriminator() {
+    let program_id = Pubkey::new_unique();
+    let key = Pubkey::new_unique();
+    let mut lamports = 0;
+    let mut data = initialized_group_data();
+    assert_eq!(&data[..8], &account_discriminator("QuasarGroup"));
+    assert_eq!(data[ACCOUNT_DISCRIMINATOR_LEN], DataType::QuasarGroup as u8);
+
+    {
+        let group_ai = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &program_id,
+            false,
+            0,
+        );
+        assert!(QuasarGroup::load_checked(&group_ai, &program_id).is_ok());
+    }
+
+    // The account of another Anchor type
+    data[..8].copy_from_slice(&account_discriminator("StubOracle"));
+    let group_ai = AccountInfo::new(
+        &key,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &program_id,
+        false,
+        0,
+    );
+    assert_quasar_err!(
+        QuasarGroup::load_checked(&group_ai, &program_id),
+        QuasarErrorCode::InvalidAccount
+    );
+    assert_quasar_err!(
+        QuasarGroup::load_mut_checked(&group_ai, &program_id),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+fn token_account_data(owner: Pubkey, state: AccountState) -> Vec<u8> {
+    let mut data = vec![0u8; TokenAccount::LEN];
+    let token_account = TokenAccount {
+        mint: Pubkey::new_unique(),
+        owner,
+        state,
+        ..TokenAccount::default()
+    };
+    TokenAccount::pack_into_slice(&token_account, &mut data);
+    data
+}
+
+#[test]
+fn test_load_token_account() {
+    let key = Pubkey::new_unique();
+    let owner = Pubkey::new_unique();
+    let cases = [
+        (owner, AccountState::Initialized, owner, true),
+        (owner, AccountState::Uninitialized, owner, false),
+        (
+            owner,
+            AccountState::Initialized,
+            Pubkey::new_unique(),
+            false,
+        ),
+    ];
+
+    for &(account_owner, state, expected_owner, ok) in cases.iter() {
+        let mut lamports = 0;
+        let mut data = token_account_data(account_owner, state);
+        let token_account_ai = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &spl_token::ID,
+            false,
+            0,
+        );
+
+        let result = load_token_account(&token_account_ai, &expected_owner);
+        if ok {
+            assert_eq!(result.unwrap().owner, owner);
+        } else {
+            assert_quasar_err!(result, QuasarErrorCode::InvalidTokenAccount);
+        }
+    }
+}
+
+#[test]
+fn test_shutdown_blocks_for_good() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    assert!(quasar_group.check_not_shutdown().is_ok());
+
+    quasar_group.shutdown = true;
+    assert_quasar_err!(
+        quasar_group.check_not_shutdown(),
+        QuasarErrorCode::GroupShutdown
+    );
+    // Every instruction but redeem checks the flag, shutting down twice included
+    assert_quasar_err!(
+        quasar_group.check_not_shutdown(),
+        QuasarErrorCode::GroupShutdown
+    );
+}
+
+#[test]
+fn test_mint_quantity_floor() {
+    let mut leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(2),
+    );
+
+    // Nothing to mint is always dust
+    assert_quasar_err!(
+        leverage_token.check_mint_quantity(0),
+        QuasarErrorCode::MintTooSmall
+    );
+    assert!(leverage_token.check_mint_quantity(1).is_ok());
+
+    leverage_token.min_mint_quantity = 1_000;
+    assert_quasar_err!(
+        leverage_token.check_mint_quantity(999),
+        QuasarErrorCode::MintTooSmall
+    );
+    assert!(leverage_token.check_mint_quantity(1_000).is_ok());
+    assert!(leverage_token.check_mint_quantity(u64::MAX).is_ok());
+}
+
+#[test]
+fn test_check_mango_group() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.mango_program_id = Pubkey::new_unique();
+    quasar_group.mango_group = Pubkey::new_unique();
+
+    assert!(quasar_group
+        .check_mango_group(&quasar_group.mango_program_id, &quasar_group.mango_group)
+        .is_ok());
+    assert_quasar_err!(
+        quasar_group.check_mango_group(&quasar_group.mango_program_id, &Pubkey::new_unique()),
+        QuasarErrorCode::InvalidMangoGroup
+    );
+    assert_quasar_err!(
+        quasar_group.check_mango_group(&Pubkey::new_unique(), &quasar_group.mango_group),
+        QuasarErrorCode::InvalidMangoProgram
+    );
+}
+
+#[test]
+fn test_base_token_matches_perp_market() {
+    let base_token = base_token(Pubkey::new_unique());
+    let mut mango_group = MangoGroup::zeroed();
+    assert!(!base_token.matches_perp_market(&mango_group, 1));
+
+    // Listed with a spot token of the same mint
+    mango_group.tokens[1].mint = base_token.mint;
+    assert!(base_token.matches_perp_market(&mango_group, 1));
+    assert!(!base_token.matches_perp_market(&mango_group, 2));
+
+    // Perp only markets have no spot token, but share the oracle
+    mango_group.oracles[2] = base_token.oracles[0];
+    assert!(base_token.matches_perp_market(&mango_group, 2));
+    // Unset oracle slots of the base token don't match unset ones of the Mango group
+    assert!(!base_token.matches_perp_market(&mango_group, 3));
+}
+
+#[test]
+fn test_signer_seeds_parts_reproduce_signer_key() {
+    let program_id = Pubkey::new_unique();
+    let quasar_group_key = Pubkey::new_unique();
+    let (signer_key, nonce) = (0..=u8::MAX as u64)
+        .rev()
+        .find_map(|nonce| {
+            gen_signer_key(nonce, &quasar_group_key, &program_id)
+                .ok()
+                .map(|key| (key, nonce))
+        })
+        .unwrap();
+
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.signer_nonce = nonce;
+    quasar_group.signer_key = signer_key;
+
+    let parts = quasar_group.signer_seeds_parts(&quasar_group_key);
+    assert_eq!(
+        parts.as_seeds(),
+        gen_signer_seeds(&nonce, &quasar_group_key)
+    );
+    assert_eq!(
+        Pubkey::create_program_address(&parts.as_seeds(), &program_id).unwrap(),
+        quasar_group.signer_key
+    );
+}
+
+#[test]
+fn test_redeem_queue() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let (mint, other_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let request = |mint, quantity| RedeemRequest {
+        owner: Pubkey::new_unique(),
+        mint,
+        quantity,
+    };
+
+    assert!(quasar_group.front_redeem_request_mut(0).is_none());
+    assert_quasar_err!(
+        quasar_group.pop_redeem_request(0),
+        QuasarErrorCode::RedeemQueueEmpty
+    );
+
+    // Enqueue, settle part of the front request, then the rest of it. The other token's queue
+    // is a queue of its own
+    let first = request(mint, 100);
+    let second = request(mint, 50);
+    let other = request(other_mint, 10);
+    quasar_group.push_redeem_request(0, first).unwrap();
+    quasar_group.push_redeem_request(1, other).unwrap();
+    quasar_group.push_redeem_request(0, second).unwrap();
+    quasar_group.front_redeem_request_mut(0).unwrap().quantity -= 30;
+    assert_eq!(
+        quasar_group.front_redeem_request_mut(0).unwrap().quantity,
+        70
+    );
+    assert_eq!(*quasar_group.front_redeem_request_mut(1).unwrap(), other);
+    assert_eq!(
+        quasar_group.pop_redeem_request(0).unwrap(),
+        RedeemRequest {
+            quantity: 70,
+            ..first
+        }
+    );
+    assert_eq!(*quasar_group.front_redeem_request_mut(0).unwrap(), second);
+    assert_eq!(quasar_group.pop_redeem_request(0).unwrap(), second);
+    assert_eq!(quasar_group.redeem_queues[0].len, 0);
+    assert_eq!(quasar_group.pop_redeem_request(1).unwrap(), other);
+
+    // Fill a queue across the end of its buffer, the other token can still queue
+    let requests: Vec<RedeemRequest> = (1..=MAX_REDEEM_REQUESTS_PER_TOKEN as u64)
+        .map(|quantity| request(mint, quantity))
+        .collect();
+    for r in requests.iter() {
+        quasar_group.push_redeem_request(0, *r).unwrap();
+    }
+    assert_quasar_err!(
+        quasar_group.push_redeem_request(0, request(mint, 1)),
+        QuasarErrorCode::RedeemQueueFull
+    );
+    quasar_group.push_redeem_request(1, other).unwrap();
+
+    // A request nothing of which could be settled goes to the back
+    quasar_group.requeue_front_redeem_request(0).unwrap();
+    for r in requests[1..].iter().chain(&requests[..1]) {
+        assert_eq!(quasar_group.pop_redeem_request(0).unwrap(), *r);
+    }
+    assert!(quasar_group.front_redeem_request_mut(0).is_none());
+    assert_quasar_err!(
+        quasar_group.requeue_front_redeem_request(0),
+        QuasarErrorCode::RedeemQueueEmpty
+    );
+}
+
+#[test]
+fn test_redeems_take_their_share_of_the_settled_pnl() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group
+        .record_settled_pnl(2, I80F48::from_num(500))
+        .unwrap();
+    quasar_group
+        .record_settled_pnl(2, I80F48::from_num(-100))
+        .unwrap();
+    assert_eq!(quasar_group.settled_pnl[2], I80F48::from_num(400));
+
+    // A quarter of the supply leaves with a quarter of it
+    quasar_group.redeem_settled_pnl(2, 25, 100).unwrap();
+    assert_eq!(quasar_group.settled_pnl[2], I80F48::from_num(300));
+
+    // and the last of the supply with the rest
+    quasar_group.redeem_settled_pnl(2, 75, 75).unwrap();
+    assert_eq!(quasar_group.settled_pnl[2], I80F48::from_num(0));
+    assert!(quasar_group
+        .settled_pnl
+        .iter()
+        .all(|pnl| *pnl == I80F48::from_num(0)));
+}
+
+#[test]
+fn test_migrate_moves_legacy_redeems_to_their_tokens_queues() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, 4, true);
+    let (mint, other_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+    quasar_group.leverage_tokens[0].mint = mint;
+    quasar_group.leverage_tokens[2].mint = other_mint;
+    quasar_group.num_leverage_tokens = 2;
+    let request = |mint, quantity| RedeemRequest {
+        owner: Pubkey::new_unique(),
+        mint,
+        quantity,
+    };
+
+    // Version 4 queued the redeems of every token in one ring, here across its end. The first
+    // token has more than its queue holds
+    let mut requests: Vec<RedeemRequest> = (1..=MAX_REDEEM_REQUESTS_PER_TOKEN as u64 + 1)
+        .map(|quantity| request(mint, quantity))
+        .collect();
+    requests.insert(1, request(other_mint, 100));
+    for (i, r) in requests.iter().enumerate() {
+        quasar_group.legacy_redeem_queue[(MAX_REDEEM_REQUESTS - 2 + i) % MAX_REDEEM_REQUESTS] = *r;
+    }
+    quasar_group.legacy_redeem_queue_head = MAX_REDEEM_REQUESTS as u64 - 2;
+    quasar_group.legacy_redeem_queue_len = requests.len() as u64;
+
+    quasar_group.migrate(6).unwrap();
+    assert_eq!(quasar_group.meta_data.version, QUASAR_GROUP_VERSION);
+    let first_token: Vec<RedeemRequest> = requests
+        .iter()
+        .filter(|r| r.mint == mint)
+        .copied()
+        .collect();
+    assert!(quasar_group.redeem_queues[0]
+        .iter()
+        .eq(first_token[..MAX_REDEEM_REQUESTS_PER_TOKEN].iter()));
+    assert!(quasar_group.redeem_queues[2]
+        .iter()
+        .eq(requests[1..2].iter()));
+
+    // The one that didn't fit waits at the front of the old ring, and follows once there's room
+    assert_eq!(quasar_group.legacy_redeem_queue_head, 0);
+    assert_eq!(quasar_group.legacy_redeem_queue_len, 1);
+    assert_eq!(
+        quasar_group.legacy_redeem_queue[0],
+        first_token[MAX_REDEEM_REQUESTS_PER_TOKEN]
+    );
+    assert_quasar_err!(
+        quasar_group.push_redeem_request(0, request(mint, 1)),
+        QuasarErrorCode::RedeemQueueFull
+    );
+    for r in first_token.iter() {
+        assert_eq!(quasar_group.pop_redeem_request(0).unwrap(), *r);
+    }
+    assert_eq!(quasar_group.legacy_redeem_queue_len, 0);
+    assert!(quasar_group.front_redeem_request_mut(0).is_none());
+}
+
+#[test]
+fn test_migrate_moves_risk_admin_out_of_redeem_queue() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, 3, true);
+    let request = |quantity| RedeemRequest {
+        owner: Pubkey::new_unique(),
+        mint: Pubkey::new_unique(),
+        quantity,
+    };
+    let requests = [request(1), request(2), request(3)];
+
+    // Version 3 queued across the end of a ring one slot short, whose last slot held the risk
+    // admin and the paused flag
+    let risk_admin = Pubkey::new_unique();
+    let mut paused = [0u8; 32];
+    paused[0] = 1;
+    quasar_group.legacy_redeem_queue[MAX_REDEEM_REQUESTS - 2] = requests[0];
+    quasar_group.legacy_redeem_queue[0] = requests[1];
+    quasar_group.legacy_redeem_queue[1] = requests[2];
+    quasar_group.legacy_redeem_queue[MAX_REDEEM_REQUESTS - 1] = RedeemRequest {
+        owner: risk_admin,
+        mint: Pubkey::new_from_array(paused),
+        quantity: 0,
+    };
+    quasar_group.legacy_redeem_queue_head = MAX_REDEEM_REQUESTS as u64 - 2;
+    quasar_group.legacy_redeem_queue_len = 3;
+
+    let mut v2_group = quasar_group;
+    v2_group.meta_data.version = 2;
+
+    quasar_group.migrate(6).unwrap();
+    assert_eq!(quasar_group.meta_data.version, QUASAR_GROUP_VERSION);
+    assert_eq!(quasar_group.risk_admin_key, risk_admin);
+    assert!(quasar_group.paused);
+    assert_eq!(quasar_group.legacy_redeem_queue_head, 0);
+    assert_eq!(quasar_group.legacy_redeem_queue_len, 3);
+    assert_eq!(quasar_group.legacy_redeem_queue[..3], requests);
+    assert!(quasar_group.legacy_redeem_queue[3..]
+        .iter()
+        .all(|r| *r == RedeemRequest::zeroed()));
+
+    // Before version 3 the last slot was a request like any other, the queue is left be
+    let v2_queue = v2_group.legacy_redeem_queue;
+    v2_group.migrate(6).unwrap();
+    assert_eq!(v2_group.legacy_redeem_queue, v2_queue);
+    assert_eq!(
+        v2_group.legacy_redeem_queue_head,
+        MAX_REDEEM_REQUESTS as u64 - 2
+    );
+    assert_eq!(v2_group.risk_admin_key, Pubkey::default());
+    assert!(!v2_group.paused);
+}
+
+#[test]
+fn test_migrate_takes_every_perp_market_to_trade_the_token_base() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.meta_data = MetaData::new(DataType::QuasarGroup, 6, true);
+    let base_mint = Pubkey::new_unique();
+    quasar_group.leverage_tokens[1] =
+        leverage_token(Pubkey::new_unique(), base_mint, I80F48::from_num(2));
+    quasar_group.leverage_tokens[1].num_perp_markets = 2;
+    quasar_group.num_leverage_tokens = 1;
+
+    quasar_group.migrate(6).unwrap();
+    assert_eq!(quasar_group.leg_base_mints(1), [base_mint, base_mint]);
+    assert!(quasar_group.perp_market_base_mints[1][2..]
+        .iter()
+        .all(|mint| *mint == Pubkey::default()));
+    assert!(quasar_group.is_base_in_use(&base_mint));
+}
+
+#[test]
+fn test_group_layout_is_pinned() {
+    // Existing group accounts were allocated at these sizes, any change here means a new
+    // layout version and a migration, not an edit of the numbers
+    #[cfg(not(feature = "anchor-discriminator"))]
+    const HEADER_LEN: usize = 0;
+    #[cfg(feature = "anchor-discriminator")]
+    const HEADER_LEN: usize = ACCOUNT_DISCRIMINATOR_LEN;
+    const QUASAR_GROUP_LEN: usize = 36_192;
+    const NUM_BASE_TOKENS_OFFSET: usize = 8;
+
+    assert_eq!(std::mem::size_of::<BaseToken>(), 400);
+    assert_eq!(std::mem::size_of::<LeverageToken>(), 400);
+    assert_eq!(
+        std::mem::size_of::<QuasarGroup>(),
+        HEADER_LEN + QUASAR_GROUP_LEN
+    );
+
+    let quasar_group = QuasarGroup::zeroed();
+    let base = &quasar_group as *const QuasarGroup as usize;
+    let num_base_tokens = &quasar_group.num_base_tokens as *const usize as usize;
+    assert_eq!(num_base_tokens - base, HEADER_LEN + NUM_BASE_TOKENS_OFFSET);
+}
+
+#[test]
+fn test_leverage_token_bytes_round_trip() {
+    let mut leverage_token = LeverageToken {
+        mint: Pubkey::new_unique(),
+        base_token_mint: Pubkey::new_unique(),
+        target_leverage: I80F48::from_num(-2.5),
+        mango_account: Pubkey::new_unique(),
+        num_perp_markets: 2,
+        deposit_cap: u64::MAX,
+        management_fee_bps_annual: 150,
+        pending_rebalance: true,
+        mango_account_bump: 254,
+        ..LeverageToken::zeroed()
+    };
+    leverage_token.perp_market_weights[0] = I80F48::from_num(0.25);
+    leverage_token.perp_market_weights[1] = I80F48::from_bits(i128::MIN + 1);
+
+    let bytes = bytemuck::bytes_of(&leverage_token).to_vec();
+    assert_eq!(bytes.len(), std::mem::size_of::<LeverageToken>());
+    // target_leverage sits right after the two mints, as its raw little endian bits
+    assert_eq!(
+        &bytes[64..80],
+        &I80F48::from_num(-2.5).to_bits().to_le_bytes()
+    );
+
+    let mut decoded = LeverageToken::zeroed();
+    bytemuck::bytes_of_mut(&mut decoded).copy_from_slice(&bytes);
+    assert_eq!(decoded.mint, leverage_token.mint);
+    assert_eq!(decoded.base_token_mint, leverage_token.base_token_mint);
+    assert_eq!(decoded.target_leverage, I80F48::from_num(-2.5));
+    assert_eq!(decoded.mango_account, leverage_token.mango_account);
+    assert_eq!(
+        decoded.perp_market_weights,
+        leverage_token.perp_market_weights
+    );
+    assert_eq!(decoded.num_perp_markets, 2);
+    assert_eq!(decoded.deposit_cap, u64::MAX);
+    assert_eq!(decoded.management_fee_bps_annual, 150);
+    assert!(decoded.pending_rebalance);
+    assert_eq!(decoded.mango_account_bump, 254);
+}
+
+#[test]
+fn test_multi_leg_rebalance_across_two_cranks() {
+    assert_eq!(MAX_LEGS_PER_REBALANCE, 2);
+    let mut leverage_token = LeverageToken {
+        num_perp_markets: 4,
+        pending_rebalance: true,
+        last_rebalance_ts: 10,
+        ..LeverageToken::zeroed()
+    };
+
+    // First crank trades the first two legs, one of which falls short, and stays pending
+    let legs = leverage_token.next_rebalance_legs();
+    assert_eq!(legs, 0..2);
+    leverage_token.record_rebalance_legs(legs, true, 100);
+    assert!(leverage_token.pending_rebalance);
+    assert_eq!(leverage_token.next_rebalance_leg, 2);
+    assert_eq!(leverage_token.last_rebalance_ts, 10);
+
+    // Second crank finishes the job, the earlier miss keeps the token pending
+    let legs = leverage_token.next_rebalance_legs();
+    assert_eq!(legs, 2..4);
+    leverage_token.record_rebalance_legs(legs, false, 200);
+    assert!(leverage_token.pending_rebalance);
+    assert_eq!(leverage_token.next_rebalance_leg, 0);
+    assert_eq!(leverage_token.last_rebalance_ts, 200);
+
+    // A clean run over both cranks settles it
+    leverage_token.record_rebalance_legs(0..2, false, 300);
+    leverage_token.record_rebalance_legs(2..4, false, 400);
+    assert!(!leverage_token.pending_rebalance);
+    assert_eq!(leverage_token.last_rebalance_ts, 400);
+
+    // Tokens with few legs are done in a single crank
+    let mut leverage_token = LeverageToken {
+        num_perp_markets: 1,
+        ..LeverageToken::zeroed()
+    };
+    let legs = leverage_token.next_rebalance_legs();
+    assert_eq!(legs, 0..1);
+    leverage_token.record_rebalance_legs(legs, false, 500);
+    assert!(!leverage_token.pending_rebalance);
+    assert_eq!(leverage_token.last_rebalance_ts, 500);
+}
+
+#[test]
+fn test_underfunded_deposit_is_a_mango_deposit_failure() {
+    let token_account = TokenAccount {
+        amount: 1_000,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+
+    assert!(check_deposit_balance(&token_account, 1_000).is_ok());
+    assert!(check_deposit_balance(&token_account, 0).is_ok());
+    assert_quasar_err!(
+        check_deposit_balance(&token_account, 1_001),
+        QuasarErrorCode::MangoDepositFailed
+    );
+}
+
+#[test]
+fn test_leverage_tokens_for_base() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let base_mint = Pubkey::new_unique();
+    let other_base_mint = Pubkey::new_unique();
+    quasar_group.leverage_tokens[0] =
+        leverage_token(Pubkey::new_unique(), base_mint, I80F48::from_num(2));
+    quasar_group.leverage_tokens[1] =
+        leverage_token(Pubkey::new_unique(), other_base_mint, I80F48::from_num(2));
+    quasar_group.leverage_tokens[4] =
+        leverage_token(Pubkey::new_unique(), base_mint, I80F48::from_num(-3));
+    quasar_group.leverage_tokens[7] =
+        leverage_token(Pubkey::new_unique(), base_mint, I80F48::from_num(5));
+
+    assert_eq!(
+        quasar_group.leverage_tokens_for_base(&base_mint),
+        vec![0, 4, 7]
+    );
+    assert_eq!(
+        quasar_group.leverage_tokens_for_base(&other_base_mint),
+        vec![1]
+    );
+    assert!(quasar_group
+        .leverage_tokens_for_base(&Pubkey::new_unique())
+        .is_empty());
+
+    // Closed tokens don't count
+    quasar_group.leverage_tokens[4] = LeverageToken::zeroed();
+    assert_eq!(
+        quasar_group.leverage_tokens_for_base(&base_mint),
+        vec![0, 7]
+    );
+}
+
+#[test]
+fn test_target_leverage_bounds() {
+    let min = I80F48::from_num(101) / I80F48::from_num(100);
+    let max = I80F48::from_num(MAX_TARGET_LEVERAGE);
+    assert!(check_target_leverage(min).is_ok());
+    assert!(check_target_leverage(I80F48::from_num(3)).is_ok());
+    assert!(check_target_leverage(max).is_ok());
+
+    for &leverage in [
+        I80F48::from_num(0),
+        I80F48::from_num(1),
+        min - I80F48::DELTA,
+        max + I80F48::DELTA,
+        I80F48::from_num(-3),
+        I80F48::MIN,
+        I80F48::MAX,
+    ]
+    .iter()
+    {
+        assert_quasar_err!(
+            check_target_leverage(leverage),
+            QuasarErrorCode::InvalidLeverage
+        );
+    }
+}
+
+#[test]
+fn test_quote_decimals_bounds() {
+    assert!(check_quote_decimals(0).is_ok());
+    assert!(check_quote_decimals(6).is_ok());
+    assert!(check_quote_decimals(MAX_QUOTE_DECIMALS).is_ok());
+    assert_quasar_err!(
+        check_quote_decimals(MAX_QUOTE_DECIMALS + 1),
+        QuasarErrorCode::InvalidQuoteMint
+    );
+}
+
+#[test]
+fn test_twap_dampens_price_spike() {
+    let mut base_token = base_token(Pubkey::new_unique());
+    assert_eq!(base_token.twap(1_000, 3_600), None);
+
+    let interval = MIN_PRICE_SAMPLE_INTERVAL_SECS;
+    let mut ts = 1_000;
+    for _ in 0..MAX_PRICE_SAMPLES - 1 {
+        base_token
+            .push_price_sample(OraclePrice::new(I80F48::from_num(100), 0), ts)
+            .unwrap();
+        ts += interval;
+    }
+    // One manipulated read at ten times the price
+    base_token
+        .push_price_sample(OraclePrice::new(I80F48::from_num(1_000), 0), ts)
+        .unwrap();
+
+    let twap = base_token
+        .twap(ts, interval * MAX_PRICE_SAMPLES as u64)
+        .unwrap();
+    let expected = I80F48::from_num(100 * (MAX_PRICE_SAMPLES - 1) + 1_000)
+        / I80F48::from_num(MAX_PRICE_SAMPLES);
+    assert_eq!(twap, OraclePrice::new(expected, 0));
+    assert!(twap.value < I80F48::from_num(300));
+
+    // Only the spike is recent enough
+    assert_eq!(
+        base_token.twap(ts, 0),
+        Some(OraclePrice::new(I80F48::from_num(1_000), 0))
+    );
+    // Samples from the future don't count
+    assert_eq!(
+        base_token.twap(ts - interval, 0),
+        Some(OraclePrice::new(I80F48::from_num(100), 0))
+    );
+}
+
+#[test]
+fn test_price_near_twap() {
+    let base_mint = Pubkey::new_unique();
+    let perp_market = Pubkey::new_unique();
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.quote_decimals = 6;
+    quasar_group.base_tokens[0] = base_token(base_mint);
+    quasar_group.num_base_tokens = 1;
+    let mut leverage_token = leverage_token(Pubkey::new_unique(), base_mint, I80F48::from_num(2));
+    leverage_token.mango_perp_markets[0] = perp_market;
+    leverage_token.num_perp_markets = 1;
+    quasar_group.leverage_tokens[0] = leverage_token;
+    quasar_group.perp_market_base_mints[0][0] = base_mint;
+    quasar_group.num_leverage_tokens = 1;
+
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.perp_markets[1].perp_market = perp_market;
+    let mut mango_cache = MangoCache::zeroed();
+    // Off by half, but with no samples there is nothing to check against
+    mango_cache.price_cache[1].price = I80F48::from_num(150_000_000);
+    let ts = 1_000_000;
+    quasar_group
+        .check_price_near_twap(0, &mango_group, &mango_cache, ts)
+        .unwrap();
+
+    // 100 quote per base token is 100_000_000 native quote per native base unit
+    quasar_group.base_tokens[0]
+        .push_price_sample(OraclePrice::new(I80F48::from_num(100), 0), ts)
+        .unwrap();
+    assert_quasar_err!(
+        quasar_group.check_price_near_twap(0, &mango_group, &mango_cache, ts),
+        QuasarErrorCode::PriceDeviatesFromTwap
+    );
+    mango_cache.price_cache[1].price = I80F48::from_num(104_000_000);
+    quasar_group
+        .check_price_near_twap(0, &mango_group, &mango_cache, ts)
+        .unwrap();
+    mango_cache.price_cache[1].price = I80F48::from_num(94_000_000);
+    assert_quasar_err!(
+        quasar_group.check_price_near_twap(0, &mango_group, &mango_cache, ts),
+        QuasarErrorCode::PriceDeviatesFromTwap
+    );
+
+    // The sample ages out of the window
+    quasar_group
+        .check_price_near_twap(0, &mango_group, &mango_cache, ts + TWAP_WINDOW_SECS + 1)
+        .unwrap();
+
+    // A leg on another base token is held against the TWAP of that one
+    let other_base_mint = Pubkey::new_unique();
+    let other_perp_market = Pubkey::new_unique();
+    quasar_group.base_tokens[1] = base_token(other_base_mint);
+    quasar_group.num_base_tokens = 2;
+    quasar_group.base_tokens[1]
+        .push_price_sample(OraclePrice::new(I80F48::from_num(10), 0), ts)
+        .unwrap();
+    quasar_group.leverage_tokens[0].mango_perp_markets[1] = other_perp_market;
+    quasar_group.leverage_tokens[0].num_perp_markets = 2;
+    quasar_group.perp_market_base_mints[0][1] = other_base_mint;
+    mango_group.perp_markets[2].perp_market = other_perp_market;
+    mango_cache.price_cache[1].price = I80F48::from_num(100_000_000);
+    mango_cache.price_cache[2].price = I80F48::from_num(10_000_000);
+    quasar_group
+        .check_price_near_twap(0, &mango_group, &mango_cache, ts)
+        .unwrap();
+    mango_cache.price_cache[2].price = I80F48::from_num(100_000_000);
+    assert_quasar_err!(
+        quasar_group.check_price_near_twap(0, &mango_group, &mango_cache, ts),
+        QuasarErrorCode::PriceDeviatesFromTwap
+    );
+
+    // Legs on markets the Mango group doesn't list are refused
+    quasar_group.leverage_tokens[0].mango_perp_markets[0] = Pubkey::new_unique();
+    assert_quasar_err!(
+        quasar_group.check_price_near_twap(0, &mango_group, &mango_cache, ts),
+        QuasarErrorCode::InvalidAccount
+    );
+}
+
+#[test]
+fn test_price_samples_ring_buffer() {
+    let mut base_token = base_token(Pubkey::new_unique());
+    let interval = MIN_PRICE_SAMPLE_INTERVAL_SECS;
+
+    base_token
+        .push_price_sample(OraclePrice::new(I80F48::from_num(1), 0), 0)
+        .unwrap();
+    assert_quasar_err!(
+        base_token.push_price_sample(OraclePrice::new(I80F48::from_num(2), 0), interval - 1),
+        QuasarErrorCode::PriceSampleTooSoon
+    );
+    // Scaled for another base token
+    assert_quasar_err!(
+        base_token.push_price_sample(OraclePrice::new(I80F48::from_num(2), 6), interval),
+        QuasarErrorCode::InvalidParam
+    );
+
+    // Once full, the oldest samples are overwritten
+    for i in 1..=MAX_PRICE_SAMPLES as u64 {
+        base_token
+            .push_price_sample(OraclePrice::new(I80F48::from_num(i + 1), 0), i * interval)
+            .unwrap();
+    }
+    assert_eq!(base_token.num_price_samples as usize, MAX_PRICE_SAMPLES);
+    let last = base_token.last_price_sample().unwrap();
+    assert_eq!(last.price, I80F48::from_num(MAX_PRICE_SAMPLES + 1));
+    assert_eq!(last.timestamp, MAX_PRICE_SAMPLES as u64 * interval);
+    assert!(base_token
+        .price_samples
+        .iter()
+        .all(|sample| sample.price != I80F48::from_num(1)));
+}
+
+#[test]
+fn test_crank_reward() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let notional = I80F48::from_num(2_000_000_000u64);
+
+    // Nothing is paid until the group sets a reward
+    assert_eq!(quasar_group.crank_reward(notional, 6, u64::MAX).unwrap(), 0);
+
+    // 5 bps of 2_000 quote tokens, whichever side the rebalance traded
+    quasar_group.crank_reward_bps = 5;
+    quasar_group.max_crank_reward = 10;
+    assert_eq!(
+        quasar_group.crank_reward(notional, 6, u64::MAX).unwrap(),
+        1_000_000
+    );
+    assert_eq!(
+        quasar_group.crank_reward(-notional, 6, u64::MAX).unwrap(),
+        1_000_000
+    );
+
+    // Capped at `max_crank_reward` whole tokens, and at what the fee vault holds
+    let large_notional = I80F48::from_num(1_000_000_000_000u64);
+    assert_eq!(
+        quasar_group
+            .crank_reward(large_notional, 6, u64::MAX)
+            .unwrap(),
+        10_000_000
+    );
+    assert_eq!(quasar_group.crank_reward(notional, 6, 300).unwrap(), 300);
+    assert_eq!(quasar_group.crank_reward(notional, 6, 0).unwrap(), 0);
+}
+
+#[test]
+fn test_creation_fee_for() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.admin_key = Pubkey::new_unique();
+    let creator = Pubkey::new_unique();
+
+    assert_eq!(
+        quasar_group
+            .creation_fee_for(&quasar_group.admin_key)
+            .unwrap(),
+        0
+    );
+    assert_quasar_err!(
+        quasar_group.creation_fee_for(&creator),
+        QuasarErrorCode::InvalidAdminKey
+    );
+
+    // Admin creation stays free once others pay
+    quasar_group.creation_fee = 5_000_000;
+    assert_eq!(
+        quasar_group
+            .creation_fee_for(&quasar_group.admin_key)
+            .unwrap(),
+        0
+    );
+    assert_eq!(quasar_group.creation_fee_for(&creator).unwrap(), 5_000_000);
+}
+
+#[test]
+fn test_check_hold_elapsed() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let minted_ts = 1_000;
+
+    // No delay, redeeming in the same second as the mint
+    quasar_group
+        .check_hold_elapsed(minted_ts, minted_ts)
+        .unwrap();
+
+    quasar_group.min_hold_secs = 60;
+    assert_quasar_err!(
+        quasar_group.check_hold_elapsed(minted_ts, minted_ts),
+        QuasarErrorCode::HoldPeriodNotElapsed
+    );
+    assert_quasar_err!(
+        quasar_group.check_hold_elapsed(minted_ts, minted_ts + 59),
+        QuasarErrorCode::HoldPeriodNotElapsed
+    );
+    quasar_group
+        .check_hold_elapsed(minted_ts, minted_ts + 60)
+        .unwrap();
+
+    // Doesn't wrap around for a delay far in the future
+    quasar_group.min_hold_secs = u64::MAX;
+    assert_quasar_err!(
+        quasar_group.check_hold_elapsed(minted_ts, u64::MAX - 1),
+        QuasarErrorCode::HoldPeriodNotElapsed
+    );
+}
+
+#[test]
+fn test_max_tokens_per_base() {
+    let mut quasar_group = QuasarGroup::zeroed();
+    let base_mint = Pubkey::new_unique();
+    quasar_group.max_tokens_per_base = 2;
+
+    // Up to the cap
+    for (i, leverage) in [2, 3].iter().enumerate() {
+        assert!(quasar_group.check_room_for_base(&base_mint).is_ok());
+        quasar_group.leverage_tokens[i] =
+            leverage_token(Pubkey::new_unique(), base_mint, I80F48::from_num(*leverage));
+    }
+    // and beyond it
+    assert_quasar_err!(
+        quasar_group.check_room_for_base(&base_mint),
+        QuasarErrorCode::TooManyTokensForBase
+    );
+    // Other base tokens have their own count
+    assert!(quasar_group
+        .check_room_for_base(&Pubkey::new_unique())
+        .is_ok());
+
+    quasar_group.max_tokens_per_base = 0;
+    assert!(quasar_group.check_room_for_base(&base_mint).is_ok());
+}
+
+#[test]
+fn test_check_mint_authority() {
+    let mint = Pubkey::new_unique();
+    let signer_key = Pubkey::new_unique();
+    let leverage_token = leverage_token(mint, Pubkey::new_unique(), I80F48::from_num(2));
+
+    let authorities = [
+        (COption::Some(signer_key), true),
+        // Authority handed to someone else
+        (COption::Some(Pubkey::new_unique()), false),
+        // Authority revoked
+        (COption::None, false),
+    ];
+    for &(mint_authority, valid) in authorities.iter() {
+        let mut lamports = 0;
+        let mut data = mint_data_with_authority(10, true, mint_authority);
+        let mint_ai = AccountInfo::new(
+            &mint,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &spl_token::ID,
+            false,
+            0,
+        );
+        let result = leverage_token.check_mint_authority(&mint_ai, &signer_key);
+        if valid {
+            assert!(result.is_ok());
+        } else {
+            assert_quasar_err!(result, QuasarErrorCode::InvalidMintAuthority);
+        }
+    }
+}
+
+#[test]
+fn test_quote_mint_matches_mint() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(40);
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(1_000_000);
+
+    let mut leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(2),
+    );
+    leverage_token.management_fee_bps_annual = 200;
+    let supply = 1_000;
+    let now_ts = SECONDS_PER_YEAR;
+
+    let quote = leverage_token
+        .quote_mint(
+            supply,
+            7,
+            now_ts,
+            6,
+            &mango_group,
+            &mango_account,
+            &mango_cache,
+        )
+        .unwrap();
+    assert_eq!(quote.management_fee_tokens, 20);
+    // 7 tokens at 1_000_000 / 1_020, rounded up
+    assert_eq!(quote.collateral, 6_863);
+
+    // A mint accrues the fee into the supply first, then prices the same quantity
+    let fee_tokens = leverage_token
+        .pending_management_fee(supply, now_ts)
+        .unwrap();
+    leverage_token.last_fee_accrual_ts = now_ts;
+    let minted = leverage_token
+        .quote_mint(
+            supply + fee_tokens,
+            7,
+            now_ts,
+            6,
+            &mango_group,
+            &mango_account,
+            &mango_cache,
+        )
+        .unwrap();
+    assert_eq!(minted.management_fee_tokens, 0);
+    assert_eq!(minted.native_price, quote.native_price);
+    assert_eq!(minted.collateral, quote.collateral);
+}
+
+#[test]
+fn test_quote_redeem_matches_redeem() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(40);
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(1_000_000);
+
+    let mut leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(2),
+    );
+    leverage_token.management_fee_bps_annual = 200;
+    let supply = 1_000;
+    let now_ts = SECONDS_PER_YEAR;
+
+    let quote = leverage_token
+        .quote_redeem(
+            supply,
+            7,
+            now_ts,
+            6,
+            &mango_group,
+            &mango_account,
+            &mango_cache,
+        )
+        .unwrap();
+    assert_eq!(quote.management_fee_tokens, 20);
+    // 7 tokens at 1_000_000 / 1_020, rounded down
+    assert_eq!(quote.collateral, 6_862);
+
+    // A redeem accrues the fee into the supply first, then prices the same quantity
+    let fee_tokens = leverage_token
+        .pending_management_fee(supply, now_ts)
+        .unwrap();
+    leverage_token.last_fee_accrual_ts = now_ts;
+    let redeemed = leverage_token
+        .quote_redeem(
+            supply + fee_tokens,
+            7,
+            now_ts,
+            6,
+            &mango_group,
+            &mango_account,
+            &mango_cache,
+        )
+        .unwrap();
+    assert_eq!(redeemed.management_fee_tokens, 0);
+    assert_eq!(redeemed.native_price, quote.native_price);
+    assert_eq!(redeemed.collateral, quote.collateral);
+}
+
+#[test]
+fn test_sweepable_dust() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    let perp_market = Pubkey::new_unique();
+    mango_group.perp_markets[0].perp_market = perp_market;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index = I80F48::from_num(1.5);
+    // The last redeem was rounded down, leaving some dust behind
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.deposits[QUOTE_INDEX] = I80F48::from_num(2.5);
+
+    let mut leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(2),
+    );
+    leverage_token.mango_perp_markets[0] = perp_market;
+    leverage_token.num_perp_markets = 1;
+
+    // 2.5 scaled by the deposit index, rounded down
+    assert_eq!(
+        leverage_token
+            .sweepable_dust(0, &mango_group, &mango_account, &mango_cache)
+            .unwrap(),
+        3
+    );
+
+    // Holders still have a claim on it
+    assert_quasar_err!(
+        leverage_token.sweepable_dust(1, &mango_group, &mango_account, &mango_cache),
+        QuasarErrorCode::TokenSupplyNonzero
+    );
+
+    // Unsettled pnl may still need the deposit
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-1);
+    assert_quasar_err!(
+        leverage_token.sweepable_dust(0, &mango_group, &mango_account, &mango_cache),
+        QuasarErrorCode::OpenPositionRemaining
+    );
+}
+
+#[test]
+fn test_load_mint() {
+    let key = Pubkey::new_unique();
+    let cases = [
+        (mint_data(5, true), spl_token::ID, true),
+        (mint_data(5, false), spl_token::ID, false),
+        // Not a mint at all
+        (vec![0u8; 10], spl_token::ID, false),
+        // Mint data owned by someone else
+        (
+            mint_data(5, true),
+            solana_program::system_program::ID,
+            false,
+        ),
+    ];
+    for (data, owner, valid) in cases.iter() {
+        let mut lamports = 0;
+        let mut data = data.clone();
+        let mint_ai = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            owner,
+            false,
+            0,
+        );
+        if *valid {
+            assert_eq!(load_mint(&mint_ai).unwrap().supply, 5);
+        } else {
+            assert_quasar_err!(load_mint(&mint_ai), QuasarErrorCode::InvalidMint);
+        }
+    }
+}
+
+#[test]
+fn test_supply_correction_detects_drift() {
+    let mut leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(3),
+    );
+    leverage_token.record_mint(1_200_000).unwrap();
+    leverage_token.record_burn(200_000);
+    assert_eq!(leverage_token.tracked_supply, 1_000_000);
+    assert_eq!(
+        leverage_token.supply_correction(1_000_000, 0),
+        SupplyCorrection::None
+    );
+
+    // Within RECONCILE_TOLERANCE_BPS either way
+    assert_eq!(
+        leverage_token.supply_correction(999_950, 0),
+        SupplyCorrection::None
+    );
+    assert_eq!(
+        leverage_token.supply_correction(1_000_100, 0),
+        SupplyCorrection::None
+    );
+
+    // 500 tokens burnt outside the program
+    assert_eq!(
+        leverage_token.supply_correction(999_500, 0),
+        SupplyCorrection::MintToFeeTokenAccount(500)
+    );
+    // 300 tokens minted outside of it, burnt back from the fee token account if it holds them
+    assert_eq!(
+        leverage_token.supply_correction(1_000_300, 1_000),
+        SupplyCorrection::BurnFromFeeTokenAccount(300)
+    );
+    assert_eq!(
+        leverage_token.supply_correction(1_000_300, 299),
+        SupplyCorrection::Flag
+    );
+
+    // Past MAX_RECONCILE_CORRECTION_BPS
+    assert_eq!(
+        leverage_token.supply_correction(1_000_000 - 10_001, 0),
+        SupplyCorrection::Flag
+    );
+    assert_eq!(
+        leverage_token.supply_correction(1_000_000 + 10_001, u64::MAX),
+        SupplyCorrection::Flag
+    );
+    assert_eq!(
+        leverage_token.supply_correction(1_000_000 - 10_000, 0),
+        SupplyCorrection::MintToFeeTokenAccount(10_000)
+    );
+
+    // Anything in circulation the program never minted is drift
+    let mut fresh_token = leverage_token;
+    fresh_token.tracked_supply = 0;
+    assert_eq!(fresh_token.supply_correction(0, 0), SupplyCorrection::None);
+    assert_eq!(fresh_token.supply_correction(1, 1), SupplyCorrection::Flag);
+
+    // A burn never underflows the tracking
+    fresh_token.record_burn(5);
+    assert_eq!(fresh_token.tracked_supply, 0);
+    assert!(fresh_token.record_mint(1).is_ok());
+    fresh_token.tracked_supply = u64::MAX;
+    assert_quasar_err!(fresh_token.record_mint(1), QuasarErrorCode::MathOverflow);
+}
+
+#[test]
+fn test_funding_reaches_nav_once_settled_into_cache() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    mango_group.perp_markets[0].base_lot_size = 1;
+    mango_group.perp_markets[0].quote_lot_size = 1;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(40);
+    let mut mango_account = MangoAccount::zeroed();
+    // 10 long at 40 against 300 of quote: 100 of NAV
+    mango_account.perp_accounts[0].base_position = 10;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-300);
+
+    let leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(2),
+    );
+    let nav = |mango_cache: &MangoCache| {
+        leverage_token
+            .native_price(100, &mango_group, &mango_account, mango_cache)
+            .unwrap()
+    };
+    assert_eq!(nav(&mango_cache), I80F48::from_num(1));
+
+    // Funding accrued on the market but not yet in the cache leaves the NAV alone, settling it
+    // charges longs 2 per base lot
+    mango_cache.perp_market_cache[0].long_funding = I80F48::from_num(2);
+    assert_eq!(nav(&mango_cache), I80F48::from_num(0.8));
+
+    // Settling again at the same index changes nothing
+    assert_eq!(nav(&mango_cache), I80F48::from_num(0.8));
+}
+
+#[test]
+fn test_actual_leverage() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    mango_group.perp_markets[0].base_lot_size = 1;
+    mango_group.perp_markets[0].quote_lot_size = 1;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(40);
+    let mut mango_account = MangoAccount::zeroed();
+
+    let leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(3),
+    );
+    let actual_leverage = |mango_account: &MangoAccount| {
+        leverage_token
+            .actual_leverage(&mango_group, mango_account, &mango_cache)
+            .unwrap()
+    };
+    assert_eq!(actual_leverage(&mango_account), I80F48::from_num(0));
+
+    // 10 long at 40 against 300 of quote: 400 of notional on 100 of NAV
+    mango_account.perp_accounts[0].base_position = 10;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-300);
+    assert_eq!(actual_leverage(&mango_account), I80F48::from_num(4));
+
+    // 5 short at 40 with 300 of quote: -200 of notional on 100 of NAV
+    mango_account.perp_accounts[0].base_position = -5;
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(300);
+    assert_eq!(actual_leverage(&mango_account), I80F48::from_num(-2));
+}
+
+#[test]
+fn test_rebalance_backoff_grows_with_failures() {
+    let mut leverage_token = leverage_token(
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        I80F48::from_num(3),
+    );
+    leverage_token.check_rebalance_backoff(0).unwrap();
+
+    let mut now_ts = 1_000;
+    let mut backoffs = vec![];
+    for _ in 0..10 {
+        leverage_token.record_rebalance_outcome(true, now_ts);
+        let backoff = leverage_token.next_rebalance_allowed_ts - now_ts;
+        assert_quasar_err!(
+            leverage_token.check_rebalance_backoff(now_ts + backoff - 1),
+            QuasarErrorCode::RebalanceBackoff
+        );
+        now_ts += backoff;
+        leverage_token.check_rebalance_backoff(now_ts).unwrap();
+        backoffs.push(backoff);
+    }
+    assert_eq!(leverage_token.rebalance_fail_count, 10);
+    assert_eq!(
+        backoffs,
+        vec![
+            REBALANCE_BACKOFF_BASE_SECS,
+            60,
+            120,
+            240,
+            480,
+            960,
+            1_920,
+            MAX_REBALANCE_BACKOFF_SECS,
+            MAX_REBALANCE_BACKOFF_SECS,
+            MAX_REBALANCE_BACKOFF_SECS,
+        ]
+    );
+
+    // Stays capped however long the market stays broken
+    leverage_token.rebalance_fail_count = u64::MAX - 1;
+    leverage_token.record_rebalance_outcome(true, now_ts);
+    assert_eq!(
+        leverage_token.next_rebalance_allowed_ts,
+        now_ts + MAX_REBALANCE_BACKOFF_SECS
+    );
+
+    // One rebalance reaching its target resets it
+    leverage_token.record_rebalance_outcome(false, now_ts);
+    assert_eq!(leverage_token.rebalance_fail_count, 0);
+    leverage_token.check_rebalance_backoff(now_ts).unwrap();
+}
+
+#[test]
+fn test_check_rebalance_order() {
+    assert_eq!(
+        check_rebalance_order(0, 0).unwrap(),
+        RebalanceOrderType::ImmediateOrCancel
+    );
+    assert_eq!(
+        check_rebalance_order(1, 0).unwrap(),
+        RebalanceOrderType::Market
+    );
+    assert_eq!(
+        check_rebalance_order(2, MAX_REBALANCE_ORDER_OFFSET_BPS).unwrap(),
+        RebalanceOrderType::PostOnly
+    );
+
+    assert_quasar_err!(check_rebalance_order(3, 0), QuasarErrorCode::InvalidParam);
+    // Only post only orders rest away from the oracle price, and they have to
+    assert_quasar_err!(check_rebalance_order(0, 10), QuasarErrorCode::InvalidParam);
+    assert_quasar_err!(check_rebalance_order(2, 0), QuasarErrorCode::InvalidParam);
+    assert_quasar_err!(
+        check_rebalance_order(2, MAX_REBALANCE_ORDER_OFFSET_BPS + 1),
+        QuasarErrorCode::InvalidParam
+    );
+}
+
+#[test]
+fn test_rebalance_order_type_is_encoded_in_place_perp_order() {
+    // Decode the order the way Mango does from the data `Rebalance` sends it
+    let placed_order = |leverage_token: &LeverageToken, side: Side| {
+        let (price, order_type) = leverage_token.rebalance_order(10_000, side);
+        let data = MangoInstruction::PlacePerpOrder {
+            price,
+            quantity: 5,
+            client_order_id: 0,
+            side,
+            order_type,
+        }
+        .pack();
+        match MangoInstruction::unpack(&data) {
+            Some(MangoInstruction::PlacePerpOrder {
+                price,
+                side: placed_side,
+                order_type,
+                ..
+            }) => {
+                assert!(placed_side == side);
+                (price, order_type)
+            }
+            _ => panic!("not a PlacePerpOrder"),
+        }
+    };
+
+    // Tokens that never set one trade immediate or cancel at the oracle price
+    let mut leverage_token = LeverageToken::zeroed();
+    for &side in &[Side::Bid, Side::Ask] {
+        let (price, order_type) = placed_order(&leverage_token, side);
+        assert_eq!(price, 10_000);
+        assert!(order_type == OrderType::ImmediateOrCancel);
+    }
+
+    leverage_token.rebalance_order_type = RebalanceOrderType::Market.into();
+    let (price, order_type) = placed_order(&leverage_token, Side::Ask);
+    assert_eq!(price, 10_000);
+    assert!(order_type == OrderType::Market);
+
+    // Post only orders rest on the passive side of the oracle price
+    leverage_token.rebalance_order_type = RebalanceOrderType::PostOnly.into();
+    leverage_token.rebalance_order_offset_bps = 25;
+    let (price, order_type) = placed_order(&leverage_token, Side::Bid);
+    assert_eq!(price, 9_975);
+    assert!(order_type == OrderType::PostOnly);
+    let (price, order_type) = placed_order(&leverage_token, Side::Ask);
+    assert_eq!(price, 10_025);
+    assert!(order_type == OrderType::PostOnly);
+
+    // Unknown types fall back to the default rather than placing something unexpected
+    leverage_token.rebalance_order_type = 7;
+    let (_, order_type) = placed_order(&leverage_token, Side::Bid);
+    assert!(order_type == OrderType::ImmediateOrCancel);
+}
@@ -0,0 +1,242 @@
+use assert_matches::*;
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use mango::state::{MangoAccount, MangoCache, MangoGroup, QUOTE_INDEX, ZERO_I80F48};
+use quasar::{
+    error::{QuasarError, QuasarErrorCode},
+    quasar_group_signer,
+    state::QuasarGroup,
+    utils::{
+        check_mango_cache_fresh, gen_leverage_token_mint_key, gen_mango_account_key,
+        gen_signer_key, get_mango_account_equity, get_rebalance_net_asset_value,
+        LEVERAGE_TOKEN_MINT_SEED, MANGO_ACCOUNT_SEED,
+    },
+};
+use solana_program::pubkey::Pubkey;
+
+macro_rules! assert_quasar_err {
+    ($result:expr, $code:pat) => {
+        assert_matches!(
+            $result,
+            Err(QuasarError::QuasarErrorCode {
+                quasar_error_code: $code,
+                ..
+            })
+        )
+    };
+}
+
+#[test]
+fn test_mango_account_key() {
+    let program_id = Pubkey::new_unique();
+    let quasar_group = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+
+    let (key, bump) = gen_mango_account_key(&quasar_group, &mint, &program_id);
+    assert_eq!(
+        gen_mango_account_key(&quasar_group, &mint, &program_id),
+        (key, bump)
+    );
+    assert_eq!(
+        Pubkey::create_program_address(
+            &[
+                quasar_group.as_ref(),
+                mint.as_ref(),
+                MANGO_ACCOUNT_SEED,
+                &[bump]
+            ],
+            &program_id
+        )
+        .unwrap(),
+        key
+    );
+
+    // An account of another token or group doesn't match
+    let (other_token_key, _) =
+        gen_mango_account_key(&quasar_group, &Pubkey::new_unique(), &program_id);
+    assert_ne!(other_token_key, key);
+    let (other_group_key, _) = gen_mango_account_key(&Pubkey::new_unique(), &mint, &program_id);
+    assert_ne!(other_group_key, key);
+    assert_ne!(Pubkey::new_unique(), key);
+}
+
+#[test]
+fn test_leverage_token_mint_key() {
+    let program_id = Pubkey::new_unique();
+    let quasar_group = Pubkey::new_unique();
+    let base_mint = Pubkey::new_unique();
+    let token_index = 3;
+
+    let (key, bump) =
+        gen_leverage_token_mint_key(&quasar_group, &base_mint, token_index, &program_id);
+    assert_eq!(
+        gen_leverage_token_mint_key(&quasar_group, &base_mint, token_index, &program_id),
+        (key, bump)
+    );
+    assert_eq!(
+        Pubkey::create_program_address(
+            &[
+                quasar_group.as_ref(),
+                base_mint.as_ref(),
+                &[token_index],
+                LEVERAGE_TOKEN_MINT_SEED,
+                &[bump]
+            ],
+            &program_id
+        )
+        .unwrap(),
+        key
+    );
+
+    // Another slot, base token or group is another mint
+    for other_key in [
+        gen_leverage_token_mint_key(&quasar_group, &base_mint, token_index + 1, &program_id),
+        gen_leverage_token_mint_key(
+            &quasar_group,
+            &Pubkey::new_unique(),
+            token_index,
+            &program_id,
+        ),
+        gen_leverage_token_mint_key(&Pubkey::new_unique(), &base_mint, token_index, &program_id),
+    ]
+    .iter()
+    .map(|(other_key, _)| other_key)
+    {
+        assert_ne!(*other_key, key);
+    }
+}
+
+#[test]
+fn test_equity_counts_unsettled_pnl() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(40);
+    let mut mango_account = MangoAccount::zeroed();
+
+    // A closed perp position whose 500 of profit was never settled into quote deposits. If
+    // equity only counted deposits, mints would be priced as if the token had no backing
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(500);
+    assert_eq!(
+        get_mango_account_equity(&mango_group, &mango_account, &mango_cache).unwrap(),
+        I80F48::from_num(500)
+    );
+
+    // Unsettled losses are taken off too
+    mango_account.perp_accounts[0].quote_position = I80F48::from_num(-200);
+    assert_eq!(
+        get_mango_account_equity(&mango_group, &mango_account, &mango_cache).unwrap(),
+        I80F48::from_num(-200)
+    );
+}
+
+#[test]
+fn test_compounding_rebalance_levers_settled_funding() {
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 1;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.price_cache[0].price = I80F48::from_num(40);
+    mango_cache.root_bank_cache[0].deposit_index = I80F48::from_num(1);
+    mango_cache.root_bank_cache[QUOTE_INDEX].deposit_index = I80F48::from_num(1);
+    let mut mango_account = MangoAccount::zeroed();
+    mango_account.deposits[0] = I80F48::from_num(25);
+
+    // 2_000 of collateral on deposit, and 300 of positive funding the token's perp leg earned
+    // settled in next to it
+    mango_account.deposits[QUOTE_INDEX] = I80F48::from_num(2_300);
+    let settled_pnl = I80F48::from_num(300);
+    let (idle_nav, _) =
+        get_rebalance_net_asset_value(&mango_group, &mango_account, &mango_cache, ZERO_I80F48)
+            .unwrap();
+    assert_eq!(idle_nav, I80F48::from_num(1_000));
+
+    // Compounding sizes the rebalance on the settled funding, and nothing else the account
+    // has on deposit, the target exposure at 3x grows by 900
+    let (nav, perp_asset_value) =
+        get_rebalance_net_asset_value(&mango_group, &mango_account, &mango_cache, settled_pnl)
+            .unwrap();
+    assert_eq!(nav, I80F48::from_num(1_300));
+    assert_eq!(perp_asset_value, I80F48::from_num(0));
+    assert_eq!(
+        nav * I80F48::from_num(3) - idle_nav * I80F48::from_num(3),
+        I80F48::from_num(900)
+    );
+
+    // Settled losses take as much off
+    let (nav, _) = get_rebalance_net_asset_value(
+        &mango_group,
+        &mango_account,
+        &mango_cache,
+        I80F48::from_num(-200),
+    )
+    .unwrap();
+    assert_eq!(nav, I80F48::from_num(800));
+}
+
+#[test]
+fn test_stale_mango_cache_is_rejected() {
+    let now_ts = 1_000;
+    let mut mango_group = MangoGroup::zeroed();
+    mango_group.num_oracles = 2;
+    mango_group.valid_interval = 10;
+    let mut mango_cache = MangoCache::zeroed();
+    mango_cache.root_bank_cache[QUOTE_INDEX].last_update = now_ts - 10;
+    let mut mango_account = MangoAccount::zeroed();
+
+    // Only the quote root bank matters to an account without positions
+    check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts).unwrap();
+    mango_cache.root_bank_cache[QUOTE_INDEX].last_update = now_ts - 11;
+    assert_quasar_err!(
+        check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts),
+        QuasarErrorCode::MangoCacheStale
+    );
+    mango_cache.root_bank_cache[QUOTE_INDEX].last_update = now_ts;
+
+    // A perp position on market 1 needs its price and perp market entries fresh, market 0
+    // can be as old as it likes
+    mango_account.perp_accounts[1].base_position = 5;
+    mango_cache.price_cache[1].last_update = now_ts - 20;
+    mango_cache.perp_market_cache[1].last_update = now_ts;
+    assert_quasar_err!(
+        check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts),
+        QuasarErrorCode::MangoCacheStale
+    );
+    mango_cache.price_cache[1].last_update = now_ts;
+    check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts).unwrap();
+
+    mango_cache.perp_market_cache[1].last_update = now_ts - 20;
+    assert_quasar_err!(
+        check_mango_cache_fresh(&mango_group, &mango_account, &mango_cache, now_ts),
+        QuasarErrorCode::MangoCacheStale
+    );
+}
+
+#[test]
+fn test_quasar_group_signer_matches_stored_signer() {
+    let program_id = Pubkey::new_unique();
+    let group = Pubkey::new_unique();
+    let nonce = (0..=u8::MAX as u64)
+        .rev()
+        .find(|nonce| gen_signer_key(*nonce, &group, &program_id).is_ok())
+        .unwrap();
+
+    // What `InitQuasarGroup` checks the signer against and stores
+    let mut quasar_group = QuasarGroup::zeroed();
+    quasar_group.signer_nonce = nonce;
+    quasar_group.signer_key = gen_signer_key(nonce, &group, &program_id).unwrap();
+
+    let signer = quasar_group_signer(&program_id, &group, nonce).unwrap();
+    assert_eq!(signer, quasar_group.signer_key);
+    assert_eq!(
+        Pubkey::create_program_address(
+            &quasar_group.signer_seeds_parts(&group).as_seeds(),
+            &program_id
+        )
+        .unwrap(),
+        signer
+    );
+    assert_ne!(
+        quasar_group_signer(&Pubkey::new_unique(), &group, nonce).ok(),
+        Some(signer)
+    );
+}